@@ -0,0 +1,198 @@
+use std::{io::{self, BufRead, BufReader, Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, path::Path, sync::Arc, thread, time::Duration, error::Error};
+use serde_json::{json, Value};
+use crate::{daemon::{Runner, TriggerError, Triggered}, status};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Requests are small, so anything bigger than this isn't one of ours
+const MAX_REQUEST: u64 = 64 * 1024;
+
+// The daemon's JSON API, for dashboards and home automation:
+//   GET  /status                  runs in progress, the last few finished and each profile's state
+//   GET  /history[?profile=NAME]  finished runs, newest first
+//   POST /trigger?profile=NAME    starts a backup of the profile now, or queues it until it can run
+// With a token, every request needs an `Authorization: Bearer <token>` header. Browsers are only let through from
+// the API's own origin, so a page elsewhere can't trigger backups through someone's browser
+pub struct Api {
+    pub runner: Arc<Runner>,
+    pub status_file: std::path::PathBuf,
+    pub token: Option<String>,
+}
+
+pub fn parse_listen(input: &str) -> Result<SocketAddr, String> {
+    input.parse().map_err(|_| format!("Invalid address '{}', expected e.g. 127.0.0.1:9753", input))
+}
+
+// Serves the API on its own thread, one more per connection
+pub fn serve(api: Api, address: SocketAddr) -> Result<thread::JoinHandle<()>, Box<dyn Error>> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    let api = Arc::new(api);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let api = api.clone();
+            thread::spawn(move || {
+                if let Err(e) = api.handle(stream) {
+                    eprintln!("Warning: API request failed: {}", e);
+                }
+            });
+        }
+    }))
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    authorization: Option<String>,
+    host: Option<String>,
+    origin: Option<String>,
+}
+
+impl Request {
+    fn read(stream: &TcpStream) -> io::Result<Request> {
+        let mut reader = BufReader::new(stream.take(MAX_REQUEST));
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed request line"));
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query.split('&').filter_map(|pair| pair.split_once('=')).map(|(key, value)| (decode(key), decode(value))).collect();
+        let mut request = Request { method: method.to_string(), path: path.to_string(), query, authorization: None, host: None, origin: None };
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "authorization" => request.authorization = Some(value.trim().to_string()),
+                    "host" => request.host = Some(value.trim().to_string()),
+                    "origin" => request.origin = Some(value.trim().to_string()),
+                    "content-length" => length = value.trim().parse().unwrap_or(0),
+                    _ => {},
+                }
+            }
+        }
+        // Nothing takes a body, but it's read so the client isn't cut off mid-send
+        io::copy(&mut reader.take(length), &mut io::sink())?;
+        Ok(request)
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+impl Api {
+    fn handle(&self, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let (code, body) = match Request::read(&stream) {
+            Ok(request) => self.respond(&request),
+            Err(e) => (400, json!({ "error": e.to_string() })),
+        };
+        let body = serde_json::to_string_pretty(&body)?;
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            code,
+            reason(code),
+            body.len(),
+            body
+        )?;
+        Ok(())
+    }
+
+    fn respond(&self, request: &Request) -> (u16, Value) {
+        // Only browsers send an Origin, and a page served from somewhere else has no business here
+        if let Some(origin) = &request.origin {
+            if request.host.as_ref().is_none_or(|host| *origin != format!("http://{}", host)) {
+                return (403, json!({ "error": format!("Requests from {} aren't allowed", origin) }));
+            }
+        }
+        if let Some(token) = &self.token {
+            // Compared by hash, so how long it takes says nothing about how much of the token was right
+            let given = request.authorization.as_deref().unwrap_or_default();
+            if blake3::hash(given.as_bytes()) != blake3::hash(format!("Bearer {}", token).as_bytes()) {
+                return (401, json!({ "error": "Missing or wrong API token" }));
+            }
+        }
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/status") => match read_status(&self.status_file) {
+                Ok(status) => {
+                    let profiles: serde_json::Map<String, Value> = self.runner.profiles()
+                        .map(|name| (name.clone(), json!(self.runner.state(name))))
+                        .collect();
+                    (200, json!({ "running": status.running, "runs": status.runs, "profiles": profiles }))
+                },
+                Err(e) => (500, json!({ "error": e.to_string() })),
+            },
+            ("GET", "/history") => match read_status(&self.status_file) {
+                Ok(status) => {
+                    let runs: Vec<status::Run> = status.runs.into_iter().filter(|run| request.param("profile").is_none_or(|profile| run.profile.as_deref() == Some(profile))).collect();
+                    (200, json!({ "runs": runs }))
+                },
+                Err(e) => (500, json!({ "error": e.to_string() })),
+            },
+            ("POST", "/trigger") => {
+                let Some(profile) = request.param("profile") else {
+                    return (400, json!({ "error": "Missing profile, e.g. /trigger?profile=home" }));
+                };
+                match self.runner.trigger(profile) {
+                    Ok(Triggered::Started(pid)) => (202, json!({ "profile": profile, "started": true, "pid": pid })),
+                    Ok(Triggered::Queued) => (202, json!({ "profile": profile, "started": false, "queued": true })),
+                    Err(TriggerError::Unknown) => (404, json!({ "error": format!("No profile '{}'", profile) })),
+                    Err(TriggerError::Queued) => (409, json!({ "error": format!("Profile '{}' is already queued", profile) })),
+                    Err(TriggerError::Failed(e)) => (500, json!({ "error": e })),
+                }
+            },
+            (_, "/status" | "/history" | "/trigger") => (405, json!({ "error": format!("{} isn't allowed on {}", request.method, request.path) })),
+            _ => (404, json!({ "error": format!("No such endpoint {}", request.path) })),
+        }
+    }
+}
+
+// The status file, or nothing yet if no run has written it
+fn read_status(path: &Path) -> Result<status::Status, Box<dyn Error>> {
+    match status::load(path) {
+        Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => Ok(status::Status::default()),
+        result => result,
+    }
+}
+
+// Undoes the percent-encoding of a query string key or value, with `+` for a space
+fn decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = encoded.get(i + 1..i + 3).filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()));
+        match (bytes[i], hex) {
+            (b'%', Some(hex)) => {
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 2;
+            },
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    }
+}
@@ -1,8 +1,441 @@
-use std::{path::PathBuf, error::Error};
+use std::{collections::HashMap, env, fs, io::{Read, Seek, SeekFrom}, path::Path, thread, time::Duration, error::Error};
+use base64::Engine;
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::Digest;
+use crate::{backend::{self, Backend, ObjectInfo}, hash, http::{self, HttpOptions}, window};
 
-// TODO: File for B2 utils (backblaze/AWS support after compression :))
+// Accounts in every region, EU included, are authorized here, which then hands out the region's own URLs
+const API_URL: &str = "https://api.backblazeb2.com";
+// B2 rejects parts smaller than this (other than the last)
+const MIN_PART_SIZE: u64 = 5000000;
 
-pub fn _try_upload_archive(archive_path: PathBuf, _remote_path: String, _remote_creds: String, _b2_or_aws: String) -> Result<PathBuf, Box<dyn Error>> {
-    // TODO: Implement
-    Ok(archive_path)
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Authorization {
+    account_id: String,
+    authorization_token: String,
+    api_url: String,
+    download_url: String,
+    recommended_part_size: u64,
+    allowed: Allowed,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Allowed {
+    bucket_id: Option<String>,
+    bucket_name: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+    name_prefix: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    bucket_id: String,
+    #[serde(default)]
+    lifecycle_rules: Vec<LifecycleRule>,
+}
+
+// Server-side expiry for files under a prefix: hidden this many days after upload, then deleted
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LifecycleRule {
+    pub file_name_prefix: String,
+    pub days_from_uploading_to_hiding: Option<u32>,
+    pub days_from_hiding_to_deleting: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct BucketList {
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrl {
+    upload_url: String,
+    authorization_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LargeFile {
+    file_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileInfo {
+    file_id: String,
+    file_name: String,
+    content_length: u64,
+    #[serde(default)]
+    file_info: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileList {
+    files: Vec<FileInfo>,
+    next_file_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    code: String,
+    message: String,
+}
+
+// Backblaze B2 native API client. Credentials are read from ATHENA_B2_KEY_ID / ATHENA_B2_KEY, falling back to
+// B2_APPLICATION_KEY_ID / B2_APPLICATION_KEY
+pub struct B2Backend {
+    bucket_name: String,
+    bucket_id: String,
+    auth: Authorization,
+    agent: ureq::Agent,
+    stall_warning: Duration,
+}
+
+impl B2Backend {
+    pub fn connect(bucket_name: &str, http: &HttpOptions) -> Result<B2Backend, Box<dyn Error>> {
+        let key_id = env::var("ATHENA_B2_KEY_ID").or_else(|_| env::var("B2_APPLICATION_KEY_ID")).map_err(|_| "ATHENA_B2_KEY_ID is not set")?;
+        let key = env::var("ATHENA_B2_KEY").or_else(|_| env::var("B2_APPLICATION_KEY")).map_err(|_| "ATHENA_B2_KEY is not set")?;
+        let agent = http.agent()?;
+
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", key_id, key));
+        let request = agent
+            .get(&format!("{}/b2api/v2/b2_authorize_account", http.endpoint.as_deref().unwrap_or(API_URL)))
+            .set("Authorization", &format!("Basic {}", credentials));
+        let auth: Authorization = http::send(&request, None)
+            .map_err(|e| format!("Failed to authorize with B2, check ATHENA_B2_KEY_ID and ATHENA_B2_KEY: {}", api_error(*e)))?
+            .into_json()?;
+
+        // Keys restricted to a single bucket can't list buckets, but already tell us its id
+        let bucket_id = match (&auth.allowed.bucket_id, &auth.allowed.bucket_name) {
+            (Some(id), Some(name)) if name == bucket_name => id.clone(),
+            (Some(_), Some(name)) => return Err(format!("Application key is restricted to bucket '{}'", name).into()),
+            _ => {
+                let request = agent.post(&format!("{}/b2api/v2/b2_list_buckets", auth.api_url)).set("Authorization", &auth.authorization_token);
+                let list: BucketList = http::send(&request, Some(&json!({ "accountId": auth.account_id, "bucketName": bucket_name })))
+                    .map_err(|e| api_error(*e))?
+                    .into_json()?;
+                match list.buckets.into_iter().next() {
+                    Some(bucket) => bucket.bucket_id,
+                    None => return Err(format!("Bucket '{}' does not exist", bucket_name).into()),
+                }
+            },
+        };
+
+        Ok(B2Backend { bucket_name: bucket_name.to_string(), bucket_id, auth, agent, stall_warning: http.stall_warning() })
+    }
+
+    fn api(&self, endpoint: &str, body: serde_json::Value) -> Result<ureq::Response, Box<dyn Error>> {
+        let request = self.agent.post(&format!("{}/b2api/v2/{}", self.auth.api_url, endpoint)).set("Authorization", &self.auth.authorization_token);
+        http::send(&request, Some(&body)).map_err(|e| api_error(*e))
+    }
+
+    pub fn lifecycle_rules(&self) -> Result<Vec<LifecycleRule>, Box<dyn Error>> {
+        let list: BucketList = self.api("b2_list_buckets", json!({ "accountId": self.auth.account_id, "bucketId": self.bucket_id }))?.into_json()?;
+        Ok(list.buckets.into_iter().next().map(|bucket| bucket.lifecycle_rules).unwrap_or_default())
+    }
+
+    // Replaces the rule for exactly this prefix, leaving rules for other prefixes alone. Files are deleted a day
+    // after they're hidden. No `keep_days` removes the rule. Returns the bucket's rules afterwards
+    pub fn set_lifecycle(&self, prefix: &str, keep_days: Option<u32>) -> Result<Vec<LifecycleRule>, Box<dyn Error>> {
+        let mut rules: Vec<LifecycleRule> = self.lifecycle_rules()?.into_iter().filter(|rule| rule.file_name_prefix != prefix).collect();
+        if let Some(days) = keep_days {
+            rules.push(LifecycleRule {
+                file_name_prefix: prefix.to_string(),
+                days_from_uploading_to_hiding: Some(days),
+                days_from_hiding_to_deleting: Some(1),
+            });
+        }
+        self.api("b2_update_bucket", json!({ "accountId": self.auth.account_id, "bucketId": self.bucket_id, "lifecycleRules": rules }))?;
+        Ok(rules)
+    }
+
+    // Lists file names from `start` onwards, a page at a time
+    fn list_page(&self, prefix: &str, start: Option<&str>, count: u32) -> Result<FileList, Box<dyn Error>> {
+        Ok(self
+            .api("b2_list_file_names", json!({ "bucketId": self.bucket_id, "prefix": prefix, "startFileName": start, "maxFileCount": count }))?
+            .into_json()?)
+    }
+
+    fn upload_small(&self, path: &Path, key: &str, info: &[(String, String)], len: u64, progress: &ProgressBar) -> Result<(), Box<dyn Error>> {
+        let sha1 = sha1_of(&mut fs::File::open(path)?)?;
+        self.send_file(key, info, len, &sha1, &mut progress.wrap_read(fs::File::open(path)?))
+    }
+
+    // Object metadata goes in X-Bz-Info-* headers, percent-encoded like file names
+    fn send_file(&self, key: &str, info: &[(String, String)], len: u64, sha1: &str, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        let target: UploadUrl = self.api("b2_get_upload_url", json!({ "bucketId": self.bucket_id }))?.into_json()?;
+        let mut request = self
+            .agent
+            .post(&target.upload_url)
+            .set("Authorization", &target.authorization_token)
+            .set("X-Bz-File-Name", &encode_file_name(key))
+            .set("Content-Type", "b2/x-auto")
+            .set("Content-Length", &len.to_string())
+            .set("X-Bz-Content-Sha1", sha1);
+        for (name, value) in info {
+            request = request.set(&format!("X-Bz-Info-{}", name), &encode_file_name(value));
+        }
+        let watch = http::StallWatch::start(format!("to {}", self.url(key)), self.stall_warning);
+        request.send(watch.wrap(reader)).map_err(api_error)?;
+        Ok(())
+    }
+
+    fn start_large_file(&self, key: &str, info: &[(String, String)]) -> Result<LargeFile, Box<dyn Error>> {
+        let file_info: HashMap<&str, &str> = info.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        Ok(self
+            .api("b2_start_large_file", json!({ "bucketId": self.bucket_id, "fileName": key, "contentType": "b2/x-auto", "fileInfo": file_info }))?
+            .into_json()?)
+    }
+
+    fn send_part(&self, target: &UploadUrl, key: &str, part_number: usize, size: u64, sha1: &str, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        let watch = http::StallWatch::start(format!("part {} to {}", part_number, self.url(key)), self.stall_warning);
+        self.agent
+            .post(&target.upload_url)
+            .set("Authorization", &target.authorization_token)
+            .set("X-Bz-Part-Number", &part_number.to_string())
+            .set("Content-Length", &size.to_string())
+            .set("X-Bz-Content-Sha1", sha1)
+            .send(watch.wrap(reader))
+            .map_err(api_error)?;
+        Ok(())
+    }
+
+    fn part_size(&self) -> u64 {
+        std::cmp::max(self.auth.recommended_part_size, MIN_PART_SIZE)
+    }
+
+    // Parts of a streamed upload can't be re-read, so they're held in memory and retried individually
+    fn send_part_with_retry(&self, file_id: &str, key: &str, part_number: usize, data: &[u8]) -> Result<String, Box<dyn Error>> {
+        let sha1 = sha1_of(&mut &data[..])?;
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .api("b2_get_upload_part_url", json!({ "fileId": file_id }))
+                .and_then(|response| Ok(response.into_json::<UploadUrl>()?))
+                .and_then(|target| self.send_part(&target, key, part_number, data.len() as u64, &sha1, &mut &data[..]));
+            match result {
+                Ok(()) => return Ok(sha1),
+                Err(_) if attempt < backend::UPLOAD_ATTEMPTS => {
+                    thread::sleep(Duration::from_secs(2_u64.pow(attempt - 1)));
+                    attempt += 1;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn stream_parts(&self, file_id: &str, key: &str, first: Vec<u8>, reader: &mut dyn Read) -> Result<(Vec<String>, u64), Box<dyn Error>> {
+        let part_size = self.part_size() as usize;
+        let mut part_sha1s = Vec::new();
+        let mut total = 0;
+        // Anything read past the first part's size carries over into the next part
+        let mut buf = first;
+        let mut carry = buf.split_off(part_size);
+        while !buf.is_empty() {
+            window::wait(&|| false);
+            part_sha1s.push(self.send_part_with_retry(file_id, key, part_sha1s.len() + 1, &buf)?);
+            total += buf.len() as u64;
+            buf = std::mem::take(&mut carry);
+            let filled = buf.len();
+            buf.resize(part_size, 0);
+            let read = backend::read_full(reader, &mut buf[filled..])?;
+            buf.truncate(filled + read);
+        }
+        Ok((part_sha1s, total))
+    }
+
+    fn upload_large(&self, path: &Path, key: &str, info: &[(String, String)], len: u64, progress: &ProgressBar) -> Result<(), Box<dyn Error>> {
+        let large_file = self.start_large_file(key, info)?;
+        match self.upload_parts(path, &large_file.file_id, key, len, progress) {
+            Ok(part_sha1s) => {
+                self.api("b2_finish_large_file", json!({ "fileId": large_file.file_id, "partSha1Array": part_sha1s }))?;
+                Ok(())
+            },
+            Err(e) => {
+                // Don't leave unfinished parts around to be billed for
+                let _ = self.api("b2_cancel_large_file", json!({ "fileId": large_file.file_id }));
+                Err(e)
+            },
+        }
+    }
+
+    fn upload_parts(&self, path: &Path, file_id: &str, key: &str, len: u64, progress: &ProgressBar) -> Result<Vec<String>, Box<dyn Error>> {
+        let part_size = self.part_size();
+        let mut target: UploadUrl = self.api("b2_get_upload_part_url", json!({ "fileId": file_id }))?.into_json()?;
+        let mut file = fs::File::open(path)?;
+        let mut part_sha1s = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            // Parts already sent are kept while the upload waits for the backup window
+            window::wait(&|| false);
+            let size = std::cmp::min(part_size, len - offset);
+            // Parts are read twice, once to hash and once to send, rather than holding them in memory
+            file.seek(SeekFrom::Start(offset))?;
+            let sha1 = sha1_of(&mut (&mut file).take(size))?;
+            // A part that fails, e.g. one stuck until --io-timeout, is sent again to a fresh upload URL rather than
+            // starting the whole file over
+            let mut attempt = 1;
+            loop {
+                file.seek(SeekFrom::Start(offset))?;
+                progress.set_position(offset);
+                match self.send_part(&target, key, part_sha1s.len() + 1, size, &sha1, &mut progress.wrap_read((&mut file).take(size))) {
+                    Ok(()) => break,
+                    Err(_) if attempt < backend::UPLOAD_ATTEMPTS => {
+                        thread::sleep(Duration::from_secs(2_u64.pow(attempt - 1)));
+                        attempt += 1;
+                        target = self.api("b2_get_upload_part_url", json!({ "fileId": file_id }))?.into_json()?;
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+            part_sha1s.push(sha1);
+            offset += size;
+        }
+        Ok(part_sha1s)
+    }
+}
+
+impl Backend for B2Backend {
+    fn url(&self, key: &str) -> String {
+        format!("b2://{}/{}", self.bucket_name, key)
+    }
+
+    // B2 verifies the content against the SHA1 sent with each upload, so a successful response means it arrived intact
+    fn upload(&self, path: &Path, key: &str, info: &[(String, String)], progress: &ProgressBar) -> Result<(), Box<dyn Error>> {
+        let len = path.metadata()?.len();
+        if len > self.auth.recommended_part_size {
+            self.upload_large(path, key, info, len, progress)
+        } else {
+            self.upload_small(path, key, info, len, progress)
+        }
+    }
+
+    // Large files need at least two parts, so a little over one part is buffered to find out whether the stream
+    // fits in a single upload instead
+    fn upload_stream(&self, reader: &mut dyn Read, key: &str, info: &[(String, String)], progress: &ProgressBar) -> Result<u64, Box<dyn Error>> {
+        let mut reader = progress.wrap_read(reader);
+        let mut first = vec![0; self.part_size() as usize + 1];
+        let read = backend::read_full(&mut reader, &mut first)?;
+        first.truncate(read);
+        if read as u64 <= self.part_size() {
+            window::wait(&|| false);
+            let sha1 = sha1_of(&mut &first[..])?;
+            self.send_file(key, info, read as u64, &sha1, &mut &first[..])?;
+            return Ok(read as u64);
+        }
+
+        let large_file = self.start_large_file(key, info)?;
+        match self.stream_parts(&large_file.file_id, key, first, &mut reader) {
+            Ok((part_sha1s, total)) => {
+                self.api("b2_finish_large_file", json!({ "fileId": large_file.file_id, "partSha1Array": part_sha1s }))?;
+                Ok(total)
+            },
+            Err(e) => {
+                let _ = self.api("b2_cancel_large_file", json!({ "fileId": large_file.file_id }));
+                Err(e)
+            },
+        }
+    }
+
+    fn download(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let request = self
+            .agent
+            .get(&format!("{}/file/{}/{}", self.auth.download_url, self.bucket_name, encode_file_name(key)))
+            .set("Authorization", &self.auth.authorization_token);
+        let response = http::send(&request, None).map_err(|e| api_error(*e))?;
+        let mut data = Vec::new();
+        response.into_reader().read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn download_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let request = self
+            .agent
+            .get(&format!("{}/file/{}/{}", self.auth.download_url, self.bucket_name, encode_file_name(key)))
+            .set("Authorization", &self.auth.authorization_token)
+            .set("Range", &format!("bytes={}-{}", start, end - 1));
+        let response = http::send(&request, None).map_err(|e| api_error(*e))?;
+        let mut data = Vec::new();
+        response.into_reader().take(end - start).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn info(&self, key: &str) -> Result<ObjectInfo, Box<dyn Error>> {
+        match self.list_page(key, Some(key), 1)?.files.into_iter().find(|file| file.file_name == key) {
+            Some(file) => Ok(file.file_info.into_iter().collect()),
+            None => Err(format!("{} does not exist", self.url(key)).into()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+        let mut objects = Vec::new();
+        let mut start = None;
+        loop {
+            let page = self.list_page(prefix, start.as_deref(), 10000)?;
+            objects.extend(page.files.into_iter().map(|file| (file.file_name, file.content_length)));
+            match page.next_file_name {
+                Some(next) => start = Some(next),
+                None => return Ok(objects),
+            }
+        }
+    }
+
+    // Deletes the latest version of the file, which is the only one athena ever creates
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let file = match self.list_page(key, Some(key), 1)?.files.into_iter().find(|file| file.file_name == key) {
+            Some(file) => file,
+            None => return Err(format!("{} does not exist", self.url(key)).into()),
+        };
+        self.api("b2_delete_file_version", json!({ "fileName": file.file_name, "fileId": file.file_id }))?;
+        Ok(())
+    }
+
+    fn check_upload(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let allowed = &self.auth.allowed;
+        if !allowed.capabilities.iter().any(|capability| capability == "writeFiles") {
+            return Err("Application key doesn't have the writeFiles capability needed to upload".into());
+        }
+        if let Some(prefix) = allowed.name_prefix.as_deref().filter(|prefix| !key.starts_with(prefix)) {
+            return Err(format!("Application key is restricted to files starting with '{}', but archives go under '{}'", prefix, key).into());
+        }
+        // Asking for somewhere to upload to catches anything else in the way, e.g. a bucket the key can't write to
+        self.api("b2_get_upload_url", json!({ "bucketId": self.bucket_id }))?;
+        Ok(())
+    }
+}
+
+fn sha1_of<R: Read>(reader: &mut R) -> Result<String, Box<dyn Error>> {
+    let mut hasher = sha1::Sha1::new();
+    std::io::copy(reader, &mut hasher)?;
+    Ok(hash::to_hex(&hasher.finalize()))
+}
+
+// B2 file names are sent percent-encoded, with '/' left as-is
+fn encode_file_name(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Turns a failed request into an error carrying B2's own error message where there is one
+fn api_error(error: ureq::Error) -> Box<dyn Error> {
+    match error {
+        ureq::Error::Status(status, response) => match response.into_json::<ApiError>() {
+            Ok(body) => format!("B2 error {} ({}): {}", status, body.code, body.message).into(),
+            Err(_) => format!("B2 request failed with status {}", status).into(),
+        },
+        ureq::Error::Transport(transport) => format!("B2 request failed: {}", transport).into(),
+    }
 }
@@ -0,0 +1,512 @@
+use std::{fs, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::mpsc, thread, time::Duration, error::Error};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use indicatif::ProgressBar;
+use crate::{b2, cancel::CancellationToken, hash, host::Host, http::HttpOptions, utils, window};
+
+// Attempts per upload before giving up, with exponential backoff between them
+pub const UPLOAD_ATTEMPTS: u32 = 5;
+// Streamed archives are handed to the uploader in chunks of this size, with at most PIPE_CHUNKS in flight
+const PIPE_CHUNK_SIZE: usize = 1024 * 1024;
+const PIPE_CHUNKS: usize = 16;
+// How much of an object is downloaded at a time when copying it between backends
+const COPY_RANGE_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    B2,
+    Local,
+}
+
+// Storage tier for uploaded archives. Colder tiers cost less to keep but more, and longer, to get back
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageClass {
+    Standard,
+    Infrequent,
+    Archive,
+    DeepArchive,
+}
+
+impl StorageClass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StorageClass::Standard => "standard",
+            StorageClass::Infrequent => "infrequent",
+            StorageClass::Archive => "archive",
+            StorageClass::DeepArchive => "deep-archive",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<StorageClass> {
+        StorageClass::from_str(name, true).ok()
+    }
+
+    // What getting an object back from this tier involves, if it's anything more than downloading it
+    pub fn retrieval(&self) -> Option<&'static str> {
+        match self {
+            StorageClass::Standard => None,
+            StorageClass::Infrequent => Some("downloads are billed per GB retrieved"),
+            StorageClass::Archive => Some("must be rehydrated before download, typically 1-5 hours"),
+            StorageClass::DeepArchive => Some("must be rehydrated before download, typically 12-48 hours"),
+        }
+    }
+}
+
+// Where archives get uploaded to
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct RemoteOptions {
+    #[arg(long = "backend", value_enum, default_value = "b2", env = "ATHENA_BACKEND")]
+    pub backend: BackendKind,
+    // Bucket name for b2, or destination directory for local
+    #[arg(long = "bucket", env = "ATHENA_BUCKET")]
+    pub bucket: Option<String>,
+    #[arg(long = "prefix", default_value = "", env = "ATHENA_PREFIX")]
+    pub prefix: String,
+    // Directories under --prefix to upload archives into, from {hostname}, {profile}, {year}, {month} and {day},
+    // e.g. '{hostname}/{profile}/{year}/{month}/'. Replaces the directory per host archives otherwise go in
+    #[arg(long = "remote-prefix", value_parser = parse_remote_prefix, env = "ATHENA_REMOTE_PREFIX")]
+    pub remote_prefix: Option<String>,
+    // Daemon profile the run is for, for {profile}
+    #[arg(skip)]
+    pub profile: Option<String>,
+    // When the run started, for {year}, {month} and {day}, so every key a run renders is dated the same. Now if unset
+    #[arg(skip)]
+    pub started: Option<chrono::DateTime<chrono::Local>>,
+    // Tier to upload archives into. Local directories accept any class, recording what kind of storage they're on
+    #[arg(long = "storage-class", value_enum, env = "ATHENA_STORAGE_CLASS")]
+    pub storage_class: Option<StorageClass>,
+    #[command(flatten)]
+    pub http: HttpOptions,
+}
+
+impl RemoteOptions {
+    // Parses a URL like b2://bucket/prefix or file:///mnt/backups. Returns None for plain paths
+    pub fn from_url(url: &str) -> Result<Option<RemoteOptions>, Box<dyn Error>> {
+        let (backend, rest) = match url.split_once("://") {
+            Some(("b2", rest)) => (BackendKind::B2, rest),
+            Some(("file", rest)) => return Ok(Some(RemoteOptions { backend: BackendKind::Local, bucket: Some(rest.to_string()), ..RemoteOptions::default() })),
+            Some((scheme, _)) => return Err(format!("Unsupported URL scheme '{}'", scheme).into()),
+            None => return Ok(None),
+        };
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(format!("No bucket in '{}'", url).into());
+        }
+        Ok(Some(RemoteOptions { backend, bucket: Some(bucket.to_string()), prefix: prefix.to_string(), ..RemoteOptions::default() }))
+    }
+
+    // Object key for a file name under the configured prefix
+    pub fn key_for(&self, file_name: &str) -> String {
+        let prefix = self.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            file_name.to_string()
+        } else {
+            format!("{}/{}", prefix, file_name)
+        }
+    }
+
+    // Object key for an archive, under a directory per host so machines sharing a bucket never collide, or
+    // wherever --remote-prefix puts it
+    pub fn archive_key(&self, host: Option<&Host>, file_name: &str) -> String {
+        if let Some(template) = &self.remote_prefix {
+            let dirs = render_prefix(template, host, self.profile.as_deref(), self.started.unwrap_or_else(chrono::Local::now));
+            return match dirs.trim_matches('/') {
+                "" => self.key_for(file_name),
+                dirs => self.key_for(&format!("{}/{}", dirs, file_name)),
+            };
+        }
+        match host {
+            Some(host) => self.key_for(&format!("{}/{}", host.name, file_name)),
+            None => self.key_for(file_name),
+        }
+    }
+}
+
+const PREFIX_FIELDS: [&str; 5] = ["hostname", "profile", "year", "month", "day"];
+
+// Checks every {field} in a --remote-prefix template is one that can be filled in
+pub fn parse_remote_prefix(input: &str) -> Result<String, String> {
+    let mut rest = input;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or_else(|| format!("Unclosed '{{' in remote prefix '{}'", input))? + start;
+        let field = &rest[start + 1..end];
+        if !PREFIX_FIELDS.contains(&field) {
+            return Err(format!("Unknown field '{{{}}}' in remote prefix, expected one of {{{}}}", field, PREFIX_FIELDS.join("}, {")));
+        }
+        rest = &rest[end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(format!("Unmatched '}}' in remote prefix '{}'", input));
+    }
+    Ok(input.to_string())
+}
+
+// Fills in a --remote-prefix template. Runs outside a daemon profile go under "default", and the hostname is
+// used even when archives aren't otherwise scoped to it
+fn render_prefix(template: &str, host: Option<&Host>, profile: Option<&str>, now: chrono::DateTime<chrono::Local>) -> String {
+    let hostname = match host {
+        Some(host) => host.name.clone(),
+        None => crate::host::HostOptions::default().resolve().map(|host| host.name).unwrap_or_default(),
+    };
+    template
+        .replace("{hostname}", &hostname)
+        .replace("{profile}", profile.unwrap_or("default"))
+        .replace("{year}", &now.format("%Y").to_string())
+        .replace("{month}", &now.format("%m").to_string())
+        .replace("{day}", &now.format("%d").to_string())
+}
+
+// Splits the key of an archive uploaded under a --remote-prefix template, relative to --prefix, into the host that
+// made it, from the directory `{hostname}` filled in, and the rest of the key. Text around `{hostname}` in its
+// directory is stripped, but other fields there can't be told apart from the hostname, so leave it unknown
+pub fn split_templated_key(template: &str, key: &str) -> Option<(Option<String>, String)> {
+    let dirs: Vec<&str> = template.split('/').filter(|dir| !dir.is_empty()).collect();
+    let parts: Vec<&str> = key.split('/').collect();
+    if parts.len() <= dirs.len() {
+        return None;
+    }
+    let at = dirs.iter().position(|dir| dir.contains("{hostname}"));
+    let host = at.and_then(|at| {
+        let (before, after) = dirs[at].split_once("{hostname}")?;
+        let host = parts[at].strip_prefix(before)?.strip_suffix(after)?;
+        (!before.contains('{') && !after.contains('{') && !host.is_empty()).then(|| host.to_string())
+    });
+    let rest = parts.iter().enumerate().filter(|(i, _)| Some(*i) != at).map(|(_, part)| *part).collect::<Vec<_>>().join("/");
+    Some((host, rest))
+}
+
+// Metadata stored alongside an uploaded object, e.g. the backup's comment
+pub type ObjectInfo = Vec<(String, String)>;
+// Suffix of the file a local backend keeps an object's metadata in
+const INFO_SUFFIX: &str = ".info.json";
+
+pub trait Backend: Send + Sync {
+    // Human-readable location of an object, e.g. b2://bucket/key
+    fn url(&self, key: &str) -> String;
+    // Uploads the file, verifying it arrived intact. Progress is reported in bytes
+    fn upload(&self, path: &Path, key: &str, info: &[(String, String)], progress: &ProgressBar) -> Result<(), Box<dyn Error>>;
+    // Uploads everything read from the stream, whose length isn't known up front. Returns the number of bytes uploaded
+    fn upload_stream(&self, reader: &mut dyn Read, key: &str, info: &[(String, String)], progress: &ProgressBar) -> Result<u64, Box<dyn Error>>;
+    fn download(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>>;
+    // Bytes `start..end` of the object
+    fn download_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, Box<dyn Error>>;
+    // Metadata the object was uploaded with
+    fn info(&self, key: &str) -> Result<ObjectInfo, Box<dyn Error>>;
+    // Keys and sizes of every object whose key starts with the prefix
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>>;
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>>;
+    // Checks an upload of `key` would be allowed, so a run can fail before archiving rather than after
+    fn check_upload(&self, key: &str) -> Result<(), Box<dyn Error>>;
+}
+
+pub fn connect(remote: &RemoteOptions) -> Result<Box<dyn Backend>, Box<dyn Error>> {
+    let bucket = match &remote.bucket {
+        Some(bucket) => bucket.clone(),
+        None => return Err("No bucket specified, pass --bucket".into()),
+    };
+    match remote.backend {
+        BackendKind::B2 if remote.storage_class.map(|class| class != StorageClass::Standard).unwrap_or(false) => {
+            Err("B2 has a single storage class. Use lifecycle rules to expire or hide old archives instead".into())
+        },
+        BackendKind::B2 => Ok(Box::new(b2::B2Backend::connect(&bucket, &remote.http)?)),
+        BackendKind::Local => Ok(Box::new(LocalBackend::new(PathBuf::from(bucket))?)),
+    }
+}
+
+// Uploads a file, retrying failed attempts with backoff. Returns the uploaded object's URL. Cancelling stops it
+// before the next attempt, but doesn't interrupt one already under way. Outside the backup window, it waits for it
+pub fn upload(backend: &dyn Backend, path: &Path, key: &str, info: &[(String, String)], verbose: bool, cancel: &CancellationToken) -> Result<String, Box<dyn Error>> {
+    let len = path.metadata()?.len();
+    let mut attempt = 1;
+    loop {
+        window::wait(&|| cancel.is_cancelled());
+        cancel.check("uploading", 0, None)?;
+        let progress = utils::construct_file_progress(len);
+        progress.set_message(format!("Uploading to {}", backend.url(key)));
+        let result = backend.upload(path, key, info, &progress);
+        progress.finish_and_clear();
+        match result {
+            Ok(()) => return Ok(backend.url(key)),
+            Err(e) if attempt < UPLOAD_ATTEMPTS => {
+                let delay = Duration::from_secs(2_u64.pow(attempt - 1));
+                if verbose {
+                    eprintln!("Upload attempt {} failed: {} (retrying in {}s)", attempt, e, delay.as_secs());
+                }
+                thread::sleep(delay);
+                attempt += 1;
+            },
+            Err(e) => return Err(format!("Upload failed after {} attempts: {}", attempt, e).into()),
+        }
+    }
+}
+
+// Whether there's already an object at exactly `key`, not just ones it's a prefix of
+pub fn exists(backend: &dyn Backend, key: &str) -> Result<bool, Box<dyn Error>> {
+    Ok(backend.list(key)?.iter().any(|(other, _)| other == key))
+}
+
+// Streams an object from one backend to another, downloading it in ranges on one thread while another uploads it,
+// so only a few ranges are ever held in memory. Returns the number of bytes copied
+pub fn copy(from: &dyn Backend, from_key: &str, to: &dyn Backend, to_key: &str) -> Result<u64, Box<dyn Error>> {
+    let size = from.list(from_key)?.into_iter().find(|(key, _)| key == from_key).map(|(_, size)| size)
+        .ok_or_else(|| format!("No object at {}", from.url(from_key)))?;
+    let info = from.info(from_key)?;
+    let progress = utils::construct_file_progress(size);
+    progress.set_message(format!("Copying to {}", to.url(to_key)));
+    let (mut writer, reader) = pipe();
+    let (downloaded, uploaded) = thread::scope(|scope| {
+        let download = scope.spawn(move || -> Result<(), String> {
+            let mut start = 0;
+            while start < size {
+                let end = (start + COPY_RANGE_SIZE).min(size);
+                let data = from.download_range(from_key, start, end).map_err(|e| e.to_string())?;
+                if data.len() as u64 != end - start {
+                    return Err(format!("{} changed while copying it", from.url(from_key)));
+                }
+                writer.write_all(&data).map_err(|e| e.to_string())?;
+                start = end;
+            }
+            writer.close().map_err(|e| e.to_string())
+        });
+        // The reader is dropped as soon as the upload ends, so a failed upload stops the download too
+        let uploaded = {
+            let mut reader = reader;
+            to.upload_stream(&mut reader, to_key, &info, &progress).map_err(|e| e.to_string())
+        };
+        (download.join().unwrap_or_else(|_| Err("Download thread panicked".to_string())), uploaded)
+    });
+    progress.finish_and_clear();
+    // A failed download also fails the upload, with a less useful error
+    downloaded?;
+    let uploaded = uploaded?;
+    if uploaded != size {
+        return Err(format!("Copied {} bytes of {}, expected {}", uploaded, from.url(from_key), size).into());
+    }
+    Ok(uploaded)
+}
+
+// Creates a bounded in-memory pipe for streaming an archive into an upload running on another thread
+pub fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = mpsc::sync_channel(PIPE_CHUNKS);
+    (
+        PipeWriter { sender, buf: Vec::with_capacity(PIPE_CHUNK_SIZE) },
+        PipeReader { receiver, chunk: Vec::new(), pos: 0, done: false },
+    )
+}
+
+pub struct PipeWriter {
+    sender: mpsc::SyncSender<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl PipeWriter {
+    // Marks the end of the stream. A writer that's dropped without being closed makes the reader fail,
+    // so an interrupted archive is never uploaded as if it were complete
+    pub fn close(mut self) -> io::Result<()> {
+        self.send()?;
+        self.sender.send(Vec::new()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(PIPE_CHUNK_SIZE));
+        // The receiving end only goes away if the upload failed
+        self.sender.send(chunk).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "upload stopped"))
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(data.len(), PIPE_CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        if self.buf.len() == PIPE_CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct PipeReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(chunk) if chunk.is_empty() => self.done = true,
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Err(_) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "archive stream was interrupted")),
+            }
+        }
+        let len = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+// Reads until `buf` is full or the reader is exhausted, returning the number of bytes read
+pub fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// "Uploads" into a directory, e.g. a mounted NAS or external drive. Object metadata goes in a JSON file next to
+// the object
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> Result<LocalBackend, Box<dyn Error>> {
+        if !root.is_dir() {
+            return Err(format!("Local backend directory {} does not exist", root.display()).into());
+        }
+        Ok(LocalBackend { root })
+    }
+
+    fn write_info(&self, key: &str, info: &[(String, String)]) -> Result<(), Box<dyn Error>> {
+        if !info.is_empty() {
+            let info: serde_json::Map<String, serde_json::Value> = info.iter().map(|(k, v)| (k.clone(), v.clone().into())).collect();
+            fs::write(self.root.join(format!("{}{}", key, INFO_SUFFIX)), serde_json::to_vec_pretty(&info)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend for LocalBackend {
+    fn url(&self, key: &str) -> String {
+        format!("file://{}", self.root.join(key).display())
+    }
+
+    fn upload(&self, path: &Path, key: &str, info: &[(String, String)], progress: &ProgressBar) -> Result<(), Box<dyn Error>> {
+        let dest = self.root.join(key);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Copy to a temporary name first so a partial upload is never mistaken for a complete one
+        let mut partial = dest.as_os_str().to_owned();
+        partial.push(".partial");
+        let mut reader = progress.wrap_read(fs::File::open(path)?);
+        let mut writer = fs::File::create(&partial)?;
+        std::io::copy(&mut reader, &mut writer)?;
+        writer.sync_all()?;
+
+        if hash::file(path, hash::HashAlgorithm::Blake3)? != hash::file(Path::new(&partial), hash::HashAlgorithm::Blake3)? {
+            fs::remove_file(&partial)?;
+            return Err("Uploaded file does not match the original".into());
+        }
+        self.write_info(key, info)?;
+        fs::rename(&partial, &dest)?;
+        Ok(())
+    }
+
+    fn upload_stream(&self, reader: &mut dyn Read, key: &str, info: &[(String, String)], progress: &ProgressBar) -> Result<u64, Box<dyn Error>> {
+        let dest = self.root.join(key);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut partial = dest.as_os_str().to_owned();
+        partial.push(".partial");
+        let mut writer = fs::File::create(&partial)?;
+        let copied = match io::copy(&mut progress.wrap_read(reader), &mut writer).and_then(|copied| writer.sync_all().map(|_| copied)) {
+            Ok(copied) => copied,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e.into());
+            },
+        };
+        self.write_info(key, info)?;
+        fs::rename(&partial, &dest)?;
+        Ok(copied)
+    }
+
+    fn download_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut file = fs::File::open(self.root.join(key)).map_err(|e| format!("Failed to read {}: {}", self.url(key), e))?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = Vec::new();
+        file.take(end - start).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn info(&self, key: &str) -> Result<ObjectInfo, Box<dyn Error>> {
+        let contents = match fs::read(self.root.join(format!("{}{}", key, INFO_SUFFIX))) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let info: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&contents)?;
+        Ok(info.into_iter().filter_map(|(k, v)| Some((k, v.as_str()?.to_string()))).collect())
+    }
+
+    fn download(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        fs::read(self.root.join(key)).map_err(|e| format!("Failed to read {}: {}", self.url(key), e).into())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+        // Only walk the directory the prefix points into, rather than the whole backend
+        let dir = match prefix.rfind('/') {
+            Some(i) => self.root.join(&prefix[..i]),
+            None => self.root.clone(),
+        };
+        let mut objects = Vec::new();
+        if dir.is_dir() {
+            list_dir(&self.root, &dir, &mut objects)?;
+        }
+        objects.retain(|(key, _)| key.starts_with(prefix) && !key.ends_with(".partial") && !key.ends_with(INFO_SUFFIX));
+        Ok(objects)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(self.root.join(key))?;
+        let _ = fs::remove_file(self.root.join(format!("{}{}", key, INFO_SUFFIX)));
+        Ok(())
+    }
+
+    fn check_upload(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        // The key's directory may not exist yet, so the nearest one that does is what has to be writable
+        let dir = self.root.join(key).ancestors().skip(1).find(|dir| dir.is_dir()).map(Path::to_path_buf).unwrap_or_else(|| self.root.clone());
+        tempfile::tempfile_in(&dir).map_err(|e| format!("Can't write to {}: {}", dir.display(), e))?;
+        Ok(())
+    }
+}
+
+fn list_dir(root: &Path, dir: &Path, objects: &mut Vec<(String, u64)>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_dir(root, &entry.path(), objects)?;
+        } else {
+            let key = entry.path().strip_prefix(root)?.to_string_lossy().replace('\\', "/");
+            objects.push((key, metadata.len()));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::{io::{self, Read}, path::Path, thread, time::Duration};
+use clap::ValueEnum;
+use crate::sourcefs::SourceFs;
+
+// Wait before the first retry of a busy file, growing by the same again for each retry after
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+// What to do with a source file that's still busy once retries run out, or that shrinks while being read
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BusyPolicy {
+    #[default]
+    Fail,
+    // Leave it out with a warning, noting it in the archive's manifest
+    Skip,
+}
+
+// Whether the error is from another process holding the file, which may well be over by the next attempt
+pub fn is_busy(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    return matches!(e.raw_os_error(), Some(libc::EBUSY) | Some(libc::ETXTBSY));
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    #[cfg(windows)]
+    return matches!(e.raw_os_error(), Some(32) | Some(33));
+    #[cfg(not(any(unix, windows)))]
+    return false;
+}
+
+// Opens a file for reading, retrying up to `retries` times while it's busy
+pub fn open(source: &dyn SourceFs, path: &Path, retries: u32) -> io::Result<Box<dyn Read + Send>> {
+    let mut attempt = 0;
+    loop {
+        match source.open(path) {
+            Err(e) if is_busy(&e) && attempt < retries => {
+                attempt += 1;
+                thread::sleep(RETRY_DELAY * attempt);
+            },
+            result => return result,
+        }
+    }
+}
+
+// Reads exactly `size` bytes, the size already written in the entry's header. Anything the file grew by since is
+// left out, and if it shrank the rest is zero-filled, so the archive stays readable either way
+pub struct SizedReader<R> {
+    inner: io::Take<R>,
+    remaining: u64,
+    shortfall: u64,
+}
+
+impl<R: Read> SizedReader<R> {
+    pub fn new(inner: R, size: u64) -> SizedReader<R> {
+        SizedReader { inner: inner.take(size), remaining: size, shortfall: 0 }
+    }
+
+    // How many bytes had to be zero-filled
+    pub fn shortfall(&self) -> u64 {
+        self.shortfall
+    }
+}
+
+impl<R: Read> Read for SizedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let mut read = self.inner.read(buf)?;
+        // The file ended before its header said it would
+        if read == 0 {
+            read = self.remaining.min(buf.len() as u64) as usize;
+            buf[..read].fill(0);
+            self.shortfall += read as u64;
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
@@ -0,0 +1,45 @@
+use std::{fmt, sync::{atomic::{AtomicBool, Ordering}, Arc}, error::Error};
+
+// Shared flag for stopping long-running work. Clones share the flag, so one can be handed to a signal handler
+// while the work itself polls another
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // Errors with how far the work got if it's been cancelled
+    pub fn check(&self, phase: &'static str, done: usize, total: Option<usize>) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled { phase, done, total });
+        }
+        Ok(())
+    }
+}
+
+// Returned by work that stopped because its token was cancelled, with what it had finished by then
+#[derive(Debug)]
+pub struct Cancelled {
+    pub phase: &'static str,
+    pub done: usize,
+    pub total: Option<usize>,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cancelled while {}", self.phase)?;
+        match self.total {
+            Some(total) => write!(f, " ({} of {} files done)", self.done, total),
+            None if self.done > 0 => write!(f, " ({} files found)", self.done),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for Cancelled {}
@@ -0,0 +1,133 @@
+use std::{io::Read, error::Error};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::{backend, utils};
+
+// Bounds FastCDC accepts; the gear chunker uses the same ones so a repo can switch between them
+const MIN_BOUNDS: (u32, u32) = (fastcdc::v2020::MINIMUM_MIN, fastcdc::v2020::MINIMUM_MAX);
+const AVG_BOUNDS: (u32, u32) = (fastcdc::v2020::AVERAGE_MIN, fastcdc::v2020::AVERAGE_MAX);
+const MAX_BOUNDS: (u32, u32) = (fastcdc::v2020::MAXIMUM_MIN, fastcdc::v2020::MAXIMUM_MAX);
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkerAlgorithm {
+    #[default]
+    Fastcdc,
+    Gear,
+}
+
+// How files are split into chunks. Stored in the repo config, since changing any of it changes where chunk
+// boundaries fall and so stops new backups deduplicating against old ones
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ChunkerConfig {
+    // Repos created before the algorithm was configurable all used FastCDC
+    #[serde(default)]
+    pub algorithm: ChunkerAlgorithm,
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> ChunkerConfig {
+        ChunkerConfig { algorithm: ChunkerAlgorithm::Fastcdc, min_size: 256 * 1024, avg_size: 1024 * 1024, max_size: 4 * 1024 * 1024 }
+    }
+}
+
+impl ChunkerConfig {
+    // Builds a config from whichever sizes were given. Unset bounds default to a quarter and four times the average
+    pub fn new(algorithm: ChunkerAlgorithm, min: Option<u64>, avg: Option<u64>, max: Option<u64>) -> Result<ChunkerConfig, Box<dyn Error>> {
+        let avg = avg.unwrap_or(ChunkerConfig::default().avg_size as u64);
+        let min = min.unwrap_or(avg / 4);
+        let max = max.unwrap_or(avg * 4);
+        let config = ChunkerConfig {
+            algorithm,
+            min_size: bounded("--chunk-min", min, MIN_BOUNDS)?,
+            avg_size: bounded("--chunk-avg", avg, AVG_BOUNDS)?,
+            max_size: bounded("--chunk-max", max, MAX_BOUNDS)?,
+        };
+        if config.min_size > config.avg_size || config.avg_size > config.max_size {
+            return Err("Chunk sizes must satisfy min <= avg <= max".into());
+        }
+        Ok(config)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} chunker, {} min / {} avg / {} max",
+            match self.algorithm { ChunkerAlgorithm::Fastcdc => "FastCDC", ChunkerAlgorithm::Gear => "gear" },
+            utils::format_size(self.min_size as u64),
+            utils::format_size(self.avg_size as u64),
+            utils::format_size(self.max_size as u64)
+        )
+    }
+
+    // Splits everything read from `reader` into chunks, passing each to `f` in order
+    pub fn chunk<R: Read>(&self, reader: R, mut f: impl FnMut(&[u8]) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+        match self.algorithm {
+            ChunkerAlgorithm::Fastcdc => {
+                for chunk in fastcdc::v2020::StreamCDC::new(reader, self.min_size, self.avg_size, self.max_size) {
+                    f(&chunk?.data)?;
+                }
+                Ok(())
+            },
+            ChunkerAlgorithm::Gear => gear_chunks(reader, self, f),
+        }
+    }
+}
+
+fn bounded(flag: &str, size: u64, (low, high): (u32, u32)) -> Result<u32, Box<dyn Error>> {
+    if size < low as u64 || size > high as u64 {
+        return Err(format!(
+            "{} must be between {} and {}",
+            flag,
+            utils::format_size(low as u64),
+            utils::format_size(high as u64)
+        ).into());
+    }
+    Ok(size as u32)
+}
+
+// Plain gear hash chunking: a cut point is wherever the rolling hash's low bits are all zero, with log2(avg)
+// bits giving roughly the average size. Simpler than FastCDC but with a wider spread of chunk sizes
+fn gear_chunks<R: Read>(mut reader: R, config: &ChunkerConfig, mut f: impl FnMut(&[u8]) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    let mask = (1u64 << (31 - config.avg_size.leading_zeros())) - 1;
+    let (min, max) = (config.min_size as usize, config.max_size as usize);
+    let mut buf = vec![0; max];
+    let mut filled = 0;
+    loop {
+        filled += backend::read_full(&mut reader, &mut buf[filled..])?;
+        if filled == 0 {
+            return Ok(());
+        }
+        let mut hash = 0u64;
+        let mut cut = filled;
+        // Bytes before the minimum size can't be a cut point, so there's no need to hash them
+        for (i, byte) in buf[..filled].iter().enumerate().skip(min) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & mask == 0 {
+                cut = i + 1;
+                break;
+            }
+        }
+        f(&buf[..cut])?;
+        buf.copy_within(cut..filled, 0);
+        filled -= cut;
+    }
+}
+
+// Pseudo-random values for each byte, from splitmix64 with a fixed seed so chunk boundaries are stable across builds
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6174_6865_6e61;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
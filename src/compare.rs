@@ -0,0 +1,141 @@
+use std::{collections::BTreeMap, fs, io::{self, BufReader, Read}, path::{Path, PathBuf}, error::Error};
+use flate2::read::MultiGzDecoder;
+use crate::{crypto, hash::{self, HashAlgorithm}, manifest, utils, validate::{self, ArchiveKind}};
+
+// What an archive entry or file on disk holds: a content hash, or a symlink's target
+#[derive(PartialEq, Eq, Debug)]
+pub enum Content {
+    File(String),
+    Symlink(PathBuf),
+}
+
+// Counts of each kind of difference found
+#[derive(Default)]
+pub struct Report {
+    pub compared: usize,
+    pub mismatched: usize,
+    pub missing: usize,
+    pub extra: usize,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.mismatched == 0 && self.missing == 0 && self.extra == 0
+    }
+}
+
+// Reads every entry of the archive and compares it against the matching file under `path`, printing each
+// mismatched, missing (in the archive, not on disk) or extra (on disk, not in the archive) path
+pub fn run(archive: &Path, path: &Path, password_file: Option<&Path>) -> Result<Report, Box<dyn Error>> {
+    let path = crate::validate::input(path.to_path_buf())?;
+    let base = crate::get_inp_path_only(&path);
+    let mut on_disk: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+    for file in crate::process_sources(vec![path.clone()], crate::cancel::CancellationToken::default(), None, None, None, None).map_err(|e| e.to_string())? {
+        on_disk.insert(file.strip_prefix(&base)?.to_path_buf(), file);
+    }
+
+    let spinner = utils::construct_spinner();
+    spinner.set_message("Comparing archive entries...");
+    let mut report = Report::default();
+    let mut compare = |name: PathBuf, content: Content| -> Result<(), Box<dyn Error>> {
+        // athena's own metadata, not a source file
+        if name == Path::new(manifest::MANIFEST_NAME) || name == Path::new(crate::footer::INDEX_NAME) {
+            return Ok(());
+        }
+        report.compared += 1;
+        spinner.tick();
+        let file = match on_disk.remove(&name) {
+            Some(file) => file,
+            None => {
+                spinner.suspend(|| println!("missing:  {}", name.display()));
+                report.missing += 1;
+                return Ok(());
+            },
+        };
+        if disk_content(&file, &content)? != content {
+            spinner.suspend(|| println!("mismatch: {}", name.display()));
+            report.mismatched += 1;
+        }
+        Ok(())
+    };
+
+    match validate::readable(validate::detect(archive)?)? {
+        ArchiveKind::Zip => compare_zip(archive, password_file, &mut compare)?,
+        ArchiveKind::Gzip => compare_tar(MultiGzDecoder::new(BufReader::new(fs::File::open(archive)?)), &mut compare)?,
+        ArchiveKind::Sealed => compare_tar(crypto::open_sealed(archive, password_file)?, &mut compare)?,
+        _ => compare_tar(BufReader::new(fs::File::open(archive)?), &mut compare)?,
+    }
+    spinner.finish_and_clear();
+
+    for name in on_disk.keys() {
+        println!("extra:    {}", name.display());
+        report.extra += 1;
+    }
+    Ok(report)
+}
+
+// Hashes the file on disk the same way as the archive entry it's compared with. A type change (file replaced
+// by a symlink or vice versa) reads as a mismatch
+pub fn disk_content(file: &Path, expected: &Content) -> Result<Content, Box<dyn Error>> {
+    let metadata = file.symlink_metadata()?;
+    if metadata.file_type().is_symlink() {
+        return Ok(Content::Symlink(file.read_link()?));
+    }
+    match expected {
+        Content::File(_) => Ok(Content::File(hash::file(file, HashAlgorithm::Blake3)?)),
+        Content::Symlink(_) => Ok(Content::File(String::new())),
+    }
+}
+
+pub type Compare<'a> = dyn FnMut(PathBuf, Content) -> Result<(), Box<dyn Error>> + 'a;
+
+pub fn compare_tar<R: Read>(reader: R, compare: &mut Compare) -> Result<(), Box<dyn Error>> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        match entry.header().entry_type() {
+            tar::EntryType::Symlink => {
+                let target = entry.link_name()?.map(|t| t.into_owned()).unwrap_or_default();
+                compare(name, Content::Symlink(target))?;
+            },
+            kind if kind.is_file() => {
+                let content = Content::File(hash::reader(&mut entry, HashAlgorithm::Blake3)?);
+                compare(name, content)?;
+            },
+            _ => {},
+        }
+    }
+    // Sealed tars are read to the end, so a truncated one fails rather than comparing as a shorter archive
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(())
+}
+
+pub fn compare_zip(archive: &Path, password_file: Option<&Path>, compare: &mut Compare) -> Result<(), Box<dyn Error>> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(fs::File::open(archive)?))?;
+    let mut password: Option<String> = None;
+    for i in 0..zip.len() {
+        let encrypted = zip.by_index_raw(i)?.encrypted();
+        if encrypted && password.is_none() {
+            password = Some(utils::read_secret(password_file, "ATHENA_PASSWORD", "Archive password", false)?);
+        }
+        let mut entry = match (&password, encrypted) {
+            (Some(password), true) => zip.by_index_decrypt(i, password.as_bytes()).map_err(|_| "Wrong archive password")?,
+            _ => zip.by_index(i)?,
+        };
+        if entry.is_dir() {
+            continue;
+        }
+        let name = entry.name().to_string();
+        let is_symlink = entry.unix_mode().map(|mode| mode & 0o170000 == 0o120000).unwrap_or(false);
+        if is_symlink {
+            let mut target = String::new();
+            entry.read_to_string(&mut target)?;
+            compare(PathBuf::from(name), Content::Symlink(PathBuf::from(target)))?;
+        } else {
+            let content = Content::File(hash::reader(&mut entry, HashAlgorithm::Blake3).map_err(|e| format!("Failed to read {}: {}", name, e))?);
+            compare(PathBuf::from(name), content)?;
+        }
+    }
+    Ok(())
+}
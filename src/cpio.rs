@@ -0,0 +1,316 @@
+use std::{collections::HashSet, io::{self, Read, Write}, path::Path, error::Error};
+use flate2::{write::GzEncoder, Compression};
+use crate::{busy, exclude::FileType, manifest::{self, Manifest}, pathstyle, pipeline::Files, progress::{Progress, ProgressReader}, readahead, scratch, space, sourcefs::Stat, utils};
+
+const MAGIC: &str = "070701";
+const TRAILER: &str = "TRAILER!!!";
+// newc sizes are 8 hex digits
+const MAX_SIZE: u64 = u32::MAX as u64;
+
+const DIR: u32 = 0o040000;
+const FILE: u32 = 0o100000;
+const SYMLINK: u32 = 0o120000;
+const FIFO: u32 = 0o010000;
+const SOCKET: u32 = 0o140000;
+const CHAR_DEVICE: u32 = 0o020000;
+const BLOCK_DEVICE: u32 = 0o060000;
+
+// Writes the given files as a cpio archive in the newc format, the one the kernel unpacks initramfs images from,
+// gzipped if compression is on. Returns the writer once the trailer's written
+pub fn write_cpio<W: Write>(files: &mut Files, writer: W, options: &utils::Options, base: &Path, progress: &dyn Progress) -> Result<W, Box<dyn Error>> {
+    if options.compression {
+        let mut archive = Cpio::new(GzEncoder::new(writer, Compression::best()));
+        append_entries(&mut archive, files, options, base, progress)?;
+        Ok(archive.finish()?.finish()?)
+    } else {
+        let mut archive = Cpio::new(writer);
+        append_entries(&mut archive, files, options, base, progress)?;
+        Ok(archive.finish()?)
+    }
+}
+
+fn append_entries<W: Write>(archive: &mut Cpio<W>, files: &mut Files, options: &utils::Options, base: &Path, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+    let now = chrono::Local::now().timestamp().clamp(0, MAX_SIZE as i64) as u32;
+    let mut manifest = Manifest::new(options.host.as_ref(), options.comment.as_deref());
+    manifest.deleted = options.deleted.clone();
+
+    // Directories written so far. Unpackers like the kernel's don't create parents, so each gets an entry of its own
+    // before anything in it
+    let mut dirs = HashSet::new();
+    let mut space = space::Guard::new(vec![options.output_path.clone(), scratch::dir()], options.min_free_space);
+    for i in 0.. {
+        let Some(path) = files.next() else {
+            break;
+        };
+        let path = &path?;
+        if files.total().is_none() {
+            progress.on_found(files.found_so_far() as u64);
+        }
+        options.cancel.check("archiving", i, files.total())?;
+        space.check()?;
+        let rel_path = path.strip_prefix(base)?;
+        if let Some(deadline) = options.deadline.as_ref().filter(|deadline| deadline.passed()) {
+            let total = files.total().unwrap_or_else(|| files.by_ref().count() + i + 1);
+            manifest.stopped = Some(deadline.stop(i, total, rel_path));
+            break;
+        }
+        let stat = match manifest::check_readable(&*options.fs, path, options.busy_retries) {
+            Ok(stat) => stat,
+            Err(e) if options.skips(&e) => {
+                progress.on_warning(&format!("Skipping {}: {}", path.display(), e));
+                options.errors.record(path, "open", &e);
+                manifest.skipped.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error: e.to_string() });
+                continue;
+            },
+            Err(e) if options.placeholder_on_error => {
+                progress.on_file(rel_path, 0);
+                progress.on_warning(&format!("Storing placeholder for {}: {}", path.display(), e));
+                options.errors.record(path, "open", &e);
+                archive.append(&name(rel_path), &Header { mode: FILE | 0o644, nlink: 1, mtime: now, ..Header::default() }, &mut io::empty())?;
+                manifest.placeholders.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error: e.to_string() });
+                continue;
+            },
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+        };
+        let parents: Vec<&Path> = rel_path.ancestors().skip(1).filter(|parent| !parent.as_os_str().is_empty()).collect();
+        for parent in parents.into_iter().rev() {
+            if dirs.insert(parent.to_path_buf()) {
+                let header = match options.fs.metadata(&base.join(parent)) {
+                    Ok(stat) if stat.is_dir() => Header::new(&stat),
+                    _ => Header { mode: DIR | 0o755, nlink: 2, mtime: now, ..Header::default() },
+                };
+                archive.append(&name(parent), &header, &mut io::empty())?;
+            }
+        }
+        if stat.is_dir() && !dirs.insert(rel_path.to_path_buf()) {
+            continue;
+        }
+        progress.on_file(rel_path, if stat.is_file() { stat.len } else { 0 });
+        let mut header = Header::new(&stat);
+        match stat.kind {
+            FileType::File => {
+                if stat.len > MAX_SIZE {
+                    return Err(format!("Can't store {} in a cpio archive, files can be at most 4GiB", path.display()).into());
+                }
+                header.size = stat.len as u32;
+                let mut reader = busy::SizedReader::new(readahead::open(path, stat.len, options)?, stat.len);
+                archive.append(&name(rel_path), &header, &mut ProgressReader::new(&mut reader, progress))?;
+                // The header was already written with the old size, so the entry can only be kept zero-filled or failed
+                if reader.shortfall() > 0 {
+                    let error = format!("shrank by {} while being read", utils::format_size(reader.shortfall()));
+                    if options.on_busy == busy::BusyPolicy::Fail && !options.keep_going {
+                        return Err(format!("Failed to read {}: {}", path.display(), error).into());
+                    }
+                    progress.on_warning(&format!("Zero-filling {}: {}", path.display(), error));
+                    options.errors.record_message(path, "read", error.clone());
+                    manifest.placeholders.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error });
+                }
+            },
+            FileType::Symlink => {
+                let target = pathstyle::link_target(&options.fs.read_link(path)?, options.path_style);
+                let target = target.as_os_str().as_encoded_bytes();
+                header.size = target.len() as u32;
+                archive.append(&name(rel_path), &header, &mut &target[..])?;
+            },
+            _ => archive.append(&name(rel_path), &header, &mut io::empty())?,
+        }
+    }
+    if manifest.is_needed() {
+        let contents = manifest.to_json()?;
+        let header = Header { mode: FILE | 0o644, nlink: 1, mtime: now, size: contents.len() as u32, ..Header::default() };
+        archive.append(manifest::MANIFEST_NAME.as_bytes(), &header, &mut &contents[..])?;
+    }
+    Ok(())
+}
+
+// Whether the stream starts with a newc header, e.g. once a .cpio.gz is decompressed
+pub fn is_cpio<R: Read>(mut reader: R) -> bool {
+    let mut magic = [0; 6];
+    reader.read_exact(&mut magic).is_ok() && magic == MAGIC.as_bytes()
+}
+
+// Reads a newc archive's headers through to the trailer, skipping over the data, so one cut short or with a
+// mangled header doesn't pass for whole
+pub fn check<R: Read>(mut reader: R) -> Result<(), Box<dyn Error>> {
+    let mut read = 0u64;
+    loop {
+        let mut header = [0; 110];
+        reader.read_exact(&mut header).map_err(|e| format!("Cpio archive ends before its trailer: {}", e))?;
+        if &header[..6] != MAGIC.as_bytes() {
+            return Err(format!("Bad cpio header at offset {}", read).into());
+        }
+        let field = |i: usize| std::str::from_utf8(&header[6 + i * 8..14 + i * 8]).ok().and_then(|hex| u32::from_str_radix(hex, 16).ok());
+        // Names are paths, so one longer than PATH_MAX is a mangled header rather than something to allocate for
+        let (Some(size), Some(name_size @ 1..=4096)) = (field(6), field(11)) else {
+            return Err(format!("Bad cpio header at offset {}", read).into());
+        };
+        // The name and the data are each padded out to 4 bytes
+        let name_end = read + 110 + name_size as u64;
+        let mut name = vec![0; (name_end.next_multiple_of(4) - read - 110) as usize];
+        reader.read_exact(&mut name)?;
+        if name[name_size as usize - 1] != 0 {
+            return Err(format!("Bad cpio entry name at offset {}", read).into());
+        }
+        if &name[..name_size as usize - 1] == TRAILER.as_bytes() {
+            return Ok(());
+        }
+        let data = (size as u64).next_multiple_of(4);
+        if io::copy(&mut reader.by_ref().take(data), &mut io::sink())? != data {
+            return Err(format!("Cpio entry {} is cut short", String::from_utf8_lossy(&name[..name_size as usize - 1])).into());
+        }
+        read = name_end.next_multiple_of(4) + data;
+    }
+}
+
+// The entry's name as stored, always with forward slashes
+fn name(rel_path: &Path) -> Vec<u8> {
+    match cfg!(windows) {
+        true => rel_path.to_string_lossy().replace('\\', "/").into_bytes(),
+        false => rel_path.as_os_str().as_encoded_bytes().to_vec(),
+    }
+}
+
+#[derive(Default)]
+struct Header {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    size: u32,
+    rdev: (u32, u32),
+}
+
+impl Header {
+    fn new(stat: &Stat) -> Header {
+        let kind = match stat.kind {
+            FileType::File => FILE,
+            FileType::Dir => DIR,
+            FileType::Symlink => SYMLINK,
+            FileType::Fifo => FIFO,
+            FileType::Socket => SOCKET,
+            FileType::CharDevice => CHAR_DEVICE,
+            FileType::BlockDevice => BLOCK_DEVICE,
+        };
+        Header {
+            mode: kind | (stat.mode & 0o7777),
+            uid: stat.uid,
+            gid: stat.gid,
+            nlink: if stat.is_dir() { 2 } else { 1 },
+            mtime: stat.modified.map_or(0, |modified| chrono::DateTime::<chrono::Utc>::from(modified).timestamp().clamp(0, MAX_SIZE as i64) as u32),
+            size: 0,
+            rdev: device_number(stat),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn device_number(stat: &Stat) -> (u32, u32) {
+    use std::os::unix::fs::MetadataExt;
+    let rdev = stat.local.as_ref().map_or(0, |metadata| metadata.rdev());
+    (libc::major(rdev) as u32, libc::minor(rdev) as u32)
+}
+
+#[cfg(not(unix))]
+fn device_number(_stat: &Stat) -> (u32, u32) {
+    (0, 0)
+}
+
+// Writes newc entries, keeping count of what's been written since names and data are padded out to 4 bytes
+struct Cpio<W: Write> {
+    inner: W,
+    written: u64,
+    // newc has no hard link support worth using, so every entry gets its own inode number
+    inode: u32,
+}
+
+impl<W: Write> Cpio<W> {
+    fn new(inner: W) -> Cpio<W> {
+        Cpio { inner, written: 0, inode: 0 }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn pad(&mut self) -> io::Result<()> {
+        let padding = self.written.next_multiple_of(4) - self.written;
+        self.write(&[0; 3][..padding as usize])
+    }
+
+    // Writes an entry of exactly `header.size` bytes of data from the reader
+    fn append(&mut self, name: &[u8], header: &Header, data: &mut dyn io::Read) -> io::Result<()> {
+        self.inode += 1;
+        self.entry(self.inode, name, header, data)
+    }
+
+    fn entry(&mut self, inode: u32, name: &[u8], header: &Header, data: &mut dyn io::Read) -> io::Result<()> {
+        let fields = [
+            inode,
+            header.mode,
+            header.uid,
+            header.gid,
+            header.nlink,
+            header.mtime,
+            header.size,
+            0,
+            0,
+            header.rdev.0,
+            header.rdev.1,
+            name.len() as u32 + 1,
+            0,
+        ];
+        let mut encoded = String::from(MAGIC);
+        for field in fields {
+            encoded.push_str(&format!("{:08x}", field));
+        }
+        self.write(encoded.as_bytes())?;
+        self.write(name)?;
+        self.write(&[0])?;
+        self.pad()?;
+        let copied = io::copy(data, &mut self.inner)?;
+        self.written += copied;
+        if copied != header.size as u64 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("expected {} bytes for {}, got {}", header.size, String::from_utf8_lossy(name), copied)));
+        }
+        self.pad()
+    }
+
+    // Ends the archive with its trailer entry
+    fn finish(mut self) -> io::Result<W> {
+        self.entry(0, TRAILER.as_bytes(), &Header { nlink: 1, ..Header::default() }, &mut io::empty())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> Vec<u8> {
+        let mut cpio = Cpio::new(Vec::new());
+        cpio.append(b"etc", &Header { mode: DIR | 0o755, nlink: 2, ..Header::default() }, &mut io::empty()).unwrap();
+        cpio.append(b"etc/hostname", &Header { mode: FILE | 0o644, nlink: 1, size: 9, ..Header::default() }, &mut &b"initramfs"[..]).unwrap();
+        cpio.finish().unwrap()
+    }
+
+    #[test]
+    fn checks_headers_through_to_the_trailer() {
+        let archive = archive();
+        check(&archive[..]).unwrap();
+
+        // Cut off in a header, in an entry's data, or before the trailer
+        assert!(check(&archive[..150]).unwrap_err().to_string().contains("ends before its trailer"));
+        assert_eq!(check(&archive[..245]).unwrap_err().to_string(), "Cpio entry etc/hostname is cut short");
+        let trailer = archive.windows(6).rposition(|window| window == MAGIC.as_bytes()).unwrap();
+        assert!(check(&archive[..trailer]).unwrap_err().to_string().contains("ends before its trailer"));
+
+        // A size that isn't hex
+        let mut mangled = archive.clone();
+        mangled[6 + 6 * 8] = b'z';
+        assert_eq!(check(&mangled[..]).unwrap_err().to_string(), "Bad cpio header at offset 0");
+    }
+}
@@ -0,0 +1,353 @@
+use std::{fs, io::{self, BufReader, Read, Write}, path::Path, sync::Mutex, error::Error};
+use base64::Engine;
+use chacha20poly1305::{aead::{rand_core::RngCore, stream::{DecryptorBE32, EncryptorBE32}, Aead, AeadCore, KeyInit, OsRng, Payload}, XChaCha20Poly1305, XNonce};
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use crate::utils;
+
+const NONCE_SIZE: usize = 24;
+// Sealed archives start with this, the last byte being the format's version
+pub const SEALED_MAGIC: &[u8; 8] = b"ATHSEAL\x01";
+// Plaintext per STREAM chunk. Each is followed by its 16 byte tag
+const SEALED_CHUNK: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+// The STREAM nonce is the XChaCha20 one less the 4 byte chunk counter and last-chunk flag
+const STREAM_NONCE_SIZE: usize = NONCE_SIZE - 5;
+// Magic, flags, argon2 memory, iterations and parallelism, salt and nonce
+const SEALED_HEADER_SIZE: usize = 8 + 1 + 12 + 16 + STREAM_NONCE_SIZE;
+const GZIP_FLAG: u8 = 1;
+// Argon2 costs are read from sealed archive headers and repo configs before anything's authenticated, so any above
+// these are refused rather than tried: a few GiB of memory, and far more iterations and lanes than athena uses
+const MAX_KDF_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_KDF_ITERATIONS: u32 = 64;
+const MAX_KDF_PARALLELISM: u32 = 64;
+
+static PASSWORD: Mutex<Option<String>> = Mutex::new(None);
+
+// How the repo key is protected, as stored in the repo config. The random repo key encrypts everything;
+// it's stored encrypted with a key derived from the passphrase, so changing the passphrase only re-encrypts this
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyConfig {
+    pub kdf: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: String,
+    // Nonce followed by the encrypted repo key, base64 encoded
+    pub key: String,
+}
+
+// Encrypts repository data and derives chunk ids. Chunk ids are keyed hashes, so they don't reveal
+// whether a repo contains some known file
+pub struct RepoKey {
+    master: [u8; 32],
+    cipher: XChaCha20Poly1305,
+    id_key: [u8; 32],
+}
+
+impl RepoKey {
+    fn new(master: [u8; 32]) -> RepoKey {
+        let data_key = blake3::derive_key("athena repository data key", &master);
+        let id_key = blake3::derive_key("athena repository chunk id key", &master);
+        RepoKey { master, cipher: XChaCha20Poly1305::new(&data_key.into()), id_key }
+    }
+
+    // Generates a new random repo key, protected by the passphrase
+    pub fn generate(passphrase: &str) -> Result<(RepoKey, KeyConfig), Box<dyn Error>> {
+        let mut master = [0; 32];
+        OsRng.fill_bytes(&mut master);
+        let key = RepoKey::new(master);
+        let config = key.wrap(passphrase)?;
+        Ok((key, config))
+    }
+
+    pub fn unlock(config: &KeyConfig, passphrase: &str) -> Result<RepoKey, Box<dyn Error>> {
+        if config.kdf != "argon2id" {
+            return Err(format!("Unsupported key derivation function '{}'", config.kdf).into());
+        }
+        let engine = base64::engine::general_purpose::STANDARD;
+        let salt = engine.decode(&config.salt)?;
+        check_kdf("repository config", config.memory_kib, config.iterations, config.parallelism)?;
+        let wrapping_key = derive(passphrase, &salt, config.memory_kib, config.iterations, config.parallelism)?;
+        let master = decrypt_with(&XChaCha20Poly1305::new(&wrapping_key.into()), &engine.decode(&config.key)?)
+            .map_err(|_| "Wrong repository password")?;
+        let master: [u8; 32] = master.try_into().map_err(|_| "Repository key is corrupt")?;
+        Ok(RepoKey::new(master))
+    }
+
+    // Encrypts the repo key under a new passphrase, with a fresh salt
+    pub fn wrap(&self, passphrase: &str) -> Result<KeyConfig, Box<dyn Error>> {
+        let params = argon2::Params::default();
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let wrapping_key = derive(passphrase, &salt, params.m_cost(), params.t_cost(), params.p_cost())?;
+        let wrapped = encrypt_with(&XChaCha20Poly1305::new(&wrapping_key.into()), &self.master)?;
+        let engine = base64::engine::general_purpose::STANDARD;
+        Ok(KeyConfig {
+            kdf: "argon2id".to_string(),
+            memory_kib: params.m_cost(),
+            iterations: params.t_cost(),
+            parallelism: params.p_cost(),
+            salt: engine.encode(salt),
+            key: engine.encode(wrapped),
+        })
+    }
+
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        blake3::keyed_hash(&self.id_key, data).to_hex().to_string()
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        encrypt_with(&self.cipher, data)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        decrypt_with(&self.cipher, data)
+    }
+}
+
+fn derive(passphrase: &str, salt: &[u8], memory_kib: u32, iterations: u32, parallelism: u32) -> Result<[u8; 32], Box<dyn Error>> {
+    let params = argon2::Params::new(memory_kib, iterations, parallelism, Some(32)).map_err(|e| e.to_string())?;
+    let mut key = [0; 32];
+    argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+// Fails for key derivation costs too high to have come from athena, naming what they were read from
+fn check_kdf(source: &str, memory_kib: u32, iterations: u32, parallelism: u32) -> Result<(), Box<dyn Error>> {
+    if memory_kib > MAX_KDF_MEMORY_KIB || iterations > MAX_KDF_ITERATIONS || parallelism > MAX_KDF_PARALLELISM {
+        return Err(format!(
+            "The {} asks for {} KiB of memory, {} iterations and {} lanes to derive its key, more than the {} KiB, {} and {} allowed, so it's corrupt or was tampered with",
+            source, memory_kib, iterations, parallelism, MAX_KDF_MEMORY_KIB, MAX_KDF_ITERATIONS, MAX_KDF_PARALLELISM,
+        ).into());
+    }
+    Ok(())
+}
+
+// Output is a random nonce followed by the ciphertext and its authentication tag
+fn encrypt_with(cipher: &XChaCha20Poly1305, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut out = nonce.to_vec();
+    out.extend(cipher.encrypt(&nonce, data).map_err(|_| "Encryption failed")?);
+    Ok(out)
+}
+
+fn decrypt_with(cipher: &XChaCha20Poly1305, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if data.len() < NONCE_SIZE {
+        return Err("Encrypted data is truncated".into());
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    Ok(cipher.decrypt(XNonce::from_slice(nonce), ciphertext).map_err(|_| "Decryption failed: data is corrupt or was tampered with")?)
+}
+
+// Encrypts a whole archive as it's written, for tars with --encrypt. The archive is cut into chunks, each sealed
+// with XChaCha20-Poly1305 in the STREAM construction: every chunk's nonce has its position and whether it's the
+// last in it, so reordered, dropped or truncated chunks fail to decrypt instead of restoring wrong files. The
+// header, with the key's salt and parameters, is authenticated along with every chunk
+pub struct SealWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> SealWriter<W> {
+    // `gzip` records whether what's sealed is a tgz rather than a tar, for reading it back
+    pub fn new(mut inner: W, password: &str, gzip: bool) -> Result<SealWriter<W>, Box<dyn Error>> {
+        let params = argon2::Params::default();
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut header = SEALED_MAGIC.to_vec();
+        header.push(if gzip { GZIP_FLAG } else { 0 });
+        for param in [params.m_cost(), params.t_cost(), params.p_cost()] {
+            header.extend(param.to_le_bytes());
+        }
+        header.extend(salt);
+        header.extend(nonce);
+        let key = derive(password, &salt, params.m_cost(), params.t_cost(), params.p_cost())?;
+        inner.write_all(&header)?;
+        let encryptor = EncryptorBE32::new(&key.into(), nonce.as_slice().into());
+        Ok(SealWriter { inner, encryptor: Some(encryptor), header, buffer: Vec::with_capacity(SEALED_CHUNK) })
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let chunk = self.buffer.split_off(0);
+        let payload = Payload { msg: &chunk, aad: &self.header };
+        let sealed = match last {
+            true => self.encryptor.take().map(|encryptor| encryptor.encrypt_last(payload)),
+            false => self.encryptor.as_mut().map(|encryptor| encryptor.encrypt_next(payload)),
+        };
+        let sealed = sealed.ok_or_else(|| io::Error::other("Sealed archive is already finished"))?;
+        self.inner.write_all(&sealed.map_err(|_| io::Error::other("Encryption failed"))?)
+    }
+
+    // Seals what's left as the last chunk, returning the writer. An archive that isn't finished has no last
+    // chunk, so reads as truncated
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(SEALED_CHUNK - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..taken]);
+        // Chunks go out as soon as they're full, so the last one, sealed by `finish`, is always short and told apart
+        // by that. It's empty when the archive fills its chunks exactly
+        if self.buffer.len() == SEALED_CHUNK {
+            self.seal(false)?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Reads back what `SealWriter` wrote, failing as soon as a chunk doesn't authenticate
+pub struct UnsealReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    plain: Vec<u8>,
+    read: usize,
+    chunks: u64,
+    pub gzip: bool,
+}
+
+impl<R: Read> UnsealReader<R> {
+    pub fn new(mut inner: R, password: &str) -> Result<UnsealReader<R>, Box<dyn Error>> {
+        let mut header = vec![0; SEALED_HEADER_SIZE];
+        inner.read_exact(&mut header).map_err(|_| "Sealed archive is truncated")?;
+        if !header.starts_with(SEALED_MAGIC) {
+            return Err("Not a sealed archive, or one made by a newer athena".into());
+        }
+        let param = |i: usize| u32::from_le_bytes(header[9 + i * 4..13 + i * 4].try_into().unwrap());
+        let (salt, nonce) = header[21..].split_at(16);
+        check_kdf("sealed archive", param(0), param(1), param(2))?;
+        let key = derive(password, salt, param(0), param(1), param(2))?;
+        let decryptor = DecryptorBE32::new(&key.into(), nonce.into());
+        Ok(UnsealReader { inner, decryptor: Some(decryptor), gzip: header[8] & GZIP_FLAG != 0, header, plain: Vec::new(), read: 0, chunks: 0 })
+    }
+
+    fn unseal(&mut self) -> io::Result<()> {
+        let Some(decryptor) = self.decryptor.as_mut() else {
+            return Ok(());
+        };
+        let mut chunk = Vec::with_capacity(SEALED_CHUNK + TAG_SIZE);
+        (&mut self.inner).take((SEALED_CHUNK + TAG_SIZE) as u64).read_to_end(&mut chunk)?;
+        let payload = Payload { msg: &chunk, aad: &self.header };
+        let full = chunk.len() == SEALED_CHUNK + TAG_SIZE;
+        let plain = match full {
+            true => decryptor.decrypt_next(payload),
+            false => self.decryptor.take().unwrap().decrypt_last(payload),
+        };
+        self.plain = plain.map_err(|_| {
+            let reason = match (self.chunks, full) {
+                (0, _) => "wrong password, or the archive is corrupt or was tampered with".to_string(),
+                (_, true) => format!("chunk {} is corrupt, out of place or was tampered with", self.chunks),
+                (_, false) => format!("the archive is truncated or chunk {} was tampered with", self.chunks),
+            };
+            io::Error::new(io::ErrorKind::InvalidData, format!("Failed to decrypt archive: {}", reason))
+        })?;
+        self.read = 0;
+        self.chunks += 1;
+        if self.decryptor.is_none() && self.inner.read(&mut [0])? > 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Failed to decrypt archive: data was added after its end"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for UnsealReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.plain.len() && self.decryptor.is_some() {
+            self.unseal()?;
+        }
+        let n = buf.len().min(self.plain.len() - self.read);
+        buf[..n].copy_from_slice(&self.plain[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+// Whether the file is an archive sealed by `SealWriter`
+pub fn is_sealed(header: &[u8]) -> bool {
+    header.starts_with(SEALED_MAGIC)
+}
+
+// Opens a sealed archive as the tar inside it, decompressed, asking for its password unless it's in a file. An
+// answer is remembered for the rest of the run, so reading an archive twice, e.g. to preview a restore, asks once
+pub fn open_sealed(archive: &Path, password_file: Option<&Path>) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    let mut remembered = PASSWORD.lock().unwrap();
+    let password = match (remembered.as_ref(), password_file) {
+        (Some(password), None) => password.clone(),
+        _ => utils::read_secret(password_file, "ATHENA_PASSWORD", "Archive password", false)?,
+    };
+    *remembered = Some(password.clone());
+    unseal_tar(BufReader::new(fs::File::open(archive)?), &password)
+}
+
+// Reads a sealed archive through, failing unless every chunk authenticates and the tar inside it is whole
+pub fn check_sealed(archive: &Path, password: &str) -> Result<(), Box<dyn Error>> {
+    let mut tar = tar::Archive::new(unseal_tar(BufReader::new(fs::File::open(archive)?), password)?);
+    for entry in tar.entries()? {
+        io::copy(&mut entry?, &mut io::sink())?;
+    }
+    io::copy(&mut tar.into_inner(), &mut io::sink())?;
+    Ok(())
+}
+
+pub fn unseal_tar<'a, R: Read + 'a>(reader: R, password: &str) -> Result<Box<dyn Read + 'a>, Box<dyn Error>> {
+    let reader = UnsealReader::new(reader, password)?;
+    Ok(match reader.gzip {
+        true => Box::new(MultiGzDecoder::new(BufReader::new(reader))),
+        false => Box::new(BufReader::new(reader)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A sealed archive header with the given argon2 costs, and nothing after it
+    fn sealed_header(memory_kib: u32, iterations: u32, parallelism: u32) -> Vec<u8> {
+        let mut header = SEALED_MAGIC.to_vec();
+        header.push(0);
+        for param in [memory_kib, iterations, parallelism] {
+            header.extend(param.to_le_bytes());
+        }
+        header.resize(SEALED_HEADER_SIZE, 0);
+        header
+    }
+
+    #[test]
+    fn unsealing_refuses_key_derivation_costs_athena_never_uses() {
+        for (memory_kib, iterations, parallelism) in [(u32::MAX, 2, 1), (19 * 1024, u32::MAX, 1), (19 * 1024, 2, 1 << 20)] {
+            let e = UnsealReader::new(sealed_header(memory_kib, iterations, parallelism).as_slice(), "password").err().unwrap();
+            assert!(e.to_string().starts_with("The sealed archive asks for"), "{}", e);
+        }
+        // Allowed costs get as far as the first chunk, which a header alone doesn't have
+        let header = sealed_header(8, 1, 1);
+        let mut reader = UnsealReader::new(header.as_slice(), "password").unwrap();
+        let e = reader.read(&mut [0; 1]).unwrap_err();
+        assert!(e.to_string().contains("wrong password"), "{}", e);
+    }
+
+    #[test]
+    fn unlocking_refuses_key_derivation_costs_athena_never_uses() {
+        let (_, config) = RepoKey::generate("password").unwrap();
+        assert!(RepoKey::unlock(&config, "password").is_ok());
+
+        let config = KeyConfig { memory_kib: u32::MAX, ..config };
+        let e = RepoKey::unlock(&config, "password").err().unwrap();
+        assert!(e.to_string().starts_with("The repository config asks for"), "{}", e);
+    }
+}
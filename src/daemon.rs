@@ -0,0 +1,476 @@
+use std::{collections::{BTreeMap, BTreeSet, VecDeque}, env, fs, path::{Path, PathBuf}, process::{self, Child}, sync::Mutex, time::Duration, error::Error};
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::utils;
+
+const MAX_INTERVAL: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+const CONFIG_KEYS: [&str; 2] = ["max_concurrent", "profiles"];
+const PROFILE_KEYS: [&str; 4] = ["schedule", "concurrency", "scrub", "scrub_count"];
+// Scrubs are queued and run like backups, under the profile's name with this in front. Names can't have a ':'
+const SCRUB: &str = "scrub:";
+// Options a scrub takes from its profile: where the archives are and how to read them
+const SCRUB_OPTIONS: [&str; 16] = [
+    "dest", "backend", "bucket", "prefix", "remote-prefix", "storage-class", "endpoint", "proxy", "ca-bundle", "connect-timeout",
+    "io-timeout", "max-requests", "password-file", "webhook", "tmpdir", "background",
+];
+
+#[derive(clap::Subcommand, Debug)]
+pub enum ConfigCommand {
+    #[command(about = "Check a daemon config file, e.g. in CI, without running anything")]
+    Validate {
+        config: PathBuf,
+    },
+}
+
+// What `athena daemon` runs: named profiles, each a set of backup options
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Config {
+    // Backups running at once across every profile. Unlimited if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+// A profile's options are the backup's long options without the dashes, e.g. `{"src": "/home", "dest":
+// "/backups", "compress": true, "exclude": ["node_modules", "*.pyc"]}`. Underscores can stand in for dashes.
+// Options taking `KEY=VALUE` can be given an object, e.g. `"compress_override": {"*.mp4": "store"}`.
+// `schedule`, `concurrency`, `scrub` and `scrub_count` are the daemon's, not the backup's
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Profile {
+    // When to run it, `every 6h` or `daily 03:00`. Without one it only runs when triggered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    // Runs of this profile at once. Defaults to one, so a run that overruns its schedule delays the next
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    // When to verify the profile's oldest-checked archives, local and uploaded, like `schedule`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scrub: Option<String>,
+    // Archives each scrub checks. Defaults to scrub's own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scrub_count: Option<u64>,
+    #[serde(flatten)]
+    pub options: serde_json::Map<String, Value>,
+}
+
+// Reads a config, rejecting keys that aren't a known setting or one of `options`, the backup's long options
+pub fn load(path: &Path, options: &[String]) -> Result<Config, Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let value: Value = serde_json::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+    let unknown = unknown_keys(&text, &value, options);
+    if !unknown.is_empty() {
+        return Err(format!("Invalid config {}:\n  {}", path.display(), unknown.join("\n  ")).into());
+    }
+    let config: Config = serde_json::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+    if config.profiles.is_empty() {
+        return Err(format!("No profiles in {}", path.display()).into());
+    }
+    if config.max_concurrent == Some(0) {
+        return Err("max_concurrent must be at least 1".into());
+    }
+    for (name, profile) in &config.profiles {
+        check_name(name)?;
+        if let Some(schedule) = &profile.schedule {
+            Schedule::parse(schedule).map_err(|e| format!("Invalid schedule for profile '{}': {}", name, e))?;
+        }
+        if let Some(schedule) = &profile.scrub {
+            Schedule::parse(schedule).map_err(|e| format!("Invalid scrub schedule for profile '{}': {}", name, e))?;
+        }
+        if profile.scrub_count == Some(0) {
+            return Err(format!("Scrub count of profile '{}' must be at least 1", name).into());
+        }
+        if profile.concurrency == Some(0) {
+            return Err(format!("Concurrency of profile '{}' must be at least 1", name).into());
+        }
+    }
+    Ok(config)
+}
+
+// Describes every key that isn't a setting, with where it is and the closest one it might have meant
+fn unknown_keys(text: &str, value: &Value, options: &[String]) -> Vec<String> {
+    let mut unknown = Vec::new();
+    let Some(config) = value.as_object() else {
+        return unknown;
+    };
+    for key in config.keys().filter(|key| !CONFIG_KEYS.contains(&key.as_str())) {
+        unknown.push(describe_unknown(text, &format!("Unknown key '{}'", key), key, locate(text, 0, key), CONFIG_KEYS.iter().map(|key| key.to_string())));
+    }
+    let Some(profiles) = config.get("profiles").and_then(Value::as_object) else {
+        return unknown;
+    };
+    for (name, profile) in profiles {
+        let Some(profile) = profile.as_object() else {
+            continue;
+        };
+        let start = locate(text, locate(text, 0, "profiles").unwrap_or(0), name).unwrap_or(0);
+        for key in profile.keys() {
+            let option = key.replace('_', "-");
+            if PROFILE_KEYS.contains(&key.as_str()) || options.contains(&option) {
+                continue;
+            }
+            // Suggested in the same style, dashes or underscores, the key was written in
+            let known = PROFILE_KEYS.iter().map(|key| key.to_string()).chain(options.iter().map(|option| match key.contains('_') {
+                true => option.replace('-', "_"),
+                false => option.clone(),
+            }));
+            unknown.push(describe_unknown(text, &format!("Unknown option '{}' in profile '{}'", key, name), key, locate(text, start, key), known));
+        }
+    }
+    unknown
+}
+
+fn describe_unknown(text: &str, message: &str, key: &str, at: Option<usize>, known: impl Iterator<Item = String>) -> String {
+    let mut description = message.to_string();
+    if let Some(at) = at {
+        let line = text[..at].matches('\n').count() + 1;
+        let column = text[..at].rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        description.push_str(&format!(" at line {} column {}", line, column));
+    }
+    // Only suggested when it's close enough to be a typo
+    let closest = known.map(|candidate| (edit_distance(key, &candidate), candidate)).min();
+    if let Some((_, candidate)) = closest.filter(|(distance, _)| *distance <= (key.chars().count() / 3).max(2)) {
+        description.push_str(&format!(", did you mean '{}'?", candidate));
+    }
+    description
+}
+
+// Where `"key":` first appears from `start` on
+fn locate(text: &str, start: usize, key: &str) -> Option<usize> {
+    let quoted = serde_json::to_string(key).ok()?;
+    let mut from = start;
+    while let Some(found) = text.get(from..)?.find(&quoted) {
+        let at = from + found;
+        if text[at + quoted.len()..].trim_start().starts_with(':') {
+            return Some(at);
+        }
+        from = at + quoted.len();
+    }
+    None
+}
+
+// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = (previous + (a != *b) as usize).min(row[j] + 1).min(current + 1);
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+// Names end up in URLs and log lines, so they're kept to the same characters as service names
+pub fn check_name(name: &str) -> Result<(), String> {
+    match !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        true => Ok(()),
+        false => Err(format!("Profile name '{}' may only contain letters, numbers, '-' and '_'", name)),
+    }
+}
+
+impl Profile {
+    // The profile as arguments to a backup run
+    pub fn args(&self) -> Result<Vec<String>, String> {
+        let mut args = Vec::new();
+        for (key, value) in &self.options {
+            let flag = format!("--{}", key.replace('_', "-"));
+            if let Value::Object(pairs) = value {
+                for (name, value) in pairs {
+                    let value = match value {
+                        Value::String(value) => value.clone(),
+                        Value::Number(value) => value.to_string(),
+                        _ => return Err(format!("Values of option '{}' must be strings or numbers", key)),
+                    };
+                    args.extend([flag.clone(), format!("{}={}", name, value)]);
+                }
+                continue;
+            }
+            let values = match value {
+                Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    Value::Bool(true) => args.push(flag.clone()),
+                    Value::Bool(false) | Value::Null => {},
+                    Value::String(value) => args.extend([flag.clone(), value.clone()]),
+                    Value::Number(value) => args.extend([flag.clone(), value.to_string()]),
+                    Value::Array(_) | Value::Object(_) => return Err(format!("Option '{}' must be a string, number, boolean, list of them or object", key)),
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    // The profile as arguments to a scrub of its archives. Only the ones in its destination are checked unless it
+    // names a bucket. Each profile keeps its own scrub state, so profiles don't take turns with one list
+    fn scrub_args(&self, state_file: &Path) -> Result<Vec<String>, String> {
+        let options = self.options.iter()
+            .filter(|(key, _)| SCRUB_OPTIONS.contains(&key.replace('_', "-").as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let mut args = vec!["scrub".to_string()];
+        args.extend(Profile { options, ..Profile::default() }.args()?);
+        if let Some(count) = self.scrub_count {
+            args.extend(["--scrub-count".to_string(), count.to_string()]);
+        }
+        args.extend(["--scrub-state".to_string(), state_file.display().to_string()]);
+        Ok(args)
+    }
+
+    // Where the profile writes to: its destination and the bucket it uploads to. Runs sharing any of these wait
+    // for each other rather than compete for the same disk or account
+    fn destinations(&self) -> BTreeSet<String> {
+        let option = |key: &str| self.options.get(key).and_then(Value::as_str);
+        let mut destinations = BTreeSet::new();
+        if let Some(dest) = option("dest") {
+            destinations.insert(format!("dest:{}", dest.trim_end_matches('/')));
+        }
+        if let Some(bucket) = option("bucket") {
+            destinations.insert(format!("bucket:{}/{}", option("backend").unwrap_or("b2"), bucket));
+        }
+        destinations
+    }
+}
+
+// When a profile runs by itself
+#[derive(Clone, Copy, Debug)]
+pub enum Schedule {
+    Every(Duration),
+    Daily(NaiveTime),
+}
+
+impl Schedule {
+    pub fn parse(input: &str) -> Result<Schedule, String> {
+        match input.trim().split_once(char::is_whitespace) {
+            Some(("every", interval)) => match utils::parse_duration(interval.trim())? {
+                interval if interval.is_zero() => Err("Interval must be more than 0".to_string()),
+                interval if interval > MAX_INTERVAL => Err("Interval must be at most a year".to_string()),
+                interval => Ok(Schedule::Every(interval)),
+            },
+            Some(("daily", time)) => NaiveTime::parse_from_str(time.trim(), "%H:%M").map(Schedule::Daily).map_err(|_| format!("Invalid time '{}', expected e.g. 03:00", time.trim())),
+            _ => Err(format!("Unknown schedule '{}', expected e.g. `every 6h` or `daily 03:00`", input)),
+        }
+    }
+
+    // The first time it's due after `now`
+    pub fn next(&self, now: DateTime<Local>) -> DateTime<Local> {
+        match self {
+            Schedule::Every(interval) => now + chrono::Duration::seconds(interval.as_secs() as i64),
+            Schedule::Daily(time) => {
+                let mut date = now.date_naive();
+                loop {
+                    // A time skipped by a DST change runs at the first one after it, on the hour
+                    let due = Local.from_local_datetime(&date.and_time(*time)).earliest().or_else(|| {
+                        Local.from_local_datetime(&(date.and_time(*time) + chrono::Duration::hours(1))).earliest()
+                    });
+                    if let Some(due) = due.filter(|due| *due > now) {
+                        return due;
+                    }
+                    date = date.succ_opt().unwrap_or(date);
+                }
+            },
+        }
+    }
+}
+
+// Why a profile couldn't be started
+#[derive(Debug)]
+pub enum TriggerError {
+    Unknown,
+    // It's already waiting for its turn
+    Queued,
+    Failed(String),
+}
+
+// A profile that's been asked for either starts straight away or waits for its turn
+pub enum Triggered {
+    Started(u32),
+    Queued,
+}
+
+// A profile's state, for the API
+#[derive(Serialize)]
+pub struct ProfileState {
+    pub pids: Vec<u32>,
+    pub queued: bool,
+    // When it's next due, as unix seconds, if it's on a schedule
+    pub next_run: Option<i64>,
+    // When its archives are next scrubbed, if they are
+    pub next_scrub: Option<i64>,
+}
+
+struct State {
+    running: Vec<(String, Child)>,
+    // Profiles waiting for a free slot or for another run to the same destination to finish, in the order asked
+    queue: VecDeque<String>,
+    next: BTreeMap<String, (Schedule, DateTime<Local>)>,
+}
+
+// Starts profiles' backups as child processes, when they're due or asked for, and keeps track of them until they
+// finish. Runs wait in a queue while the global or profile's limit is reached, or while another run is writing to
+// the same destination
+pub struct Runner {
+    config: Config,
+    status_file: PathBuf,
+    state: Mutex<State>,
+}
+
+impl Runner {
+    pub fn new(config: Config, status_file: PathBuf) -> Runner {
+        let now = Local::now();
+        let next = config.profiles.iter()
+            .flat_map(|(name, profile)| [(name.clone(), &profile.schedule), (format!("{}{}", SCRUB, name), &profile.scrub)])
+            .filter_map(|(name, schedule)| schedule.as_deref().and_then(|schedule| Schedule::parse(schedule).ok()).map(|schedule| (name, (schedule, schedule.next(now)))))
+            .collect();
+        Runner { config, status_file, state: Mutex::new(State { running: Vec::new(), queue: VecDeque::new(), next }) }
+    }
+
+    pub fn profiles(&self) -> impl Iterator<Item = &String> {
+        self.config.profiles.keys()
+    }
+
+    pub fn scheduled(&self) -> bool {
+        !self.state.lock().unwrap().next.is_empty()
+    }
+
+    pub fn state(&self, name: &str) -> ProfileState {
+        let mut state = self.state.lock().unwrap();
+        reap(&mut state);
+        ProfileState {
+            pids: state.running.iter().filter(|(running, _)| running == name).map(|(_, child)| child.id()).collect(),
+            queued: state.queue.iter().any(|queued| queued == name),
+            next_run: state.next.get(name).map(|(_, next)| next.timestamp()),
+            next_scrub: state.next.get(&format!("{}{}", SCRUB, name)).map(|(_, next)| next.timestamp()),
+        }
+    }
+
+    // Asks for a backup of the profile, which starts now if nothing's in its way
+    pub fn trigger(&self, name: &str) -> Result<Triggered, TriggerError> {
+        if !self.config.profiles.contains_key(name) {
+            return Err(TriggerError::Unknown);
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.queue.iter().any(|queued| queued == name) {
+            return Err(TriggerError::Queued);
+        }
+        state.queue.push_back(name.to_string());
+        match self.dispatch(&mut state).into_iter().find(|(started, _)| started == name) {
+            Some((_, Ok(pid))) => Ok(Triggered::Started(pid)),
+            Some((_, Err(e))) => Err(TriggerError::Failed(e)),
+            None => {
+                println!("Queued profile {}", name);
+                Ok(Triggered::Queued)
+            },
+        }
+    }
+
+    // Collects finished runs, queues profiles that are due and starts what can be
+    pub fn tick(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Local::now();
+        let due: Vec<String> = state.next.iter().filter(|(_, (_, next))| *next <= now).map(|(name, _)| name.clone()).collect();
+        for name in due {
+            if let Some((schedule, next)) = state.next.get_mut(&name) {
+                *next = schedule.next(now);
+            }
+            // One run waiting is enough, however many times it came due meanwhile
+            if !state.queue.contains(&name) {
+                state.queue.push_back(name);
+            }
+        }
+        self.dispatch(&mut state);
+    }
+
+    // Starts every queued profile that can run now, skipping over ones that still have to wait
+    fn dispatch(&self, state: &mut State) -> Vec<(String, Result<u32, String>)> {
+        reap(state);
+        let mut started = Vec::new();
+        let mut waiting = VecDeque::new();
+        while let Some(name) = state.queue.pop_front() {
+            let profile = self.profile(&name);
+            let destinations = profile.destinations();
+            // A scrub waits for backups to the same place too, so it never reads an archive being written
+            let busy = self.config.max_concurrent.is_some_and(|max| state.running.len() >= max)
+                || state.running.iter().filter(|(running, _)| *running == name).count() >= profile.concurrency.unwrap_or(1)
+                || state.running.iter().any(|(running, _)| *running != name && !self.profile(running).destinations().is_disjoint(&destinations));
+            if busy {
+                waiting.push_back(name);
+                continue;
+            }
+            let child = match name.strip_prefix(SCRUB) {
+                Some(profile_name) => profile.scrub_args(&self.scrub_state(profile_name)).and_then(|args| spawn(&args)),
+                None => self.start(&name, profile),
+            };
+            match child {
+                Ok(child) => {
+                    let pid = child.id();
+                    println!("Started profile {} (pid {})", name, pid);
+                    state.running.push((name.clone(), child));
+                    started.push((name, Ok(pid)));
+                },
+                Err(e) => {
+                    eprintln!("Error: failed to start profile {}: {}", name, e);
+                    started.push((name, Err(e)));
+                },
+            }
+        }
+        state.queue = waiting;
+        started
+    }
+
+    // Kept next to the status file, like the scrub state of runs outside the daemon
+    fn scrub_state(&self, profile: &str) -> PathBuf {
+        self.status_file.with_file_name(format!("scrub-{}.json", profile))
+    }
+
+    // The profile a backup or scrub is of
+    fn profile(&self, name: &str) -> &Profile {
+        &self.config.profiles[name.strip_prefix(SCRUB).unwrap_or(name)]
+    }
+
+    // Runs a backup of the profile. Its output goes to the daemon's
+    fn start(&self, name: &str, profile: &Profile) -> Result<Child, String> {
+        let args = profile.args()?;
+        let exe = env::current_exe().map_err(|e| e.to_string())?;
+        process::Command::new(exe)
+            .args(&args)
+            .arg("--profile")
+            .arg(name)
+            .env("ATHENA_STATUS_FILE", &self.status_file)
+            .stdin(process::Stdio::null())
+            .spawn()
+            .map_err(|e| format!("failed to start backup: {}", e))
+    }
+}
+
+// Runs athena with the arguments, e.g. a scrub. Its output goes to the daemon's
+fn spawn(args: &[String]) -> Result<Child, String> {
+    let exe = env::current_exe().map_err(|e| e.to_string())?;
+    process::Command::new(exe)
+        .args(args)
+        .stdin(process::Stdio::null())
+        .spawn()
+        .map_err(|e| format!("failed to start {}: {}", args[0], e))
+}
+
+// Collects backups that have finished, so they don't linger as zombies or count as running
+fn reap(state: &mut State) {
+    state.running.retain_mut(|(name, child)| match child.try_wait() {
+        Ok(Some(exit)) => {
+            println!("Profile {} finished with {}", name, exit.code().map_or_else(|| "a signal".to_string(), |code| format!("code {}", code)));
+            false
+        },
+        Ok(None) => true,
+        Err(e) => {
+            eprintln!("Warning: lost track of profile {}: {}", name, e);
+            false
+        },
+    });
+}
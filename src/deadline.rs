@@ -0,0 +1,37 @@
+use std::{path::Path, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use indicatif::HumanDuration;
+use crate::manifest::StoppedEarly;
+
+// When a run has to stop by, for --max-duration. Clones share whether archiving was stopped for it
+#[derive(Clone, Debug)]
+pub struct Deadline {
+    at: Instant,
+    limit: Duration,
+    stopped: Arc<Mutex<Option<(usize, usize)>>>,
+}
+
+impl Deadline {
+    // Counted from when the run started, so scanning uses up the window too
+    pub fn new(limit: Duration, started: Instant) -> Deadline {
+        Deadline { at: started + limit, limit, stopped: Arc::default() }
+    }
+
+    pub fn passed(&self) -> bool {
+        Instant::now() >= self.at
+    }
+
+    // Records that archiving stopped with `archived` of `total` files done, returning what goes in the manifest
+    pub fn stop(&self, archived: usize, total: usize, next: &Path) -> StoppedEarly {
+        *self.stopped.lock().unwrap() = Some((archived, total));
+        StoppedEarly { reason: self.reason(), archived, total, next: next.to_string_lossy().to_string() }
+    }
+
+    // How many of how many files were archived, if archiving was stopped
+    pub fn stopped(&self) -> Option<(usize, usize)> {
+        *self.stopped.lock().unwrap()
+    }
+
+    pub fn reason(&self) -> String {
+        format!("reached --max-duration of {}", HumanDuration(self.limit))
+    }
+}
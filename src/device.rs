@@ -0,0 +1,119 @@
+use std::{io::{self, Write}, path::Path, sync::atomic::{AtomicU64, Ordering}};
+
+// tar's own default record size, 20 blocks of 512 bytes, which tape drives set to variable block mode accept
+pub const DEFAULT_BLOCK_SIZE: usize = 10240;
+
+// Bytes written to the device by the last archive, padding included, since its size can't be read back
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+// Whether the destination is a block or character device, like a tape drive or a raw partition, which the archive
+// is written straight to rather than into a file in it
+#[cfg(unix)]
+pub fn is_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    path.metadata().is_ok_and(|metadata| metadata.file_type().is_block_device() || metadata.file_type().is_char_device())
+}
+
+#[cfg(not(unix))]
+pub fn is_device(_path: &Path) -> bool {
+    false
+}
+
+pub fn written() -> u64 {
+    WRITTEN.load(Ordering::Relaxed)
+}
+
+// Writes in whole blocks of a fixed size, as tape drives need each write to be, padding the last with zeros
+pub struct BlockWriter<W: Write> {
+    inner: W,
+    block: Vec<u8>,
+    size: usize,
+    written: u64,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(inner: W, size: usize) -> BlockWriter<W> {
+        BlockWriter { inner, block: Vec::with_capacity(size), size, written: 0 }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.block)?;
+        self.written += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    // Pads out and writes the last block
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.block.is_empty() {
+            self.block.resize(self.size, 0);
+            self.write_block()?;
+        }
+        self.inner.flush()?;
+        WRITTEN.store(self.written, Ordering::Relaxed);
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(self.size - self.block.len());
+        self.block.extend_from_slice(&data[..len]);
+        if self.block.len() == self.size {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    // Only whole blocks go out, so what's buffered waits for the rest of its block or for finish
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Read};
+
+    // Records the size of each write, as a tape drive would see them
+    struct Writes {
+        file: fs::File,
+        sizes: Vec<usize>,
+    }
+
+    impl Write for Writes {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.sizes.push(data.len());
+            self.file.write_all(data)?;
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    #[test]
+    fn writes_whole_blocks_padding_the_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tape");
+        let mut writer = BlockWriter::new(Writes { file: fs::File::create(&path).unwrap(), sizes: Vec::new() }, 1024);
+        // Odd sizes, so writes straddle blocks
+        for chunk in [&[1u8; 700][..], &[2; 700], &[3; 5000], &[4; 3]] {
+            writer.write_all(chunk).unwrap();
+        }
+        let writes = writer.finish().unwrap();
+        drop(writes.file);
+
+        // 6403 bytes go out as seven blocks, each in a write of its own
+        assert_eq!(writes.sizes, vec![1024; 7]);
+        assert_eq!(written(), 7 * 1024);
+        let mut contents = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 7 * 1024);
+        assert_eq!(&contents[695..705], &[1, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+        assert_eq!(&contents[6400..6403], &[4, 4, 4]);
+        assert!(contents[6403..].iter().all(|&b| b == 0));
+    }
+}
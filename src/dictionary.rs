@@ -0,0 +1,109 @@
+use std::{collections::{BinaryHeap, HashMap, HashSet}, io::{self, Read, Write}};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+// Deflate only refers back 32 KiB, which has to cover the dictionary as well as the start of each chunk
+pub const SIZE: usize = 16 * 1024;
+// Only the first 32 KiB of a chunk can refer to the dictionary, so bigger ones are compressed without it
+pub const MAX_CHUNK: usize = 128 * 1024;
+// Files up to this size are sampled for training
+pub const MAX_SAMPLE: u64 = 64 * 1024;
+// How much is sampled in total, enough to see what's common without holding every file
+pub const SAMPLE_BUDGET: u64 = 1024 * 1024;
+// Fewer samples than this don't say much about what's common between files
+pub const MIN_SAMPLES: usize = 8;
+// Length of the substrings counted across samples
+const KMER: usize = 8;
+// Length of the pieces of samples the dictionary is built from, and how far apart candidates start
+const SEGMENT: usize = 64;
+const STEP: usize = 16;
+
+// Content common to many small files, which chunks are compressed against. The dictionary is compressed ahead of
+// each chunk and only what comes after it kept, so references back into it stay valid when it's put back in front
+// of the chunk to decompress it
+pub struct Dictionary {
+    pub data: Vec<u8>,
+    // The dictionary compressed up to a sync flush, which decompressing a chunk starts from
+    prefix: Vec<u8>,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> io::Result<Dictionary> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data)?;
+        encoder.flush()?;
+        let prefix = encoder.get_ref().clone();
+        Ok(Dictionary { data, prefix })
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.data)?;
+        encoder.flush()?;
+        let start = encoder.get_ref().len();
+        encoder.write_all(data)?;
+        Ok(encoder.finish()?.split_off(start))
+    }
+
+    pub fn decompress(&self, compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        DeflateDecoder::new((&self.prefix[..]).chain(compressed)).read_to_end(&mut data)?;
+        if data.len() < self.data.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "shorter than its dictionary"));
+        }
+        Ok(data.split_off(self.data.len()))
+    }
+}
+
+// Builds a dictionary of up to `size` bytes from the pieces of the samples that share the most substrings with
+// other samples. Each piece picked stops counting the substrings it covers, so later picks add something new
+pub fn train(samples: &[Vec<u8>], size: usize) -> Vec<u8> {
+    // How many samples each substring appears in
+    let mut frequency: HashMap<&[u8], u32> = HashMap::new();
+    for sample in samples {
+        let mut seen = HashSet::new();
+        for kmer in sample.windows(KMER) {
+            if seen.insert(kmer) {
+                *frequency.entry(kmer).or_default() += 1;
+            }
+        }
+    }
+    let score = |segment: &[u8], frequency: &HashMap<&[u8], u32>| -> u64 {
+        let mut seen = HashSet::new();
+        // Substrings in only one sample are no use to the others
+        segment.windows(KMER).filter(|kmer| seen.insert(*kmer)).map(|kmer| frequency[kmer] as u64).filter(|&count| count > 1).sum()
+    };
+
+    let mut candidates = BinaryHeap::new();
+    for (i, sample) in samples.iter().enumerate() {
+        for start in (0..sample.len().saturating_sub(KMER - 1)).step_by(STEP) {
+            let segment = &sample[start..(start + SEGMENT).min(sample.len())];
+            candidates.push((score(segment, &frequency), i, start));
+        }
+    }
+    let mut picked = Vec::new();
+    let mut total = 0;
+    while total < size {
+        let Some((stale, i, start)) = candidates.pop() else {
+            break;
+        };
+        let segment = &samples[i][start..(start + SEGMENT).min(samples[i].len())];
+        // Scores only drop as picks are made, so one that's still as high as when it was queued is the best left
+        let current = score(segment, &frequency);
+        if current == 0 {
+            continue;
+        }
+        if current < stale {
+            candidates.push((current, i, start));
+            continue;
+        }
+        for kmer in segment.windows(KMER) {
+            frequency.insert(kmer, 0);
+        }
+        picked.push(segment);
+        total += segment.len();
+    }
+    // The best pieces go last, nearest the chunk, where references to them are shortest
+    let mut dictionary: Vec<u8> = picked.into_iter().rev().flatten().copied().collect();
+    dictionary.drain(..dictionary.len().saturating_sub(size));
+    dictionary
+}
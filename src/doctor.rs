@@ -0,0 +1,81 @@
+use std::{fs, path::{Path, PathBuf}, process};
+use crate::utils;
+
+// Warn when the destination has less free space than this
+const LOW_SPACE_WARNING: u64 = 1000000000;
+
+#[derive(PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    status: Status,
+    message: String,
+}
+
+impl Check {
+    fn new(status: Status, message: String) -> Check {
+        Check { status, message }
+    }
+
+    fn print(&self) {
+        let label = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        };
+        println!("[{:>4}] {}", label, self.message);
+    }
+}
+
+// Runs all diagnostics, printing a line per check. Returns false if any of them failed
+pub fn run(dest: Option<PathBuf>) -> bool {
+    let mut checks = vec![versions()];
+    if let Some(dest) = dest {
+        checks.append(&mut destination(&dest));
+    }
+
+    for check in &checks {
+        check.print();
+    }
+    !checks.iter().any(|check| check.status == Status::Fail)
+}
+
+fn versions() -> Check {
+    Check::new(Status::Ok, format!(
+        "athena {} ({}/{})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    ))
+}
+
+// Checks the destination exists, can be written to, and has room for an archive
+fn destination(dest: &Path) -> Vec<Check> {
+    if !dest.is_dir() {
+        return vec![Check::new(Status::Fail, format!("Destination {} does not exist or is not a directory", dest.display()))];
+    }
+
+    let mut checks = Vec::new();
+    let probe = dest.join(format!(".athena-doctor-{}", process::id()));
+    match fs::write(&probe, b"athena") {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            checks.push(Check::new(Status::Ok, format!("Destination {} is writable", dest.display())));
+        },
+        Err(e) => checks.push(Check::new(Status::Fail, format!("Destination {} is not writable: {}", dest.display(), e))),
+    }
+
+    match utils::available_space(dest) {
+        Some(space) if space < LOW_SPACE_WARNING => checks.push(Check::new(
+            Status::Warn,
+            format!("Destination has only {} free", utils::format_size(space))
+        )),
+        Some(space) => checks.push(Check::new(Status::Ok, format!("Destination has {} free", utils::format_size(space)))),
+        None => checks.push(Check::new(Status::Warn, "Could not determine free space on destination".to_string())),
+    }
+    checks
+}
@@ -0,0 +1,150 @@
+use std::{collections::{BTreeSet, VecDeque}, fs, io::{self, BufRead, BufReader, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Mutex}, thread, time::Duration, error::Error};
+use crate::{backend::Backend, hash::{self, HashAlgorithm}, manifest::{self, RunManifest}, ratelimit::RateLimiter, scratch, utils};
+
+// Archives are fetched in ranges of this size, several at once, so a dropped connection only costs one range
+const RANGE_SIZE: u64 = 8 * 1024 * 1024;
+// Attempts per range before giving up, with exponential backoff between them
+pub const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    pub limit: Option<RateLimiter>,
+    // Ranges downloaded at once
+    pub parallel: usize,
+}
+
+// Downloads bytes `start..end` of an object, retrying failed attempts with backoff and keeping to the rate limit
+pub fn range(backend: &dyn Backend, key: &str, start: u64, end: u64, options: &DownloadOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut attempt = 1;
+    loop {
+        let result = backend.download_range(key, start, end).and_then(|data| match data.len() as u64 == end - start {
+            true => Ok(data),
+            false => Err(format!("got {} bytes of {}", data.len(), end - start).into()),
+        });
+        match result {
+            Ok(data) => {
+                if let Some(limit) = &options.limit {
+                    limit.take(data.len());
+                }
+                return Ok(data);
+            },
+            Err(e) if attempt < DOWNLOAD_ATTEMPTS => {
+                let delay = Duration::from_secs(2_u64.pow(attempt - 1));
+                eprintln!("Download of bytes {}-{} failed: {} (retrying in {}s)", start, end, e, delay.as_secs());
+                thread::sleep(delay);
+                attempt += 1;
+            },
+            Err(e) => return Err(format!("Download of bytes {}-{} of {} failed after {} attempts: {}", start, end, backend.url(key), attempt, e).into()),
+        }
+    }
+}
+
+// A whole archive downloaded to the scratch directory. It's named after the object rather than the run, so it
+// outlives a run that fails part way through and the next one for the same object carries on from it
+pub struct Download {
+    pub path: PathBuf,
+    // Bytes fetched by this run, which is less than the size when an earlier one was resumed
+    pub fetched: u64,
+}
+
+impl Download {
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(log_path(&self.path));
+    }
+}
+
+// Where an object is downloaded to, and the log of ranges already fetched into it
+fn download_path(key: &str) -> PathBuf {
+    scratch::dir().join(format!("athena-download-{}", key.replace(['/', '\\'], "_")))
+}
+
+fn log_path(path: &Path) -> PathBuf {
+    let mut log = path.as_os_str().to_os_string();
+    log.push(".ranges");
+    log.into()
+}
+
+// Downloads the whole object, `parallel` ranges at a time. The log starts with the object's URL and size, then
+// has the start of each range as it's written, so only the ranges missing from it are fetched when resuming
+pub fn fetch(backend: &dyn Backend, key: &str, size: u64, options: &DownloadOptions) -> Result<Download, Box<dyn Error>> {
+    let path = download_path(key);
+    let log = log_path(&path);
+    let header = format!("{} {}", backend.url(key), size);
+    let done = resumable(&path, &log, &header, size).unwrap_or_default();
+    if done.is_empty() {
+        fs::File::create(&path)?.set_len(size)?;
+        fs::write(&log, format!("{}\n", header))?;
+    } else {
+        eprintln!("Resuming download of {}, {} of {} already fetched", backend.url(key), utils::format_size((done.len() as u64 * RANGE_SIZE).min(size)), utils::format_size(size));
+    }
+    let pending: VecDeque<u64> = (0..size).step_by(RANGE_SIZE as usize).filter(|start| !done.contains(start)).collect();
+    let fetched = pending.iter().map(|start| (start + RANGE_SIZE).min(size) - start).sum();
+    let progress = utils::construct_file_progress(size);
+    progress.set_message(format!("Downloading {}", backend.url(key)));
+    progress.set_position(size - fetched);
+    let pending = Mutex::new(pending);
+    let log = Mutex::new(fs::OpenOptions::new().append(true).open(&log)?);
+    let failed = AtomicBool::new(false);
+    let errors: Vec<String> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.parallel.max(1)).map(|_| scope.spawn(|| -> Result<(), String> {
+            let mut file = fs::OpenOptions::new().write(true).open(&path).map_err(|e| e.to_string())?;
+            loop {
+                let Some(start) = pending.lock().unwrap().pop_front() else {
+                    return Ok(());
+                };
+                // One range failing for good stops the others picking up more
+                if failed.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                let end = (start + RANGE_SIZE).min(size);
+                let written = range(backend, key, start, end, options).map_err(|e| e.to_string()).and_then(|data| {
+                    file.seek(SeekFrom::Start(start)).and_then(|_| file.write_all(&data)).and_then(|_| file.sync_data()).map_err(|e| e.to_string())
+                });
+                if let Err(e) = written {
+                    failed.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+                writeln!(log.lock().unwrap(), "{}", start).map_err(|e| e.to_string())?;
+                progress.inc(end - start);
+            }
+        })).collect();
+        workers.into_iter().filter_map(|worker| worker.join().unwrap_or_else(|_| Err("Download thread panicked".to_string())).err()).collect()
+    });
+    progress.finish_and_clear();
+    if let Some(e) = errors.into_iter().next() {
+        return Err(format!("{} (run it again to resume the download)", e).into());
+    }
+    Ok(Download { path, fetched })
+}
+
+// The ranges already in a download left by an earlier run, if it was of the same object
+fn resumable(path: &Path, log: &Path, header: &str, size: u64) -> io::Result<BTreeSet<u64>> {
+    if path.metadata()?.len() != size {
+        return Ok(BTreeSet::new());
+    }
+    let mut lines = BufReader::new(fs::File::open(log)?).lines();
+    if lines.next().transpose()?.as_deref() != Some(header) {
+        return Ok(BTreeSet::new());
+    }
+    // A line cut short by the run being killed doesn't parse, and its range is fetched again
+    Ok(lines.map_while(Result::ok).filter_map(|line| line.parse().ok()).collect())
+}
+
+// Checks a downloaded archive against the sha256 in its run manifest. Returns false when there's no manifest to
+// check it against, e.g. for archives uploaded before they had one
+pub fn verify(backend: &dyn Backend, key: &str, archive: &Path) -> Result<bool, Box<dyn Error>> {
+    let manifest_key = format!("{}{}", key, manifest::RUN_MANIFEST_SUFFIX);
+    if !crate::backend::exists(backend, &manifest_key)? {
+        return Ok(false);
+    }
+    let run: RunManifest = serde_json::from_slice(&backend.download(&manifest_key)?)?;
+    let Some(expected) = run.checksums.get("sha256") else {
+        return Ok(false);
+    };
+    let actual = hash::file(archive, HashAlgorithm::Sha256)?;
+    if &actual != expected {
+        return Err(format!("Downloaded archive doesn't match its checksum (sha256 {}, expected {})", actual, expected).into());
+    }
+    Ok(true)
+}
@@ -0,0 +1,118 @@
+use std::{collections::BTreeMap, fs, io::BufReader, path::{Path, PathBuf}, sync::Arc, error::Error};
+use flate2::read::MultiGzDecoder;
+use crate::{compare::{self, Content}, crypto, exclude::Excludes, footer, hash::HashAlgorithm, incremental, manifest, validate::{self, ArchiveKind}};
+
+// What the source is compared against. An incremental state records every file as of the last run, so is used
+// over the latest archive where there is one, which for an incremental run only holds what changed
+#[derive(Debug)]
+pub enum Baseline {
+    State(PathBuf),
+    Archive(PathBuf),
+}
+
+impl std::fmt::Display for Baseline {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Baseline::State(path) => write!(f, "the last incremental run ({})", path.display()),
+            Baseline::Archive(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+// Paths relative to the source, like they are in the archive
+#[derive(Default)]
+pub struct Report {
+    pub new: Vec<String>,
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.new.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+}
+
+// Works out the baseline for `--against`: `latest` is the source's incremental state in `dest` if it has one,
+// otherwise the newest archive of the source there. Anything else is taken as a state file or an archive
+pub fn resolve(against: &str, src: &Path, dest: Option<&Path>) -> Result<Baseline, Box<dyn Error>> {
+    if against != "latest" {
+        let path = PathBuf::from(against);
+        return match validate::detect(&path)? {
+            Some(_) => Ok(Baseline::Archive(path)),
+            None => Ok(Baseline::State(path)),
+        };
+    }
+    let dest = dest.ok_or("--against latest needs --dest, the directory the backups are in")?;
+    let state = incremental::default_state_path(src, dest);
+    if state.exists() {
+        return Ok(Baseline::State(state));
+    }
+    // Archive names start with the time they were made and include the source's name
+    let name = src.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| "root".to_string());
+    let mut archives: Vec<PathBuf> = fs::read_dir(dest)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().is_some_and(|file_name| file_name.to_string_lossy().contains(&format!("-{}", name))))
+        .filter(|path| validate::readable(validate::detect(path).ok().flatten()).is_ok())
+        .collect();
+    archives.sort();
+    archives.pop().map(Baseline::Archive).ok_or_else(|| format!("No backups of {} in {}", src.display(), dest.display()).into())
+}
+
+// Files the backup leaves out with `excludes` are left out here too, so they aren't reported as new, or as deleted
+// when the filters were added since
+pub fn run(src: &Path, baseline: &Baseline, excludes: Excludes, password_file: Option<&Path>) -> Result<Report, Box<dyn Error>> {
+    let src = validate::input(src.to_path_buf())?;
+    let base = crate::get_inp_path_only(&src);
+    let excludes = Arc::new(excludes);
+    let files = crate::process_sources(vec![src.clone()], crate::cancel::CancellationToken::default(), None, None, None, Some(excludes.clone())).map_err(|e| e.to_string())?;
+    let relative = |path: &Path| path.strip_prefix(&base).unwrap_or(path).to_string_lossy().to_string();
+    let mut report = Report::default();
+    match baseline {
+        Baseline::State(path) => {
+            if !path.exists() {
+                return Err(format!("No incremental state at {}", path.display()).into());
+            }
+            let state = incremental::State::load(path)?;
+            let algorithm = <HashAlgorithm as clap::ValueEnum>::from_str(&state.algorithm, true)?;
+            // Files whose size, mtime and inode match the state aren't hashed again, so this is quick to run often
+            let scan = incremental::scan(&files, &state, algorithm, false, false)?;
+            for path in scan.changed {
+                match state.entries.contains_key(path.to_string_lossy().as_ref()) {
+                    true => report.modified.push(relative(&path)),
+                    false => report.new.push(relative(&path)),
+                }
+            }
+            report.deleted = scan.deleted.iter().filter(|path| !excludes.matches(path)).map(|path| relative(path)).collect();
+        },
+        Baseline::Archive(archive) => {
+            let mut on_disk: BTreeMap<String, PathBuf> = files.into_iter().map(|file| (relative(&file), file)).collect();
+            let mut recorded = Vec::new();
+            let mut collect = |name: PathBuf, content: Content| -> Result<(), Box<dyn Error>> {
+                if name != Path::new(manifest::MANIFEST_NAME) && name != Path::new(footer::INDEX_NAME) {
+                    recorded.push((name.to_string_lossy().to_string(), content));
+                }
+                Ok(())
+            };
+            match validate::readable(validate::detect(archive)?)? {
+                ArchiveKind::Zip => compare::compare_zip(archive, password_file, &mut collect)?,
+                ArchiveKind::Gzip => compare::compare_tar(MultiGzDecoder::new(BufReader::new(fs::File::open(archive)?)), &mut collect)?,
+                ArchiveKind::Sealed => compare::compare_tar(crypto::open_sealed(archive, password_file)?, &mut collect)?,
+                _ => compare::compare_tar(BufReader::new(fs::File::open(archive)?), &mut collect)?,
+            }
+            for (name, content) in recorded {
+                match on_disk.remove(&name) {
+                    Some(file) if compare::disk_content(&file, &content)? != content => report.modified.push(name),
+                    Some(_) => {},
+                    None if excludes.matches(&base.join(&name)) => {},
+                    None => report.deleted.push(name),
+                }
+            }
+            report.new = on_disk.into_keys().collect();
+        },
+    }
+    report.new.sort();
+    report.modified.sort();
+    report.deleted.sort();
+    Ok(report)
+}
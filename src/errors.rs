@@ -0,0 +1,61 @@
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use serde::Serialize;
+
+// Suffix of the log of files `--keep-going` couldn't back up, written next to the archive
+pub const ERRORS_SUFFIX: &str = ".errors.jsonl";
+// Exit code for a run that finished, but without some of the files it should have had
+pub const PARTIAL_FAILURE: i32 = 3;
+
+// One file that couldn't be backed up, as a line of the error log
+#[derive(Serialize, Debug)]
+pub struct FileError {
+    pub path: PathBuf,
+    // What was being done to it: "list", "open" or "read"
+    pub operation: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errno: Option<i32>,
+    pub message: String,
+}
+
+// Collects per-file errors a run carried on past. Clones share the log, so it can be handed to the threads doing
+// the work and read back once they're done
+#[derive(Clone, Debug, Default)]
+pub struct ErrorLog(Arc<Mutex<Vec<FileError>>>);
+
+impl ErrorLog {
+    pub fn record(&self, path: &Path, operation: &'static str, e: &io::Error) {
+        self.push(FileError { path: path.to_path_buf(), operation, errno: e.raw_os_error(), message: e.to_string() });
+    }
+
+    // For problems that aren't an OS error, like a file shrinking while it's read
+    pub fn record_message(&self, path: &Path, operation: &'static str, message: String) {
+        self.push(FileError { path: path.to_path_buf(), operation, errno: None, message });
+    }
+
+    fn push(&self, error: FileError) {
+        self.0.lock().unwrap().push(error);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    // One line per error, for showing to people rather than tools
+    pub fn messages(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().map(|error| format!("{} ({}): {}", error.path.display(), error.operation, error.message)).collect()
+    }
+
+    // Writes the log as one JSON object per line, so it can be read with standard tools
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for error in self.0.lock().unwrap().iter() {
+            serde_json::to_writer(&mut file, error)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()
+    }
+}
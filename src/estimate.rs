@@ -0,0 +1,116 @@
+use std::{fs, io::{Read, Write}, path::{Path, PathBuf}, time::{Duration, Instant}, error::Error};
+use flate2::{write::DeflateEncoder, Compression};
+use indicatif::HumanDuration;
+use crate::utils;
+
+// Only the start of each sampled file is read, so a few huge files can't dominate the sample
+const SAMPLE_PER_FILE: u64 = 1024 * 1024;
+// Per-entry overhead: a tar header, or a zip local header plus central directory record, for a typical path length
+const TAR_ENTRY_OVERHEAD: u64 = 512;
+const ZIP_ENTRY_OVERHEAD: u64 = 150;
+
+// Predicted archive size and time to write it, for one format/compression combination
+pub struct Prediction {
+    pub label: &'static str,
+    pub size: u64,
+    pub duration: Duration,
+}
+
+pub struct Estimate {
+    pub files: usize,
+    pub total_size: u64,
+    pub sampled_files: usize,
+    pub sampled_bytes: u64,
+    pub predictions: Vec<Prediction>,
+}
+
+// Reads and compresses a sample of up to `sample_size` bytes, spread evenly across the files, and extrapolates
+// to the whole set. Nothing is written
+pub fn run(files: &[PathBuf], sample_size: u64) -> Result<Estimate, Box<dyn Error>> {
+    let mut sizes = Vec::new();
+    for file in files {
+        let metadata = file.symlink_metadata()?;
+        sizes.push(if metadata.is_file() { metadata.len() } else { 0 });
+    }
+    let total_size: u64 = sizes.iter().sum();
+    let regular: Vec<usize> = (0..files.len()).filter(|&i| sizes[i] > 0).collect();
+
+    // Every nth file, so the sample covers the whole tree rather than just its first directories
+    let wanted = ((sample_size / SAMPLE_PER_FILE).max(1) as usize).min(regular.len().max(1));
+    let stride = (regular.len() / wanted).max(1);
+    let mut sample = Vec::new();
+    let mut read_time = Duration::ZERO;
+    let mut sampled_files = 0;
+    for &i in regular.iter().step_by(stride) {
+        if sample.len() as u64 >= sample_size {
+            break;
+        }
+        let start = Instant::now();
+        fs::File::open(&files[i])?.take(SAMPLE_PER_FILE.min(sample_size - sample.len() as u64)).read_to_end(&mut sample)?;
+        read_time += start.elapsed();
+        sampled_files += 1;
+    }
+
+    let sampled_bytes = sample.len() as u64;
+    let scale = |sampled: Duration| -> Duration {
+        if sampled_bytes == 0 {
+            Duration::ZERO
+        } else {
+            sampled.mul_f64(total_size as f64 / sampled_bytes as f64)
+        }
+    };
+    let (best_ratio, best_time) = deflate(&sample, Compression::best())?;
+    let (default_ratio, default_time) = deflate(&sample, Compression::default())?;
+    let tar_size = total_size + files.len() as u64 * TAR_ENTRY_OVERHEAD;
+    let zip_overhead = files.len() as u64 * ZIP_ENTRY_OVERHEAD;
+
+    // A tgz compresses the whole stream, headers included, while zip compresses each file's data separately
+    let predictions = vec![
+        Prediction { label: "tar", size: tar_size, duration: scale(read_time) },
+        Prediction { label: "tgz (-c, deflate level 9)", size: (tar_size as f64 * best_ratio) as u64, duration: scale(read_time + best_time) },
+        Prediction { label: "zip", size: total_size + zip_overhead, duration: scale(read_time) },
+        Prediction { label: "zip (-c, deflate level 6)", size: (total_size as f64 * default_ratio) as u64 + zip_overhead, duration: scale(read_time + default_time) },
+    ];
+    Ok(Estimate { files: files.len(), total_size, sampled_files, sampled_bytes, predictions })
+}
+
+// Compressed size as a fraction of the original, and how long compressing took
+fn deflate(data: &[u8], level: Compression) -> Result<(f64, Duration), Box<dyn Error>> {
+    if data.is_empty() {
+        return Ok((1., Duration::ZERO));
+    }
+    let start = Instant::now();
+    let mut encoder = DeflateEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    Ok((compressed.len() as f64 / data.len() as f64, start.elapsed()))
+}
+
+pub fn print(estimate: &Estimate, dest: Option<&Path>) {
+    println!(
+        "{} files, {} (sampled {} from {} files)",
+        estimate.files,
+        utils::format_size(estimate.total_size),
+        utils::format_size(estimate.sampled_bytes),
+        estimate.sampled_files
+    );
+    for prediction in &estimate.predictions {
+        let size = format!("~{}", utils::format_size(prediction.size));
+        println!("  {:<28} {:>11}  ~{:#}", prediction.label, size, HumanDuration(prediction.duration));
+    }
+    if let Some(dest) = dest {
+        match utils::available_space(dest) {
+            Some(free) => {
+                let fitting: Vec<&str> = estimate.predictions.iter().filter(|p| p.size < free).map(|p| p.label).collect();
+                if fitting.len() == estimate.predictions.len() {
+                    println!("{} has {} free, enough for any format", dest.display(), utils::format_size(free));
+                } else if fitting.is_empty() {
+                    println!("{} has {} free, not enough for any format", dest.display(), utils::format_size(free));
+                } else {
+                    println!("{} has {} free, enough for: {}", dest.display(), utils::format_size(free), fitting.join(", "));
+                }
+            },
+            None => println!("Couldn't check free space on {}", dest.display()),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::{fs, path::Path};
+use clap::ValueEnum;
+use glob::{MatchOptions, Pattern};
+
+// Curated sets of paths hardly anyone wants in a backup, for --exclude-preset
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    LinuxSystem,
+    DevCaches,
+    MacosJunk,
+}
+
+impl Preset {
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::LinuxSystem => "linux-system",
+            Preset::DevCaches => "dev-caches",
+            Preset::MacosJunk => "macos-junk",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Preset::LinuxSystem => "Virtual filesystems, runtime state and caches the system rebuilds, for backing up /",
+            Preset::DevCaches => "Dependency and build directories that can be fetched or rebuilt from the project",
+            Preset::MacosJunk => "Finder metadata, Spotlight indexes and trash folders",
+        }
+    }
+
+    pub fn patterns(self) -> &'static [&'static str] {
+        match self {
+            Preset::LinuxSystem => &[
+                "/proc", "/sys", "/dev", "/run", "/tmp", "/var/tmp", "/var/cache", "/var/lib/docker/overlay2", "/lost+found", "/swapfile",
+                ".cache", ".local/share/Trash",
+            ],
+            Preset::DevCaches => &[
+                "node_modules", "target", "__pycache__", "*.pyc", ".pytest_cache", ".mypy_cache", ".tox", ".gradle", ".next", ".parcel-cache",
+                ".terraform",
+            ],
+            Preset::MacosJunk => &[".DS_Store", "._*", ".Spotlight-V100", ".Trashes", ".Trash", ".fseventsd", ".TemporaryItems", ".AppleDouble"],
+        }
+    }
+}
+
+// File types for --only-type and --exclude-type, with the letters `find -type` uses
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    #[value(name = "f")]
+    File,
+    #[value(name = "d")]
+    Dir,
+    #[value(name = "l")]
+    Symlink,
+    #[value(name = "p")]
+    Fifo,
+    #[value(name = "s")]
+    Socket,
+    #[value(name = "b")]
+    BlockDevice,
+    #[value(name = "c")]
+    CharDevice,
+}
+
+impl FileType {
+    pub fn of(file_type: fs::FileType) -> FileType {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if file_type.is_fifo() {
+                return FileType::Fifo;
+            } else if file_type.is_socket() {
+                return FileType::Socket;
+            } else if file_type.is_block_device() {
+                return FileType::BlockDevice;
+            } else if file_type.is_char_device() {
+                return FileType::CharDevice;
+            }
+        }
+        if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_dir() {
+            FileType::Dir
+        } else {
+            FileType::File
+        }
+    }
+}
+
+// Checks an --exclude pattern is a valid glob
+pub fn parse_pattern(input: &str) -> Result<String, String> {
+    compile(input).map(|_| input.to_string())
+}
+
+// Patterns starting with / match from the root, e.g. `/var/cache`. Other patterns with a / match the end of the
+// path, e.g. `.local/share/Trash`, and ones without match any file or directory by name, e.g. `node_modules`
+pub fn compile(pattern: &str) -> Result<Pattern, String> {
+    let pattern = pattern.trim_end_matches('/');
+    let pattern = match pattern.starts_with('/') || !pattern.contains('/') {
+        true => pattern.to_string(),
+        false => format!("**/{}", pattern),
+    };
+    Pattern::new(&pattern).map_err(|e| format!("Invalid exclude pattern '{}': {}", pattern, e))
+}
+
+// What to leave out of a backup, and of anything comparing a source against one
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct FilterOptions {
+    // Leave out files and directories matching these globs. `/var/cache` matches from the root, `.local/share/Trash`
+    // the end of a path, and `node_modules` or `*.pyc` any file or directory by name
+    #[arg(long = "exclude", value_parser = parse_pattern, value_delimiter = ',', env = "ATHENA_EXCLUDE")]
+    pub exclude: Vec<String>,
+    // Leave out a curated set of paths as well, see `athena presets` for what each covers
+    #[arg(long = "exclude-preset", value_enum, value_delimiter = ',', env = "ATHENA_EXCLUDE_PRESET")]
+    pub exclude_preset: Vec<Preset>,
+    // Only archive files of these types: f (regular files), d (directories, so empty ones are kept), l (symlinks),
+    // p (FIFOs), s (sockets), b and c (block and character devices). Directories are searched either way
+    #[arg(long = "only-type", value_enum, value_delimiter = ',', env = "ATHENA_ONLY_TYPE")]
+    pub only_type: Vec<FileType>,
+    // Leave out files of these types, e.g. `--exclude-type s,p`
+    #[arg(long = "exclude-type", value_enum, value_delimiter = ',', env = "ATHENA_EXCLUDE_TYPE")]
+    pub exclude_type: Vec<FileType>,
+}
+
+impl FilterOptions {
+    pub fn excludes(&self) -> Result<Excludes, String> {
+        Excludes::new(&self.exclude, &self.exclude_preset, &self.only_type, &self.exclude_type)
+    }
+}
+
+// Paths and file types left out of the scan. An excluded directory isn't read at all, but directories are read
+// whatever their type's filtered to, for the files in them
+#[derive(Default)]
+pub struct Excludes {
+    // Patterns matched against the whole path, and against just the name
+    paths: Vec<Pattern>,
+    names: Vec<Pattern>,
+    only_types: Vec<FileType>,
+    except_types: Vec<FileType>,
+}
+
+impl Excludes {
+    pub fn new(patterns: &[String], presets: &[Preset], only_types: &[FileType], except_types: &[FileType]) -> Result<Excludes, String> {
+        let mut excludes = Excludes { only_types: only_types.to_vec(), except_types: except_types.to_vec(), ..Excludes::default() };
+        let presets = presets.iter().flat_map(|preset| preset.patterns().iter().copied());
+        for pattern in patterns.iter().map(String::as_str).chain(presets) {
+            let compiled = compile(pattern)?;
+            let pattern = pattern.trim_end_matches('/');
+            match pattern.contains('/') {
+                true => excludes.paths.push(compiled),
+                false => excludes.names.push(compiled),
+            }
+        }
+        Ok(excludes)
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let options = MatchOptions { require_literal_separator: true, ..MatchOptions::default() };
+        let name = path.file_name().map(Path::new).unwrap_or(path);
+        if self.names.iter().any(|pattern| pattern.matches_path_with(name, options)) {
+            return true;
+        }
+        // Sources given as relative paths are matched from the root like any other
+        let path = match path.is_relative() && !self.paths.is_empty() {
+            true => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+            false => path.to_path_buf(),
+        };
+        self.paths.iter().any(|pattern| pattern.matches_path_with(&path, options))
+    }
+
+    pub fn filters_types(&self) -> bool {
+        !self.only_types.is_empty() || !self.except_types.is_empty()
+    }
+
+    pub fn keeps_type(&self, file_type: FileType) -> bool {
+        (self.only_types.is_empty() || self.only_types.contains(&file_type)) && !self.except_types.contains(&file_type)
+    }
+
+    // Directories are only archived as entries of their own, keeping empty ones, when asked for with
+    // `--only-type d`. Otherwise they're just where the files are
+    pub fn keeps_dirs(&self) -> bool {
+        self.only_types.contains(&FileType::Dir) && !self.except_types.contains(&FileType::Dir)
+    }
+
+    // Tells filter sets apart: the patterns, presets' included, and the type filters. Order doesn't matter
+    pub fn fingerprint(&self) -> String {
+        let mut patterns: Vec<&str> = self.paths.iter().chain(&self.names).map(Pattern::as_str).collect();
+        patterns.sort_unstable();
+        patterns.dedup();
+        let types = |filter: &[FileType]| {
+            let mut letters: Vec<String> = filter.iter().filter_map(|file_type| file_type.to_possible_value()).map(|value| value.get_name().to_string()).collect();
+            letters.sort_unstable();
+            letters.join(",")
+        };
+        let mut hasher = crate::hash::HashAlgorithm::Blake3.hasher();
+        hasher.update(format!("{}\0{}\0{}", patterns.join("\0"), types(&self.only_types), types(&self.except_types)).as_bytes());
+        hasher.finalize()
+    }
+}
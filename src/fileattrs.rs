@@ -0,0 +1,100 @@
+use std::{fs, path::Path, error::Error};
+use crate::pax;
+
+// File capabilities, under the key GNU tar and star use for extended attributes, so they can restore them too
+pub const CAPABILITY_KEY: &str = "SCHILY.xattr.security.capability";
+// chattr flags worth keeping, as the letters lsattr shows for them
+pub const FLAGS_KEY: &str = "ATHENA.linux.flags";
+#[cfg(target_os = "linux")]
+const CAPABILITY_XATTR: &std::ffi::CStr = c"security.capability";
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+#[cfg(target_os = "linux")]
+const FS_APPEND_FL: libc::c_int = 0x20;
+
+// PAX records for a file's capabilities and its immutable (i) and append-only (a) flags, if it has any. Only regular
+// files and directories are looked at, as opening anything else to read its flags can have side effects
+#[cfg(target_os = "linux")]
+pub fn pax_records(path: &Path, metadata: &fs::Metadata) -> Result<pax::Records, Box<dyn Error>> {
+    use std::os::unix::{ffi::OsStrExt, fs::OpenOptionsExt, io::AsRawFd};
+    let mut records = Vec::new();
+    if !metadata.is_file() && !metadata.is_dir() {
+        return Ok(records);
+    }
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = [0u8; 64];
+    // Safety: `c_path` is NUL-terminated and `buf` is as long as the length passed
+    let len = unsafe { libc::lgetxattr(c_path.as_ptr(), CAPABILITY_XATTR.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    if len > 0 {
+        records.push((CAPABILITY_KEY.to_string(), buf[..len as usize].to_vec()));
+    }
+
+    // Directories can be listable without being readable, and then their flags are just left out
+    let Ok(file) = fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW).open(path) else {
+        return Ok(records);
+    };
+    let mut flags: libc::c_int = 0;
+    // Safety: FS_IOC_GETFLAGS writes an int. Filesystems without flags fail it, and have none to keep
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } == 0 {
+        let letters: String = [(FS_IMMUTABLE_FL, 'i'), (FS_APPEND_FL, 'a')].iter().filter(|(flag, _)| flags & flag != 0).map(|(_, letter)| letter).collect();
+        if !letters.is_empty() {
+            records.push((FLAGS_KEY.to_string(), letters.into_bytes()));
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pax_records(_path: &Path, _metadata: &fs::Metadata) -> Result<pax::Records, Box<dyn Error>> {
+    Ok(Vec::new())
+}
+
+// Sets the capabilities recorded for a restored file. Needs CAP_SETFCAP, usually meaning root
+#[cfg(target_os = "linux")]
+pub fn apply_capability(path: &Path, records: &pax::Records) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::ffi::OsStrExt;
+    let Some((_, value)) = records.iter().find(|(key, _)| key == CAPABILITY_KEY) else {
+        return Ok(());
+    };
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // Safety: `c_path` is NUL-terminated and `value` is as long as the length passed
+    if unsafe { libc::lsetxattr(c_path.as_ptr(), CAPABILITY_XATTR.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) } != 0 {
+        return Err(format!("Failed to set capabilities on {}: {}", path.display(), std::io::Error::last_os_error()).into());
+    }
+    Ok(())
+}
+
+// Sets recorded flags on a restored file. Needs CAP_LINUX_IMMUTABLE, and has to come after everything else is
+// written, as nothing can be added to an immutable directory
+#[cfg(target_os = "linux")]
+pub fn apply_flags(path: &Path, letters: &[u8]) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd};
+    let file = fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW).open(path)?;
+    let mut flags: libc::c_int = 0;
+    // Safety: FS_IOC_GETFLAGS and FS_IOC_SETFLAGS read and write an int
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(format!("Failed to read flags of {}: {}", path.display(), std::io::Error::last_os_error()).into());
+    }
+    for letter in letters {
+        flags |= match letter {
+            b'i' => FS_IMMUTABLE_FL,
+            b'a' => FS_APPEND_FL,
+            _ => 0,
+        };
+    }
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(format!("Failed to set flags on {}: {}", path.display(), std::io::Error::last_os_error()).into());
+    }
+    Ok(())
+}
+
+// Capabilities and chattr flags are Linux-only, so elsewhere they're skipped on restore
+#[cfg(not(target_os = "linux"))]
+pub fn apply_capability(_path: &Path, _records: &pax::Records) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply_flags(_path: &Path, _letters: &[u8]) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use std::{fs, path::{Path, PathBuf}, error::Error};
+use glob::{MatchOptions, Pattern};
+use crate::{footer, list, manifest, validate};
+
+// An entry matching the glob, and the archive it's in
+pub struct Found {
+    pub archive: PathBuf,
+    pub entry: list::Listed,
+}
+
+// Looks through every archive in `locations`, each an archive or a directory of them, for entries matching the
+// glob: against the whole path if it has a /, otherwise against the name. Archives with a footer index are answered
+// from it without reading the rest. Matches come oldest archive first, going by the time athena puts in their names
+pub fn run(glob: &str, locations: &[PathBuf], password_file: Option<&Path>) -> Result<Vec<Found>, Box<dyn Error>> {
+    let glob = Pattern::new(glob).map_err(|e| format!("Invalid glob '{}': {}", glob, e))?;
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::default() };
+    let matches = |path: &str| match glob.as_str().contains('/') {
+        true => glob.matches_with(path.trim_end_matches('/'), options),
+        false => glob.matches_with(path.trim_end_matches('/').rsplit('/').next().unwrap_or(path), options),
+    };
+    let mut found = Vec::new();
+    for archive in archives(locations)? {
+        for entry in list::list(&archive, password_file).map_err(|e| format!("Failed to read {}: {}", archive.display(), e))? {
+            if entry.path != manifest::MANIFEST_NAME && entry.path != footer::INDEX_NAME && matches(&entry.path) {
+                found.push(Found { archive: archive.clone(), entry });
+            }
+        }
+    }
+    Ok(found)
+}
+
+// Archives named directly, and those in directories named. Checksums, indexes and anything else alongside them
+// are skipped
+pub fn archives(locations: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let is_archive = |path: &Path| path.is_file() && validate::readable(validate::detect(path).ok().flatten()).is_ok();
+    let mut archives = Vec::new();
+    for location in locations {
+        if !location.is_dir() {
+            archives.push(location.clone());
+            continue;
+        }
+        let mut found: Vec<PathBuf> = fs::read_dir(location)?.filter_map(|entry| entry.ok().map(|entry| entry.path())).filter(|path| is_archive(path)).collect();
+        found.sort_by_key(|path| path.file_name().map(|name| name.to_os_string()));
+        archives.extend(found);
+    }
+    Ok(archives)
+}
@@ -0,0 +1,230 @@
+use std::{fs, io::{self, BufReader, Read, Seek, SeekFrom, Write}, path::Path, error::Error};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression, GzBuilder};
+use crate::{index::{EntryIndex, Frame, IndexedEntry}, pax, validate::{self, ArchiveKind}};
+
+// Name of the entry holding the footer index, written after every other entry
+pub const INDEX_NAME: &str = ".athena-index.json";
+// Precedes the offset the footer index starts at, as 16 hex digits, near the end of the archive. In a plain tar
+// it's in a PAX global header, which tar tools read as metadata rather than a file. In a compressed one it's in
+// the extra field of a final empty gzip member, so it can be found without decompressing anything
+const MARKER: &[u8] = b"athena.index=";
+const MARKER_KEY: &str = "athena.index";
+// How far from the end of the archive the marker is looked for
+const TAIL_SIZE: u64 = 4096;
+
+// What a tar is written into, keeping track of where its entries start so they can be indexed in the footer
+pub trait TarWriter: Write {
+    // Bytes of uncompressed tar written so far
+    fn position(&self) -> u64;
+    // Starts the footer, returning the offset in the archive file it can be read from
+    fn start_footer(&mut self) -> io::Result<u64>;
+    fn compressed(&self) -> bool;
+    // Compresses what's written from here on at `level`, for entries with a --compress-override
+    fn set_level(&mut self, _level: Compression) -> io::Result<()> {
+        Ok(())
+    }
+    // Where each seekable frame starts so far
+    fn frames(&self) -> Vec<Frame> {
+        Vec::new()
+    }
+}
+
+// Writes the footer index as the last entry of the archive, followed by the marker for plain tars. Compressed
+// ones get theirs once the writer is finished
+pub fn write<W: TarWriter>(archive: &mut tar::Builder<W>, entries: Vec<IndexedEntry>) -> io::Result<()> {
+    let frames = archive.get_ref().frames();
+    let offset = archive.get_mut().start_footer()?;
+    let contents = serde_json::to_vec(&EntryIndex { entries, frames })?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Local::now().timestamp() as u64);
+    archive.append_data(&mut header, INDEX_NAME, &contents[..])?;
+    if !archive.get_ref().compressed() {
+        let record = pax::record(MARKER_KEY, format!("{:016x}", offset).as_bytes());
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XGlobalHeader);
+        header.set_path("pax_global_header")?;
+        header.set_size(record.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append(&header, &record[..])?;
+    }
+    Ok(())
+}
+
+// The final gzip member of a compressed archive with a footer: empty, with the marker in its extra field
+pub fn marker_member<W: Write>(writer: W, offset: u64) -> io::Result<W> {
+    let marker = [MARKER, format!("{:016x}", offset).as_bytes()].concat();
+    // One extra subfield, "AI", holding the marker
+    let mut extra = b"AI".to_vec();
+    extra.extend((marker.len() as u16).to_le_bytes());
+    extra.extend(marker);
+    GzBuilder::new().extra(extra).write(writer, Compression::none()).finish()
+}
+
+// Reads the footer index, if the archive has one, without reading the rest of the archive. Archives that only
+// look like they have one, e.g. because their last file happens to contain the marker, are treated as having none
+pub fn read(archive: &Path) -> Result<Option<EntryIndex>, Box<dyn Error>> {
+    let mut file = fs::File::open(archive)?;
+    let Some(offset) = find_marker(&mut file)? else {
+        return Ok(None);
+    };
+    let mut tar = tar::Archive::new(open_at(archive, file, offset)?);
+    let Some(Ok(entry)) = tar.entries()?.next() else {
+        return Ok(None);
+    };
+    if entry.path()? != Path::new(INDEX_NAME) {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_reader(entry)?))
+}
+
+// Copies one entry's contents to `out`, reading only from the frame it's in for seekable archives, or just skipping
+// ahead to it otherwise
+pub fn cat(archive: &Path, index: &EntryIndex, entry: &IndexedEntry, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::open(archive)?;
+    let reader: Box<dyn Read> = if validate::detect(archive)? == Some(ArchiveKind::Gzip) {
+        let frame = index.frames.iter().rev().find(|frame| frame.start <= entry.start).copied().unwrap_or(Frame { offset: 0, start: 0 });
+        file.seek(SeekFrom::Start(frame.offset))?;
+        let mut decoder = MultiGzDecoder::new(BufReader::new(file));
+        io::copy(&mut (&mut decoder).take(entry.start - frame.start), &mut io::sink())?;
+        Box::new(decoder)
+    } else {
+        file.seek(SeekFrom::Start(entry.start))?;
+        Box::new(BufReader::new(file))
+    };
+    let mut tar = tar::Archive::new(reader);
+    let mut found = tar.entries()?.next().ok_or("Footer index doesn't match the archive")??;
+    if found.path()?.to_string_lossy() != entry.path {
+        return Err("Footer index doesn't match the archive".into());
+    }
+    io::copy(&mut found, out)?;
+    Ok(())
+}
+
+fn find_marker(file: &mut fs::File) -> io::Result<Option<u64>> {
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL_SIZE)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let Some(start) = tail.windows(MARKER.len()).rposition(|window| window == MARKER) else {
+        return Ok(None);
+    };
+    let hex = tail.get(start + MARKER.len()..start + MARKER.len() + 16).and_then(|hex| std::str::from_utf8(hex).ok());
+    Ok(hex.and_then(|hex| u64::from_str_radix(hex, 16).ok()))
+}
+
+// The uncompressed tar from `offset` in the archive file on
+fn open_at(archive: &Path, mut file: fs::File, offset: u64) -> io::Result<Box<dyn Read>> {
+    let gzip = validate::detect(archive)? == Some(ArchiveKind::Gzip);
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(if gzip { Box::new(MultiGzDecoder::new(BufReader::new(file))) } else { Box::new(BufReader::new(file)) })
+}
+
+// An uncompressed tar
+pub struct PlainWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> PlainWriter<W> {
+    pub fn new(inner: W) -> PlainWriter<W> {
+        PlainWriter { inner, written: 0 }
+    }
+
+    pub fn finish(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for PlainWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> TarWriter for PlainWriter<W> {
+    fn position(&self) -> u64 {
+        self.written
+    }
+
+    fn start_footer(&mut self) -> io::Result<u64> {
+        Ok(self.written)
+    }
+
+    fn compressed(&self) -> bool {
+        false
+    }
+}
+
+// A gzipped tar, in one member unless a footer is written, which starts a member of its own, or the level changes
+pub struct GzWriter<W: Write> {
+    encoder: Option<GzEncoder<PlainWriter<W>>>,
+    level: Compression,
+    // The level the current member's compressed at
+    current: Compression,
+    uncompressed: u64,
+    footer: Option<u64>,
+}
+
+impl<W: Write> GzWriter<W> {
+    pub fn new(inner: W, level: Compression) -> GzWriter<W> {
+        GzWriter { encoder: Some(GzEncoder::new(PlainWriter::new(inner), level)), level, current: level, uncompressed: 0, footer: None }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let inner = self.encoder.take().unwrap().finish()?.finish();
+        match self.footer {
+            Some(offset) => marker_member(inner, offset),
+            None => Ok(inner),
+        }
+    }
+}
+
+impl<W: Write> Write for GzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.encoder.as_mut().unwrap().write(buf)?;
+        self.uncompressed += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> TarWriter for GzWriter<W> {
+    fn position(&self) -> u64 {
+        self.uncompressed
+    }
+
+    fn start_footer(&mut self) -> io::Result<u64> {
+        let inner = self.encoder.take().unwrap().finish()?;
+        let offset = inner.written;
+        self.encoder = Some(GzEncoder::new(inner, self.level));
+        self.current = self.level;
+        self.footer = Some(offset);
+        Ok(offset)
+    }
+
+    fn compressed(&self) -> bool {
+        true
+    }
+
+    // A member can't change level part way, so a new one's started, which readers carry straight on through
+    fn set_level(&mut self, level: Compression) -> io::Result<()> {
+        if level != self.current {
+            let inner = self.encoder.take().unwrap().finish()?;
+            self.encoder = Some(GzEncoder::new(inner, level));
+            self.current = level;
+        }
+        Ok(())
+    }
+}
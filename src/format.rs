@@ -0,0 +1,241 @@
+use std::{fs, io, path::{Path, PathBuf}, error::Error};
+use chrono::{Datelike, Timelike};
+use clap::ValueEnum;
+use flate2::Compression;
+use glob::{MatchOptions, Pattern};
+use zip::{write::SimpleFileOptions, AesMode, CompressionMethod, ZipWriter};
+use crate::{exclude, index, manifest::{self, Manifest}, pathstyle, progress::{Progress, ProgressReader}, readahead, scratch, space, squashfs, utils};
+
+// Entries over this size need zip64 extensions
+const ZIP64_THRESHOLD: u64 = 0xFFFFFFFF;
+// Roughly what deflate (gzip, and compressed zip) holds while compressing: its 32 KiB window plus hash chains,
+// the same at every level
+const DEFLATE_MEMORY: u64 = 320 * 1024;
+// Seekable frames smaller than this compress too poorly to be worth it
+const MIN_FRAME_SIZE: u64 = 64 * 1024;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    #[default]
+    Tar,
+    Zip,
+    // A mountable, read-only filesystem image, for archives that should stay browsable
+    Squashfs,
+    // newc, as initramfs images and other cpio tools take
+    Cpio,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self, compression: bool) -> &'static str {
+        match (self, compression) {
+            (ArchiveFormat::Tar, true) => "tgz",
+            (ArchiveFormat::Tar, false) => "tar",
+            (ArchiveFormat::Zip, _) => "zip",
+            (ArchiveFormat::Squashfs, _) => "sqfs",
+            (ArchiveFormat::Cpio, true) => "cpio.gz",
+            (ArchiveFormat::Cpio, false) => "cpio",
+        }
+    }
+}
+
+// How files matching a --compress-override are compressed, in place of the archive's default
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Store,
+    Deflate(u32),
+}
+
+impl Method {
+    pub fn level(self) -> Compression {
+        match self {
+            Method::Store => Compression::none(),
+            Method::Deflate(level) => Compression::new(level),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompressOverride {
+    pattern: Pattern,
+    // Whether the pattern's matched against the whole path rather than just the name
+    path: bool,
+    method: Method,
+}
+
+// Parses `PATTERN=METHOD`, e.g. `*.mp4=store` or `*.sqlite=deflate:9`. Patterns match like --exclude's do
+pub fn parse_override(input: &str) -> Result<CompressOverride, String> {
+    let (pattern, method) = input.rsplit_once('=').ok_or_else(|| format!("Invalid override '{}', expected e.g. '*.mp4=store'", input))?;
+    let (name, level) = match method.trim().split_once(':') {
+        Some((name, level)) => (name, Some(level)),
+        None => (method.trim(), None),
+    };
+    let method = match (name, level) {
+        ("store", None) => Method::Store,
+        ("store", Some(_)) => return Err("store doesn't take a level".to_string()),
+        ("deflate", None) => Method::Deflate(Compression::default().level()),
+        ("deflate", Some(level)) => Method::Deflate(level.parse().ok().filter(|level| *level <= 9).ok_or_else(|| format!("Invalid level '{}', expected 0 to 9", level))?),
+        ("zstd" | "xz" | "lzma" | "bzip2" | "brotli", _) => return Err(format!("{} isn't available, only store and deflate[:LEVEL]", name)),
+        _ => return Err(format!("Unknown compression method '{}', expected store or deflate[:LEVEL]", name)),
+    };
+    let compiled = exclude::compile(pattern).map_err(|_| format!("Invalid pattern '{}' in override", pattern))?;
+    Ok(CompressOverride { pattern: compiled, path: pattern.trim_end_matches('/').contains('/'), method })
+}
+
+// The method for the file at `path`, from the first override that matches it
+pub fn method_for(overrides: &[CompressOverride], path: &Path) -> Option<Method> {
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::default() };
+    let name = path.file_name().map(Path::new).unwrap_or(path);
+    overrides.iter()
+        .find(|o| o.pattern.matches_path_with(if o.path { path } else { name }, options))
+        .map(|o| o.method)
+}
+
+// Frame size for seekable archives, lowered from the default when `max_memory` can't also hold a whole frame's
+// compressed output, which is buffered until the frame is cut, along with the smallest read buffers
+pub fn frame_size(max_memory: Option<u64>) -> u64 {
+    match max_memory {
+        Some(max_memory) => max_memory.saturating_sub(DEFLATE_MEMORY + min_read_memory()).min(index::FRAME_SIZE),
+        None => index::FRAME_SIZE,
+    }
+}
+
+// Largest read buffer that fits in `max_memory` next to the compressor, with a file's read-ahead holding several
+pub fn max_buffer_size(format: ArchiveFormat, compression: bool, seekable: bool, max_memory: u64) -> usize {
+    let compressor = match (format, compression) {
+        (ArchiveFormat::Tar, true) if seekable => DEFLATE_MEMORY + frame_size(Some(max_memory)),
+        (ArchiveFormat::Squashfs, true) => DEFLATE_MEMORY + squashfs::BLOCK_SIZE as u64,
+        (_, true) => DEFLATE_MEMORY,
+        (_, false) => 0,
+    };
+    (max_memory.saturating_sub(compressor) / readahead::READ_BUFFERS) as usize
+}
+
+fn min_read_memory() -> u64 {
+    readahead::READ_BUFFERS * readahead::MIN_BUFFER_SIZE as u64
+}
+
+// Refuses settings whose compressor and read buffers can't fit in `max_memory`
+pub fn check_memory(format: ArchiveFormat, compression: bool, seekable: bool, max_memory: u64, buffer_size: Option<u64>) -> Result<(), String> {
+    let needed = match (format, compression) {
+        (ArchiveFormat::Tar, true) if seekable => DEFLATE_MEMORY + MIN_FRAME_SIZE,
+        // Each block is read whole before it's compressed
+        (ArchiveFormat::Squashfs, true) => DEFLATE_MEMORY + squashfs::BLOCK_SIZE as u64,
+        (_, true) => DEFLATE_MEMORY,
+        (_, false) => 0,
+    } + min_read_memory();
+    if needed > max_memory {
+        return Err(format!(
+            "{} needs at least {}, over --max-memory {}",
+            match (compression, seekable) {
+                (false, _) => "Reading files",
+                (true, true) => "Seekable compression",
+                (true, false) => "Deflate compression",
+            },
+            utils::format_size(needed),
+            utils::format_size(max_memory)
+        ));
+    }
+    let fits = max_buffer_size(format, compression, seekable, max_memory);
+    if let Some(buffer_size) = buffer_size.filter(|&size| size > fits as u64) {
+        return Err(format!(
+            "--buffer-size {} doesn't fit in --max-memory {} next to compression, use at most {}",
+            utils::format_size(buffer_size),
+            utils::format_size(max_memory),
+            utils::format_size(fits as u64)
+        ));
+    }
+    Ok(())
+}
+
+// Reads the archive password from a file, or prompts for it (twice, to catch typos)
+pub fn read_password(password_file: Option<&Path>) -> Result<String, Box<dyn Error>> {
+    utils::read_secret(password_file, "ATHENA_PASSWORD", "Archive password", true)
+}
+
+// Writes the given files into a zip archive, AES-256 encrypting each entry if a password is set
+pub fn write_zip(paths: &[PathBuf], file: fs::File, options: &utils::Options, base: &Path, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+    let mut zip = ZipWriter::new(file);
+    let method = if options.compression { CompressionMethod::Deflated } else { CompressionMethod::Stored };
+    let method_for = |path: &Path| match method_for(&options.compress_overrides, path) {
+        Some(Method::Store) => (CompressionMethod::Stored, None),
+        Some(Method::Deflate(level)) => (CompressionMethod::Deflated, Some(level as i64)),
+        None => (method, None),
+    };
+    let encrypted = |entry_options: SimpleFileOptions| match &options.password {
+        Some(password) => entry_options.with_aes_encryption(AesMode::Aes256, password),
+        None => entry_options,
+    };
+    let mut manifest = Manifest::new(options.host.as_ref(), options.comment.as_deref());
+    manifest.deleted = options.deleted.clone();
+
+    let mut space = space::Guard::new(vec![options.output_path.clone(), scratch::dir()], options.min_free_space);
+    for (i, path) in paths.iter().enumerate() {
+        options.cancel.check("archiving", i, Some(paths.len()))?;
+        space.check()?;
+        let rel_path = path.strip_prefix(base)?;
+        if let Some(deadline) = options.deadline.as_ref().filter(|deadline| deadline.passed()) {
+            manifest.stopped = Some(deadline.stop(i, paths.len(), rel_path));
+            break;
+        }
+        let stat = match manifest::check_readable(&*options.fs, path, options.busy_retries) {
+            Ok(stat) => stat,
+            Err(e) if options.skips(&e) => {
+                progress.on_warning(&format!("Skipping {}: {}", path.display(), e));
+                options.errors.record(path, "open", &e);
+                manifest.skipped.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error: e.to_string() });
+                continue;
+            },
+            Err(e) if options.placeholder_on_error => {
+                progress.on_file(rel_path, 0);
+                progress.on_warning(&format!("Storing placeholder for {}: {}", path.display(), e));
+                options.errors.record(path, "open", &e);
+                zip.start_file_from_path(rel_path, encrypted(SimpleFileOptions::default().compression_method(method)))?;
+                manifest.placeholders.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error: e.to_string() });
+                continue;
+            },
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+        };
+        progress.on_file(rel_path, if stat.is_file() { stat.len } else { 0 });
+        let (entry_method, level) = method_for(path);
+        let mut entry_options = SimpleFileOptions::default()
+            .compression_method(entry_method)
+            .compression_level(level)
+            .large_file(stat.len > ZIP64_THRESHOLD);
+        if let Some(modified) = stat.modified.and_then(zip_time) {
+            entry_options = entry_options.last_modified_time(modified);
+        }
+        if cfg!(unix) || stat.local.is_none() {
+            entry_options = entry_options.unix_permissions(stat.mode);
+        }
+        let entry_options = encrypted(entry_options);
+
+        if stat.is_dir() {
+            zip.add_directory_from_path(rel_path, entry_options)?;
+        } else if stat.is_symlink() {
+            zip.add_symlink_from_path(rel_path, pathstyle::link_target(&options.fs.read_link(path)?, options.path_style), entry_options)?;
+        } else {
+            zip.start_file_from_path(rel_path, entry_options)?;
+            let file = readahead::open(path, stat.len, options)?;
+            io::copy(&mut ProgressReader::new(file, progress), &mut zip)?;
+        }
+    }
+    if manifest.is_needed() {
+        zip.start_file(manifest::MANIFEST_NAME, encrypted(SimpleFileOptions::default().compression_method(method)))?;
+        io::Write::write_all(&mut zip, &manifest.to_json()?)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+// Zip timestamps are local time with 2 second resolution, and can't represent anything before 1980
+fn zip_time(time: std::time::SystemTime) -> Option<zip::DateTime> {
+    let local: chrono::DateTime<chrono::Local> = time.into();
+    zip::DateTime::from_date_and_time(
+        u16::try_from(local.year()).ok()?,
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        local.second() as u8,
+    ).ok()
+}
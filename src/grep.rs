@@ -0,0 +1,100 @@
+use std::{io::{self, BufRead, BufReader, Read, Write}, path::Path, error::Error};
+use glob::{MatchOptions, Pattern};
+use regex::bytes::{Regex, RegexBuilder};
+use crate::{footer, list::{self, Opened}, manifest, utils};
+
+// How far into an entry to look for a NUL byte when deciding whether it's binary, as grep does
+const BINARY_PROBE: usize = 8 * 1024;
+
+pub struct GrepOptions<'a> {
+    pub pattern: &'a str,
+    pub ignore_case: bool,
+    // Only entries whose name, or whole path if the glob has a /, matches
+    pub name_glob: Option<&'a str>,
+    pub password_file: Option<&'a Path>,
+}
+
+// Searches the contents of each regular file in the archive without extracting it, writing `entry:line:text` for
+// every matching line. Returns the number of matching lines
+pub fn run(archive: &Path, options: &GrepOptions, out: &mut dyn Write) -> Result<usize, Box<dyn Error>> {
+    let pattern = RegexBuilder::new(options.pattern).case_insensitive(options.ignore_case).build()?;
+    let name_glob = options.name_glob.map(|glob| Pattern::new(glob).map_err(|e| format!("Invalid --name-glob '{}': {}", glob, e))).transpose()?;
+    let wanted = |name: &str| wanted(name, name_glob.as_ref());
+    let mut matches = 0;
+    match list::open(archive, options.password_file)? {
+        Opened::Zip(mut zip) => {
+            let mut password: Option<String> = None;
+            for i in 0..zip.len() {
+                let (name, encrypted, is_file) = {
+                    let entry = zip.by_index_raw(i)?;
+                    let is_symlink = entry.unix_mode().is_some_and(|mode| mode & 0o170000 == 0o120000);
+                    (entry.name().to_string(), entry.encrypted(), entry.is_file() && !is_symlink)
+                };
+                if !is_file || !wanted(&name) {
+                    continue;
+                }
+                if encrypted && password.is_none() {
+                    password = Some(utils::read_secret(options.password_file, "ATHENA_PASSWORD", "Archive password", false)?);
+                }
+                let entry = match (&password, encrypted) {
+                    (Some(password), true) => zip.by_index_decrypt(i, password.as_bytes()).map_err(|_| "Wrong archive password")?,
+                    _ => zip.by_index(i)?,
+                };
+                matches += search(&name, entry, &pattern, out)?;
+            }
+        },
+        Opened::Tar(reader) => {
+            let mut tar = tar::Archive::new(reader);
+            for entry in tar.entries()? {
+                let entry = entry?;
+                let name = entry.path()?.to_string_lossy().to_string();
+                if entry.header().entry_type().is_file() && wanted(&name) {
+                    matches += search(&name, entry, &pattern, out)?;
+                }
+            }
+            io::copy(&mut tar.into_inner(), &mut io::sink())?;
+        },
+    }
+    Ok(matches)
+}
+
+fn wanted(name: &str, name_glob: Option<&Pattern>) -> bool {
+    if name == manifest::MANIFEST_NAME || name == footer::INDEX_NAME {
+        return false;
+    }
+    let Some(glob) = name_glob else {
+        return true;
+    };
+    let match_options = MatchOptions { require_literal_separator: true, ..MatchOptions::default() };
+    match glob.as_str().contains('/') {
+        true => glob.matches_with(name, match_options),
+        false => glob.matches_with(name.rsplit('/').next().unwrap_or(name), match_options),
+    }
+}
+
+// Binary entries are searched too, but only reported as matching, since their "lines" would be noise
+fn search(name: &str, entry: impl Read, pattern: &Regex, out: &mut dyn Write) -> Result<usize, Box<dyn Error>> {
+    let mut reader = BufReader::new(entry);
+    let binary = reader.fill_buf()?.iter().take(BINARY_PROBE).any(|&byte| byte == 0);
+    let mut matches = 0;
+    let mut line = Vec::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        number += 1;
+        if !pattern.is_match(&line) {
+            continue;
+        }
+        matches += 1;
+        if binary {
+            writeln!(out, "{}: binary entry matches", name)?;
+            break;
+        }
+        let text = String::from_utf8_lossy(&line);
+        writeln!(out, "{}:{}:{}", name, number, text.trim_end_matches(['\n', '\r']))?;
+    }
+    Ok(matches)
+}
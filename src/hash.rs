@@ -0,0 +1,154 @@
+use std::{fs, io::{self, Read, Write}, path::Path, error::Error};
+use clap::ValueEnum;
+use sha2::Digest;
+
+// Files at least this large are hashed from a memory map, which lets blake3 spread the work over all cores
+const MMAP_HASH_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Blake3,
+    Sha256,
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new())),
+        }
+    }
+}
+
+// Streaming hasher over any of the supported algorithms
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => { hasher.update(data); },
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Xxh3(hasher) => hasher.update(data),
+        }
+    }
+
+    // Returns the lowercase hex digest
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            Hasher::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Xxh3(hasher) => format!("{:032x}", hasher.digest128()),
+        }
+    }
+}
+
+// Hashes everything written through it, for archives that are never on disk to hash afterwards
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    len: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, algorithm: HashAlgorithm) -> HashingWriter<W> {
+        HashingWriter { inner, hasher: algorithm.hasher(), len: 0 }
+    }
+
+    // Returns the writer, along with the digest and length of what was written
+    pub fn finish(self) -> (W, String, u64) {
+        (self.inner, self.hasher.finalize(), self.len)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Hashes everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, algorithm: HashAlgorithm) -> HashingReader<R> {
+        HashingReader { inner, hasher: algorithm.hasher() }
+    }
+
+    pub fn finish(self) -> String {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Hashes the contents of the file at the given path
+pub fn file(path: &Path, algorithm: HashAlgorithm) -> Result<String, Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    if algorithm == HashAlgorithm::Blake3 && len >= MMAP_HASH_THRESHOLD {
+        // Safety: the mapping is only read for the duration of the hash
+        if let Ok(mmap) = unsafe { memmap2::Mmap::map(&file) } {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_rayon(&mmap);
+            return Ok(hasher.finalize().to_hex().to_string());
+        }
+    }
+    reader(&mut file, algorithm)
+}
+
+// Hashes everything read from the given reader
+pub fn reader<R: Read>(reader: &mut R, algorithm: HashAlgorithm) -> Result<String, Box<dyn Error>> {
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+// Writes a checksum file next to the archive, in the same format as the coreutils `*sum` tools
+// so it can be checked with e.g. `sha256sum -c` / `b3sum -c`
+pub fn write_checksum_file(archive_path: &Path, digest: &str, algorithm: HashAlgorithm) -> Result<std::path::PathBuf, Box<dyn Error>> {
+    let file_name = archive_path.file_name().unwrap().to_string_lossy().to_string();
+    let checksum_path = archive_path.with_file_name(format!("{}.{}", file_name, algorithm.name()));
+    fs::write(&checksum_path, format!("{}  {}\n", digest, file_name))?;
+    Ok(checksum_path)
+}
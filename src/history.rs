@@ -0,0 +1,149 @@
+use std::{collections::HashSet, fs, path::{Path, PathBuf}, error::Error};
+use clap::Subcommand;
+use crate::{backend::{self, RemoteOptions}, incremental::{self, State}, repo, utils};
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryCommand {
+    #[command(about = "Forget the chains in a state file whose archives are gone, and cap how many runs it keeps")]
+    Prune {
+        // The incremental state, e.g. `dest/.athena-src.state.json`
+        state_file: PathBuf,
+        // Where the chains' archives are, if not alongside the state file
+        #[arg(long = "archives", env = "ATHENA_ARCHIVES")]
+        archives: Option<PathBuf>,
+        // Uploaded archives under --prefix count too when a bucket is given, so a chain is only forgotten once
+        // its archives are gone from both
+        #[command(flatten)]
+        remote: RemoteOptions,
+        // Keep at most this many runs, forgetting the oldest chains whole. Their archives are left where they are
+        #[arg(long = "max-runs", value_parser = clap::value_parser!(u64).range(1..), env = "ATHENA_MAX_RUNS")]
+        max_runs: Option<u64>,
+        // Show what would be forgotten without changing the state
+        #[arg(long = "dry-run", env = "ATHENA_DRY_RUN")]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CatalogCommand {
+    #[command(about = "Compact a state file's run history and merge a repository's chunk indexes into one")]
+    Vacuum {
+        // The incremental state, e.g. `dest/.athena-src.state.json`
+        #[arg(long = "state-file", required_unless_present = "repo", env = "ATHENA_STATE_FILE")]
+        state_file: Option<PathBuf>,
+        // Repository path or URL, as for `athena repo`
+        #[arg(long = "repo", env = "ATHENA_REPO")]
+        repo: Option<String>,
+        // Password for encrypted repositories, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+}
+
+pub fn run_history(command: HistoryCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        HistoryCommand::Prune { state_file, archives, remote, max_runs, dry_run } => {
+            let remote = remote.bucket.is_some().then_some(remote);
+            prune(&state_file, archives.as_deref(), remote.as_ref(), max_runs.map(|max| max as usize), dry_run)
+        },
+    }
+}
+
+pub fn run_catalog(command: CatalogCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        CatalogCommand::Vacuum { state_file, repo, password_file } => {
+            if let Some(state_file) = state_file {
+                vacuum_state(&state_file)?;
+            }
+            if let Some(location) = repo {
+                let repo = repo::Repository::open(&location, password_file.as_deref().map(Path::new))?;
+                let merged = repo.rebuild_index()?;
+                println!("Merged {} chunk {} in {} into one", merged, if merged == 1 { "index" } else { "indexes" }, location);
+            }
+            Ok(())
+        },
+    }
+}
+
+// Forgets chains none of whose archives are left, locally or uploaded, then the oldest chains while there are
+// more than `max_runs` runs. The newest chain is always kept, as the next incremental builds on it. The remote
+// is listed before anything's forgotten, so one that can't be reached fails the prune rather than making every
+// uploaded archive look gone
+fn prune(state_path: &Path, archives: Option<&Path>, remote: Option<&RemoteOptions>, max_runs: Option<usize>, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let dir = archives.or(state_path.parent().filter(|dir| !dir.as_os_str().is_empty())).unwrap_or(Path::new("."));
+    if !state_path.exists() {
+        return Err(format!("No incremental state at {}", state_path.display()).into());
+    }
+    let mut state = State::load(state_path)?;
+    // Uploaded archives by name, wherever --remote-prefix put them under the prefix
+    let uploaded: HashSet<String> = match remote {
+        Some(remote) => {
+            let backend = backend::connect(remote)?;
+            let keys = backend.list(&remote.key_for("")).map_err(|e| format!("Failed to list {}: {}", backend.url(&remote.key_for("")), e))?;
+            keys.into_iter().map(|(key, _)| key.rsplit('/').next().unwrap_or_default().to_string()).collect()
+        },
+        None => HashSet::new(),
+    };
+    let exists = |archive: &str| !archive.is_empty() && (dir.join(archive).exists() || uploaded.contains(archive));
+
+    let verb = if dry_run { "Would forget" } else { "Forgot" };
+    let chains = incremental::chains(&state);
+    let newest = chains.len().saturating_sub(1);
+    let mut forget = vec![false; chains.len()];
+    for (i, chain) in chains.iter().enumerate() {
+        let missing: Vec<&str> = chain.runs.iter().map(|run| run.archive.as_str()).filter(|archive| !exists(archive)).collect();
+        if missing.len() == chain.runs.len() && i != newest {
+            forget[i] = true;
+            println!("{} chain {} ({} {}): its archives are gone", verb, chain.name, chain.runs.len(), if chain.runs.len() == 1 { "run" } else { "runs" });
+        } else if missing.len() == chain.runs.len() {
+            eprintln!("Warning: every archive of the newest chain {} is gone, so the next incremental has nothing to build on. Run a full backup, e.g. with --full-every 0s", chain.name);
+        } else if !missing.is_empty() {
+            eprintln!("Warning: chain {} is missing {}, so can't be restored past it", chain.name, missing.join(", "));
+        }
+    }
+    if let Some(max_runs) = max_runs {
+        let mut kept: usize = chains.iter().zip(&forget).filter(|(_, forget)| !**forget).map(|(chain, _)| chain.runs.len()).sum();
+        for (i, chain) in chains.iter().enumerate().take(newest) {
+            if kept <= max_runs {
+                break;
+            }
+            if !forget[i] {
+                forget[i] = true;
+                kept -= chain.runs.len();
+                println!("{} chain {} ({} {}) to keep at most {} runs, leaving its archives in place", verb, chain.name, chain.runs.len(), if chain.runs.len() == 1 { "run" } else { "runs" }, max_runs);
+            }
+        }
+    }
+
+    let runs: Vec<incremental::RunRecord> = chains.iter().zip(&forget).filter(|(_, forget)| !**forget).flat_map(|(chain, _)| chain.runs.iter().map(|run| (*run).clone())).collect();
+    let forgotten = state.runs.len() - runs.len();
+    println!("{} {} of {} runs", verb, forgotten, state.runs.len());
+    if !dry_run && forgotten > 0 {
+        state.runs = runs;
+        state.save(state_path)?;
+    }
+    Ok(())
+}
+
+// Rewrites the state without the records of runs that never got as far as recording their archive. A full
+// backup's record is kept even then, as dropping it would make its incrementals look like part of the chain before
+fn vacuum_state(state_path: &Path) -> Result<(), Box<dyn Error>> {
+    if !state_path.exists() {
+        return Err(format!("No incremental state at {}", state_path.display()).into());
+    }
+    let before = fs::metadata(state_path)?.len();
+    let mut state = State::load(state_path)?;
+    let runs = state.runs.len();
+    state.runs.retain(|run| run.full || !run.archive.is_empty());
+    state.save(state_path)?;
+    let after = fs::metadata(state_path)?.len();
+    println!(
+        "Vacuumed {}: removed {} of {} runs, {} to {}",
+        state_path.display(),
+        runs - state.runs.len(),
+        runs,
+        utils::format_size(before),
+        utils::format_size(after)
+    );
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::fs;
+
+// Options for scoping archives to the machine that made them
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct HostOptions {
+    // Name to scope archives under, instead of this machine's hostname
+    #[arg(long = "host", env = "ATHENA_HOST")]
+    pub host: Option<String>,
+    // Leave the hostname out of archive names, remote keys and the manifest
+    #[arg(long = "no-host-scope", env = "ATHENA_NO_HOST_SCOPE", conflicts_with = "host")]
+    pub no_host_scope: bool,
+}
+
+impl HostOptions {
+    pub fn resolve(&self) -> Option<Host> {
+        if self.no_host_scope {
+            return None;
+        }
+        let name = self.host.clone().or_else(hostname).unwrap_or_else(|| "unknown".to_string());
+        Some(Host { name: sanitize(&name), machine_id: machine_id() })
+    }
+}
+
+// The machine a backup came from
+#[derive(Clone, Debug)]
+pub struct Host {
+    // Hostname, made safe for file names and object keys
+    pub name: String,
+    // Stable ID of the install, so machines that share a hostname can still be told apart
+    pub machine_id: Option<String>,
+}
+
+// Keeps letters, digits, '.', '_' and '-', replacing anything else with '-'
+fn sanitize(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '-' }).collect();
+    match name.trim_matches('.') {
+        "" => "unknown".to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // Safety: the buffer is writable for its whole length
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]).trim().to_string();
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok().filter(|name| !name.is_empty())
+}
+
+#[cfg(target_os = "linux")]
+fn machine_id() -> Option<String> {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok().map(|id| id.trim().to_string()).filter(|id| !id.is_empty()))
+}
+
+#[cfg(target_os = "macos")]
+fn machine_id() -> Option<String> {
+    let output = std::process::Command::new("ioreg").args(["-rd1", "-c", "IOPlatformExpertDevice"]).output().ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let line = output.lines().find(|line| line.contains("IOPlatformUUID"))?;
+    line.rsplit('"').nth(1).map(str::to_string)
+}
+
+#[cfg(windows)]
+fn machine_id() -> Option<String> {
+    let output = std::process::Command::new("reg")
+        .args(["query", r"HKLM\SOFTWARE\Microsoft\Cryptography", "/v", "MachineGuid"])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    output.lines().find(|line| line.contains("MachineGuid"))?.split_whitespace().last().map(str::to_string)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn machine_id() -> Option<String> {
+    None
+}
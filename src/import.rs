@@ -0,0 +1,689 @@
+use std::{collections::BTreeMap, fs, path::Path, error::Error};
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use crate::{daemon::{self, Config, Profile}, exclude, incremental};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Restic,
+    Borg,
+    Duplicity,
+}
+
+impl Tool {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::Restic => "restic",
+            Tool::Borg => "borg",
+            Tool::Duplicity => "duplicity",
+        }
+    }
+
+    // Options that take a value, so what follows them isn't mistaken for a path. Anything else is taken as a switch
+    fn value_flags(&self) -> &'static [&'static str] {
+        match self {
+            Tool::Restic => &[
+                "-r", "--repo", "--repository-file", "-p", "--password-file", "--password-command", "--cache-dir", "-e", "--exclude",
+                "--iexclude", "--exclude-file", "--iexclude-file", "--exclude-if-present", "--exclude-larger-than", "--files-from",
+                "--files-from-verbatim", "--files-from-raw", "--tag", "--host", "-H", "--parent", "--time", "--stdin-filename",
+                "--compression", "--pack-size", "--limit-upload", "--limit-download", "-o", "--option", "--read-concurrency",
+                "--key-hint", "--cacert", "--tls-client-cert", "--group-by", "-l", "--keep-last", "--keep-hourly", "-d",
+                "--keep-daily", "-w", "--keep-weekly", "-m", "--keep-monthly", "-y", "--keep-yearly", "--keep-within",
+                "--keep-within-hourly", "--keep-within-daily", "--keep-within-weekly", "--keep-within-monthly",
+                "--keep-within-yearly", "--keep-tag", "--max-unused", "--max-repack-size", "--verbose", "-v",
+            ],
+            Tool::Borg => &[
+                "--remote-path", "--upload-ratelimit", "--remote-ratelimit", "--upload-buffer", "--lock-wait", "--umask", "-e",
+                "--exclude", "--exclude-from", "--pattern", "--patterns-from", "--exclude-if-present", "-C", "--compression",
+                "--comment", "--timestamp", "--chunker-params", "-c", "--checkpoint-interval", "--files-cache", "--stdin-name",
+                "--keep-within", "--keep-last", "--keep-secondly", "--keep-minutely", "-H", "--keep-hourly", "-d",
+                "--keep-daily", "-w", "--keep-weekly", "-m", "--keep-monthly", "-y", "--keep-yearly", "-P", "--prefix",
+                "-a", "--glob-archives", "--encryption",
+            ],
+            Tool::Duplicity => &[
+                "--exclude", "--include", "--exclude-filelist", "--include-filelist", "--exclude-regexp", "--include-regexp",
+                "--exclude-if-present", "--exclude-older-than", "--files-from", "--full-if-older-than", "--encrypt-key",
+                "--hidden-encrypt-key", "--sign-key", "--volsize", "--archive-dir", "--name", "--tempdir", "--log-file",
+                "--log-fd", "-v", "--verbosity", "--gpg-options", "--gpg-binary", "-t", "--time", "--file-to-restore",
+                "--ssh-options", "--timeout", "--num-retries", "--backend-retry-delay", "--max-blocksize", "--rename",
+            ],
+        }
+    }
+}
+
+// Where the old tool kept its backups
+enum Repo {
+    Local(String),
+    B2 { bucket: String, prefix: String },
+}
+
+// How long the old tool kept backups for
+#[derive(Default)]
+struct Retention {
+    last: u64,
+    daily: u64,
+    weekly: u64,
+    monthly: u64,
+    yearly: u64,
+    // Everything newer than this many days
+    within: Option<u64>,
+    // Full backups, each with the incrementals made on it
+    full: Option<u64>,
+}
+
+impl Retention {
+    fn is_empty(&self) -> bool {
+        !self.has_counts() && self.within.is_none() && self.full.is_none()
+    }
+
+    fn has_counts(&self) -> bool {
+        self.last > 0 || self.daily > 0 || self.weekly > 0 || self.monthly > 0 || self.yearly > 0
+    }
+
+    // The furthest back the policy keeps anything, which is what an expiry by age has to cover
+    fn days(&self) -> Option<u64> {
+        let days = [self.within.unwrap_or(0), self.daily, self.weekly * 7, self.monthly * 31, self.yearly * 366].into_iter().max().unwrap_or(0);
+        (days > 0).then_some(days)
+    }
+}
+
+// What's been gathered from the script's invocations of the tool
+#[derive(Default)]
+struct Settings {
+    sources: Vec<String>,
+    repo: Option<Repo>,
+    excludes: Vec<String>,
+    tags: Vec<String>,
+    compress: bool,
+    encrypted: bool,
+    password_file: Option<String>,
+    // Days between full backups
+    full_every: Option<u64>,
+    schedule: Option<String>,
+    retention: Retention,
+    notes: Vec<String>,
+}
+
+// Converts the settings in a script or crontab that runs the tool, e.g. the cron job's, into a daemon config with
+// a profile per source. Returns the config and notes on anything that couldn't be carried over
+pub fn import(tool: Tool, path: &Path, name: &str) -> Result<(Config, Vec<String>), Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut settings = Settings { compress: true, encrypted: tool != Tool::Borg, ..Settings::default() };
+    let mut env = BTreeMap::new();
+    for (schedule, words) in parse_script(&text, &mut env) {
+        let Some(start) = words.iter().position(|word| Path::new(word).file_name().is_some_and(|file| file == tool.name())) else {
+            continue;
+        };
+        let (flags, positionals) = split_args(tool, &words[start + 1..]);
+        let backs_up = match tool {
+            Tool::Restic => restic(&mut settings, &flags, &positionals, &env, base),
+            Tool::Borg => borg(&mut settings, &flags, &positionals, &env, base),
+            Tool::Duplicity => duplicity(&mut settings, &flags, &positionals, &env, base),
+        };
+        if backs_up && schedule.is_some() {
+            settings.schedule = schedule;
+        }
+    }
+    if settings.sources.is_empty() {
+        return Err(format!("No {} backup command found in {}", tool.name(), path.display()).into());
+    }
+    Ok(to_config(settings, name))
+}
+
+fn restic(settings: &mut Settings, flags: &[(String, Option<String>)], positionals: &[String], env: &BTreeMap<String, String>, base: &Path) -> bool {
+    let value = |names: &[&str]| flags.iter().rev().find(|(flag, _)| names.contains(&flag.as_str())).and_then(|(_, value)| value.clone());
+    if let Some(repo) = value(&["-r", "--repo"]).or_else(|| env.get("RESTIC_REPOSITORY").cloned()) {
+        settings.repo = restic_repo(&repo, &mut settings.notes);
+    }
+    if let Some(file) = value(&["-p", "--password-file"]).or_else(|| env.get("RESTIC_PASSWORD_FILE").cloned()) {
+        settings.password_file = Some(file);
+    }
+    if env.contains_key("RESTIC_PASSWORD") || value(&["--password-command"]).is_some() || env.contains_key("RESTIC_PASSWORD_COMMAND") {
+        settings.notes.push("The password wasn't in a file, so isn't carried over. Put it in one and set password_file".to_string());
+    }
+    if let Some(compression) = value(&["--compression"]).or_else(|| env.get("RESTIC_COMPRESSION").cloned()) {
+        settings.compress = compression != "off";
+    }
+    match positionals.first().map(String::as_str) {
+        Some("backup") => {
+            settings.sources.extend(positionals[1..].iter().cloned());
+            for (flag, value) in flags {
+                match (flag.as_str(), value) {
+                    ("-e" | "--exclude", Some(pattern)) => settings.excludes.push(pattern.clone()),
+                    ("--iexclude", Some(pattern)) => {
+                        settings.notes.push(format!("Exclude '{}' was case-insensitive, athena's are case-sensitive", pattern));
+                        settings.excludes.push(pattern.clone());
+                    },
+                    ("--exclude-file" | "--iexclude-file", Some(file)) => read_exclude_file(settings, base, file),
+                    ("--tag", Some(tags)) => settings.tags.extend(tags.split(',').map(str::to_string)),
+                    ("--exclude-caches" | "--exclude-if-present" | "--exclude-larger-than" | "--files-from" | "--files-from-verbatim" | "--files-from-raw", _) => {
+                        settings.notes.push(format!("{} has no athena equivalent and was left out", flag));
+                    },
+                    _ => {},
+                }
+            }
+            true
+        },
+        Some("forget") => {
+            for (flag, value) in flags {
+                let count = || value.as_deref().and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+                match flag.as_str() {
+                    "-l" | "--keep-last" => settings.retention.last = count(),
+                    "-d" | "--keep-daily" => settings.retention.daily = count(),
+                    "-w" | "--keep-weekly" => settings.retention.weekly = count(),
+                    "-m" | "--keep-monthly" => settings.retention.monthly = count(),
+                    "-y" | "--keep-yearly" => settings.retention.yearly = count(),
+                    "--keep-within" => settings.retention.within = value.as_deref().and_then(|age| days(age, Tool::Restic)),
+                    "--keep-hourly" | "--keep-tag" => settings.notes.push(format!("{} has no athena equivalent and was left out", flag)),
+                    _ => {},
+                }
+            }
+            false
+        },
+        _ => false,
+    }
+}
+
+fn restic_repo(repo: &str, notes: &mut Vec<String>) -> Option<Repo> {
+    if let Some(rest) = repo.strip_prefix("b2:") {
+        let (bucket, prefix) = rest.split_once(':').unwrap_or((rest, ""));
+        return Some(Repo::B2 { bucket: bucket.to_string(), prefix: prefix.trim_matches('/').to_string() });
+    }
+    if let Some(path) = repo.strip_prefix("local:") {
+        return Some(Repo::Local(path.to_string()));
+    }
+    match repo.split_once(':') {
+        Some((scheme, _)) if scheme.len() > 1 => {
+            notes.push(format!("Repository {} is on a backend athena doesn't support", repo));
+            None
+        },
+        _ => Some(Repo::Local(repo.to_string())),
+    }
+}
+
+fn borg(settings: &mut Settings, flags: &[(String, Option<String>)], positionals: &[String], env: &BTreeMap<String, String>, base: &Path) -> bool {
+    if ["BORG_PASSPHRASE", "BORG_PASSCOMMAND", "BORG_PASSPHRASE_FD"].iter().any(|key| env.contains_key(*key)) {
+        settings.encrypted = true;
+    }
+    match env.get("BORG_PASSCOMMAND").and_then(|command| command.strip_prefix("cat ")) {
+        Some(file) => settings.password_file = Some(file.trim().to_string()),
+        None if env.contains_key("BORG_PASSPHRASE") || env.contains_key("BORG_PASSCOMMAND") => {
+            settings.notes.push("The passphrase wasn't in a file, so isn't carried over. Put it in one and set password_file".to_string());
+        },
+        None => {},
+    }
+    // The repository is either before the :: of an archive or the whole argument, falling back on BORG_REPO
+    let repo = |location: Option<&String>| {
+        let location = location.map(|location| location.split_once("::").map_or(location.as_str(), |(repo, _)| repo).to_string());
+        location.filter(|repo| !repo.is_empty()).or_else(|| env.get("BORG_REPO").cloned())
+    };
+    match positionals.first().map(String::as_str) {
+        Some("init") => {
+            if flags.iter().any(|(flag, value)| matches!(flag.as_str(), "-e" | "--encryption") && value.as_deref() != Some("none")) {
+                settings.encrypted = true;
+            }
+            false
+        },
+        Some("create") => {
+            if let Some(repo) = repo(positionals.get(1)) {
+                settings.repo = borg_repo(&repo, &mut settings.notes);
+            }
+            settings.sources.extend(positionals.iter().skip(2).cloned());
+            for (flag, value) in flags {
+                match (flag.as_str(), value) {
+                    ("-e" | "--exclude", Some(pattern)) => add_borg_pattern(settings, pattern),
+                    ("--exclude-from", Some(file)) => read_exclude_file(settings, base, file),
+                    ("-C" | "--compression", Some(compression)) => settings.compress = compression != "none",
+                    ("--pattern" | "--patterns-from" | "--exclude-caches" | "--exclude-if-present" | "--keep-exclude-tags", _) => {
+                        settings.notes.push(format!("{} has no athena equivalent and was left out", flag));
+                    },
+                    _ => {},
+                }
+            }
+            true
+        },
+        Some("prune") => {
+            if settings.repo.is_none() {
+                if let Some(repo) = repo(positionals.get(1)) {
+                    settings.repo = borg_repo(&repo, &mut settings.notes);
+                }
+            }
+            for (flag, value) in flags {
+                let count = || value.as_deref().and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+                match flag.as_str() {
+                    "--keep-last" => settings.retention.last = count(),
+                    "-d" | "--keep-daily" => settings.retention.daily = count(),
+                    "-w" | "--keep-weekly" => settings.retention.weekly = count(),
+                    "-m" | "--keep-monthly" => settings.retention.monthly = count(),
+                    "-y" | "--keep-yearly" => settings.retention.yearly = count(),
+                    "--keep-within" => settings.retention.within = value.as_deref().and_then(|age| days(age, Tool::Borg)),
+                    "--keep-secondly" | "--keep-minutely" | "-H" | "--keep-hourly" => {
+                        settings.notes.push(format!("{} has no athena equivalent and was left out", flag));
+                    },
+                    _ => {},
+                }
+            }
+            false
+        },
+        _ => false,
+    }
+}
+
+fn borg_repo(repo: &str, notes: &mut Vec<String>) -> Option<Repo> {
+    let path = repo.strip_prefix("file://").unwrap_or(repo);
+    if path.starts_with("ssh://") || path.split_once(':').is_some_and(|(host, _)| !host.contains('/')) {
+        notes.push(format!("Repository {} is over SSH, which athena can't write archives to", repo));
+        return None;
+    }
+    Some(Repo::Local(path.to_string()))
+}
+
+// Borg patterns are shell-style unless prefixed with another style. Regular expressions can't be carried over
+fn add_borg_pattern(settings: &mut Settings, pattern: &str) {
+    match pattern.split_once(':').filter(|(style, _)| style.len() == 2) {
+        Some(("sh" | "fm" | "pp" | "pf", pattern)) => settings.excludes.push(pattern.to_string()),
+        Some(("re", _)) => settings.notes.push(format!("Exclude '{}' is a regular expression, which athena doesn't support", pattern)),
+        _ => settings.excludes.push(pattern.to_string()),
+    }
+}
+
+fn duplicity(settings: &mut Settings, flags: &[(String, Option<String>)], positionals: &[String], env: &BTreeMap<String, String>, base: &Path) -> bool {
+    if flags.iter().any(|(flag, _)| flag == "--no-encryption") {
+        settings.encrypted = false;
+    } else if env.contains_key("PASSPHRASE") {
+        settings.notes.push("The passphrase wasn't in a file, so isn't carried over. Put it in one and set password_file".to_string());
+    }
+    if flags.iter().any(|(flag, _)| flag == "--no-compression") {
+        settings.compress = false;
+    }
+    let action = positionals.first().map(String::as_str);
+    let paths = match action {
+        Some("full" | "incr" | "incremental" | "backup") => &positionals[1..],
+        Some("remove-older-than") => {
+            settings.retention.within = positionals.get(1).and_then(|age| days(age, Tool::Duplicity));
+            if let Some(url) = positionals.get(2) {
+                settings.repo = settings.repo.take().or_else(|| duplicity_url(url, &mut settings.notes));
+            }
+            return false;
+        },
+        Some("remove-all-but-n-full") => {
+            settings.retention.full = positionals.get(1).and_then(|count| count.parse().ok()).filter(|count| *count > 0);
+            if let Some(url) = positionals.get(2) {
+                settings.repo = settings.repo.take().or_else(|| duplicity_url(url, &mut settings.notes));
+            }
+            return false;
+        },
+        Some("remove-all-inc-of-but-n-full") => {
+            settings.notes.push(format!("{} counts full backups, which athena can't prune by", action.unwrap_or_default()));
+            return false;
+        },
+        Some(_) if positionals.len() == 2 => positionals,
+        _ => return false,
+    };
+    let [source, url] = paths else {
+        return false;
+    };
+    // Restores go the other way, from the URL to a path
+    if source.contains("://") {
+        return false;
+    }
+    settings.sources.push(source.clone());
+    settings.repo = duplicity_url(url, &mut settings.notes);
+    for (flag, value) in flags {
+        match (flag.as_str(), value) {
+            ("--exclude", Some(pattern)) => match pattern.strip_prefix("ignorecase:") {
+                Some(pattern) => {
+                    settings.notes.push(format!("Exclude '{}' was case-insensitive, athena's are case-sensitive", pattern));
+                    settings.excludes.push(pattern.to_string());
+                },
+                None => settings.excludes.push(pattern.clone()),
+            },
+            ("--exclude-filelist", Some(file)) => read_exclude_file(settings, base, file),
+            ("--full-if-older-than", Some(time)) => settings.full_every = days(time, Tool::Duplicity).filter(|days| *days > 0),
+            ("--include" | "--include-filelist" | "--include-regexp" | "--exclude-regexp" | "--exclude-if-present" | "--exclude-older-than" | "--files-from", _) => {
+                settings.notes.push(format!("{} has no athena equivalent and was left out", flag));
+            },
+            _ => {},
+        }
+    }
+    true
+}
+
+fn duplicity_url(url: &str, notes: &mut Vec<String>) -> Option<Repo> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Some(Repo::Local(path.to_string()));
+    }
+    if let Some(rest) = url.strip_prefix("b2://") {
+        // Credentials in the URL stay out of the config, athena reads them from its environment
+        let rest = rest.rsplit_once('@').map_or(rest, |(_, rest)| rest);
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        notes.push("Set ATHENA_B2_KEY_ID and ATHENA_B2_KEY for the profile, the key in the URL isn't carried over".to_string());
+        return Some(Repo::B2 { bucket: bucket.to_string(), prefix: prefix.trim_matches('/').to_string() });
+    }
+    notes.push(format!("Backend of {} isn't one athena supports", url));
+    None
+}
+
+// Adds the patterns in an exclude file, one per line, all three tools skipping blank lines and # comments
+fn read_exclude_file(settings: &mut Settings, base: &Path, file: &str) {
+    match fs::read_to_string(base.join(file)) {
+        Ok(contents) => {
+            let lines = contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'));
+            settings.excludes.extend(lines.map(str::to_string));
+        },
+        Err(e) => settings.notes.push(format!("Couldn't read exclude file {}: {}", file, e)),
+    }
+}
+
+// Days in an age like restic's `1y6m`, borg's `30d` or duplicity's `2W`, where `m` is minutes rather than months.
+// Months are taken as 31 days and years as 366, so nothing is expired sooner than it was before, and anything less
+// than a day rounds up to one
+fn days(input: &str, tool: Tool) -> Option<u64> {
+    let mut days = 0.0;
+    let mut number = String::new();
+    for c in input.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let n: f64 = number.parse().ok()?;
+        number.clear();
+        days += n * match c {
+            's' => 1.0 / 86400.0,
+            'm' if tool == Tool::Duplicity => 1.0 / 1440.0,
+            'm' | 'M' => 31.0,
+            'h' | 'H' => 1.0 / 24.0,
+            'd' | 'D' => 1.0,
+            'w' | 'W' => 7.0,
+            'y' | 'Y' => 366.0,
+            _ => return None,
+        };
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    Some(days.ceil() as u64)
+}
+
+fn to_config(settings: Settings, name: &str) -> (Config, Vec<String>) {
+    // Each of the tool's commands can raise the same note
+    let mut notes = Vec::new();
+    for note in settings.notes {
+        if !notes.contains(&note) {
+            notes.push(note);
+        }
+    }
+    let mut options = Map::new();
+    match &settings.repo {
+        Some(Repo::Local(path)) => {
+            options.insert("dest".to_string(), json!(path));
+        },
+        Some(Repo::B2 { bucket, prefix }) => {
+            options.insert("upload".to_string(), json!(true));
+            options.insert("no_local_copy".to_string(), json!(true));
+            options.insert("bucket".to_string(), json!(bucket));
+            if !prefix.is_empty() {
+                options.insert("prefix".to_string(), json!(prefix));
+            }
+            notes.push("Archives upload without a local copy, but dest still needs setting to a local directory".to_string());
+        },
+        None => notes.push("Set dest, the repository couldn't be carried over".to_string()),
+    }
+    options.insert("compress".to_string(), json!(settings.compress));
+    if settings.encrypted {
+        // Encrypted tars stream, so they can still upload without a local copy
+        options.insert("format".to_string(), json!("tar"));
+        options.insert("encrypt".to_string(), json!(true));
+        match &settings.password_file {
+            Some(file) => {
+                options.insert("password_file".to_string(), json!(file));
+            },
+            None => notes.push("Set password_file, scheduled runs can't prompt for the password".to_string()),
+        }
+    }
+    let mut excludes = Vec::new();
+    for pattern in settings.excludes {
+        // Lists of excludes are split on commas
+        match exclude::parse_pattern(&pattern) {
+            Ok(_) if !pattern.contains(',') => excludes.push(Value::String(pattern)),
+            Ok(_) => notes.push(format!("Exclude '{}' contains a comma, which athena would split it on, so was left out", pattern)),
+            Err(e) => notes.push(format!("Exclude '{}' was left out: {}", pattern, e)),
+        }
+    }
+    if !excludes.is_empty() {
+        options.insert("exclude".to_string(), Value::Array(excludes));
+    }
+    if !settings.tags.is_empty() {
+        options.insert("tag".to_string(), json!(settings.tags));
+    }
+    if let Some(full_every) = settings.full_every {
+        options.insert("incremental".to_string(), json!(true));
+        options.insert("full_every".to_string(), json!(format!("{}d", full_every)));
+    }
+    notes.extend(retention_notes(&settings.retention, settings.repo.as_ref(), settings.full_every, &settings.sources));
+
+    let mut profiles = BTreeMap::new();
+    for source in &settings.sources {
+        let mut options = options.clone();
+        options.insert("src".to_string(), json!(source));
+        let name = match settings.sources.len() {
+            1 => name.to_string(),
+            _ => format!("{}-{}", name, profile_suffix(source)),
+        };
+        profiles.insert(name, Profile { schedule: settings.schedule.clone(), options, ..Profile::default() });
+    }
+    if settings.schedule.is_none() {
+        notes.push("No cron schedule the daemon can follow was found, so the profiles only run when triggered. Give them one like \"daily 03:00\"".to_string());
+    }
+    (Config { max_concurrent: None, profiles }, notes)
+}
+
+// A source's last component, in the characters profile names allow
+fn profile_suffix(source: &str) -> String {
+    let name = Path::new(source).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| "root".to_string());
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' }).collect();
+    match daemon::check_name(&name) {
+        Ok(()) => name,
+        Err(_) => "root".to_string(),
+    }
+}
+
+// Profiles don't prune, so retention is carried over as the commands that do. Counts of snapshots map onto `repo
+// prune`, full backups kept onto `chains --keep` for incremental profiles, and anything by age onto a B2 lifecycle
+// rule
+fn retention_notes(retention: &Retention, repo: Option<&Repo>, full_every: Option<u64>, sources: &[String]) -> Vec<String> {
+    let mut notes = Vec::new();
+    if retention.is_empty() {
+        return notes;
+    }
+    if retention.has_counts() {
+        let flags: Vec<String> = [("last", retention.last), ("daily", retention.daily), ("weekly", retention.weekly), ("monthly", retention.monthly), ("yearly", retention.yearly)]
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(rule, count)| format!("--keep-{} {}", rule, count))
+            .collect();
+        notes.push(format!("To keep the same snapshots in an athena repository, prune it with: athena repo prune <repo> {}", flags.join(" ")));
+    }
+    // An incremental chain spans `full_every` days, so keeping everything from the last `within` needs enough whole
+    // chains to cover it, plus the one being added to
+    let chains = retention.full.or_else(|| Some(retention.within?.div_ceil(full_every?) + 1));
+    if let (Some(keep), Some(full_every)) = (chains, full_every) {
+        let dest = match repo {
+            Some(Repo::Local(path)) => path.as_str(),
+            _ => "<dest>",
+        };
+        for source in sources {
+            let state = Path::new(dest).join(incremental::state_file_name(Path::new(source)));
+            notes.push(format!("To keep the last {} full backups, made every {} days, with their incrementals, run: athena chains {} --keep {}", keep, full_every, state.display(), keep));
+        }
+    } else if retention.full.is_some() {
+        notes.push("Keeping a number of full backups needs an incremental profile, set incremental and full_every".to_string());
+    }
+    match (repo, retention.days()) {
+        (Some(Repo::B2 { bucket, prefix }), Some(days)) => notes.push(format!(
+            "To expire archives in B2 once they're older than the retention covers, run: athena remote lifecycle --bucket {}{} --keep-days {}",
+            bucket,
+            if prefix.is_empty() { String::new() } else { format!(" --prefix {}", prefix) },
+            days
+        )),
+        (_, Some(days)) if !retention.has_counts() && chains.is_none() => {
+            notes.push(format!("Retention of {} days has no athena equivalent outside B2 and incremental chains", days));
+        },
+        _ => {},
+    }
+    notes
+}
+
+// Splits the tool's arguments into options, with their values, and everything else
+fn split_args(tool: Tool, args: &[String]) -> (Vec<(String, Option<String>)>, Vec<String>) {
+    let mut flags = Vec::new();
+    let mut positionals = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            positionals.extend(args.by_ref().cloned());
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            positionals.push(arg.clone());
+            continue;
+        }
+        match arg.split_once('=').filter(|_| arg.starts_with("--")) {
+            Some((flag, value)) => flags.push((flag.to_string(), Some(value.to_string()))),
+            None if tool.value_flags().contains(&arg.as_str()) => flags.push((arg.clone(), args.next().cloned())),
+            None => flags.push((arg.clone(), None)),
+        }
+    }
+    (flags, positionals)
+}
+
+// Splits a shell script or crontab into commands of words, each with the daemon schedule its cron entry amounts to.
+// Variables it assigns are expanded. Only as much of the shell as backup scripts tend to use is understood: quotes,
+// escapes, comments, line continuations, and `;`, `&&`, `||` and `|` between commands
+fn parse_script(text: &str, env: &mut BTreeMap<String, String>) -> Vec<(Option<String>, Vec<String>)> {
+    let mut commands = Vec::new();
+    for line in text.replace("\\\n", " ").lines() {
+        let (schedule, line) = cron_schedule(line);
+        for mut words in split_words(line, env) {
+            if words.first().is_some_and(|word| word == "export") {
+                words.remove(0);
+            }
+            // Leading assignments set variables, for the command if there is one and the script if not. Either way
+            // they're what the tool would see
+            while let Some((key, value)) = words.first().and_then(|word| assignment(word)) {
+                env.insert(key, value);
+                words.remove(0);
+            }
+            if !words.is_empty() {
+                commands.push((schedule.clone(), words));
+            }
+        }
+    }
+    commands
+}
+
+fn assignment(word: &str) -> Option<(String, String)> {
+    let (key, value) = word.split_once('=')?;
+    let valid = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then(|| (key.to_string(), value.to_string()))
+}
+
+// Splits a line into commands of words, expanding $NAME and ${NAME} outside single quotes. Redirections are dropped
+fn split_words(line: &str, env: &BTreeMap<String, String>) -> Vec<Vec<String>> {
+    let mut commands = vec![Vec::new()];
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    // Whether the next word is a redirection's target
+    let mut redirected = false;
+    let end_word = |commands: &mut Vec<Vec<String>>, word: &mut Option<String>, redirected: &mut bool| {
+        if let Some(word) = word.take() {
+            match *redirected {
+                true => *redirected = false,
+                false => commands.last_mut().unwrap().push(word),
+            }
+        }
+    };
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_default().push(c),
+            (_, '\\') => {
+                if let Some(next) = chars.next() {
+                    word.get_or_insert_default().push(next);
+                }
+            },
+            (_, '$') if chars.peek().is_some_and(|next| next.is_ascii_alphabetic() || *next == '_' || *next == '{') => {
+                let braced = chars.next_if_eq(&'{').is_some();
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    name.push(c);
+                }
+                if braced {
+                    chars.next_if_eq(&'}');
+                }
+                let value = env.get(&name).cloned().unwrap_or_else(|| if braced { format!("${{{}}}", name) } else { format!("${}", name) });
+                word.get_or_insert_default().push_str(&value);
+            },
+            (Some(_), c) => word.get_or_insert_default().push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_default();
+            },
+            (None, '#') if word.is_none() => break,
+            (None, '>' | '<') => {
+                // A file descriptor number before it, as in 2>, is part of it
+                if word.as_ref().is_some_and(|word| word.chars().all(|c| c.is_ascii_digit())) {
+                    word = None;
+                }
+                end_word(&mut commands, &mut word, &mut redirected);
+                chars.next_if(|next| *next == c);
+                // Duplicating a descriptor, as in 2>&1, has no target word
+                match chars.next_if_eq(&'&') {
+                    Some(_) => while chars.next_if(|next| next.is_ascii_digit() || *next == '-').is_some() {},
+                    None => redirected = true,
+                }
+            },
+            (None, ';' | '&' | '|') => {
+                end_word(&mut commands, &mut word, &mut redirected);
+                commands.push(Vec::new());
+            },
+            (None, c) if c.is_whitespace() => end_word(&mut commands, &mut word, &mut redirected),
+            (None, c) => word.get_or_insert_default().push(c),
+        }
+    }
+    end_word(&mut commands, &mut word, &mut redirected);
+    commands.retain(|words| !words.is_empty());
+    commands
+}
+
+// Strips a crontab entry's timing from a line, returning the schedule it amounts to where the daemon has one
+fn cron_schedule(line: &str) -> (Option<String>, &str) {
+    let trimmed = line.trim_start();
+    if let Some((special, rest)) = trimmed.strip_prefix('@').and_then(|rest| rest.split_once(char::is_whitespace)) {
+        let schedule = match special {
+            "hourly" => Some("every 1h".to_string()),
+            "daily" | "midnight" => Some("daily 00:00".to_string()),
+            "weekly" => Some("every 7d".to_string()),
+            _ => None,
+        };
+        return (schedule, rest);
+    }
+    let fields: Vec<&str> = trimmed.splitn(6, char::is_whitespace).collect();
+    let is_cron = fields.len() == 6 && fields[..5].iter().all(|field| !field.is_empty() && field.chars().all(|c| c.is_ascii_digit() || "*/,-".contains(c)));
+    if !is_cron {
+        return (None, line);
+    }
+    let step = |field: &str| field.strip_prefix("*/").and_then(|step| step.parse::<u32>().ok());
+    let schedule = match fields[..5] {
+        [minute, hour, "*", "*", "*"] => match (minute.parse::<u32>(), hour.parse::<u32>(), step(hour)) {
+            (Ok(minute), Ok(hour), _) if minute < 60 && hour < 24 => Some(format!("daily {:02}:{:02}", hour, minute)),
+            (Ok(_), _, Some(hours)) if hours > 0 => Some(format!("every {}h", hours)),
+            _ if hour == "*" => step(minute).filter(|minutes| *minutes > 0).map(|minutes| format!("every {}m", minutes)),
+            _ => None,
+        },
+        _ => None,
+    };
+    (schedule, fields[5])
+}
@@ -0,0 +1,287 @@
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}, sync::{atomic::{AtomicUsize, Ordering}, Mutex}, time::Duration, error::Error};
+use chrono::{DateTime, Local};
+use indicatif::HumanDuration;
+use serde::{Deserialize, Serialize};
+use crate::hash::{self, HashAlgorithm};
+
+// How often --trust-mtime runs hash every file anyway, unless --full-hash-every says otherwise
+pub const DEFAULT_FULL_HASH_EVERY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Everything recorded about a source file on the last run. The hash is only recomputed when
+// size, mtime or inode change (or on --rescan). Files --trust-mtime saw change have an empty hash, as they
+// weren't hashed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: i64,
+    pub inode: u64,
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct State {
+    pub algorithm: String,
+    pub entries: BTreeMap<String, FileState>,
+    // When the last full backup was taken, as RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_full: Option<String>,
+    // Every run made with this state, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runs: Vec<RunRecord>,
+    // Source directories and their mtimes as of the last --trust-mtime run, whose listings are reused while the
+    // mtime stays the same
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dirs: BTreeMap<String, i64>,
+    // When every file was last hashed, as RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_full_hash: Option<String>,
+    // Fingerprint of the excludes and type filters `dirs` was walked with. The listings only hold what they let
+    // through, so they're read again under any others
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filters: Option<String>,
+}
+
+// A run recorded in the state, with whether it archived everything or just the changes, and why
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunRecord {
+    pub time: String,
+    pub archive: String,
+    pub full: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // The run's place in its chain, e.g. `full-2024-06-01.2`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain: Option<String>,
+}
+
+// A full backup and the incrementals made on top of it, which can only be restored (or removed) together
+pub struct Chain<'a> {
+    pub name: String,
+    pub runs: Vec<&'a RunRecord>,
+}
+
+// Result of comparing the current source files against the previous run
+pub struct Scan {
+    pub changed: Vec<PathBuf>,
+    // Files in the previous run that are gone now
+    pub deleted: Vec<PathBuf>,
+    pub state: State,
+    pub hashed: usize,
+}
+
+impl State {
+    // Loads state from a previous run, or an empty state if there wasn't one
+    pub fn load(path: &Path) -> Result<State, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let contents = fs::read(path)?;
+        serde_json::from_slice(&contents).map_err(|e| format!("Invalid state file {}: {}", path.display(), e).into())
+    }
+
+    // Writes the state atomically, so an interrupted run never leaves a truncated state file
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+// Default location of the state file for a given source, kept in the destination dir
+pub fn default_state_path(input_path: &Path, output_path: &Path) -> PathBuf {
+    let dir = if output_path.is_dir() { output_path } else { output_path.parent().unwrap() };
+    dir.join(state_file_name(input_path))
+}
+
+pub fn state_file_name(input_path: &Path) -> String {
+    let name = input_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| "root".to_string());
+    format!(".athena-{}.state.json", name)
+}
+
+// Why this run should be a full backup rather than an incremental one, if it should be
+pub fn full_reason(previous: &State, full_every: Option<Duration>, now: DateTime<Local>) -> Option<String> {
+    if previous.entries.is_empty() && previous.runs.is_empty() {
+        return Some("first run".to_string());
+    }
+    let full_every = full_every?;
+    let last_full = match previous.last_full.as_deref().map(DateTime::parse_from_rfc3339) {
+        Some(Ok(last_full)) => last_full,
+        _ => return Some("no full backup recorded".to_string()),
+    };
+    let age = now.signed_duration_since(last_full).to_std().unwrap_or_default();
+    if age >= full_every {
+        return Some(format!("last full backup was {} ago", HumanDuration(age)));
+    }
+    None
+}
+
+// Names this run's place in its chain, e.g. `full-2024-06-01.2`: the day the chain's full backup was taken, then
+// how many runs into the chain it is, 0 being the full backup. Another full backup the same day starts
+// `full-2024-06-01-2`
+pub fn chain_id(previous: &State, full: bool, now: DateTime<Local>) -> String {
+    let chains = chains(previous);
+    if let Some(chain) = chains.last().filter(|_| !full) {
+        return format!("{}.{}", chain.name, chain.runs.len());
+    }
+    let day = now.format("full-%Y-%m-%d").to_string();
+    let name = (1..)
+        .map(|n| if n == 1 { day.clone() } else { format!("{}-{}", day, n) })
+        .find(|name| !chains.iter().any(|chain| &chain.name == name))
+        .unwrap();
+    format!("{}.0", name)
+}
+
+// The state's runs grouped into chains, oldest first. Runs recorded before chains were named are put in one
+// named for the day of its full backup
+pub fn chains(state: &State) -> Vec<Chain<'_>> {
+    let mut chains: Vec<Chain> = Vec::new();
+    for run in &state.runs {
+        match chains.last_mut() {
+            Some(chain) if !run.full => chain.runs.push(run),
+            _ => {
+                let name = match run.chain.as_deref().and_then(|chain| chain.rsplit_once('.')) {
+                    Some((name, _)) => name.to_string(),
+                    None => DateTime::parse_from_rfc3339(&run.time).map(|time| time.format("full-%Y-%m-%d").to_string()).unwrap_or_else(|_| "full".to_string()),
+                };
+                chains.push(Chain { name, runs: vec![run] });
+            },
+        }
+    }
+    chains
+}
+
+// Drops the runs of all but the newest `keep` chains from the state, returning the runs dropped
+pub fn drop_chains(state: &mut State, keep: usize) -> Vec<RunRecord> {
+    let chains = chains(state);
+    let dropped = chains.len().saturating_sub(keep);
+    let runs: usize = chains[..dropped].iter().map(|chain| chain.runs.len()).sum();
+    state.runs.drain(..runs).collect()
+}
+
+// Why a --trust-mtime run should hash every file this time, catching changes that kept a file's size and mtime
+pub fn full_hash_reason(previous: &State, every: Duration, now: DateTime<Local>) -> Option<String> {
+    let last = match previous.last_full_hash.as_deref().map(DateTime::parse_from_rfc3339) {
+        Some(Ok(last)) => last,
+        _ => return Some("no full hash recorded".to_string()),
+    };
+    let age = now.signed_duration_since(last).to_std().unwrap_or_default();
+    (age >= every).then(|| format!("last full hash was {} ago", HumanDuration(age)))
+}
+
+// Directory listings from the last --trust-mtime run. A directory's mtime changes whenever an entry is added,
+// removed or renamed in it, so while it's the same the names in it are too, and it needn't be read again
+pub struct DirCache {
+    previous: BTreeMap<String, i64>,
+    // Files and subdirectories by the directory they're in
+    files: HashMap<PathBuf, Vec<PathBuf>>,
+    subdirs: HashMap<PathBuf, Vec<PathBuf>>,
+    // Directories reached on this walk, saved for the next
+    walked: Mutex<BTreeMap<String, i64>>,
+    pub reused: AtomicUsize,
+}
+
+impl DirCache {
+    pub fn new(previous: &State) -> DirCache {
+        let mut files: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        for key in previous.entries.keys() {
+            let path = PathBuf::from(key);
+            if let Some(parent) = path.parent() {
+                files.entry(parent.to_path_buf()).or_default().push(path);
+            }
+        }
+        let mut subdirs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        for key in previous.dirs.keys() {
+            let path = PathBuf::from(key);
+            if let Some(parent) = path.parent() {
+                subdirs.entry(parent.to_path_buf()).or_default().push(path);
+            }
+        }
+        DirCache { previous: previous.dirs.clone(), files, subdirs, walked: Mutex::new(BTreeMap::new()), reused: AtomicUsize::new(0) }
+    }
+
+    // Records a directory reached on the walk, returning its files and subdirectories from last time if it hasn't
+    // changed since
+    pub fn listing(&self, dir: &Path, modified: Option<std::time::SystemTime>) -> Option<(Vec<PathBuf>, Vec<PathBuf>)> {
+        let key = dir.to_string_lossy().to_string();
+        let mtime = mtime_key(modified);
+        self.walked.lock().unwrap().insert(key.clone(), mtime);
+        if self.previous.get(&key) != Some(&mtime) {
+            return None;
+        }
+        self.reused.fetch_add(1, Ordering::Relaxed);
+        Some((self.files.get(dir).cloned().unwrap_or_default(), self.subdirs.get(dir).cloned().unwrap_or_default()))
+    }
+
+    pub fn walked(&self) -> BTreeMap<String, i64> {
+        self.walked.lock().unwrap().clone()
+    }
+}
+
+// Works out which files changed since the previous run, reusing cached hashes for files whose
+// metadata is unchanged unless `rescan` is set. With `trust_mtime`, files whose metadata changed are taken to
+// have changed without hashing them
+pub fn scan(files: &[PathBuf], previous: &State, algorithm: HashAlgorithm, rescan: bool, trust_mtime: bool) -> Result<Scan, Box<dyn Error>> {
+    // Hashes from a different algorithm can't be compared, so everything needs re-hashing
+    let rescan = rescan || previous.algorithm != algorithm.name();
+    let mut state = State {
+        algorithm: algorithm.name().to_string(),
+        entries: BTreeMap::new(),
+        last_full: previous.last_full.clone(),
+        runs: previous.runs.clone(),
+        dirs: BTreeMap::new(),
+        last_full_hash: previous.last_full_hash.clone(),
+        filters: previous.filters.clone(),
+    };
+    let mut changed = Vec::new();
+    let mut hashed = 0;
+
+    for path in files {
+        let key = path.to_string_lossy().to_string();
+        let metadata = path.symlink_metadata()?;
+        let (size, mtime, inode) = stat_key(&metadata);
+        let cached = previous.entries.get(&key);
+
+        let hash = match cached {
+            Some(entry) if !rescan && entry.size == size && entry.mtime == mtime && entry.inode == inode => entry.hash.clone(),
+            _ if trust_mtime => {
+                changed.push(path.clone());
+                state.entries.insert(key, FileState { size, mtime, inode, hash: String::new() });
+                continue;
+            },
+            // Directories archived with `--only-type d` have no contents to hash, so are only new or not
+            _ if metadata.is_dir() => String::new(),
+            _ => {
+                hashed += 1;
+                if metadata.file_type().is_symlink() {
+                    let mut hasher = algorithm.hasher();
+                    hasher.update(path.read_link()?.as_os_str().as_encoded_bytes());
+                    hasher.finalize()
+                } else {
+                    hash::file(path, algorithm)?
+                }
+            },
+        };
+
+        if cached.map(|entry| &entry.hash) != Some(&hash) {
+            changed.push(path.clone());
+        }
+        state.entries.insert(key, FileState { size, mtime, inode, hash });
+    }
+    let deleted = previous.entries.keys().filter(|key| !state.entries.contains_key(*key)).map(PathBuf::from).collect();
+    Ok(Scan { changed, deleted, state, hashed })
+}
+
+fn mtime_key(modified: Option<std::time::SystemTime>) -> i64 {
+    modified.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()).map(|d| d.as_nanos() as i64).unwrap_or(0)
+}
+
+fn stat_key(metadata: &fs::Metadata) -> (u64, i64, u64) {
+    let mtime = mtime_key(metadata.modified().ok());
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+    (metadata.len(), mtime, inode)
+}
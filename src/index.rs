@@ -0,0 +1,210 @@
+use std::{fs, io::{self, BufReader, Read, Write}, path::{Path, PathBuf}, error::Error};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use crate::footer::{self, TarWriter};
+use crate::validate::{self, ArchiveKind};
+
+// Suffix of the file or object holding an archive's entry index
+pub const INDEX_SUFFIX: &str = ".idx";
+// Uncompressed bytes per gzip member in a seekable archive, unless lowered by `--max-memory`. Smaller frames mean
+// less to download for a single entry, but a worse compression ratio since each starts with an empty dictionary
+pub const FRAME_SIZE: u64 = 1024 * 1024;
+
+// Where each entry sits in an uncompressed tar, so single entries can be listed, or fetched with ranged downloads,
+// rather than reading the whole archive. Kept next to seekable archives, and at the end of ones written with a
+// footer index
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EntryIndex {
+    pub entries: Vec<IndexedEntry>,
+    // For seekable compressed tars, where each gzip member starts. Entry offsets are then into the uncompressed tar
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Frame {
+    // Offset of the member in the archive file
+    pub offset: u64,
+    // Offset of its first byte in the uncompressed tar
+    pub start: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexedEntry {
+    pub path: String,
+    // From the first header belonging to the entry (long name and PAX headers come before its own) to the end of
+    // its padded data. Footer indexes from before they recorded the end called the start `offset`
+    #[serde(alias = "offset")]
+    pub start: u64,
+    #[serde(default)]
+    pub end: u64,
+    // Size of the entry's contents
+    #[serde(default)]
+    pub size: u64,
+    // blake3 of regular files' contents, for archives written with a footer index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    // Modification time of the source file, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+}
+
+impl EntryIndex {
+    // Indexes a finished archive. Plain tars are indexed directly, while compressed ones can only be read from an
+    // offset if they were written seekable, in which case the index was saved alongside them
+    pub fn build(archive: &Path) -> Result<Option<EntryIndex>, Box<dyn Error>> {
+        let sidecar = sidecar_path(archive);
+        if sidecar.exists() {
+            return Ok(Some(serde_json::from_slice(&fs::read(sidecar)?)?));
+        }
+        if validate::detect(archive)? != Some(ArchiveKind::Tar) {
+            return Ok(None);
+        }
+        Ok(Some(EntryIndex { entries: entries(BufReader::new(fs::File::open(archive)?))?, frames: Vec::new() }))
+    }
+
+    // Indexes a seekable archive written with `FrameWriter`, saving the index next to it
+    pub fn write(archive: &Path, frames: Vec<Frame>) -> Result<PathBuf, Box<dyn Error>> {
+        let entries = entries(MultiGzDecoder::new(BufReader::new(fs::File::open(archive)?)))?;
+        let sidecar = sidecar_path(archive);
+        fs::write(&sidecar, serde_json::to_vec(&EntryIndex { entries, frames })?)?;
+        Ok(sidecar)
+    }
+
+    // The compressed bytes `offset..end` to fetch for uncompressed bytes `start..end` of a seekable archive of
+    // `size` bytes, along with the uncompressed offset they decompress from
+    pub fn frame_span(&self, start: u64, end: u64, size: u64) -> (u64, u64, u64) {
+        let first = self.frames.partition_point(|frame| frame.start <= start).saturating_sub(1);
+        let next = self.frames.partition_point(|frame| frame.start < end);
+        let compressed_end = self.frames.get(next).map(|frame| frame.offset).unwrap_or(size);
+        (self.frames[first].offset, compressed_end, self.frames[first].start)
+    }
+
+    // Byte ranges holding the wanted entries, with neighbouring ranges merged so they're fetched together
+    pub fn ranges(&self, wanted: impl Fn(&str) -> bool) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for entry in self.entries.iter().filter(|entry| wanted(&entry.path)) {
+            match ranges.last_mut() {
+                Some(last) if last.1 == entry.start => last.1 = entry.end,
+                _ => ranges.push((entry.start, entry.end)),
+            }
+        }
+        ranges
+    }
+}
+
+fn sidecar_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(INDEX_SUFFIX);
+    PathBuf::from(path)
+}
+
+// Where each entry of an uncompressed tar stream starts and ends
+fn entries<R: Read>(reader: R) -> Result<Vec<IndexedEntry>, Box<dyn Error>> {
+    let mut tar = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    let mut start = 0;
+    for entry in tar.entries()? {
+        let entry = entry?;
+        let end = entry.raw_file_position() + entry.size().div_ceil(512) * 512;
+        let mtime = entry.header().mtime().ok().map(|mtime| mtime as i64);
+        entries.push(IndexedEntry { path: entry.path()?.to_string_lossy().to_string(), start, end, size: entry.size(), hash: None, mtime });
+        start = end;
+    }
+    Ok(entries)
+}
+
+// Gzips into a series of independent members of `frame_size` uncompressed bytes each, recording where each one
+// starts. The result is still an ordinary gzip file to any reader that handles multiple members
+pub struct FrameWriter<W: Write> {
+    inner: W,
+    level: Compression,
+    // The level the current frame's compressed at, which --compress-override can change
+    current: Compression,
+    frame_size: u64,
+    encoder: GzEncoder<Vec<u8>>,
+    frames: Vec<Frame>,
+    compressed: u64,
+    uncompressed: u64,
+    footer: Option<u64>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(inner: W, level: Compression, frame_size: u64) -> FrameWriter<W> {
+        FrameWriter { inner, level, current: level, frame_size, encoder: GzEncoder::new(Vec::new(), level), frames: vec![Frame { offset: 0, start: 0 }], compressed: 0, uncompressed: 0, footer: None }
+    }
+
+    // Ends the current member, starting the next at the current position, which is returned
+    fn cut(&mut self) -> io::Result<u64> {
+        let member = std::mem::replace(&mut self.encoder, GzEncoder::new(Vec::new(), self.current)).finish()?;
+        self.inner.write_all(&member)?;
+        self.compressed += member.len() as u64;
+        self.frames.push(Frame { offset: self.compressed, start: self.uncompressed });
+        Ok(self.compressed)
+    }
+
+    pub fn finish(mut self) -> io::Result<(W, Vec<Frame>)> {
+        let member = self.encoder.finish()?;
+        self.inner.write_all(&member)?;
+        let inner = match self.footer {
+            Some(offset) => footer::marker_member(self.inner, offset)?,
+            None => self.inner,
+        };
+        Ok((inner, self.frames))
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut filled = self.uncompressed - self.frames.last().unwrap().start;
+        // Frames are only cut once there's more to write, so the last one is never empty
+        if filled >= self.frame_size && !buf.is_empty() {
+            self.cut()?;
+            filled = 0;
+        }
+        let len = buf.len().min((self.frame_size - filled) as usize);
+        self.encoder.write_all(&buf[..len])?;
+        self.uncompressed += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> TarWriter for FrameWriter<W> {
+    fn position(&self) -> u64 {
+        self.uncompressed
+    }
+
+    // The footer starts a frame of its own
+    fn start_footer(&mut self) -> io::Result<u64> {
+        self.current = self.level;
+        let offset = self.cut()?;
+        self.footer = Some(offset);
+        Ok(offset)
+    }
+
+    fn compressed(&self) -> bool {
+        true
+    }
+
+    fn frames(&self) -> Vec<Frame> {
+        self.frames.clone()
+    }
+
+    // A new level starts a new frame, unless nothing's been written to this one yet
+    fn set_level(&mut self, level: Compression) -> io::Result<()> {
+        if level == self.current {
+            return Ok(());
+        }
+        self.current = level;
+        if self.uncompressed > self.frames.last().unwrap().start {
+            self.cut()?;
+        } else {
+            self.encoder = GzEncoder::new(Vec::new(), level);
+        }
+        Ok(())
+    }
+}
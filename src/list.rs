@@ -0,0 +1,90 @@
+use std::{fs, io::{self, BufReader, Read, Write}, path::Path, error::Error};
+use flate2::read::MultiGzDecoder;
+use crate::{crypto, footer, validate::{self, ArchiveKind}};
+
+// An entry as listed, with its size in bytes and modification time in seconds since the epoch. Footer indexes
+// from before they recorded mtimes have none
+pub struct Listed {
+    pub path: String,
+    pub size: u64,
+    pub mtime: Option<i64>,
+}
+
+// Lists an archive's entries, from its footer index if it has one, otherwise by reading through it. Encrypted
+// tars have to be decrypted to be listed, with the password in `password_file` or asked for
+pub fn list(archive: &Path, password_file: Option<&Path>) -> Result<Vec<Listed>, Box<dyn Error>> {
+    if let Some(index) = footer::read(archive)? {
+        return Ok(index.entries.into_iter().map(|entry| Listed { path: entry.path, size: entry.size, mtime: entry.mtime }).collect());
+    }
+    let mut listed = Vec::new();
+    match open(archive, password_file)? {
+        Opened::Zip(mut zip) => {
+            for i in 0..zip.len() {
+                let entry = zip.by_index_raw(i)?;
+                listed.push(Listed { path: entry.name().to_string(), size: entry.size(), mtime: entry.last_modified().and_then(zip_timestamp) });
+            }
+        },
+        Opened::Tar(reader) => {
+            let mut tar = tar::Archive::new(reader);
+            for entry in tar.entries()? {
+                let entry = entry?;
+                if !entry.header().entry_type().is_pax_global_extensions() {
+                    listed.push(Listed { path: entry.path()?.to_string_lossy().to_string(), size: entry.size(), mtime: entry.header().mtime().ok().map(|mtime| mtime as i64) });
+                }
+            }
+            // A sealed tar is only known to be whole once its last chunk, past the end of the tar, authenticates
+            io::copy(&mut tar.into_inner(), &mut io::sink())?;
+        },
+    }
+    Ok(listed)
+}
+
+// Writes the contents of one entry to `out`
+pub fn cat(archive: &Path, path: &str, password_file: Option<&Path>, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let not_found = || -> Box<dyn Error> { format!("No entry {} in {}", path, archive.display()).into() };
+    if let Some(index) = footer::read(archive)? {
+        let entry = index.entries.iter().find(|entry| entry.path == path).ok_or_else(not_found)?;
+        return footer::cat(archive, &index, entry, out);
+    }
+    match open(archive, password_file)? {
+        Opened::Zip(mut zip) => {
+            let i = zip.index_for_name(path).ok_or_else(not_found)?;
+            if zip.by_index_raw(i)?.encrypted() {
+                return Err("Entry is encrypted, use `athena restore` instead".into());
+            }
+            io::copy(&mut zip.by_index(i)?, out)?;
+        },
+        Opened::Tar(reader) => {
+            let mut tar = tar::Archive::new(reader);
+            let mut entry = tar.entries()?.find(|entry| entry.as_ref().is_ok_and(|entry| entry.path().is_ok_and(|name| name.to_string_lossy() == path))).ok_or_else(not_found)??;
+            io::copy(&mut entry, out)?;
+            drop(entry);
+            io::copy(&mut tar.into_inner(), &mut io::sink())?;
+        },
+    }
+    Ok(())
+}
+
+pub enum Opened {
+    Zip(zip::ZipArchive<BufReader<fs::File>>),
+    Tar(Box<dyn Read>),
+}
+
+pub fn open(archive: &Path, password_file: Option<&Path>) -> Result<Opened, Box<dyn Error>> {
+    let kind = validate::readable(validate::detect(archive)?)?;
+    let file = BufReader::new(fs::File::open(archive)?);
+    Ok(match kind {
+        ArchiveKind::Zip => Opened::Zip(zip::ZipArchive::new(file)?),
+        ArchiveKind::Gzip => Opened::Tar(Box::new(MultiGzDecoder::new(file))),
+        ArchiveKind::Sealed => Opened::Tar(crypto::open_sealed(archive, password_file)?),
+        _ => Opened::Tar(Box::new(file)),
+    })
+}
+
+// Zip timestamps are local time, without a zone
+pub fn zip_timestamp(time: zip::DateTime) -> Option<i64> {
+    use chrono::TimeZone;
+    let date = chrono::NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?;
+    let time = date.and_hms_opt(time.hour().into(), time.minute().into(), time.second().into())?;
+    chrono::Local.from_local_datetime(&time).earliest().map(|time| time.timestamp())
+}
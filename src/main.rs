@@ -1,131 +1,1793 @@
-use std::{time::Duration, path::PathBuf, fs, process, error};
-use clap::Parser;
-use flate2::{write::GzEncoder, Compression};
-use futures::future::{BoxFuture, FutureExt};
+use std::{time::{Duration, Instant}, ffi::{OsStr, OsString}, io::IsTerminal, path::{Path, PathBuf}, fs, process, sync::Arc, error};
+use clap::{CommandFactory, Parser, Subcommand};
+use flate2::Compression;
 use indicatif::ProgressBar;
-use file_owner::PathExt;
 use tokio::signal::ctrl_c;
 
 mod validate;
 mod utils;
 mod b2;
+mod hash;
+mod doctor;
+mod service;
+mod selftest;
+mod incremental;
+mod backend;
+mod format;
+mod repo;
+mod chunker;
+mod crypto;
+mod store;
+mod compare;
+mod manifest;
+mod snapshot;
+mod pax;
+mod ntfs;
+mod fileattrs;
+mod restore;
+mod host;
+mod http;
+mod remote;
+mod retention;
+mod estimate;
+mod index;
+mod cancel;
+mod progress;
+mod privileges;
+mod busy;
+mod priority;
+mod errors;
+mod readahead;
+mod footer;
+mod list;
+mod prompt;
+mod ratelimit;
+mod dictionary;
+mod merge;
+mod spool;
+mod report;
+mod webhook;
+mod scratch;
+mod verify;
+mod grep;
+mod find;
+mod drift;
+mod download;
+mod exclude;
+mod space;
+mod deadline;
+mod pathstyle;
+mod pipeline;
+mod status;
+mod daemon;
+mod api;
+mod window;
+mod signals;
+mod device;
+mod squashfs;
+mod cpio;
+mod sourcefs;
+mod sftp;
+mod import;
+mod schema;
+mod scrub;
+mod history;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short = 'i', long = "src")]
-    src: String,
-    #[arg(short = 'o', long = "dest")]
-    dest: String,
-    #[arg(short = 'c', long = "compress")]
+    #[command(subcommand)]
+    command: Option<Command>,
+    // A local path or glob, or ssh://[user@]host[:port]/path to read another machine's files over SFTP
+    #[arg(short = 'i', long = "src", required = true, env = "ATHENA_SRC")]
+    src: Option<PathBuf>,
+    #[arg(short = 'o', long = "dest", required = true, env = "ATHENA_DEST")]
+    dest: Option<PathBuf>,
+    #[arg(short = 'c', long = "compress", env = "ATHENA_COMPRESS")]
     compress: bool,
-    #[arg(short = 'u', long = "upload")]
+    // Compress files matching a pattern their own way, e.g. `*.mp4=store` for video that won't shrink further, or
+    // `*.sqlite=deflate:9`. The first matching pattern wins. Zip and squashfs apply it per entry; a tgz starts a
+    // new gzip member wherever the level changes
+    #[arg(long = "compress-override", value_parser = format::parse_override, requires = "compress", env = "ATHENA_COMPRESS_OVERRIDE")]
+    compress_override: Vec<format::CompressOverride>,
+    // Compress tars in independent 1 MiB frames, indexed in a .idx file alongside, so single entries can be restored
+    // from an upload without downloading all of it
+    #[arg(long = "seekable", requires = "compress", env = "ATHENA_SEEKABLE")]
+    seekable: bool,
+    // End tar archives with an index of every entry, so `athena list` and `athena cat` only need to read the end
+    // of the archive. The result is still a valid tar, with the index as an extra .athena-index.json entry
+    #[arg(long = "footer-index", env = "ATHENA_FOOTER_INDEX")]
+    footer_index: bool,
+    // Keep compression and read buffers within this much memory, e.g. `64MiB` on a small VPS. Seekable frames and
+    // the default read buffers shrink to fit, and settings that can't fit are refused
+    #[arg(long = "max-memory", value_parser = utils::parse_size, env = "ATHENA_MAX_MEMORY")]
+    max_memory: Option<u64>,
+    #[arg(short = 'u', long = "upload", env = "ATHENA_UPLOAD")]
     upload: bool,
-    #[arg(short = 'v', long = "verbose")]
+    #[arg(short = 'v', long = "verbose", env = "ATHENA_VERBOSE")]
     verbose: bool,
+    #[arg(long = "mmap-threshold", value_parser = utils::parse_size, env = "ATHENA_MMAP_THRESHOLD")]
+    mmap_threshold: Option<u64>,
+    // How much of a file to read at a time, with the next chunk read ahead while the last is compressed. Defaults
+    // to 256 KiB, or 4 MiB when the sources are on a network filesystem
+    #[arg(long = "buffer-size", value_parser = utils::parse_size, env = "ATHENA_BUFFER_SIZE")]
+    buffer_size: Option<u64>,
+    // Cap how fast source files are read, e.g. `50MB/s`, so archiving from a shared NAS or a busy database volume
+    // leaves bandwidth for everything else using it
+    #[arg(long = "limit-read", value_parser = ratelimit::parse_rate, env = "ATHENA_LIMIT_READ")]
+    limit_read: Option<u64>,
+    #[arg(long = "hash", value_enum, env = "ATHENA_HASH")]
+    hash: Option<hash::HashAlgorithm>,
+    // Size of each write when the destination is a tape drive or other device, a multiple of 512. Defaults to
+    // tar's 10KiB records
+    #[arg(long = "block-size", value_parser = utils::parse_size, env = "ATHENA_BLOCK_SIZE")]
+    block_size: Option<u64>,
+    #[arg(long = "on-invalid", value_enum, default_value = "delete", env = "ATHENA_ON_INVALID")]
+    on_invalid: validate::InvalidPolicy,
+    #[arg(long = "file-progress-threshold", value_parser = utils::parse_size, env = "ATHENA_FILE_PROGRESS_THRESHOLD")]
+    file_progress_threshold: Option<u64>,
+    #[arg(long = "incremental", env = "ATHENA_INCREMENTAL")]
+    incremental: bool,
+    #[arg(long = "state-file", requires = "incremental", env = "ATHENA_STATE_FILE")]
+    state_file: Option<String>,
+    #[arg(long = "rescan", requires = "incremental", env = "ATHENA_RESCAN")]
+    rescan: bool,
+    // Archive everything instead of just the changes when the last full backup is older than this, e.g. `7d`
+    #[arg(long = "full-every", value_parser = utils::parse_duration, requires = "incremental", env = "ATHENA_FULL_EVERY")]
+    full_every: Option<Duration>,
+    // Decide what changed from size, mtime and inode alone, without hashing, and reuse the listings of directories
+    // whose mtime hasn't changed. Much faster on big trees, but misses changes that keep a file's size and mtime
+    // (e.g. tools that restore timestamps) until the next full hash
+    #[arg(long = "trust-mtime", requires = "incremental", env = "ATHENA_TRUST_MTIME")]
+    trust_mtime: bool,
+    // How often --trust-mtime runs hash every file anyway, e.g. `30d`. Defaults to 7 days
+    #[arg(long = "full-hash-every", value_parser = utils::parse_duration, requires = "trust_mtime", env = "ATHENA_FULL_HASH_EVERY")]
+    full_hash_every: Option<Duration>,
+    #[arg(long = "format", value_enum, default_value = "tar", env = "ATHENA_FORMAT")]
+    format: format::ArchiveFormat,
+    // Encrypt the archive: zips entry by entry with AES-256, tars whole, in authenticated chunks that fail to
+    // decrypt if the archive's truncated, reordered or tampered with
+    #[arg(long = "encrypt", env = "ATHENA_ENCRYPT")]
+    encrypt: bool,
+    #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+    password_file: Option<String>,
+    #[arg(long = "no-local-copy", requires = "upload", conflicts_with = "hash", env = "ATHENA_NO_LOCAL_COPY")]
+    no_local_copy: bool,
+    // Queue the upload in this directory instead of uploading straight away, for `athena flush` to do later with
+    // retries. The backup still completes when the network is down, and gets shipped once it's back
+    #[arg(long = "spool", requires = "upload", conflicts_with = "no_local_copy", env = "ATHENA_SPOOL")]
+    spool: Option<PathBuf>,
+    // Store unreadable files as empty entries (noted in the archive's manifest) rather than failing
+    #[arg(long = "placeholder-on-error", env = "ATHENA_PLACEHOLDER_ON_ERROR")]
+    placeholder_on_error: bool,
+    // Back up everything that can be read rather than failing on the first unreadable file or directory. What was
+    // left out is logged to <archive>.errors.jsonl, and the run exits with 3 instead of 0
+    #[arg(long = "keep-going", env = "ATHENA_KEEP_GOING")]
+    keep_going: bool,
+    // Times to retry opening a source file another process has busy (EBUSY/ETXTBSY, or a sharing violation on Windows)
+    #[arg(long = "busy-retries", default_value_t = 0, env = "ATHENA_BUSY_RETRIES")]
+    busy_retries: u32,
+    // What to do with a file that's still busy after retrying, or that shrinks while it's being read
+    #[arg(long = "on-busy", value_enum, default_value = "fail", env = "ATHENA_ON_BUSY")]
+    on_busy: busy::BusyPolicy,
+    // Flush the finished archive and its directory to disk before reporting success
+    #[arg(long = "fsync", env = "ATHENA_FSYNC")]
+    fsync: bool,
+    // Stop if the destination or temp directory has less than this free, e.g. `5G`, checked before starting and
+    // while archiving, rather than filling the disk under other services. The unfinished archive is removed
+    #[arg(long = "min-free-space", value_parser = utils::parse_size, env = "ATHENA_MIN_FREE_SPACE")]
+    min_free_space: Option<u64>,
+    // Stop archiving once the run has taken this long, e.g. `2h` for a maintenance window. The file being written
+    // is finished and the archive closed, so it's valid but partial, and the run exits with code 3
+    #[arg(long = "max-duration", value_parser = utils::parse_duration, env = "ATHENA_MAX_DURATION")]
+    max_duration: Option<Duration>,
+    // Read the sources from a snapshot of their volume. Paths inside it change every run, so incremental state can't be matched up
+    #[arg(long = "snapshot", value_enum, conflicts_with = "incremental", env = "ATHENA_SNAPSHOT")]
+    snapshot: Option<snapshot::SnapshotKind>,
+    // `unix` writes symlink targets with / separators so they work when restored on any platform. `native` keeps
+    // them as this platform has them
+    #[arg(long = "path-style", value_enum, default_value = "unix", env = "ATHENA_PATH_STYLE")]
+    path_style: pathstyle::PathStyle,
+    // Record NTFS attributes, FILETIMEs and alternate data streams in PAX headers (Windows only)
+    #[arg(long = "windows-metadata", env = "ATHENA_WINDOWS_METADATA")]
+    windows_metadata: bool,
+    // When started as root to read protected files, switch to this account (`user` or `user:group`) once the
+    // archive is written, so checksums, state and uploads don't run as root. The destination must be writable by it
+    #[arg(long = "run-as", value_parser = privileges::parse_run_as, conflicts_with = "no_local_copy", env = "ATHENA_RUN_AS")]
+    run_as: Option<privileges::RunAs>,
+    // Log progress as occasional plain lines instead of animated bars. The default when stderr isn't a terminal
+    #[arg(long = "no-tty", global = true, env = "ATHENA_NO_TTY")]
+    no_tty: bool,
+    // Run at the lowest CPU priority and idle I/O priority, so foreground work on the machine isn't slowed down
+    #[arg(long = "background", global = true, env = "ATHENA_BACKGROUND")]
+    background: bool,
+    // Answer yes to every question instead of asking, e.g. whether to create a missing output directory
+    #[arg(short = 'y', long = "yes", global = true, conflicts_with = "no", env = "ATHENA_YES")]
+    yes: bool,
+    // Answer no to every question instead of asking
+    #[arg(long = "no", global = true, env = "ATHENA_NO")]
+    no: bool,
+    // Answers to questions in the order they're asked, e.g. "y,n", falling back to each question's default once
+    // they run out. Without any of these, answers are read from stdin, which can be piped
+    #[arg(long = "answers", global = true, conflicts_with_all = ["yes", "no"], env = "ATHENA_ANSWERS")]
+    answers: Option<String>,
+    // Reads scripted answers from a file, one per line
+    #[arg(long = "answers-file", global = true, conflicts_with_all = ["yes", "no", "answers"], env = "ATHENA_ANSWERS_FILE")]
+    answers_file: Option<PathBuf>,
+    // POST a JSON event here as each phase finishes (scan, archive, upload, prune) and when the run ends, so other
+    // jobs can be chained off backups. Can be given more than once
+    #[arg(long = "webhook", global = true, value_parser = webhook::parse_url, value_delimiter = ',', env = "ATHENA_WEBHOOK")]
+    webhook: Vec<String>,
+    // Where scratch files go, like archives downloaded to restore from. Defaults to TMPDIR or the system's temp
+    // directory. Ones left behind by a run that crashed are removed by the next one
+    #[arg(long = "tmpdir", global = true, env = "ATHENA_TMPDIR")]
+    tmpdir: Option<PathBuf>,
+    // Only upload during these hours, e.g. 22:00-06:00 on a metered or shared link. Uploads outside it pause between
+    // parts, keeping the ones already sent, and carry on when it opens again
+    #[arg(long = "window", global = true, value_parser = window::parse, env = "ATHENA_WINDOW")]
+    window: Option<window::Window>,
+    // What SIGUSR1 does to a running backup: print a progress snapshot, toggle logging each file as it's archived,
+    // or pause and resume uploads
+    #[arg(long = "on-sigusr1", global = true, value_enum, default_value = "snapshot", env = "ATHENA_ON_SIGUSR1")]
+    on_sigusr1: signals::Action,
+    #[arg(long = "on-sigusr2", global = true, value_enum, default_value = "pause", env = "ATHENA_ON_SIGUSR2")]
+    on_sigusr2: signals::Action,
+    #[command(flatten)]
+    remote: backend::RemoteOptions,
+    #[command(flatten)]
+    host: host::HostOptions,
+    // Describes the backup, e.g. "pre-upgrade snapshot of /etc". Kept in the archive's manifest and uploaded object's metadata
+    #[arg(long = "comment", env = "ATHENA_COMMENT")]
+    comment: Option<String>,
+    // Email a summary of the run when it finishes, whether it succeeded or not, e.g. `email://ops@example.com`
+    #[arg(long = "report", value_parser = report::parse_recipients, env = "ATHENA_REPORT")]
+    report: Option<report::Recipients>,
+    #[command(flatten)]
+    smtp: report::SmtpOptions,
+    // Stop with an error if the sources turn out to hold more than this many files, e.g. from a mount that wasn't
+    // meant to be included
+    #[arg(long = "max-files", env = "ATHENA_MAX_FILES")]
+    max_files: Option<usize>,
+    #[command(flatten)]
+    filters: exclude::FilterOptions,
+    // Labels for the backup, e.g. `--tag nightly --tag db`. Listed in the manifest uploaded with the archive
+    #[arg(long = "tag", value_delimiter = ',', env = "ATHENA_TAG")]
+    tag: Vec<String>,
+    // Keep the run's phase and progress in this file as it goes, along with the results of the last few runs, for
+    // `athena status` to show. Services installed with install-service keep it in the default place
+    #[arg(long = "status-file", env = "ATHENA_STATUS_FILE")]
+    status_file: Option<PathBuf>,
+    // Name of the daemon profile the run is for, kept in its status
+    #[arg(long = "profile", value_parser = parse_profile_name, env = "ATHENA_PROFILE")]
+    profile: Option<String>,
+}
+
+fn parse_profile_name(input: &str) -> Result<String, String> {
+    daemon::check_name(input).map(|_| input.to_string())
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "Check the environment for problems before running a backup")]
+    Doctor {
+        #[arg(short = 'o', long = "dest", env = "ATHENA_DEST")]
+        dest: Option<String>,
+    },
+    #[command(about = "List the exclude presets and the paths each one leaves out")]
+    Presets,
+    #[command(about = "Install a systemd service and timer that run a backup on a schedule")]
+    InstallService {
+        #[arg(long = "name", env = "ATHENA_NAME")]
+        name: String,
+        #[arg(long = "on-calendar", env = "ATHENA_ON_CALENDAR")]
+        on_calendar: String,
+        #[arg(long = "system", env = "ATHENA_SYSTEM")]
+        system: bool,
+        #[arg(long = "unit-dir", env = "ATHENA_UNIT_DIR")]
+        unit_dir: Option<String>,
+        // Arguments for the scheduled run, e.g. `-- -i ./src -o ./dest -c`
+        #[arg(last = true, required = true)]
+        args: Vec<String>,
+    },
+    #[command(about = "Upload the archives queued with --spool, keeping any that fail for the next flush")]
+    Flush {
+        #[arg(long = "spool", env = "ATHENA_SPOOL")]
+        spool: PathBuf,
+        // Proxy to upload through instead of the one each archive was queued with. Queued archives don't keep the
+        // proxy's user and password, so one that needs them is given again here
+        #[arg(long = "proxy", env = "ATHENA_PROXY")]
+        proxy: Option<String>,
+        #[arg(short = 'v', long = "verbose", env = "ATHENA_VERBOSE")]
+        verbose: bool,
+    },
+    #[command(about = "Upload an existing archive")]
+    Upload {
+        file: String,
+        #[command(flatten)]
+        remote: backend::RemoteOptions,
+        #[command(flatten)]
+        host: host::HostOptions,
+        // Stored as metadata on the uploaded object
+        #[arg(long = "comment", env = "ATHENA_COMMENT")]
+        comment: Option<String>,
+        // Labels listed in the manifest uploaded with the archive
+        #[arg(long = "tag", value_delimiter = ',', env = "ATHENA_TAG")]
+        tag: Vec<String>,
+        #[arg(short = 'v', long = "verbose", env = "ATHENA_VERBOSE")]
+        verbose: bool,
+    },
+    #[command(about = "Predict archive size and duration per format from a sample of the sources, without writing anything")]
+    Estimate {
+        #[arg(short = 'i', long = "src", env = "ATHENA_SRC")]
+        src: PathBuf,
+        // Also check whether the predicted archives fit in this destination
+        #[arg(short = 'o', long = "dest", env = "ATHENA_DEST")]
+        dest: Option<PathBuf>,
+        // How much data to read and compress for the prediction. Larger samples are slower but more accurate
+        #[arg(long = "sample-size", value_parser = utils::parse_size, default_value = "32MB", env = "ATHENA_SAMPLE_SIZE")]
+        sample_size: u64,
+    },
+    #[command(about = "Inspect uploaded archives")]
+    Remote {
+        #[command(subcommand)]
+        command: remote::RemoteCommand,
+    },
+    #[command(about = "Manage a deduplicating backup repository")]
+    Repo {
+        // Password for encrypted repositories, instead of prompting for it
+        #[arg(long = "password-file", global = true, env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+        #[command(subcommand)]
+        command: repo::RepoCommand,
+    },
+    #[command(about = "Check every entry in an archive matches the files it was made from")]
+    Compare {
+        #[arg(long = "archive", env = "ATHENA_ARCHIVE")]
+        archive: PathBuf,
+        #[arg(long = "path", env = "ATHENA_PATH")]
+        path: PathBuf,
+        // Password for encrypted zip archives, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+    #[command(about = "Report files added, changed or removed since the last backup, e.g. from cron to catch unexpected changes")]
+    Drift {
+        // The source, given as it is for backups, since incremental state records paths that way
+        #[arg(short = 'i', long = "src", env = "ATHENA_SRC")]
+        src: PathBuf,
+        // Where the backups are, for finding the latest
+        #[arg(short = 'o', long = "dest", env = "ATHENA_DEST")]
+        dest: Option<PathBuf>,
+        // `latest`, or an archive or incremental state file to compare against
+        #[arg(long = "against", default_value = "latest", env = "ATHENA_AGAINST")]
+        against: String,
+        // Password for encrypted zip archives, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+        // The backup's excludes and type filters, so what it leaves out isn't reported as new or deleted
+        #[command(flatten)]
+        filters: exclude::FilterOptions,
+    },
+    #[command(about = "Read an archive end to end, checking its entries against the hashes recorded when it was made")]
+    Verify {
+        archive: PathBuf,
+        // Password for encrypted zip archives, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+    #[command(about = "Verify the archives that have gone longest without being checked, locally and uploaded")]
+    Scrub {
+        // Directory of local archives to check, e.g. a backup's --dest
+        #[arg(short = 'o', long = "dest", env = "ATHENA_DEST")]
+        dest: Option<PathBuf>,
+        // Uploaded archives under --prefix are checked too when a bucket is given, downloading each in full
+        #[command(flatten)]
+        remote: backend::RemoteOptions,
+        // Archives to check this run
+        #[arg(long = "scrub-count", default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..), env = "ATHENA_SCRUB_COUNT")]
+        scrub_count: u64,
+        // Where each archive was last checked. Defaults to scrub.json next to the status file
+        #[arg(long = "scrub-state", env = "ATHENA_SCRUB_STATE")]
+        scrub_state: Option<PathBuf>,
+        // Password for encrypted zip archives, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+    #[command(about = "List the incremental chains in a state file, or remove the oldest ones whole")]
+    Chains {
+        // The incremental state, e.g. `dest/.athena-src.state.json`
+        state_file: PathBuf,
+        // Where the chains' archives are, if not alongside the state file
+        #[arg(long = "archives", env = "ATHENA_ARCHIVES")]
+        archives: Option<PathBuf>,
+        // Remove every chain but the newest N, archives and all. The newest is always kept, as the next
+        // incremental builds on it
+        #[arg(long = "keep", value_parser = clap::value_parser!(u64).range(1..), env = "ATHENA_KEEP")]
+        keep: Option<u64>,
+    },
+    #[command(about = "Prune the run history kept in incremental state files")]
+    History {
+        #[command(subcommand)]
+        command: history::HistoryCommand,
+    },
+    #[command(about = "Maintain state files and repository indexes built up over many runs")]
+    Catalog {
+        #[command(subcommand)]
+        command: history::CatalogCommand,
+    },
+    #[command(about = "Extract files from an archive")]
+    Restore {
+        archive: PathBuf,
+        // Only restore these paths (and anything under them)
+        paths: Vec<String>,
+        #[arg(short = 't', long = "target", required_unless_present = "to_stdout", env = "ATHENA_TARGET")]
+        target: Option<PathBuf>,
+        // Write the entries to stdout as a plain tar instead, decompressed and decrypted, e.g. to pipe into
+        // `ssh host tar -x` or `docker cp - container:/`
+        #[arg(long = "to-stdout", conflicts_with_all = ["target", "file_attrs", "chown", "map_uid", "no_same_owner"], env = "ATHENA_TO_STDOUT")]
+        to_stdout: bool,
+        // Point absolute symlinks under OLD at NEW instead, e.g. when restoring to a different root
+        #[arg(long = "rewrite-links", value_name = "OLD=NEW", value_parser = restore::parse_link_rewrite, env = "ATHENA_REWRITE_LINKS")]
+        rewrite_links: Vec<restore::LinkRewrite>,
+        // Password for encrypted zip archives, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+        // Restore entry paths as-is, even absolute ones or ones with `..`. Only for archives you trust
+        #[arg(long = "unsafe-paths", env = "ATHENA_UNSAFE_PATHS")]
+        unsafe_paths: bool,
+        // `unix` treats backslashes in entry paths as separators and drops drive prefixes like `C:\`, so archives
+        // from Windows restore into directories. `native` uses entry paths exactly as stored
+        #[arg(long = "path-style", value_enum, default_value = "unix", env = "ATHENA_PATH_STYLE")]
+        path_style: pathstyle::PathStyle,
+        // Restore file capabilities and immutable/append-only flags recorded on Linux. Needs root, and leaves
+        // immutable files that have to be `chattr -i`'d before they can be changed or removed
+        #[arg(long = "file-attrs", env = "ATHENA_FILE_ATTRS")]
+        file_attrs: bool,
+        #[command(flatten)]
+        owners: restore::OwnerOptions,
+        // For an archive from an incremental run, first restore the full backup and incrementals leading up to it
+        #[arg(long = "chain", env = "ATHENA_CHAIN")]
+        chain: bool,
+        // Incremental state recording the chain, if not kept alongside the archive
+        #[arg(long = "state-file", requires = "chain", env = "ATHENA_STATE_FILE")]
+        state_file: Option<PathBuf>,
+    },
+    #[command(about = "List the entries in an archive, reading only its footer index if it has one")]
+    List {
+        archive: PathBuf,
+        // Password for encrypted tars, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+    #[command(about = "Combine tar or tgz archives into one, e.g. to consolidate per-directory backups")]
+    Merge {
+        #[arg(required = true, num_args = 2..)]
+        archives: Vec<PathBuf>,
+        // The merged archive, compressed if it ends in .tgz or .gz
+        #[arg(short = 'o', long = "output", env = "ATHENA_OUTPUT")]
+        output: PathBuf,
+        // Which copy to keep of a path that's in more than one archive. By default all are kept, and extracting
+        // the result leaves the last
+        #[arg(long = "on-duplicate", value_enum, default_value = "keep", env = "ATHENA_ON_DUPLICATE")]
+        on_duplicate: merge::DuplicatePolicy,
+        // Password for encrypted tars, which are merged into a plain one, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+    #[command(about = "Search the contents of an archive's entries without extracting them")]
+    Grep {
+        archive: PathBuf,
+        // Regular expression matched against each line, e.g. 'max_connections\s*='
+        pattern: String,
+        // Only search entries whose name matches, e.g. '*.conf', or whose path does if it has a /
+        #[arg(long = "name-glob", env = "ATHENA_NAME_GLOB")]
+        name_glob: Option<String>,
+        #[arg(short = 'i', long = "ignore-case", env = "ATHENA_IGNORE_CASE")]
+        ignore_case: bool,
+        // Password for encrypted zip archives, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+    #[command(about = "Find which archives contain files matching a glob, with their sizes and mtimes")]
+    Find {
+        // Matched against the whole path if it has a /, e.g. 'etc/nginx/*.conf', otherwise against the name
+        glob: String,
+        // Archives, or directories of them, to look through
+        #[arg(default_value = ".")]
+        archives: Vec<PathBuf>,
+        // Password for encrypted tars without a footer index, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+    #[command(about = "Write one entry of an archive to stdout")]
+    Cat {
+        archive: PathBuf,
+        // Path of the entry, as shown by `athena list`
+        path: String,
+        // Password for encrypted tars, instead of prompting for it
+        #[arg(long = "password-file", env = "ATHENA_PASSWORD_FILE")]
+        password_file: Option<String>,
+    },
+    #[command(about = "Show the progress of a scheduled backup that's running and the results of the last few")]
+    Status {
+        // Defaults to where scheduled runs keep it
+        #[arg(long = "status-file", env = "ATHENA_STATUS_FILE")]
+        status_file: Option<PathBuf>,
+        // Print the status file's JSON instead
+        #[arg(long = "json", env = "ATHENA_JSON")]
+        json: bool,
+    },
+    #[command(about = "Run the profiles in a config file on their schedules or on request, keeping their status for `athena status`")]
+    Daemon {
+        // JSON file of profiles, e.g. {"max_concurrent": 2, "profiles": {"home": {"src": "/home", "dest": "/backups",
+        // "compress": true, "schedule": "daily 03:00"}}}
+        #[arg(long = "config", env = "ATHENA_CONFIG")]
+        config: PathBuf,
+        // Serve a JSON API of the runs' status and history here, with POST /trigger?profile=NAME to start one
+        #[arg(long = "listen", value_parser = api::parse_listen, env = "ATHENA_LISTEN")]
+        listen: Option<std::net::SocketAddr>,
+        // Require `Authorization: Bearer <token>` on API requests. Needed to listen anywhere but a loopback address
+        #[arg(long = "api-token", requires = "listen", env = "ATHENA_API_TOKEN")]
+        api_token: Option<String>,
+        // Where the profiles' runs keep their status. Defaults to where `athena status` looks
+        #[arg(long = "status-file", env = "ATHENA_STATUS_FILE")]
+        status_file: Option<PathBuf>,
+    },
+    #[command(about = "Turn a restic, borg or duplicity script or crontab into daemon profiles, printed as a config")]
+    Import {
+        #[arg(long = "from", value_enum)]
+        from: import::Tool,
+        // The script or crontab that runs the tool, which its settings are read from
+        file: PathBuf,
+        // Profile name, suffixed with each source's name if there's more than one. Defaults to the tool's
+        #[arg(long = "name", value_parser = parse_profile_name)]
+        name: Option<String>,
+    },
+    #[command(about = "Print the JSON Schema of a file athena writes, for tooling that indexes backups")]
+    Schema {
+        #[arg(value_enum, default_value = "run-manifest")]
+        document: schema::Document,
+    },
+    #[command(about = "Work with daemon config files")]
+    Config {
+        #[command(subcommand)]
+        command: daemon::ConfigCommand,
+    },
+    #[command(about = "Archive and restore a generated fixture tree to check round-trip correctness")]
+    SelfTest {
+        #[arg(short = 'v', long = "verbose", env = "ATHENA_VERBOSE")]
+        verbose: bool,
+    },
 }
 
 // Handle early SIGINT / SIGTERM
 async fn handle_term() {
-    // TODO: Properly handle termination by sending a signal to any running fns
     eprintln!("Terminating...");
-    process::exit(0);
+    report::update(|report| report.error = Some("Terminated before finishing".to_string()));
+    status::update(|outcome| outcome.error = Some("Terminated before finishing".to_string()));
+    exit(0);
+}
+
+// The first Ctrl-C cancels the returned token, so running work stops at its next check and reports how far it
+// got. A second one exits straight away
+fn cancel_on_interrupt() -> cancel::CancellationToken {
+    let cancel = cancel::CancellationToken::default();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            ctrl_c().await.unwrap();
+            eprintln!("Cancelling, press Ctrl-C again to exit immediately...");
+            cancel.cancel();
+            ctrl_c().await.unwrap();
+            handle_term().await;
+        }
+    });
+    cancel
+}
+
+// Reports a failed run and exits, with the conventional 130 if it was cancelled by Ctrl-C
+fn fail(e: &(dyn error::Error + 'static)) -> ! {
+    if e.is::<cancel::Cancelled>() {
+        eprintln!("{}", e);
+        exit(130);
+    }
+    error(e)
+}
+
+// Reports an error that ends the run, including in the run's report, and exits
+fn error(message: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", message);
+    let message = message.to_string();
+    status::update(|outcome| outcome.error = Some(message.clone()));
+    report::update(|report| report.error = Some(message));
+    exit(1)
+}
+
+// Exits, first deleting any snapshot taken of the sources and sending the run's report
+fn exit(code: i32) -> ! {
+    snapshot::release();
+    scratch::cleanup();
+    status::finish(code);
+    report::send(code);
+    webhook::emit("run.complete", serde_json::json!({ "code": code, "success": code == 0 }));
+    process::exit(code)
 }
 
 #[tokio::main]
 async fn main() {
     let args: Args = Args::parse();
+    utils::set_plain_progress(args.no_tty || !std::io::stderr().is_terminal());
+    // Not being able to lower priority shouldn't stop the backup
+    if args.background {
+        if let Err(e) = priority::lower() {
+            eprintln!("Warning: {}", e);
+        }
+    }
+    let prompter = match prompt::from_args(args.yes, args.no, args.answers.as_deref(), args.answers_file.as_deref()) {
+        Ok(prompter) => prompter,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        },
+    };
 
-    let input_path = match validate::input(PathBuf::from(&args.src)) {
-        Ok(path) => path,
+    webhook::configure(args.webhook.clone());
+    window::configure(args.window);
+    signals::handle(args.on_sigusr1, args.on_sigusr2);
+    if let Err(e) = scratch::configure(args.tmpdir.clone()) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+    scratch::remove_stale();
+
+    if let Some(command) = args.command {
+        match command {
+            Command::Doctor { dest } => {
+                let passed = doctor::run(dest.map(PathBuf::from));
+                process::exit(if passed { 0 } else { 1 });
+            },
+            Command::Presets => {
+                for preset in <exclude::Preset as clap::ValueEnum>::value_variants() {
+                    println!("{}: {}", preset.name(), preset.description());
+                    println!("  {}", preset.patterns().join(" "));
+                }
+                process::exit(0);
+            },
+            Command::InstallService { name, on_calendar, system, unit_dir, args } => {
+                // Make sure the scheduled run would at least parse before installing it
+                if let Err(e) = Args::try_parse_from(std::iter::once("athena".to_string()).chain(args.iter().cloned())) {
+                    eprintln!("Error: invalid arguments for scheduled run\n{}", e);
+                    process::exit(1);
+                }
+                match service::install(&name, &on_calendar, system, unit_dir.map(PathBuf::from), &args) {
+                    Ok(paths) => {
+                        for path in paths {
+                            println!("Wrote {}", path.display());
+                        }
+                        println!(
+                            "Enable with: systemctl {}enable --now athena-{}.timer",
+                            if system { "" } else { "--user " },
+                            name
+                        );
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Flush { spool, proxy, verbose } => {
+                match spool::flush(&spool, proxy.as_deref(), verbose, &cancel_on_interrupt()) {
+                    Ok(flushed) if flushed.uploaded == 0 && flushed.failed == 0 => println!("Nothing to upload in {}", spool.display()),
+                    Ok(flushed) if flushed.failed == 0 => println!("Uploaded {} queued archives", flushed.uploaded),
+                    Ok(flushed) => {
+                        eprintln!("Error: {} of {} queued archives failed to upload and remain in {}", flushed.failed, flushed.uploaded + flushed.failed, spool.display());
+                        process::exit(1);
+                    },
+                    Err(e) => fail(e.as_ref()),
+                }
+                process::exit(0);
+            },
+            Command::Upload { file, remote, host, comment, tag, verbose } => {
+                let path = PathBuf::from(file);
+                if !path.is_file() {
+                    eprintln!("Error: {} is not a file", path.display());
+                    process::exit(1);
+                }
+                let host = host.resolve();
+                let run = manifest::RunManifest::new(host.as_ref(), comment.as_deref(), &tag);
+                let key = remote.archive_key(host.as_ref(), &path.file_name().unwrap().to_string_lossy());
+                match backend::connect(&remote).and_then(|backend| confirm_remote_overwrite(backend.as_ref(), &key, &prompter)) {
+                    Ok(true) => {},
+                    Ok(false) => process::exit(0),
+                    Err(e) => fail(e.as_ref()),
+                }
+                match upload_archive(&path, &remote, key, run, verbose, &cancel_on_interrupt()) {
+                    Ok(url) => {
+                        println!("Uploaded {} to {}", path.display(), url);
+                        webhook::emit("upload.complete", serde_json::json!({ "url": url }));
+                        process::exit(0);
+                    },
+                    Err(e) => fail(e.as_ref()),
+                }
+            },
+            Command::Repo { password_file, command } => {
+                if let Err(e) = repo::run(command, password_file.as_deref().map(Path::new)) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            },
+            Command::Estimate { src, dest, sample_size } => {
+                let result = match validate::input(src) {
+                    Ok(src) => process_sources(vec![src], cancel::CancellationToken::default(), None, None, None, None).map_err(|e| e.to_string().into()).and_then(|files| estimate::run(&files, sample_size)),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(estimate) => {
+                        estimate::print(&estimate, dest.as_deref());
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Remote { command } => {
+                if let Err(e) = remote::run(command) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            },
+            Command::Compare { archive, path, password_file } => {
+                match compare::run(&archive, &path, password_file.as_deref().map(Path::new)) {
+                    Ok(report) => {
+                        println!(
+                            "Compared {} entries: {} mismatched, {} missing, {} extra",
+                            report.compared, report.mismatched, report.missing, report.extra
+                        );
+                        process::exit(if report.is_clean() { 0 } else { 1 });
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Drift { src, dest, against, password_file, filters } => {
+                let baseline = match drift::resolve(&against, &src, dest.as_deref()) {
+                    Ok(baseline) => baseline,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(2);
+                    },
+                };
+                // Exits 0 without drift, 1 with it and 2 on errors, so cron jobs can tell them apart
+                match filters.excludes().map_err(Box::from).and_then(|excludes| drift::run(&src, &baseline, excludes, password_file.as_deref().map(Path::new))) {
+                    Ok(report) => {
+                        for (label, paths) in [("new:     ", &report.new), ("modified:", &report.modified), ("deleted: ", &report.deleted)] {
+                            for path in paths {
+                                println!("{} {}", label, path);
+                            }
+                        }
+                        if report.is_clean() {
+                            println!("No changes since {}", baseline);
+                        } else {
+                            println!("Since {}: {} new, {} modified, {} deleted", baseline, report.new.len(), report.modified.len(), report.deleted.len());
+                        }
+                        process::exit(if report.is_clean() { 0 } else { 1 });
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(2);
+                    },
+                }
+            },
+            Command::Verify { archive, password_file } => {
+                match verify::run(&archive, password_file.as_deref().map(Path::new)) {
+                    Ok(report) => {
+                        println!("Verified {} entries: {} checked, {} mismatched, {} missing", report.entries, report.checked, report.mismatched, report.missing);
+                        if report.checked == 0 && report.entries > 0 {
+                            println!("Note: the archive has no footer index with entry hashes, so only its structure was checked");
+                        }
+                        if report.placeholders > 0 {
+                            println!("Note: {} entries are placeholders for files that couldn't be read when it was made", report.placeholders);
+                        }
+                        if let Some((algorithm, matched)) = report.checksum {
+                            println!("{} checksum {}", algorithm, if matched { "matches" } else { "does NOT match" });
+                        }
+                        println!("{}", if report.is_clean() { "PASS" } else { "FAIL" });
+                        process::exit(if report.is_clean() { 0 } else { 1 });
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        println!("FAIL");
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Scrub { dest, remote, scrub_count, scrub_state, password_file } => {
+                if dest.is_none() && remote.bucket.is_none() {
+                    eprintln!("Error: nothing to scrub, pass --dest, --bucket or both");
+                    process::exit(1);
+                }
+                let remote = remote.bucket.is_some().then_some(remote);
+                let state_file = scrub_state.unwrap_or_else(scrub::default_state_path);
+                match scrub::run(dest.as_deref(), remote.as_ref(), scrub_count as usize, &state_file, password_file.as_deref().map(Path::new)) {
+                    Ok(report) => {
+                        println!("Scrubbed {} of {} archives, {} failed", report.checked.len(), report.archives, report.failed.len());
+                        process::exit(if report.failed.is_empty() { 0 } else { 1 });
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Chains { state_file, archives, keep } => {
+                if let Err(e) = chains(&state_file, archives.as_deref(), keep.map(|keep| keep as usize)) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            },
+            Command::History { command } => {
+                if let Err(e) = history::run_history(command) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            },
+            Command::Catalog { command } => {
+                if let Err(e) = history::run_catalog(command) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            },
+            Command::Restore { archive, paths, target, to_stdout, rewrite_links, password_file, unsafe_paths, path_style, file_attrs, owners, chain, state_file } => {
+                if let Err(e) = owners.check() {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                let target = target.unwrap_or_default();
+                let options = restore::RestoreOptions {
+                    target: &target,
+                    paths: &paths,
+                    rewrites: &rewrite_links,
+                    password_file: password_file.as_deref().map(Path::new),
+                    unsafe_paths,
+                    apply_deletions: chain,
+                    path_style,
+                    file_attrs,
+                    owners: &owners,
+                };
+                let archives = if chain { restore::chain(&archive, state_file.as_deref()) } else { Ok(vec![archive]) };
+                // Nothing's written locally, so there's nothing to preview or ask about
+                if to_stdout {
+                    let written = archives.and_then(|archives| {
+                        let mut out = tar::Builder::new(std::io::BufWriter::new(std::io::stdout().lock()));
+                        let mut written = 0;
+                        for archive in &archives {
+                            written += restore::stream(archive, &options, &mut out)?;
+                        }
+                        // Ends the archive, then writes out what's still buffered
+                        std::io::Write::flush(&mut out.into_inner()?)?;
+                        Ok(written)
+                    });
+                    match written {
+                        Ok(0) if !paths.is_empty() => {
+                            eprintln!("Error: No entries in the archive match the given paths");
+                            process::exit(1);
+                        },
+                        Ok(written) => {
+                            eprintln!("Wrote {} files to stdout", written);
+                            process::exit(0);
+                        },
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            process::exit(1);
+                        },
+                    }
+                }
+                let archives = archives.and_then(|archives| restore::preview(&archives, &options).map(|preview| (archives, preview)));
+                if let Ok((_, preview)) = &archives {
+                    let message = format!("Restoring to {}: {}", target.display(), preview);
+                    // Nothing to ask about when nothing matched, which is reported as an error below. Otherwise it
+                    // only goes ahead by default when there's room for it
+                    if preview.entries > 0 && !prompter.confirm(&message, "Continue?", preview.fits()) {
+                        eprintln!("Error: Restore cancelled");
+                        process::exit(1);
+                    }
+                }
+                let restored = archives.and_then(|(archives, _)| {
+                    let mut restored = 0;
+                    for archive in &archives {
+                        if archives.len() > 1 {
+                            println!("Restoring {}", archive.display());
+                        }
+                        restored += restore::run(archive, &options)?;
+                    }
+                    Ok(restored)
+                });
+                match restored {
+                    Ok(0) if !paths.is_empty() => {
+                        eprintln!("Error: No entries in the archive match the given paths");
+                        process::exit(1);
+                    },
+                    Ok(restored) => {
+                        println!("Restored {} files to {}", restored, target.display());
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::List { archive, password_file } => {
+                match list::list(&archive, password_file.as_deref().map(Path::new)) {
+                    Ok(entries) => {
+                        for entry in entries {
+                            println!("{:>10}  {}", utils::format_size(entry.size), entry.path);
+                        }
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Merge { archives, output, on_duplicate, password_file } => {
+                match merge::run(&archives, &output, on_duplicate, password_file.as_deref().map(Path::new)) {
+                    Ok(merged) => {
+                        println!("Merged {} entries from {} archives into {}", merged.entries, archives.len(), output.display());
+                        if merged.duplicates > 0 {
+                            println!("Left out {} duplicate {}", merged.duplicates, if merged.duplicates == 1 { "copy" } else { "copies" });
+                        }
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Grep { archive, pattern, name_glob, ignore_case, password_file } => {
+                let options = grep::GrepOptions { pattern: &pattern, ignore_case, name_glob: name_glob.as_deref(), password_file: password_file.as_deref().map(Path::new) };
+                let searched = grep::run(&archive, &options, &mut std::io::stdout().lock());
+                // Exits like grep does: 0 with matches, 1 without and 2 on errors
+                match searched {
+                    Ok(matches) => process::exit(if matches > 0 { 0 } else { 1 }),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(2);
+                    },
+                }
+            },
+            Command::Find { glob, archives, password_file } => {
+                match find::run(&glob, &archives, password_file.as_deref().map(Path::new)) {
+                    Ok(found) if found.is_empty() => {
+                        eprintln!("No archives contain {}", glob);
+                        process::exit(1);
+                    },
+                    Ok(found) => {
+                        for found in found {
+                            let mtime = found.entry.mtime.and_then(|mtime| chrono::TimeZone::timestamp_opt(&chrono::Local, mtime, 0).single());
+                            let mtime = mtime.map(|mtime| mtime.format("%Y-%m-%d %H:%M").to_string());
+                            println!(
+                                "{}  {:>10}  {:<16}  {}",
+                                found.archive.display(),
+                                utils::format_size(found.entry.size),
+                                mtime.unwrap_or_else(|| "-".to_string()),
+                                found.entry.path
+                            );
+                        }
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Cat { archive, path, password_file } => {
+                if let Err(e) = list::cat(&archive, &path, password_file.as_deref().map(Path::new), &mut std::io::stdout().lock()) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            },
+            Command::SelfTest { verbose } => {
+                match selftest::run(verbose) {
+                    Ok(passed) => process::exit(if passed { 0 } else { 1 }),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Daemon { config, listen, api_token, status_file } => {
+                let config = match load_config(&config) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                };
+                let status_file = status_file.unwrap_or_else(status::default_path);
+                let runner = Arc::new(daemon::Runner::new(config, status_file.clone()));
+                if listen.is_none() && !runner.scheduled() {
+                    eprintln!("Error: nothing to do, give profiles a schedule or pass --listen to serve the API");
+                    process::exit(1);
+                }
+                // Anyone who can reach the API could otherwise start backups
+                if listen.is_some_and(|listen| !listen.ip().is_loopback()) && api_token.is_none() {
+                    eprintln!("Error: --listen on an address other hosts can reach needs --api-token");
+                    process::exit(1);
+                }
+                let server = listen.map(|listen| api::serve(api::Api { runner: runner.clone(), status_file, token: api_token }, listen));
+                let server = match server.transpose() {
+                    Ok(server) => server,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                };
+                // Runs are started as they come due and collected as they finish, not only when the API's asked
+                while server.as_ref().is_none_or(|server| !server.is_finished()) {
+                    runner.tick();
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+                process::exit(1);
+            },
+            Command::Import { from, file, name } => {
+                let name = name.unwrap_or_else(|| from.name().to_string());
+                match import::import(from, &file, &name).and_then(|(config, notes)| Ok((serde_json::to_string_pretty(&config)?, notes))) {
+                    Ok((config, notes)) => {
+                        println!("{}", config);
+                        for note in notes {
+                            eprintln!("Note: {}", note);
+                        }
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Schema { document } => {
+                match serde_json::to_string_pretty(&schema::schema(document)) {
+                    Ok(schema) => {
+                        println!("{}", schema);
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Config { command: daemon::ConfigCommand::Validate { config } } => {
+                match load_config(&config) {
+                    Ok(loaded) => {
+                        println!("{} is valid ({} profiles)", config.display(), loaded.profiles.len());
+                        process::exit(0);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            Command::Status { status_file, json } => {
+                let path = status_file.unwrap_or_else(status::default_path);
+                let status = match status::load(&path) {
+                    Ok(status) => status,
+                    Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => {
+                        eprintln!("Error: no status in {}, runs only keep one with --status-file or once installed with install-service", path.display());
+                        process::exit(1);
+                    },
+                    Err(e) => {
+                        eprintln!("Error: failed to read {}: {}", path.display(), e);
+                        process::exit(1);
+                    },
+                };
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&status).unwrap());
+                    process::exit(0);
+                }
+                if status.running.is_empty() {
+                    println!("No backup running");
+                }
+                for running in &status.running {
+                    print!("{}", running.text());
+                }
+                if !status.runs.is_empty() {
+                    println!("\nLast {} runs:", status.runs.len());
+                    for run in &status.runs {
+                        println!("  {}", run.text());
+                    }
+                }
+                process::exit(0);
+            },
+        }
+    }
+
+    if let Err(e) = check_options(&args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+    let src = args.src.unwrap();
+    let ssh = match src.to_str().map(sftp::SshSource::parse).transpose() {
+        Ok(ssh) => ssh.flatten(),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        },
+    };
+    // Remote sources are only walked and read, everything else athena does with sources needs them local
+    if ssh.is_some() && (args.incremental || args.snapshot.is_some() || args.windows_metadata) {
+        eprintln!("Error: --incremental, --snapshot and --windows-metadata need a local source");
+        process::exit(1);
+    }
+    // Patterns need to be valid UTF-8, but plain paths can be anything the filesystem allows
+    let (resolved, source_fs) = match (&ssh, src.to_str()) {
+        (Some(ssh), _) => match connect_ssh(ssh) {
+            Ok(fs) => (Ok((ssh.path.clone(), vec![ssh.path.clone()])), fs),
+            Err(e) => (Err(e), sourcefs::SharedFs::default()),
+        },
+        (None, Some(pattern)) if validate::is_glob(pattern) && !src.exists() => (validate::glob_input(pattern), sourcefs::SharedFs::default()),
+        (None, _) => (validate::input(src).map(|path| (path.clone(), vec![path])), sourcefs::SharedFs::default()),
+    };
+    let (input_path, sources) = match resolved {
+        Ok(resolved) => resolved,
         Err(e) => {
             eprintln!("Error: {}", e);
             process::exit(1);
         },
     };
-    let output_path = match validate::output(PathBuf::from(&args.dest)) {
+    let output_path = match validate::output(args.dest.unwrap(), &*prompter) {
         Ok(path) => path,
         Err(e) => {
             eprintln!("Error: {}", e);
             process::exit(1);
         }
     };
+    if let Err(e) = privileges::check_destination(&output_path) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+    // A device is written to in place: there's no file to name, hash, upload or read back afterwards
+    if device::is_device(&output_path) {
+        let unsupported = [
+            (args.format == format::ArchiveFormat::Zip, "--format zip"),
+            (args.format == format::ArchiveFormat::Squashfs, "--format squashfs"),
+            (args.seekable, "--seekable"),
+            (args.upload, "--upload"),
+            (args.hash.is_some(), "--hash"),
+            (args.incremental && args.state_file.is_none(), "--incremental without --state-file"),
+        ];
+        if let Some((_, option)) = unsupported.iter().find(|(given, _)| *given) {
+            eprintln!("Error: {} can't be used when writing to a device like {}", option, output_path.display());
+            process::exit(1);
+        }
+        if args.block_size.is_some_and(|size| size == 0 || size % 512 != 0 || size > 1 << 24) {
+            eprintln!("Error: --block-size must be a multiple of 512 bytes, up to 16MiB");
+            process::exit(1);
+        }
+    } else if args.block_size.is_some() {
+        eprintln!("Error: --block-size is only for writing to a device, like a tape drive");
+        process::exit(1);
+    }
+    if args.run_as.is_some() && !privileges::is_root() {
+        eprintln!("Error: --run-as needs athena to be started as root");
+        process::exit(1);
+    }
+
+    if let Some(max_memory) = args.max_memory {
+        if args.seekable && args.verbose && format::frame_size(Some(max_memory)) < index::FRAME_SIZE {
+            println!("Using {} seekable frames to stay within --max-memory", utils::format_size(format::frame_size(Some(max_memory))));
+        }
+    }
+    let password = if args.encrypt || args.password_file.is_some() {
+        match format::read_password(args.password_file.as_ref().map(Path::new)) {
+            Ok(password) => Some(password),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            },
+        }
+    } else {
+        None
+    };
+
+    let (input_path, sources) = match args.snapshot {
+        Some(kind) => match snapshot::Snapshot::create(kind, &input_path) {
+            Ok(snapshot) => {
+                if args.verbose {
+                    println!("Reading sources from {}", snapshot.map(&input_path).display());
+                }
+                let sources = sources.iter().map(|source| snapshot.map(source)).collect();
+                (snapshot.map(&input_path), sources)
+            },
+            Err(e) => {
+                eprintln!("Error: failed to create snapshot: {}", e);
+                process::exit(1);
+            },
+        },
+        None => (input_path, sources),
+    };
+
+    let buffer_size = match args.buffer_size {
+        Some(size) if !(readahead::MIN_BUFFER_SIZE as u64..=1 << 30).contains(&size) => {
+            eprintln!("Error: --buffer-size must be between 4KB and 1GiB");
+            exit(1);
+        },
+        Some(size) => size as usize,
+        None => {
+            let (size, filesystem) = readahead::default_buffer_size(&input_path);
+            // Smaller chunks to stay within --max-memory, which check_options made sure has room for some
+            let size = match args.max_memory {
+                Some(max_memory) => size.min(format::max_buffer_size(args.format, args.compress, args.seekable, max_memory)),
+                None => size,
+            };
+            if args.verbose {
+                println!("Reading in {} chunks ({} filesystem)", utils::format_size(size as u64), filesystem);
+            }
+            size
+        },
+    };
+
+    let state_path = match (args.incremental, args.state_file) {
+        (true, Some(state_file)) => Some(PathBuf::from(state_file)),
+        (true, None) => Some(incremental::default_state_path(&input_path, &output_path)),
+        _ => None,
+    };
 
-    let options = utils::Options {
+    let mut options = utils::Options {
         verbose: args.verbose,
         upload: args.upload,
         compression: args.compress,
+        compress_overrides: args.compress_override,
+        seekable: args.seekable,
+        footer_index: args.footer_index,
+        max_memory: args.max_memory,
+        mmap_threshold: args.mmap_threshold,
+        buffer_size: Some(buffer_size),
+        limit_read: args.limit_read.map(ratelimit::RateLimiter::new),
+        file_progress_threshold: args.file_progress_threshold,
+        hash: args.hash,
+        on_invalid: args.on_invalid,
+        block_size: args.block_size.map(|size| size as usize),
+        state_path,
+        rescan: args.rescan,
+        full_every: args.full_every,
+        chain: None,
+        trust_mtime: args.trust_mtime,
+        full_hash_every: args.full_hash_every.unwrap_or(incremental::DEFAULT_FULL_HASH_EVERY),
+        format: args.format,
+        password,
+        no_local_copy: args.no_local_copy,
+        spool: args.spool,
+        placeholder_on_error: args.placeholder_on_error,
+        busy_retries: args.busy_retries,
+        on_busy: args.on_busy,
+        fsync: args.fsync,
+        min_free_space: args.min_free_space,
+        deadline: args.max_duration.map(|limit| deadline::Deadline::new(limit, Instant::now())),
+        keep_going: args.keep_going,
+        errors: errors::ErrorLog::default(),
+        windows_metadata: args.windows_metadata,
+        path_style: args.path_style,
+        remote: backend::RemoteOptions { profile: args.profile.clone(), started: Some(chrono::Local::now()), ..args.remote },
+        host: args.host.resolve(),
+        comment: args.comment,
+        tags: args.tag,
+        deleted: Vec::new(),
         input_path,
+        file_name: OsString::new(),
+        remote_key: String::new(),
+        sources,
+        fs: source_fs,
         output_path,
+        cancel: cancel_on_interrupt(),
+        prompter,
     };
 
-    tokio::spawn(async move {
-        ctrl_c().await.unwrap();
-        handle_term().await;
-    });
+    if let Some(recipients) = args.report {
+        let host = options.host.as_ref().map(|host| host.name.clone()).or_else(|| host::HostOptions::default().resolve().map(|host| host.name)).unwrap_or_default();
+        report::start(recipients, args.smtp, host, options.input_path.display().to_string());
+    }
+    status::start(args.status_file, options.input_path.display().to_string(), args.profile.clone());
+
+    let previous = match options.state_path.as_deref().map(incremental::State::load) {
+        Some(Ok(previous)) => Some(previous),
+        Some(Err(e)) => error(e),
+        None => None,
+    };
+    // Whether this is a full backup is settled before anything's named, since the chain goes in the archive's name
+    let full = previous.as_ref().and_then(|previous| incremental::full_reason(previous, options.full_every, chrono::Local::now()));
+    options.chain = previous.as_ref().map(|previous| incremental::chain_id(previous, full.is_some(), chrono::Local::now()));
+    // Named once, from one reading of the clock, so the names checked for overwrites are the ones written to
+    options.file_name = archive_file_name(&options);
+    options.remote_key = options.remote.archive_key(options.host.as_ref(), &options.file_name.to_string_lossy());
+
+    // Bad credentials or a missing bucket should fail the run now, not after the archive's been built. Spooled uploads
+    // happen later, when the network may well be back
+    if options.upload && options.spool.is_none() {
+        let key = &options.remote_key;
+        let checked = backend::connect(&options.remote).and_then(|backend| backend.check_upload(key).and_then(|_| confirm_remote_overwrite(backend.as_ref(), key, &options.prompter)));
+        match checked {
+            Ok(true) => {},
+            Ok(false) => exit(0),
+            Err(e) => error(format!("can't upload: {}", e)),
+        }
+    }
+
+    if let Some(min) = options.min_free_space {
+        if let Err(e) = space::check(&[&options.output_path, &scratch::dir()], min) {
+            error(format!("not starting, {}", e));
+        }
+    }
+
+    let excludes = match args.filters.excludes() {
+        Ok(excludes) => Arc::new(excludes),
+        Err(e) => error(e),
+    };
 
     let spinner = utils::construct_spinner();
     spinner.enable_steady_tick(Duration::from_millis(150));
-    println!("");
+    println!();
     spinner.set_message("Processing files...");
+    status::phase("Processing files", None, None);
 
-    let handle = tokio::task::spawn_blocking({
-        let path = options.input_path.clone();
-        move || {
-            process_input(path)
-    }}).await.unwrap();
-
-    match handle.await {
-        Ok(files) => {
-            spinner.finish_and_clear();
-            if options.verbose {
-                println!(
-                    "{} {} processed",
-                    files.len(),
-                    if files.len() == 1 { "file" } else { "files" }
-                );
-            }
+    // Every so often everything's hashed and every directory read, to catch what trusting mtimes missed
+    let full_hash = previous.as_ref().filter(|_| options.trust_mtime).and_then(|previous| incremental::full_hash_reason(previous, options.full_hash_every, chrono::Local::now()));
+    let filters = excludes.fingerprint();
+    let cache = previous.as_ref().filter(|_| options.trust_mtime).map(|previous| {
+        let empty = incremental::State::default();
+        let filtered = !previous.dirs.is_empty() && previous.filters.as_ref() != Some(&filters);
+        if filtered && options.verbose {
+            println!("Reading every directory: the excludes or type filters changed since the last run");
+        }
+        Arc::new(incremental::DirCache::new(if full_hash.is_some() || filtered { &empty } else { previous }))
+    });
+
+    let files = pipeline::traverse({
+        let sources = options.sources.clone();
+        let cancel = options.cancel.clone();
+        let max_files = args.max_files;
+        let excludes = Some(excludes.clone());
+        let errors = options.keep_going.then(|| options.errors.clone());
+        let cache = cache.clone();
+        let fs = options.fs.clone();
+        move |found| walk_sources(sources, fs, cancel, max_files, errors, cache, excludes, found)
+    });
 
-            let progress_bar = utils::construct_progress(files.len() as u64);
-            progress_bar.set_message(format!(
-                "{m} {f} {t}...",
-                m = if options.compression { "Compressing" } else { "Writing" },
-                f = files.len(),
-                t = if files.len() > 1 { "files" } else { "file" }
-            ));
-
-            let handle = tokio::task::spawn_blocking({
-                let options = options.to_owned();
-                let files = files.to_owned();
-                move || {
-                construct_archive(files, options, progress_bar)
-            }}).await.unwrap();
-
-            match handle.await {
-                Ok(archive_buf) => {
-                    // if !options.upload,
-                    print_done(files, archive_buf, &options.compression);
-                    // else call upload_archive
+    // Without a previous run to compare against, archiving starts on the files as the walk finds them. Otherwise
+    // every file has to be found before it's known which have changed
+    let mut next_state = None;
+    let files = match previous {
+        Some(previous) => {
+            let files = match files.collect().await {
+                Ok(files) => files,
+                Err(e) => {
+                    spinner.finish_and_clear();
+                    fail(e.as_ref());
+                },
+            };
+            spinner.set_message("Checking for changes...");
+            status::phase("Checking for changes", Some(files.len() as u64), None);
+            match scan_changes(&files, previous, cache.as_deref(), full_hash, full, &options) {
+                Ok(scan) => {
+                    let base = get_inp_path_only(&options.input_path);
+                    options.deleted = scan.deleted.iter().filter_map(|path| path.strip_prefix(&base).ok()).map(|path| path.to_string_lossy().to_string()).collect();
+                    next_state = Some(incremental::State { filters: cache.as_ref().map(|_| filters.clone()), ..scan.state });
+                    pipeline::Files::listed(scan.changed)
                 },
                 Err(e) => {
-                    eprintln!("Error: {}", e);
-                    process::exit(1);
+                    spinner.finish_and_clear();
+                    error(e);
                 },
             }
         },
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            process::exit(1);
+        None => files,
+    };
+    spinner.finish_and_clear();
+    if let Some(total) = files.total() {
+        report_found(total, &options);
+        if total == 0 && options.deleted.is_empty() && next_state.is_some() {
+            println!("No changes since last run");
+            exit(0);
+        }
+    }
+
+    let progress = Arc::new(progress::BarProgress::new(utils::construct_progress(files.total().unwrap_or(0) as u64), options.file_progress_threshold));
+    // Only worth another look at every file when someone can watch the bytes go by, and they've all been found
+    let bytes_total = files.as_listed().filter(|_| status::writing()).map(|files| files.iter().filter_map(|file| options.fs.symlink_metadata(file).ok()).filter(|stat| stat.is_file()).map(|stat| stat.len).sum());
+    let tracked = status::track(progress.clone(), bytes_total);
+    let streamed = files.total().is_none();
+
+    if options.no_local_copy {
+        // Streaming, the archive and upload stages run together, joined by the upload's bounded pipe
+        let result = pipeline::run(pipeline::Stage::Archive, {
+            let options = options.to_owned();
+            let progress = tracked.clone();
+            move || {
+                let mut files = files;
+                stream_archive(&mut files, options, progress).map(|uploaded| (uploaded, files.into_found()))
+            }
+        }).await;
+        progress.finish();
+        match result {
+            Ok((None, files)) => {
+                if streamed {
+                    report_found(files.len(), &options);
+                }
+                let stopped = report_stopped(&options);
+                eprintln!("Not uploading the partial archive");
+                exit(stopped);
+            },
+            Ok((Some((url, size)), files)) => {
+                if streamed {
+                    report_found(files.len(), &options);
+                }
+                let name = url.rsplit('/').next().unwrap_or_default();
+                let stopped = report_stopped(&options);
+                if stopped == 0 {
+                    save_state(next_state, name, &options);
+                }
+                println!("Successfully uploaded {} to {}", utils::format_size(size), url);
+                report::update(|report| report.uploaded = Some(url.clone()));
+                status::update(|outcome| outcome.uploaded = Some(url.clone()));
+                webhook::emit("upload.complete", serde_json::json!({ "url": url, "size": size }));
+                exit(report_errors(&options, &options.output_path.join(name)).max(stopped));
+            },
+            Err(e) => fail(e.as_ref()),
+        }
+    }
+
+    let result = pipeline::run(pipeline::Stage::Archive, {
+        let options = options.to_owned();
+        let progress = tracked.clone();
+        move || {
+            let mut files = files;
+            construct_archive(&mut files, options, progress).map(|archive| (archive, files.into_found()))
+        }
+    }).await;
+    progress.finish();
+    match result {
+        Ok((archive_buf, files)) => {
+            if streamed {
+                report_found(files.len(), &options);
+            }
+            report::update(|report| report.archive = Some(archive_buf.clone()));
+            status::update(|outcome| outcome.archive = Some(archive_buf.clone()));
+            webhook::emit("archive.complete", serde_json::json!({
+                "archive": archive_buf.to_string_lossy(),
+                "size": archive_size(&archive_buf),
+                "files": files.len(),
+            }));
+            if let Some(run_as) = &args.run_as {
+                if let Err(e) = switch_user(run_as, &archive_buf, &options) {
+                    error(e);
+                }
+            }
+            if let Some(algorithm) = options.hash {
+                if let Err(e) = write_checksum(&archive_buf, algorithm, options.verbose) {
+                    error(e);
+                }
+            }
+            let stopped = report_stopped(&options);
+            if stopped == 0 {
+                save_state(next_state, &archive_buf.file_name().unwrap().to_string_lossy(), &options);
+            }
+            if options.upload {
+                let mut run = manifest::RunManifest::new(options.host.as_ref(), options.comment.as_deref(), &options.tags);
+                run.files = Some(files.len());
+                if let Some(spool) = &options.spool {
+                    match upload_job(&archive_buf, &options.remote, options.remote_key.clone(), run).and_then(|job| spool::queue(spool, &job)) {
+                        Ok(_) => println!("Queued upload in {}, run `athena flush` to upload it", spool.display()),
+                        Err(e) => {
+                            error(format!("failed to queue upload: {}", e));
+                        },
+                    }
+                    let code = report_errors(&options, &archive_buf).max(stopped);
+                    print_done(progress.bytes(), archive_buf, &options.compression, code);
+                    return;
+                }
+                // The window's over, so uploading has to wait for a run that finishes in time
+                if stopped != 0 {
+                    eprintln!("Not uploading the partial archive");
+                    let code = report_errors(&options, &archive_buf).max(stopped);
+                    print_done(progress.bytes(), archive_buf, &options.compression, code);
+                    return;
+                }
+                status::phase("Uploading", None, archive_buf.metadata().ok().map(|metadata| metadata.len()));
+                let uploaded = pipeline::run(pipeline::Stage::Upload, {
+                    let archive_buf = archive_buf.clone();
+                    let remote = options.remote.clone();
+                    let key = options.remote_key.clone();
+                    let (verbose, cancel) = (options.verbose, options.cancel.clone());
+                    move || upload_archive(&archive_buf, &remote, key, run, verbose, &cancel)
+                }).await;
+                match uploaded {
+                    Ok(url) => {
+                        println!("Uploaded to {}", url);
+                        webhook::emit("upload.complete", serde_json::json!({ "url": url }));
+                        status::update(|outcome| outcome.uploaded = Some(url.clone()));
+                        report::update(|report| report.uploaded = Some(url));
+                    },
+                    // The archive itself is complete, so it's kept
+                    Err(e) if e.is::<cancel::Cancelled>() => {
+                        eprintln!("{}, archive kept at {}", e, archive_buf.display());
+                        exit(130);
+                    },
+                    Err(e) => {
+                        error(e);
+                    },
+                }
+            }
+            let code = report_errors(&options, &archive_buf).max(stopped);
+            print_done(progress.bytes(), archive_buf, &options.compression, code);
+        },
+        Err(e) => fail(e.as_ref()),
+    }
+}
+
+// Opens an SFTP session for an ssh:// source, which has to be a directory to archive
+fn connect_ssh(ssh: &sftp::SshSource) -> Result<sourcefs::SharedFs, Box<dyn error::Error>> {
+    let fs = sftp::SftpFs::connect(ssh)?;
+    match sourcefs::SourceFs::metadata(&fs, &ssh.path) {
+        Ok(stat) if stat.is_dir() => Ok(sourcefs::SharedFs::new(fs)),
+        Ok(_) => Err(format!("{} is not a directory", ssh).into()),
+        Err(e) => Err(format!("Can't read {}: {}", ssh, e).into()),
+    }
+}
+
+// Gives up root once the sources have been read. The snapshot can only be deleted as root, and is no longer
+// needed, so it goes first. Files already written are handed over so the rest of the run can update them
+fn switch_user(run_as: &privileges::RunAs, archive_buf: &Path, options: &utils::Options) -> Result<(), Box<dyn error::Error>> {
+    snapshot::release();
+    let mut index = archive_buf.as_os_str().to_owned();
+    index.push(index::INDEX_SUFFIX);
+    for path in [Some(archive_buf), Some(Path::new(&index)), options.state_path.as_deref()].into_iter().flatten() {
+        if path.exists() && !device::is_device(path) {
+            run_as.chown(path)?;
+        }
+    }
+    run_as.drop_privileges()?;
+    if options.verbose {
+        println!("Switched to {}", run_as.user);
+    }
+    Ok(())
+}
+
+// Records the incremental state once the run has succeeded, along with the archive it made
+fn save_state(state: Option<incremental::State>, archive: &str, options: &utils::Options) {
+    if let (Some(mut state), Some(state_path)) = (state, &options.state_path) {
+        if let Some(run) = state.runs.last_mut() {
+            run.archive = archive.to_string();
+        }
+        if let Err(e) = state.save(state_path) {
+            error(format!("failed to save incremental state: {}", e));
+        }
+    }
+}
+
+// Lists the chains recorded in the state with their archives, first removing all but the newest `keep`. The
+// state's saved without them before any archive's deleted, so a removal that fails partway leaves stray files
+// rather than a chain missing its full backup
+fn chains(state_path: &Path, archives: Option<&Path>, keep: Option<usize>) -> Result<(), Box<dyn error::Error>> {
+    let dir = archives.or(state_path.parent().filter(|dir| !dir.as_os_str().is_empty())).unwrap_or(Path::new("."));
+    if !state_path.exists() {
+        return Err(format!("No incremental state at {}", state_path.display()).into());
+    }
+    let mut state = incremental::State::load(state_path)?;
+    if let Some(keep) = keep {
+        let dropped = incremental::drop_chains(&mut state, keep);
+        if !dropped.is_empty() {
+            state.save(state_path)?;
+        }
+        let dropped_state = incremental::State { runs: dropped, ..Default::default() };
+        for chain in incremental::chains(&dropped_state) {
+            for run in &chain.runs {
+                let archive = dir.join(&run.archive);
+                let mut sidecars = vec![format!("{}{}", run.archive, index::INDEX_SUFFIX)];
+                sidecars.extend(<hash::HashAlgorithm as clap::ValueEnum>::value_variants().iter().map(|algorithm| format!("{}.{}", run.archive, algorithm.name())));
+                for sidecar in sidecars.iter().map(|sidecar| dir.join(sidecar)).filter(|sidecar| sidecar.exists()) {
+                    fs::remove_file(sidecar)?;
+                }
+                if archive.exists() {
+                    fs::remove_file(&archive)?;
+                }
+            }
+            println!("Removed chain {} ({} {})", chain.name, chain.runs.len(), if chain.runs.len() == 1 { "archive" } else { "archives" });
+        }
+    }
+    for chain in incremental::chains(&state) {
+        println!("{}", chain.name);
+        for run in &chain.runs {
+            let missing = if dir.join(&run.archive).exists() { "" } else { " (missing)" };
+            println!("  {}  {}{}", run.time, run.archive, missing);
+        }
+    }
+    Ok(())
+}
+
+// Uploads the archive to the configured backend under `key`, followed by the run's manifest
+fn upload_archive(
+    archive_buf: &Path,
+    remote: &backend::RemoteOptions,
+    key: String,
+    run: manifest::RunManifest,
+    verbose: bool,
+    cancel: &cancel::CancellationToken,
+) -> Result<String, Box<dyn error::Error>> {
+    let backend = backend::connect(remote)?;
+    upload_job(archive_buf, remote, key, run)?.upload(backend.as_ref(), verbose, cancel)
+}
+
+// Asks before replacing an archive already uploaded under the same name, as for one in the output directory
+fn confirm_remote_overwrite(backend: &dyn backend::Backend, key: &str, prompter: &prompt::SharedPrompter) -> Result<bool, Box<dyn error::Error>> {
+    if !backend::exists(backend, key)? {
+        return Ok(true);
+    }
+    Ok(prompter.confirm(&format!("Archive {} already exists", backend.url(key)), "Overwrite?", false))
+}
+
+fn upload_job(archive_buf: &Path, remote: &backend::RemoteOptions, key: String, run: manifest::RunManifest) -> Result<spool::Job, Box<dyn error::Error>> {
+    let info = object_info(remote, run.comment.as_deref());
+    spool::Job::new(archive_buf, remote, key, info, run)
+}
+
+// Metadata uploaded archives are stored with, so they can be identified without downloading them. The host is
+// already in the key, so isn't repeated
+fn object_info(remote: &backend::RemoteOptions, comment: Option<&str>) -> backend::ObjectInfo {
+    let mut info = Vec::new();
+    if let Some(comment) = comment {
+        info.push(("comment".to_string(), comment.to_string()));
+    }
+    if let Some(class) = remote.storage_class {
+        info.push(("storage-class".to_string(), class.name().to_string()));
+    }
+    info
+}
+
+// Compares the source files against the previous run's state, returning only the changed ones. `cache` is the
+// directory listings --trust-mtime walked with, `full_hash` why this run hashes everything despite it, and
+// `reason` why it's a full backup, if it is
+fn scan_changes(
+    files: &[PathBuf],
+    previous: incremental::State,
+    cache: Option<&incremental::DirCache>,
+    full_hash: Option<String>,
+    reason: Option<String>,
+    options: &utils::Options,
+) -> Result<incremental::Scan, Box<dyn error::Error>> {
+    let algorithm = options.hash.unwrap_or(hash::HashAlgorithm::Blake3);
+    if let Some(reason) = full_hash.as_ref().filter(|_| !previous.runs.is_empty()) {
+        println!("Hashing every file: {}", reason);
+    }
+    let rescan = options.rescan || full_hash.is_some();
+    let mut scan = incremental::scan(files, &previous, algorithm, rescan, options.trust_mtime && full_hash.is_none())?;
+    let now = chrono::Local::now();
+    if rescan {
+        scan.state.last_full_hash = Some(now.to_rfc3339());
+    }
+    if let Some(cache) = cache {
+        scan.state.dirs = cache.walked();
+        if options.verbose {
+            println!("Reused the listings of {} of {} directories", cache.reused.load(std::sync::atomic::Ordering::Relaxed), scan.state.dirs.len());
         }
     }
+    if let Some(reason) = &reason {
+        if !previous.runs.is_empty() {
+            println!("Running a full backup: {}", reason);
+        }
+        scan.changed = files.to_vec();
+        // A full backup has the whole tree, so nothing before it needs deleting
+        scan.deleted.clear();
+        scan.state.last_full = Some(now.to_rfc3339());
+    }
+    // The archive's name is filled in once it's been written
+    scan.state.runs.push(incremental::RunRecord { time: now.to_rfc3339(), archive: String::new(), full: reason.is_some(), reason, chain: options.chain.clone() });
+    if options.verbose {
+        println!(
+            "{} of {} files changed since last run ({} hashed)",
+            scan.changed.len(),
+            files.len(),
+            scan.hashed
+        );
+    }
+    Ok(scan)
 }
 
-fn print_done(input_files: Vec<PathBuf>, archive_buf: PathBuf, compression: &bool) {
-    let mut input_size = 0.;
-    for file in input_files {
-        input_size += file.metadata().unwrap().len() as f64;
+// Records how many files are being backed up, once that's known: before archiving if they were all found first,
+// otherwise once archiving has taken the last of them
+fn report_found(files: usize, options: &utils::Options) {
+    report::update(|report| {
+        report.files = Some(files);
+        report.deleted = options.deleted.len();
+    });
+    status::update(|outcome| outcome.files = Some(files as u64));
+    webhook::emit("scan.complete", serde_json::json!({
+        "source": options.input_path.to_string_lossy(),
+        "files": files,
+        "deleted": options.deleted.len(),
+    }));
+    if options.verbose {
+        println!("{} {} processed", files, if files == 1 { "file" } else { "files" });
+    }
+}
+
+// Hashes the finished archive and writes the digest alongside it
+fn write_checksum(archive_buf: &Path, algorithm: hash::HashAlgorithm, verbose: bool) -> Result<(), Box<dyn error::Error>> {
+    let digest = hash::file(archive_buf, algorithm)?;
+    let checksum_path = hash::write_checksum_file(archive_buf, &digest, algorithm)?;
+    if verbose {
+        println!("{} {} (written to {})", algorithm.name(), digest, checksum_path.display());
     }
-    let mut out_size = archive_buf.metadata().unwrap().len() as f64;
+    Ok(())
+}
+
+// Says how far a run stopped at --max-duration got, returning the exit code for a partial run, or 0 if it wasn't
+// stopped. Its incremental state isn't saved, so the next run backs up what was left out
+fn report_stopped(options: &utils::Options) -> i32 {
+    let Some(deadline) = &options.deadline else {
+        return 0;
+    };
+    let Some((archived, total)) = deadline.stopped() else {
+        return 0;
+    };
+    eprintln!("Stopped early, {}: archived {} of {} files, so the archive is partial", deadline.reason(), archived, total);
+    errors::PARTIAL_FAILURE
+}
+
+// With --keep-going, logs the files that were left out next to the archive, returning the exit code for the run
+fn report_errors(options: &utils::Options, archive: &Path) -> i32 {
+    if !options.keep_going || options.errors.is_empty() {
+        return 0;
+    }
+    report::update(|report| report.failures = options.errors.messages());
+    // There's nowhere beside a device to put the log
+    let mut log = match device::is_device(archive) {
+        true => {
+            let _ = fs::create_dir_all(utils::cache_dir());
+            utils::cache_dir().join(archive.file_name().unwrap_or(OsStr::new("device"))).into_os_string()
+        },
+        false => archive.as_os_str().to_owned(),
+    };
+    log.push(errors::ERRORS_SUFFIX);
+    if let Err(e) = options.errors.write(Path::new(&log)) {
+        error(format!("failed to write error log: {}", e));
+    }
+    eprintln!(
+        "Finished with {} unreadable {}, see {}",
+        options.errors.len(),
+        if options.errors.len() == 1 { "path" } else { "paths" },
+        Path::new(&log).display()
+    );
+    errors::PARTIAL_FAILURE
+}
+
+// How big the archive written is. A device's size is its capacity, so it's what was written to it
+fn archive_size(archive: &Path) -> u64 {
+    match device::is_device(archive) {
+        true => device::written(),
+        false => archive.metadata().map(|metadata| metadata.len()).unwrap_or_default(),
+    }
+}
+
+fn print_done(input_bytes: u64, archive_buf: PathBuf, compression: &bool, code: i32) {
+    let mut input_size = input_bytes as f64;
+    let mut out_size = archive_size(&archive_buf) as f64;
     let mut size_unit = "B";
 
     match input_size {
@@ -169,108 +1831,740 @@ fn print_done(input_files: Vec<PathBuf>, archive_buf: PathBuf, compression: &boo
             );
         },
     };
-    process::exit(0);
+    exit(code);
 }
 
 // Used in getting the relative path of files added to the archive
 // so that the archive can be extracted to the same directory structure
-fn get_inp_path_only(path: &PathBuf) -> String {
+fn get_inp_path_only(path: &Path) -> PathBuf {
     if path.is_file() {
-        path.parent().unwrap().to_str().unwrap().to_string()
+        path.parent().unwrap().to_path_buf()
     } else {
-        path.to_str().unwrap().to_string()
+        path.to_path_buf()
     }
 }
 
 // Fn to handle adding files to the dest archive, and compressing them if specified
-async fn construct_archive(paths: Vec<PathBuf>, options: utils::Options, progress: ProgressBar) -> Result<PathBuf, Box<dyn error::Error>> {
-    let input_path = options.input_path.clone();
+fn construct_archive(files: &mut pipeline::Files, options: utils::Options, progress: Arc<dyn progress::Progress>) -> Result<PathBuf, Box<dyn error::Error>> {
     let output_path = options.output_path.clone();
-
-    // Unless overridden, default filename is the current time (YYYYMMDDHHMMSS).tar.gz plus the filename, or last directory name
-    let mut file_name = if output_path.is_file() {
-        output_path.file_name().unwrap().to_str().unwrap().to_string()
-    } else {
-        chrono::Local::now().format(&format!("%Y%m%d%H%M-{}", input_path.file_name().unwrap().to_str().unwrap().to_string())).to_string()
-    };
-    let extension = match options.compression {
-        true => "tgz",
-        _ => "tar",
-    };
-    file_name.push_str(&format!(".{}", extension));
+    if device::is_device(&output_path) {
+        return write_to_device(files, &options, progress.as_ref());
+    }
+    let file_name = options.file_name.clone();
 
     let file_path = output_path.clone().join(&file_name);
     if file_path.exists() {
-        let overwrite = utils::prompt_user(format!("File {} already exists in {}", &file_name, &output_path.display()), "Overwrite?".to_string(), Some(false));
+        let overwrite = options.prompter.confirm(&format!("File {} already exists in {}", Path::new(&file_name).display(), &output_path.display()), "Overwrite?", false);
 
         if !overwrite {
-            process::exit(0);
+            exit(0);
         }
     }
 
     let archive_file = fs::File::create(&file_path).unwrap();
+    let progress = progress.as_ref();
+    progress.on_phase(&archiving_phase(&options, files.total()), files.total().map(|total| total as u64));
+    // Zip and squashfs writers need every file before they start
+    let written = match options.format {
+        format::ArchiveFormat::Zip => files
+            .all()
+            .and_then(|paths| format::write_zip(paths, archive_file, &options, &get_inp_path_only(&options.input_path), progress))
+            .map(|_| Vec::new()),
+        format::ArchiveFormat::Squashfs => files
+            .all()
+            .and_then(|paths| squashfs::write_squashfs(paths, archive_file, &options, &get_inp_path_only(&options.input_path), progress))
+            .map(|_| Vec::new()),
+        format::ArchiveFormat::Tar | format::ArchiveFormat::Cpio => write_archive(files, archive_file, &options, progress).map(|(_, index)| index.map(|index| index.frames).unwrap_or_default()),
+    };
+    let frames = match written {
+        Ok(frames) => frames,
+        // A cancelled archive is missing files, so isn't worth keeping, and one stopped for space is taking it up. So
+        // is one whose files stopped coming because the walk failed
+        Err(e) if e.is::<cancel::Cancelled>() || e.is::<space::LowSpace>() || files.total().is_none() => {
+            let _ = fs::remove_file(&file_path);
+            return Err(e);
+        },
+        Err(e) => return Err(e),
+    };
 
-    let mut archive = tar::Builder::new(match &options.compression {
-        true => Box::new(GzEncoder::new(archive_file, Compression::best())) as Box<dyn std::io::Write>,
-        _ => Box::new(archive_file) as Box<dyn std::io::Write>,
+    let path = validate::archive(file_path, options.on_invalid, options.password.as_deref())?;
+    if !frames.is_empty() {
+        let sidecar = index::EntryIndex::write(&path, frames)?;
+        if options.fsync {
+            utils::fsync(&sidecar)?;
+        }
+    }
+    if options.fsync {
+        utils::fsync(&path)?;
+    }
+    Ok(path)
+}
+
+// Writes the archive straight to a device like a tape drive, in whole blocks and without a file to put it in, so
+// there's nothing to rename into place or read back to validate
+fn write_to_device(files: &mut pipeline::Files, options: &utils::Options, progress: &dyn progress::Progress) -> Result<PathBuf, Box<dyn error::Error>> {
+    let device = fs::OpenOptions::new().write(true).open(&options.output_path)
+        .map_err(|e| format!("Failed to open {}: {}", options.output_path.display(), e))?;
+    progress.on_phase(&archiving_phase(options, files.total()), files.total().map(|total| total as u64));
+    let writer = device::BlockWriter::new(device, options.block_size.unwrap_or(device::DEFAULT_BLOCK_SIZE));
+    let (writer, _) = write_archive(files, writer, options, progress)?;
+    let device = writer.finish()?;
+    // Tape drives don't all support syncing, and have had every block by now anyway
+    if options.fsync {
+        let _ = device.sync_all();
+    }
+    Ok(options.output_path.clone())
+}
+
+// Counts the files when they've all been found
+fn archiving_phase(options: &utils::Options, files: Option<usize>) -> String {
+    let verb = if options.compression { "Compressing" } else { "Writing" };
+    match files {
+        Some(files) => format!("{} {} {}...", verb, files, if files > 1 { "files" } else { "file" }),
+        None => format!("{} files...", verb),
+    }
+}
+
+// Archives straight into an upload, without writing the archive to disk. Returns the uploaded object's URL and size,
+// or None if archiving was stopped at the deadline, in which case nothing's uploaded
+fn stream_archive(files: &mut pipeline::Files, options: utils::Options, progress: Arc<dyn progress::Progress>) -> Result<Option<(String, u64)>, Box<dyn error::Error>> {
+    let backend: Arc<dyn backend::Backend> = Arc::from(backend::connect(&options.remote)?);
+    let file_name = options.file_name.to_string_lossy().to_string();
+    let key = options.remote_key.clone();
+    let info = object_info(&options.remote, options.comment.as_deref());
+    let (writer, mut reader) = backend::pipe();
+    let uploader = std::thread::spawn({
+        let backend = backend.clone();
+        let key = key.clone();
+        move || {
+            let result = backend.upload_stream(&mut reader, &key, &info, &ProgressBar::hidden()).map_err(|e| e.to_string());
+            result.map(|size| (backend.url(&key), size))
+        }
+    });
+
+    progress.on_phase(&archiving_phase(&options, files.total()), files.total().map(|total| total as u64));
+    // The archive never touches the disk, so it's hashed for the manifest on the way out
+    let writer = hash::HashingWriter::new(writer, hash::HashAlgorithm::Sha256);
+    let written = write_archive(files, writer, &options, progress.as_ref()).and_then(|(writer, index)| {
+        let (writer, digest, _) = writer.finish();
+        // A partial archive isn't uploaded, as it isn't from a local copy either
+        if options.deadline.as_ref().is_some_and(|deadline| deadline.stopped().is_some()) {
+            return Ok(None);
+        }
+        writer.close()?;
+        Ok(Some((digest, index)))
     });
-  
-    progress.enable_steady_tick(Duration::from_millis(150));
-    let input_path_only = get_inp_path_only(&input_path);
+    // If archiving failed or stopped, the writer was dropped without being closed, which aborts the upload
+    let uploaded = uploader.join().map_err(|_| "Upload thread panicked")?;
+    match (written, uploaded) {
+        (Ok(None), _) => Ok(None),
+        // The upload failing cuts off the archive being written to it, so the upload's error says why
+        (Err(e), Err(upload)) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) => Err(upload.into()),
+        (Err(e), _) => Err(e),
+        (_, Err(e)) => Err(e.into()),
+        (Ok(Some((digest, index))), Ok((url, size))) => {
+            // With no archive to index afterwards, a seekable one's index is the one kept while writing it
+            if let Some(index) = index {
+                let index_key = format!("{}{}", key, index::INDEX_SUFFIX);
+                backend.upload_stream(&mut &serde_json::to_vec(&index)?[..], &index_key, &[], &ProgressBar::hidden())?;
+            }
+            let mut run = manifest::RunManifest::new(options.host.as_ref(), options.comment.as_deref(), &options.tags);
+            run.archive = file_name;
+            run.size = size;
+            run.checksums.insert("sha256".to_string(), digest);
+            run.files = Some(files.found_so_far());
+            spool::upload_run_manifest(backend.as_ref(), &key, &run)?;
+            Ok(Some((url, size)))
+        },
+    }
+}
+
+// Loads a daemon config, making sure every profile would at least parse before waiting on any of them
+fn load_config(path: &Path) -> Result<daemon::Config, String> {
+    let options: Vec<String> = Args::command().get_arguments().filter_map(|arg| arg.get_long()).map(String::from).collect();
+    let config = daemon::load(path, &options).map_err(|e| e.to_string())?;
+    for (name, profile) in &config.profiles {
+        profile
+            .args()
+            .and_then(|args| Args::try_parse_from(std::iter::once("athena".to_string()).chain(args)).map_err(|e| e.to_string()))
+            .and_then(|args| check_options(&args).map_err(|e| format!("error: {}", e)))
+            .map_err(|e| format!("invalid profile '{}'\n{}", name, e))?;
+    }
+    Ok(config)
+}
+
+// Options that can't be used together, checked for backups and for each profile of a daemon config
+fn check_options(args: &Args) -> Result<(), String> {
+    let encrypted = args.encrypt || args.password_file.is_some();
+    if !matches!(args.format, format::ArchiveFormat::Zip | format::ArchiveFormat::Tar) && encrypted {
+        return Err("encryption is only supported with --format zip or tar".to_string());
+    }
+    // Both are read at offsets into the archive file, which an encrypted tar doesn't have
+    if args.format == format::ArchiveFormat::Tar && encrypted && (args.seekable || args.footer_index) {
+        return Err(format!("{} can't be used with an encrypted tar", if args.seekable { "--seekable" } else { "--footer-index" }));
+    }
+    if args.windows_metadata && (!cfg!(windows) || args.format != format::ArchiveFormat::Tar) {
+        return Err("--windows-metadata needs Windows and a tar archive".to_string());
+    }
+    if let Some(max_memory) = args.max_memory {
+        format::check_memory(args.format, args.compress, args.seekable, max_memory, args.buffer_size)?;
+    }
+    if args.format == format::ArchiveFormat::Zip && args.no_local_copy {
+        return Err("zip archives can't be streamed, drop --no-local-copy".to_string());
+    }
+    // Seekable frames and the footer index are laid out around tar entries. A squashfs image's superblock is filled
+    // in last, so it can't be streamed either
+    if matches!(args.format, format::ArchiveFormat::Squashfs | format::ArchiveFormat::Cpio) {
+        let unsupported = [
+            (args.no_local_copy && args.format == format::ArchiveFormat::Squashfs, "--no-local-copy"),
+            (!args.compress_override.is_empty() && args.format == format::ArchiveFormat::Cpio, "--compress-override"),
+            (args.seekable, "--seekable"),
+            (args.footer_index, "--footer-index"),
+        ];
+        if let Some((_, option)) = unsupported.iter().find(|(given, _)| *given) {
+            return Err(format!("{} can't be used with --format {}", option, <format::ArchiveFormat as clap::ValueEnum>::to_possible_value(&args.format).unwrap().get_name()));
+        }
+    }
+    Ok(())
+}
+
+// Unless overridden, default filename is the current time (YYYYMMDDHHMMSS).tar.gz plus the filename, or last directory name,
+// and the run's place in its incremental chain
+fn archive_file_name(options: &utils::Options) -> OsString {
+    let mut file_name = if options.output_path.is_file() {
+        options.output_path.file_name().unwrap().to_os_string()
+    } else {
+        // The source name is appended rather than formatted in, since it may contain '%' or not be valid UTF-8
+        let mut file_name = OsString::from(chrono::Local::now().format("%Y%m%d%H%M-").to_string());
+        if let Some(host) = &options.host {
+            file_name.push(format!("{}-", host.name));
+        }
+        file_name.push(options.input_path.file_name().unwrap_or(OsStr::new("root")));
+        if let Some(chain) = &options.chain {
+            file_name.push(format!(".{}", chain));
+        }
+        file_name
+    };
+    file_name.push(format!(".{}", options.format.extension(options.compression)));
+    // Encrypted tars aren't tars to other tools, so aren't named like them
+    if options.format == format::ArchiveFormat::Tar && options.password.is_some() {
+        file_name.push(".sealed");
+    }
+    file_name
+}
+
+// Writes the archive for the given files into the writer, compressing it if specified. Returns the writer once
+// the archive is complete, along with its entry index if it was written seekable. Tars and cpio archives
+// are both streams, so either can go here
+fn write_archive<W: std::io::Write>(files: &mut pipeline::Files, writer: W, options: &utils::Options, progress: &dyn progress::Progress) -> Result<(W, Option<index::EntryIndex>), Box<dyn error::Error>> {
+    if options.format == format::ArchiveFormat::Cpio {
+        return Ok((cpio::write_cpio(files, writer, options, &get_inp_path_only(&options.input_path), progress)?, None));
+    }
+    if let Some(password) = &options.password {
+        let (sealed, _) = write_tar(files, crypto::SealWriter::new(writer, password, options.compression)?, options, progress)?;
+        return Ok((sealed.finish()?, None));
+    }
+    write_tar(files, writer, options, progress)
+}
+
+fn write_tar<W: std::io::Write>(files: &mut pipeline::Files, writer: W, options: &utils::Options, progress: &dyn progress::Progress) -> Result<(W, Option<index::EntryIndex>), Box<dyn error::Error>> {
+    if options.compression && options.seekable {
+        let mut archive = tar::Builder::new(index::FrameWriter::new(writer, Compression::best(), format::frame_size(options.max_memory)));
+        let entries = append_entries(&mut archive, files, options, progress)?;
+        let (writer, frames) = archive.into_inner()?.finish()?;
+        Ok((writer, Some(index::EntryIndex { entries, frames })))
+    } else if options.compression {
+        let mut archive = tar::Builder::new(footer::GzWriter::new(writer, Compression::best()));
+        append_entries(&mut archive, files, options, progress)?;
+        Ok((archive.into_inner()?.finish()?, None))
+    } else {
+        let mut archive = tar::Builder::new(footer::PlainWriter::new(writer));
+        append_entries(&mut archive, files, options, progress)?;
+        Ok((archive.into_inner()?.finish(), None))
+    }
+}
+
+fn append_entries<W: footer::TarWriter>(archive: &mut tar::Builder<W>, files: &mut pipeline::Files, options: &utils::Options, progress: &dyn progress::Progress) -> Result<Vec<index::IndexedEntry>, Box<dyn error::Error>> {
+    let input_path_only = get_inp_path_only(&options.input_path);
+    let mut manifest = manifest::Manifest::new(options.host.as_ref(), options.comment.as_deref());
+    manifest.deleted = options.deleted.clone();
     let mut files_processed = 0;
-    for path in paths {
+    // Every entry written, for the footer index and a streamed archive's entry index
+    let mut entries = Vec::new();
+    let mut space = space::Guard::new(vec![options.output_path.clone(), scratch::dir()], options.min_free_space);
+    while let Some(path) = files.next() {
+        let path = &path?;
+        if files.total().is_none() {
+            progress.on_found(files.found_so_far() as u64);
+        }
+        options.cancel.check("archiving", files_processed, files.total())?;
+        space.check()?;
         let rel_path = path.strip_prefix(&input_path_only).unwrap();
-        if path.symlink_metadata().unwrap().file_type().is_symlink() {
-            // Add symlink to archive, with header, rel path in archive, and target path on sys
-            let mut header = tar::Header::new_gnu();
-            header.set_uid(path.owner().unwrap().id() as u64);
-            header.set_gid(path.group().unwrap().id() as u64);
-            header.set_entry_type(tar::EntryType::Symlink);
-            header.set_size(0);
-            archive.append_link(&mut header, rel_path, path.read_link().unwrap().to_str().unwrap())?;
+        // Past the deadline, the archive is finished with what's in it so far
+        if let Some(deadline) = options.deadline.as_ref().filter(|deadline| deadline.passed()) {
+            let total = files.total().unwrap_or_else(|| files.by_ref().count() + files_processed + 1);
+            manifest.stopped = Some(deadline.stop(files_processed, total, rel_path));
+            break;
+        }
+        let offset = archive.get_ref().position();
+        let stat = match manifest::check_readable(&*options.fs, path, options.busy_retries) {
+            Ok(stat) => stat,
+            Err(e) if options.skips(&e) => {
+                progress.on_warning(&format!("Skipping {}: {}", path.display(), e));
+                options.errors.record(path, "open", &e);
+                manifest.skipped.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error: e.to_string() });
+                files_processed += 1;
+                continue;
+            },
+            Err(e) if options.placeholder_on_error => {
+                progress.on_file(rel_path, 0);
+                progress.on_warning(&format!("Storing placeholder for {}: {}", path.display(), e));
+                options.errors.record(path, "open", &e);
+                let mut header = tar::Header::new_gnu();
+                header.set_size(0);
+                header.set_mode(0o644);
+                header.set_mtime(chrono::Local::now().timestamp() as u64);
+                archive.append_data(&mut header, rel_path, std::io::empty())?;
+                manifest.placeholders.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error: e.to_string() });
+                entries.push(index::IndexedEntry { path: rel_path.to_string_lossy().to_string(), start: offset, end: archive.get_ref().position(), size: 0, hash: None, mtime: None });
+                files_processed += 1;
+                continue;
+            },
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+        };
+        progress.on_file(rel_path, if stat.is_file() { stat.len } else { 0 });
+        let mtime = stat.modified.map(|modified| chrono::DateTime::<chrono::Utc>::from(modified).timestamp());
+        // Readers only apply the PAX header right before an entry, so all its records go in one. Only local files
+        // have the attributes they come from
+        let mut records = Vec::new();
+        if let Some(metadata) = &stat.local {
+            if options.windows_metadata {
+                records = ntfs::pax_records(path, metadata)?;
+            }
+            records.extend(fileattrs::pax_records(path, metadata)?);
+        }
+        if !options.compress_overrides.is_empty() {
+            let method = format::method_for(&options.compress_overrides, path);
+            archive.get_mut().set_level(method.map_or(Compression::best(), format::Method::level))?;
+        }
+        if !records.is_empty() {
+            pax::append(archive, &records)?;
+        }
+        if stat.is_symlink() {
+            // Add symlink to archive, with header, rel path in archive, and target path on sys.
+            // Metadata comes from the link itself, since the target may not exist
+            let mut header = stat.header();
+            archive.append_link(&mut header, rel_path, pathstyle::link_target(&options.fs.read_link(path)?, options.path_style))?;
+            entries.push(index::IndexedEntry { path: rel_path.to_string_lossy().to_string(), start: offset, end: archive.get_ref().position(), size: 0, hash: None, mtime });
         } else {
-            // Since set_path() using this lib can't take pathnames > 255 bytes, use
-            // its append_path_with_name method to insert the pathname at the same time as the file content
-            archive.append_path_with_name(&path, rel_path)?;
+            let (shortfall, hash) = append_file(archive, path, rel_path, &stat, options, progress)?;
+            let size = if stat.is_file() { stat.len } else { 0 };
+            entries.push(index::IndexedEntry { path: rel_path.to_string_lossy().to_string(), start: offset, end: archive.get_ref().position(), size, hash, mtime });
+            // The header was already written with the old size, so the entry can only be kept zero-filled or failed
+            if shortfall > 0 {
+                let error = format!("shrank by {} while being read", utils::format_size(shortfall));
+                if options.on_busy == busy::BusyPolicy::Fail && !options.keep_going {
+                    return Err(format!("Failed to read {}: {}", path.display(), error).into());
+                }
+                progress.on_warning(&format!("Zero-filling {}: {}", path.display(), error));
+                options.errors.record_message(path, "read", error.clone());
+                manifest.placeholders.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error });
+            }
         }
         files_processed += 1;
-        progress.set_position(files_processed as u64);
     }
-    archive.finish()?;
+    if manifest.is_needed() {
+        let contents = manifest.to_json()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(chrono::Local::now().timestamp() as u64);
+        let offset = archive.get_ref().position();
+        archive.append_data(&mut header, manifest::MANIFEST_NAME, &contents[..])?;
+        entries.push(index::IndexedEntry { path: manifest::MANIFEST_NAME.to_string(), start: offset, end: archive.get_ref().position(), size: contents.len() as u64, hash: None, mtime: None });
+    }
+    if options.footer_index {
+        footer::write(archive, entries.clone())?;
+    }
+    Ok(entries)
+}
 
-    match validate::archive(file_path) {
-        Ok(path) => {
-            progress.finish_and_clear();
-            Ok(path)
-        },
-        Err(e) => {
-            progress.finish_with_message("Failed");
-            Err(e)
-        },
+// Appends a regular file, reporting bytes read as it goes. Files of at least `mmap_threshold` bytes are
+// memory-mapped rather than going through buffered reads. Returns how many bytes had to be zero-filled because
+// the file shrank after its size was taken, and the blake3 of the contents when there's a footer index to hold it
+fn append_file<W: std::io::Write>(
+    archive: &mut tar::Builder<W>,
+    path: &Path,
+    rel_path: &Path,
+    stat: &sourcefs::Stat,
+    options: &utils::Options,
+    progress: &dyn progress::Progress,
+) -> Result<(u64, Option<String>), Box<dyn error::Error>> {
+    if stat.is_file() {
+        let mut header = stat.header();
+        if let Some(mmap) = stat.local.as_ref().and_then(|metadata| map_file(path, metadata, options.mmap_threshold)) {
+            header.set_size(mmap.len() as u64);
+            archive.append_data(&mut header, rel_path, progress::ProgressReader::new(ratelimit::LimitedReader::new(&mmap[..], options.limit_read.clone()), progress))?;
+            let hash = options.footer_index.then(|| blake3::hash(&mmap).to_hex().to_string());
+            return Ok((0, hash));
+        }
+        let file = readahead::open(path, stat.len, options)?;
+        let mut reader = busy::SizedReader::new(file, stat.len);
+        if options.footer_index {
+            let mut hashing = hash::HashingReader::new(&mut reader, hash::HashAlgorithm::Blake3);
+            archive.append_data(&mut header, rel_path, progress::ProgressReader::new(&mut hashing, progress))?;
+            let hash = hashing.finish();
+            return Ok((reader.shortfall(), Some(hash)));
+        }
+        archive.append_data(&mut header, rel_path, progress::ProgressReader::new(&mut reader, progress))?;
+        return Ok((reader.shortfall(), None));
+    }
+    // Since set_path() using this lib can't take pathnames > 255 bytes, use
+    // its append_path_with_name method to insert the pathname at the same time as the file content
+    if stat.local.is_some() {
+        archive.append_path_with_name(path, rel_path)?;
+        return Ok((0, None));
+    }
+    // Devices and sockets need more than SFTP says about them
+    match stat.kind {
+        exclude::FileType::Dir | exclude::FileType::Fifo => archive.append_data(&mut stat.header(), rel_path, std::io::empty())?,
+        _ => return Err(format!("Can't archive special file {} from a remote source", path.display()).into()),
+    }
+    Ok((0, None))
+}
+
+// Memory-maps the file if it's at least `threshold` bytes, returning None if it's smaller or can't be mapped
+fn map_file(path: &Path, metadata: &fs::Metadata, threshold: Option<u64>) -> Option<memmap2::Mmap> {
+    let threshold = threshold?;
+    // Zero-length files can't be mapped
+    if metadata.len() == 0 || metadata.len() < threshold {
+        return None;
+    }
+    let file = fs::File::open(path).ok()?;
+    // Safety: the mapping is only read while appending, but the file may still be modified by
+    // another process in the meantime - same as with buffered reads, the archive would get torn content
+    unsafe { memmap2::Mmap::map(&file) }.ok()
+}
+
+// What a traversal of one source has come across so far, shared through its recursion
+struct Traversal {
+    // Directories by (device, inode), so one reached again through a bind mount or symlink loop isn't re-entered
+    dirs: std::sync::Mutex<std::collections::HashSet<(u64, u64)>>,
+    // Files found across all sources, counted against `max_files`
+    files: Arc<std::sync::atomic::AtomicUsize>,
+    max_files: Option<usize>,
+    // Where directories that can't be listed are recorded and skipped, with --keep-going. Otherwise they fail the scan
+    errors: Option<errors::ErrorLog>,
+    // Listings from the last --trust-mtime run, for directories that haven't changed since
+    cache: Option<Arc<incremental::DirCache>>,
+    excludes: Option<Arc<exclude::Excludes>>,
+    fs: sourcefs::SharedFs,
+}
+
+impl Traversal {
+    fn excluded(&self, path: &Path) -> bool {
+        self.excludes.as_ref().is_some_and(|excludes| excludes.matches(path))
     }
+
+    // Whether a file found on the walk is of a type that's archived. Ones that can't be stat'ed are kept, for
+    // archiving to report
+    fn wanted(&self, path: &Path) -> bool {
+        match &self.excludes {
+            Some(excludes) if excludes.filters_types() => self.fs.symlink_metadata(path).map(|stat| excludes.keeps_type(stat.kind)).unwrap_or(true),
+            _ => true,
+        }
+    }
+
+    // Whether a directory reached on the walk is archived as an entry itself, rather than just searched
+    fn wanted_dir(&self, dir: &Path) -> bool {
+        self.excludes.as_ref().is_some_and(|excludes| excludes.keeps_dirs()) && !self.is_symlink(dir)
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.fs.symlink_metadata(path).is_ok_and(|stat| stat.kind == exclude::FileType::Symlink)
+    }
+
+    // Whether this is the first time the directory's been reached. Always true where there are no inode numbers
+    fn enter(&self, dir: &Path) -> bool {
+        match self.fs.metadata(dir).ok().and_then(|stat| stat.id) {
+            Some(id) => self.dirs.lock().unwrap().insert(id),
+            None => true,
+        }
+    }
+
+    fn found(&self) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let total = self.files.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+        match self.max_files {
+            Some(max_files) if total > max_files => Err(format!("Found more than {} files in the sources, raise --max-files if that's expected", max_files).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Collects the files from all sources, skipping any that were already found through an overlapping source. Errors
+// once more than `max_files` are found, or on a directory that can't be listed unless there's a log to record it in
+fn process_sources(
+    sources: Vec<PathBuf>,
+    cancel: cancel::CancellationToken,
+    max_files: Option<usize>,
+    errors: Option<errors::ErrorLog>,
+    cache: Option<Arc<incremental::DirCache>>,
+    excludes: Option<Arc<exclude::Excludes>>,
+) -> Result<Vec<PathBuf>, Box<dyn error::Error + Send + Sync>> {
+    let mut files = Vec::new();
+    walk_sources(sources, sourcefs::SharedFs::default(), cancel, max_files, errors, cache, excludes, &mut |file| {
+        files.push(file);
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+// Walks all sources as for `process_sources`, through `fs`, passing each file to `found` as soon as it's reached
+#[allow(clippy::too_many_arguments)]
+fn walk_sources(
+    sources: Vec<PathBuf>,
+    fs: sourcefs::SharedFs,
+    cancel: cancel::CancellationToken,
+    max_files: Option<usize>,
+    errors: Option<errors::ErrorLog>,
+    cache: Option<Arc<incremental::DirCache>>,
+    excludes: Option<Arc<exclude::Excludes>>,
+    found: &mut dyn FnMut(PathBuf) -> pipeline::StageResult<()>,
+) -> pipeline::StageResult<()> {
+    let mut seen = std::collections::HashSet::new();
+    let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut unseen = |file: PathBuf| match seen.insert(file.clone()) {
+        true => found(file),
+        false => Ok(()),
+    };
+    for source in sources {
+        // Overlapping sources are expected to meet the same directories, so only loops within a source are reported
+        let traversal = Traversal { dirs: Default::default(), files: count.clone(), max_files, errors: errors.clone(), cache: cache.clone(), excludes: excludes.clone(), fs: fs.clone() };
+        process_input(source, &cancel, &traversal, &mut unseen)?;
+    }
+    Ok(())
+}
+
+// A step of the walk: a file or directory to pass on, or a path to look at and, if it's a directory, list
+enum Walked {
+    Found(PathBuf),
+    Enter(PathBuf),
 }
 
-// Checks over the given input directory, counting files and subdirs and returning a BoxFuture that resolves to a Vec of PathBufs
-// of the absolute paths to all files in the given directory
-// TODO: Find another way to achieve this without storing all PathBufs in memory, this could be a problem for
-// dirs with a lot of files (although at least up to 100k files it seems to be fine so ehhhhh)
-fn process_input(input_path: PathBuf) -> BoxFuture<'static, Result<Vec<PathBuf>, Box<dyn error::Error + Send + Sync>>> {
-    async move {
-        if input_path.is_symlink() || input_path.is_file() {
-            Ok(vec![input_path])
+// Walks the given input path, passing the absolute path of every file under it to `found`. Directories still to be
+// read wait on a stack rather than being recursed into, so however deep the tree goes the walk can't overflow
+fn process_input(input_path: PathBuf, cancel: &cancel::CancellationToken, traversal: &Traversal, found: &mut dyn FnMut(PathBuf) -> pipeline::StageResult<()>) -> pipeline::StageResult<()> {
+    let mut pending = vec![Walked::Enter(input_path)];
+    while let Some(next) = pending.pop() {
+        let input_path = match next {
+            Walked::Found(path) => {
+                traversal.found()?;
+                found(path)?;
+                continue;
+            },
+            Walked::Enter(path) => path,
+        };
+        if traversal.is_symlink(&input_path) || traversal.fs.metadata(&input_path).is_ok_and(|stat| stat.is_file()) {
+            if traversal.wanted(&input_path) {
+                traversal.found()?;
+                found(input_path)?;
+            }
+            continue;
+        }
+        cancel.check("scanning sources", 0, None)?;
+        if !traversal.enter(&input_path) {
+            eprintln!("Warning: skipping {}, already visited through a bind mount or hard-linked directory", input_path.display());
+            continue;
+        }
+        let mut listed = Vec::new();
+        let cached = traversal.cache.as_ref().and_then(|cache| traversal.fs.metadata(&input_path).ok().and_then(|stat| cache.listing(&input_path, stat.modified)));
+        if let Some((cached_files, subdirs)) = cached {
+            listed.extend(cached_files.into_iter().filter(|file| !traversal.excluded(file) && traversal.wanted(file)).map(Walked::Found));
+            for dir in subdirs.into_iter().filter(|dir| !traversal.excluded(dir)) {
+                if traversal.wanted_dir(&dir) {
+                    listed.push(Walked::Found(dir.clone()));
+                }
+                listed.push(Walked::Enter(dir));
+            }
         } else {
-            let mut files = Vec::new();
-            for entry in fs::read_dir(input_path)? {
-                let entry = entry?;
-                let path = entry.path();
-                if path.is_dir() {
-                    // println!("Processing directory: {}", path.display());
-                    files.append(&mut process_input(path).await?);
-                } else {
-                    files.push(path);
+            let entries = match (traversal.fs.read_dir(&input_path), &traversal.errors) {
+                (Ok(entries), _) => entries,
+                (Err(e), Some(errors)) => {
+                    eprintln!("Warning: skipping {}: {}", input_path.display(), e);
+                    errors.record(&input_path, "list", &e);
+                    continue;
+                },
+                (Err(e), None) => return Err(e.into()),
+            };
+            for path in entries {
+                if traversal.excluded(&path) {
+                    continue;
+                }
+                if traversal.fs.metadata(&path).is_ok_and(|stat| stat.is_dir()) {
+                    if traversal.wanted_dir(&path) {
+                        listed.push(Walked::Found(path.clone()));
+                    }
+                    listed.push(Walked::Enter(path));
+                } else if traversal.wanted(&path) {
+                    listed.push(Walked::Found(path));
                 }
             }
-            Ok(files)
         }
-    }.boxed()
+        // Reversed, so they come off the stack in the order they were listed, each directory's contents before
+        // what follows it
+        pending.extend(listed.into_iter().rev());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sourcefs::{MemFs, SharedFs};
+
+    fn walk(fs: &SharedFs, errors: Option<errors::ErrorLog>) -> pipeline::StageResult<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        walk_sources(vec![PathBuf::from("/src")], fs.clone(), cancel::CancellationToken::default(), None, errors, None, None, &mut |file| {
+            files.push(file);
+            Ok(())
+        })?;
+        Ok(files)
+    }
+
+    fn archive(fs: &SharedFs, paths: &[PathBuf], keep_going: bool) -> Result<(Vec<String>, errors::ErrorLog), Box<dyn error::Error>> {
+        let (entries, errors) = archive_with(paths, utils::Options { fs: fs.clone(), keep_going, ..utils::Options::default() })?;
+        Ok((entries.into_iter().map(|(name, _)| name).collect(), errors))
+    }
+
+    // Each entry's name and contents
+    type Entries = Vec<(String, Vec<u8>)>;
+
+    fn archive_with(paths: &[PathBuf], options: utils::Options) -> Result<(Entries, errors::ErrorLog), Box<dyn error::Error>> {
+        let options = utils::Options { input_path: PathBuf::from("/src"), ..options };
+        let (tar, _) = write_tar(&mut pipeline::Files::listed(paths.to_vec()), Vec::new(), &options, &progress::NoProgress)?;
+        let mut entries = Vec::new();
+        for entry in tar::Archive::new(tar.as_slice()).entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().to_string();
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut contents)?;
+            entries.push((name, contents));
+        }
+        Ok((entries, options.errors))
+    }
+
+    #[test]
+    fn walk_skips_directories_it_cant_list_only_when_keeping_going() {
+        let fs = SharedFs::new(MemFs::default().file("/src/a.txt", b"a").file("/src/private/b.txt", b"b").deny("/src/private"));
+        let errors = errors::ErrorLog::default();
+        assert_eq!(walk(&fs, Some(errors.clone())).unwrap(), vec![PathBuf::from("/src/a.txt")]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors.messages()[0].starts_with("/src/private (list)"));
+
+        let e = walk(&fs, None).unwrap_err();
+        assert!(e.to_string().contains("permission denied"), "{}", e);
+    }
+
+    // A chain of directories as many deep under /src as it's given, with a file at the bottom. Made up as it's
+    // walked, as a tree that deep is slow to build in a MemFs
+    struct Deep(usize);
+
+    impl sourcefs::SourceFs for Deep {
+        fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+            Ok(vec![dir.join(if dir.components().count() - 2 < self.0 { "d" } else { "deep.txt" })])
+        }
+
+        fn metadata(&self, path: &Path) -> std::io::Result<sourcefs::Stat> {
+            let kind = if path.ends_with("deep.txt") { exclude::FileType::File } else { exclude::FileType::Dir };
+            Ok(sourcefs::Stat { kind, len: 0, mode: 0o644, uid: 0, gid: 0, modified: None, id: None, local: None })
+        }
+
+        fn symlink_metadata(&self, path: &Path) -> std::io::Result<sourcefs::Stat> {
+            self.metadata(path)
+        }
+
+        fn read_link(&self, _: &Path) -> std::io::Result<PathBuf> {
+            Err(std::io::ErrorKind::InvalidInput.into())
+        }
+
+        fn open(&self, _: &Path) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+            Ok(Box::new(std::io::empty()))
+        }
+    }
+
+    #[test]
+    fn walks_trees_too_deep_to_recurse_into() {
+        let files = walk(&SharedFs::new(Deep(10_000)), None).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].components().count(), 10_003);
+    }
+
+    #[test]
+    fn archiving_leaves_out_files_that_vanished_or_cant_be_read() {
+        let fs = SharedFs::new(
+            MemFs::default().file("/src/a.txt", b"a").file("/src/gone.txt", b"gone").file("/src/secret.txt", b"secret").symlink("/src/link", "a.txt").vanish("/src/gone.txt").deny("/src/secret.txt"),
+        );
+        // Listed before it went, so the walk still finds it, leaving archiving to notice
+        let files = walk(&fs, None).unwrap();
+        assert_eq!(files.len(), 4);
+
+        let (names, errors) = archive(&fs, &files, true).unwrap();
+        assert!(names.contains(&"a.txt".to_string()) && names.contains(&"link".to_string()), "{:?}", names);
+        assert!(!names.contains(&"gone.txt".to_string()) && !names.contains(&"secret.txt".to_string()), "{:?}", names);
+        let mut messages = errors.messages();
+        messages.sort();
+        assert!(messages[0].starts_with("/src/gone.txt (open)"), "{:?}", messages);
+        assert!(messages[1].starts_with("/src/secret.txt (open)"), "{:?}", messages);
+
+        let e = archive(&fs, &files, false).unwrap_err();
+        assert!(e.to_string().starts_with("Failed to read /src/gone.txt"), "{}", e);
+    }
+
+    #[test]
+    fn archiving_counts_only_what_was_read_of_files_that_vanished() {
+        let fs = SharedFs::new(MemFs::default().file("/src/a.txt", b"aaaa").file("/src/gone.txt", b"gone").vanish("/src/gone.txt"));
+        let files = walk(&fs, None).unwrap();
+        let options = utils::Options { fs, input_path: PathBuf::from("/src"), placeholder_on_error: true, ..utils::Options::default() };
+        let progress = progress::BarProgress::new(ProgressBar::hidden(), None);
+
+        // The summary's sized from this, so a file that's gone by then has nothing left to look up
+        write_tar(&mut pipeline::Files::listed(files), Vec::new(), &options, &progress).unwrap();
+        assert_eq!(progress.bytes(), 4);
+    }
+
+    #[test]
+    fn archiving_retries_busy_files_then_fails_or_skips_them() {
+        let files = vec![PathBuf::from("/src/a.txt"), PathBuf::from("/src/db.sqlite")];
+        let busy = |opens| SharedFs::new(MemFs::default().file("/src/a.txt", b"a").file("/src/db.sqlite", b"data").busy("/src/db.sqlite", opens));
+
+        // Free again by the retry
+        let (entries, _) = archive_with(&files, utils::Options { fs: busy(1), busy_retries: 1, ..utils::Options::default() }).unwrap();
+        assert!(entries.contains(&("db.sqlite".to_string(), b"data".to_vec())), "{:?}", entries);
+
+        let e = archive_with(&files, utils::Options { fs: busy(2), busy_retries: 1, ..utils::Options::default() }).unwrap_err();
+        assert!(e.to_string().starts_with("Failed to read /src/db.sqlite"), "{}", e);
+
+        let (entries, errors) = archive_with(&files, utils::Options { fs: busy(2), busy_retries: 1, on_busy: busy::BusyPolicy::Skip, ..utils::Options::default() }).unwrap();
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a.txt", manifest::MANIFEST_NAME]);
+        let manifest: serde_json::Value = serde_json::from_slice(&entries[1].1).unwrap();
+        assert_eq!(manifest["skipped"][0]["path"], "db.sqlite");
+        assert!(errors.messages()[0].starts_with("/src/db.sqlite (open)"), "{:?}", errors.messages());
+    }
+
+    #[test]
+    fn archiving_fails_or_zero_fills_files_that_shrink() {
+        let files = vec![PathBuf::from("/src/log.txt")];
+        let fs = SharedFs::new(MemFs::default().file("/src/log.txt", b"0123456789").shrink("/src/log.txt", 4));
+
+        let e = archive_with(&files, utils::Options { fs: fs.clone(), ..utils::Options::default() }).unwrap_err();
+        assert_eq!(e.to_string(), "Failed to read /src/log.txt: shrank by 4B while being read");
+
+        // Kept at the size its header was written with, the missing end zeroed
+        let (entries, errors) = archive_with(&files, utils::Options { fs, on_busy: busy::BusyPolicy::Skip, ..utils::Options::default() }).unwrap();
+        assert_eq!(entries[0], ("log.txt".to_string(), b"012345\0\0\0\0".to_vec()));
+        let manifest: serde_json::Value = serde_json::from_slice(&entries[1].1).unwrap();
+        assert_eq!(manifest["placeholders"][0]["error"], "shrank by 4B while being read");
+        assert_eq!(errors.len(), 1);
+    }
 }
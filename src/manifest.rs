@@ -0,0 +1,131 @@
+use std::{collections::BTreeMap, io, path::Path, error::Error};
+use serde::{Deserialize, Serialize};
+use crate::{host::Host, sourcefs::{SourceFs, Stat}};
+
+// Name of the manifest entry, written last in an archive
+pub const MANIFEST_NAME: &str = ".athena-manifest.json";
+// Suffix of the object describing an uploaded archive, next to it
+pub const RUN_MANIFEST_SUFFIX: &str = ".manifest.json";
+
+// Metadata about an archive, stored inside it
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    pub tool_version: String,
+    pub created: String,
+    // The machine the archive was made on, unless host scoping was turned off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    // Files that couldn't be read, and were stored as empty entries instead. Also files that shrank while being
+    // read, whose entries were zero-filled to the size they started at
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub placeholders: Vec<Placeholder>,
+    // Files left out because another process kept them busy (`--on-busy skip`) or they were unreadable (`--keep-going`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<Placeholder>,
+    // Tombstones: files an incremental run found deleted since the previous run, so restoring the chain removes them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<String>,
+    // Set when archiving stopped before every file was in, e.g. at --max-duration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stopped: Option<StoppedEarly>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoppedEarly {
+    pub reason: String,
+    pub archived: usize,
+    pub total: usize,
+    // The first file left out. The rest follow it in the order the sources were scanned
+    pub next: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Placeholder {
+    pub path: String,
+    pub error: String,
+}
+
+impl Manifest {
+    pub fn new(host: Option<&Host>, comment: Option<&str>) -> Manifest {
+        Manifest {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: chrono::Local::now().to_rfc3339(),
+            hostname: host.map(|host| host.name.clone()),
+            machine_id: host.and_then(|host| host.machine_id.clone()),
+            comment: comment.map(str::to_string),
+            placeholders: Vec::new(),
+            skipped: Vec::new(),
+            deleted: Vec::new(),
+            stopped: None,
+        }
+    }
+
+    // Whether there's anything to note. The hostname is recorded by default, so this is nearly always true. Only
+    // archives made with --no-host-scope and nothing else to note are left without a manifest
+    pub fn is_needed(&self) -> bool {
+        self.hostname.is_some() || self.comment.is_some() || !self.placeholders.is_empty() || !self.skipped.is_empty() || !self.deleted.is_empty() || self.stopped.is_some()
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec_pretty(self)
+    }
+}
+
+// Describes an uploaded archive, so remote-only tooling can check and sort backups without downloading them
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RunManifest {
+    pub archive: String,
+    pub size: u64,
+    // Digest of the archive by algorithm name
+    pub checksums: BTreeMap<String, String>,
+    // Files archived, when the archive was made by this run rather than uploaded with `athena upload`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub tool_version: String,
+    pub created: String,
+}
+
+impl RunManifest {
+    pub fn new(host: Option<&Host>, comment: Option<&str>, tags: &[String]) -> RunManifest {
+        let manifest = Manifest::new(host, comment);
+        RunManifest {
+            hostname: manifest.hostname,
+            machine_id: manifest.machine_id,
+            comment: manifest.comment,
+            tags: tags.to_vec(),
+            tool_version: manifest.tool_version,
+            created: manifest.created,
+            ..RunManifest::default()
+        }
+    }
+
+    // Fills in the archive's name, size and sha256 from the file
+    pub fn describe(&mut self, archive: &Path) -> Result<(), Box<dyn Error>> {
+        self.archive = archive.file_name().unwrap_or_default().to_string_lossy().to_string();
+        self.size = archive.metadata()?.len();
+        self.checksums.insert("sha256".to_string(), crate::hash::file(archive, crate::hash::HashAlgorithm::Sha256)?);
+        Ok(())
+    }
+}
+
+// Checks a source file can still be read before it's archived, returning its metadata. Files that vanished or
+// can't be opened fail here, before anything has been written for them. Busy files are retried `busy_retries` times
+pub fn check_readable(source: &dyn SourceFs, path: &Path, busy_retries: u32) -> io::Result<Stat> {
+    let stat = source.symlink_metadata(path)?;
+    if stat.is_file() {
+        crate::busy::open(source, path, busy_retries)?;
+    }
+    Ok(stat)
+}
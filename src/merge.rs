@@ -0,0 +1,141 @@
+use std::{collections::{HashMap, HashSet}, fs, io::{self, BufReader, Read, Write}, path::{Path, PathBuf}, error::Error};
+use clap::ValueEnum;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use crate::{crypto, footer, list, manifest, pax, validate::{self, ArchiveKind}};
+
+// Which copy of a path found in more than one archive ends up in the merged one
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    // Every copy, in order, so extracting the result leaves the last one
+    #[default]
+    Keep,
+    First,
+    Last,
+}
+
+pub struct Merged {
+    pub entries: usize,
+    pub duplicates: usize,
+}
+
+// Streams the entries of each archive in turn into one new archive, compressed if `output` ends in .tgz or .gz.
+// Each archive's own manifest and footer index are left out, as they only describe that archive. Encrypted tars
+// are decrypted with the password in `password_file` or asked for
+pub fn run(archives: &[impl AsRef<Path>], output: &Path, on_duplicate: DuplicatePolicy, password_file: Option<&Path>) -> Result<Merged, Box<dyn Error>> {
+    let name = output.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let compress = if name.ends_with(".tgz") || name.ends_with(".gz") {
+        true
+    } else if name.ends_with(".tar") {
+        false
+    } else {
+        return Err("Output must end in .tar, .tgz or .tar.gz".into());
+    };
+    // Keeping the last copy means knowing which copy is last before writing any, so the archives are listed first,
+    // which only reads their footer indexes where they have one
+    let mut remaining: HashMap<String, usize> = HashMap::new();
+    if on_duplicate == DuplicatePolicy::Last {
+        for archive in archives {
+            for entry in list::list(archive.as_ref(), password_file)? {
+                *remaining.entry(entry.path.trim_end_matches('/').to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    // Written alongside the output and renamed into place, so a failed merge doesn't leave half an archive
+    let dir = output.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let file = tempfile::NamedTempFile::new_in(dir)?;
+    let merged = if compress {
+        let mut builder = tar::Builder::new(GzEncoder::new(file.as_file(), Compression::default()));
+        let merged = append_archives(&mut builder, archives, on_duplicate, password_file, remaining)?;
+        builder.into_inner()?.finish()?;
+        merged
+    } else {
+        let mut builder = tar::Builder::new(file.as_file());
+        let merged = append_archives(&mut builder, archives, on_duplicate, password_file, remaining)?;
+        builder.into_inner()?.flush()?;
+        merged
+    };
+    file.persist(output)?;
+    Ok(merged)
+}
+
+// `remaining` is how many copies of each path are still to come, for keeping the last one
+fn append_archives<W: Write>(
+    builder: &mut tar::Builder<W>,
+    archives: &[impl AsRef<Path>],
+    on_duplicate: DuplicatePolicy,
+    password_file: Option<&Path>,
+    mut remaining: HashMap<String, usize>,
+) -> Result<Merged, Box<dyn Error>> {
+    let mut seen = HashSet::new();
+    let mut merged = Merged { entries: 0, duplicates: 0 };
+    for archive in archives {
+        let archive = archive.as_ref();
+        let reader: Box<dyn Read> = match validate::readable(validate::detect(archive)?)? {
+            ArchiveKind::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(fs::File::open(archive)?))),
+            ArchiveKind::Tar => Box::new(BufReader::new(fs::File::open(archive)?)),
+            ArchiveKind::Sealed => crypto::open_sealed(archive, password_file)?,
+            _ => return Err(format!("Can't merge {}: only tar, tgz and encrypted tar archives can be merged", archive.display()).into()),
+        };
+        let mut tar = tar::Archive::new(reader);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_path_buf();
+            let key = path.to_string_lossy().trim_end_matches('/').to_string();
+            if entry.header().entry_type().is_pax_global_extensions() || path == Path::new(manifest::MANIFEST_NAME) || path == Path::new(footer::INDEX_NAME) {
+                continue;
+            }
+            let wanted = match on_duplicate {
+                DuplicatePolicy::Keep => true,
+                DuplicatePolicy::First => seen.insert(key),
+                DuplicatePolicy::Last => {
+                    let left = remaining.get_mut(&key).ok_or_else(|| format!("{} changed while merging", archive.display()))?;
+                    *left -= 1;
+                    *left == 0
+                },
+            };
+            if !wanted {
+                merged.duplicates += 1;
+                continue;
+            }
+            copy_entry(builder, &mut entry, &path, |target, _| target.to_path_buf())?;
+            merged.entries += 1;
+        }
+        // Read to the end so a truncated sealed tar fails the merge
+        io::copy(&mut tar.into_inner(), &mut io::sink())?;
+    }
+    Ok(merged)
+}
+
+// Copies an entry as it was, including PAX records like NTFS metadata. Paths and link targets are written
+// again by the builder, which uses long name entries for ones too long for the header. `link` maps link targets,
+// given whether it's a symlink's
+pub fn copy_entry<W: Write, R: Read>(
+    builder: &mut tar::Builder<W>,
+    entry: &mut tar::Entry<R>,
+    path: &Path,
+    link: impl Fn(&Path, bool) -> PathBuf,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(extensions) = entry.pax_extensions()? {
+        let mut records = pax::Records::new();
+        for extension in extensions {
+            let extension = extension?;
+            let key = extension.key().map_err(|e| format!("Invalid PAX record in {}: {}", path.display(), e))?;
+            if !matches!(key, "path" | "linkpath" | "size") {
+                records.push((key.to_string(), extension.value_bytes().to_vec()));
+            }
+        }
+        if !records.is_empty() {
+            pax::append(builder, &records)?;
+        }
+    }
+    let mut header = entry.header().clone();
+    match entry.link_name()? {
+        Some(target) if header.entry_type().is_symlink() || header.entry_type().is_hard_link() => {
+            let target = link(&target, header.entry_type().is_symlink());
+            builder.append_link(&mut header, path, target)?;
+        },
+        _ => builder.append_data(&mut header, path, entry)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::{fs, path::Path, error::Error};
+use crate::pax;
+
+// PAX keys for Windows metadata tar has no standard fields for. Times are FILETIMEs (100ns ticks since 1601),
+// kept as-is so they round-trip exactly, and alternate data streams are base64 encoded
+pub const KEY_PREFIX: &str = "ATHENA.windows.";
+#[cfg(windows)]
+const ATTRIBUTES_KEY: &str = "ATHENA.windows.attributes";
+#[cfg(windows)]
+const CREATION_TIME_KEY: &str = "ATHENA.windows.creationtime";
+#[cfg(windows)]
+const LAST_WRITE_TIME_KEY: &str = "ATHENA.windows.lastwritetime";
+#[cfg(windows)]
+const STREAM_KEY_PREFIX: &str = "ATHENA.windows.ads.";
+
+// PAX records describing a file's attributes (hidden, system, readonly...), timestamps and alternate data streams
+#[cfg(windows)]
+pub fn pax_records(path: &Path, metadata: &fs::Metadata) -> Result<pax::Records, Box<dyn Error>> {
+    use base64::Engine;
+    use std::os::windows::fs::MetadataExt;
+    let mut records = vec![
+        (ATTRIBUTES_KEY.to_string(), metadata.file_attributes().to_string().into_bytes()),
+        (CREATION_TIME_KEY.to_string(), metadata.creation_time().to_string().into_bytes()),
+        (LAST_WRITE_TIME_KEY.to_string(), metadata.last_write_time().to_string().into_bytes()),
+    ];
+    if metadata.is_file() {
+        for name in alternate_streams(path)? {
+            let mut stream_path = path.as_os_str().to_owned();
+            stream_path.push(format!(":{}", name));
+            let data = fs::read(&stream_path).map_err(|e| format!("Failed to read stream {} of {}: {}", name, path.display(), e))?;
+            records.push((format!("{}{}", STREAM_KEY_PREFIX, name), base64::engine::general_purpose::STANDARD.encode(data).into_bytes()));
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(not(windows))]
+pub fn pax_records(_path: &Path, _metadata: &fs::Metadata) -> Result<pax::Records, Box<dyn Error>> {
+    Err("Windows metadata can only be captured on Windows".into())
+}
+
+// Restores what pax_records captured onto a restored file. Attributes go last, since a readonly file
+// can't have its streams or times changed
+#[cfg(windows)]
+pub fn apply(path: &Path, records: &pax::Records) -> Result<(), Box<dyn Error>> {
+    use base64::Engine;
+    use std::os::windows::{ffi::OsStrExt, fs::FileTimesExt};
+    let value = |key: &str| records.iter().find(|(k, _)| k == key).and_then(|(_, v)| std::str::from_utf8(v).ok()?.parse::<u64>().ok());
+
+    for (key, data) in records {
+        if let Some(name) = key.strip_prefix(STREAM_KEY_PREFIX) {
+            let mut stream_path = path.as_os_str().to_owned();
+            stream_path.push(format!(":{}", name));
+            fs::write(&stream_path, base64::engine::general_purpose::STANDARD.decode(data)?)?;
+        }
+    }
+    let mut times = fs::FileTimes::new();
+    if let Some(created) = value(CREATION_TIME_KEY) {
+        times = times.set_created(from_filetime(created));
+    }
+    if let Some(modified) = value(LAST_WRITE_TIME_KEY) {
+        times = times.set_modified(from_filetime(modified));
+    }
+    if path.is_file() {
+        fs::OpenOptions::new().write(true).open(path)?.set_times(times)?;
+    }
+    if let Some(attributes) = value(ATTRIBUTES_KEY) {
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        // Safety: `wide` is a NUL-terminated path
+        if unsafe { windows_sys::Win32::Storage::FileSystem::SetFileAttributesW(wide.as_ptr(), attributes as u32) } == 0 {
+            return Err(format!("Failed to set attributes on {}: {}", path.display(), std::io::Error::last_os_error()).into());
+        }
+    }
+    Ok(())
+}
+
+// Windows metadata is meaningless elsewhere, so restoring a Windows archive on another platform just skips it
+#[cfg(not(windows))]
+pub fn apply(_path: &Path, _records: &pax::Records) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+// FILETIMEs count 100ns ticks from 1601-01-01
+#[cfg(windows)]
+fn from_filetime(ticks: u64) -> std::time::SystemTime {
+    const UNIX_EPOCH_TICKS: u64 = 116444736000000000;
+    let since_epoch = std::time::Duration::from_nanos(ticks.abs_diff(UNIX_EPOCH_TICKS) * 100);
+    if ticks >= UNIX_EPOCH_TICKS {
+        std::time::UNIX_EPOCH + since_epoch
+    } else {
+        std::time::UNIX_EPOCH - since_epoch
+    }
+}
+
+// Names of the file's alternate data streams, without the main unnamed one
+#[cfg(windows)]
+fn alternate_streams(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::{
+        Foundation::INVALID_HANDLE_VALUE,
+        Storage::FileSystem::{FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard, WIN32_FIND_STREAM_DATA},
+    };
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut data: WIN32_FIND_STREAM_DATA = unsafe { std::mem::zeroed() };
+    // Safety: `wide` is NUL-terminated and `data` is the struct FindStreamInfoStandard fills in
+    let handle = unsafe { FindFirstStreamW(wide.as_ptr(), FindStreamInfoStandard, &mut data as *mut _ as *mut _, 0) };
+    if handle == INVALID_HANDLE_VALUE {
+        // Files on filesystems without stream support (FAT, network shares) simply have none
+        return Ok(Vec::new());
+    }
+    let mut streams = Vec::new();
+    loop {
+        let len = data.cStreamName.iter().position(|&c| c == 0).unwrap_or(data.cStreamName.len());
+        let name = String::from_utf16_lossy(&data.cStreamName[..len]);
+        // Names look like ":Zone.Identifier:$DATA", and the main stream is "::$DATA"
+        if let Some(name) = name.strip_prefix(':').and_then(|n| n.strip_suffix(":$DATA")) {
+            if !name.is_empty() {
+                streams.push(name.to_string());
+            }
+        }
+        // Safety: same as above, with the handle from FindFirstStreamW
+        if unsafe { FindNextStreamW(handle, &mut data as *mut _ as *mut _) } == 0 {
+            break;
+        }
+    }
+    unsafe { FindClose(handle) };
+    Ok(streams)
+}
@@ -0,0 +1,35 @@
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+
+// How entry paths are written and read, so archives made on Windows restore properly elsewhere and vice versa
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathStyle {
+    // Entry paths and symlink targets use / separators, and drive prefixes are dropped from entry paths on restore
+    #[default]
+    Unix,
+    // Paths are kept exactly as this platform or the archive has them
+    Native,
+}
+
+// Turns an entry path from any platform into one relative to the restore target: backslashes become separators and
+// a drive prefix is dropped along with the root after it, e.g. `C:\Users\me\notes.txt` becomes `Users/me/notes.txt`.
+// Names that aren't valid UTF-8 can't have come from Windows, so they're left alone
+pub fn entry_path(name: &Path, style: PathStyle) -> PathBuf {
+    let Some(text) = name.to_str().filter(|_| style == PathStyle::Unix) else {
+        return name.to_path_buf();
+    };
+    let text = match text.as_bytes() {
+        [drive, b':', ..] if drive.is_ascii_alphabetic() => text[2..].trim_start_matches(['\\', '/']),
+        _ => text,
+    };
+    PathBuf::from(text.replace('\\', "/"))
+}
+
+// A symlink's target with / separators, as written to archives and restored from them. Relative targets from
+// Windows use backslashes, which elsewhere would be part of a file name
+pub fn link_target(target: &Path, style: PathStyle) -> PathBuf {
+    match target.to_str() {
+        Some(text) if style == PathStyle::Unix && text.contains('\\') => PathBuf::from(text.replace('\\', "/")),
+        _ => target.to_path_buf(),
+    }
+}
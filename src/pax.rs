@@ -0,0 +1,32 @@
+use std::io::{self, Write};
+
+// Keys and values of PAX extended header records
+pub type Records = Vec<(String, Vec<u8>)>;
+
+// Appends a PAX extended header, whose records apply to the next entry written to the archive
+pub fn append<W: Write>(archive: &mut tar::Builder<W>, records: &[(String, Vec<u8>)]) -> io::Result<()> {
+    let mut data = Vec::new();
+    for (key, value) in records {
+        data.extend(record(key, value));
+    }
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_path("././@PaxHeader")?;
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive.append(&header, &data[..])
+}
+
+// Records are "<length> <key>=<value>\n", where the length includes its own digits
+pub fn record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
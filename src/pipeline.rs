@@ -0,0 +1,228 @@
+use std::{any::Any, error::Error, fmt, panic::{self, AssertUnwindSafe}, path::PathBuf, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
+use tokio::sync::mpsc;
+use crate::{cancel::Cancelled, space::LowSpace};
+
+// Files the walk can get ahead of the stage taking them before it waits
+const TRAVERSAL_BUFFER: usize = 4096;
+
+pub type StageResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// A backup runs as a traversal stage finding the files, an archive stage writing them out and an upload stage
+// sending the archive off. Each runs on a blocking thread of its own, so none of them hold up the runtime
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    Traversal,
+    Archive,
+    Upload,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Traversal => "traversal",
+            Stage::Archive => "archive",
+            Stage::Upload => "upload",
+        })
+    }
+}
+
+// Runs a stage to completion. Its error comes back as it was for the ones the run handles specially, like being
+// cancelled, and as a message otherwise. A stage that panics fails the run with an error naming it
+pub async fn run<T: Send + 'static>(stage: Stage, work: impl FnOnce() -> Result<T, Box<dyn Error>> + Send + 'static) -> StageResult<T> {
+    joined(stage, tokio::task::spawn_blocking(move || work().map_err(sendable)).await)
+}
+
+// Walks the sources on the traversal stage, which hands each file found over a bounded channel as it goes, so the
+// stage taking them can start on them while the walk carries on. An error from the walk, or it panicking, ends the
+// files with that error, so whatever was taking them fails too rather than finishing with only some of them
+pub fn traverse<W>(walk: W) -> Files
+where
+    W: FnOnce(&mut dyn FnMut(PathBuf) -> StageResult<()>) -> StageResult<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(TRAVERSAL_BUFFER);
+    let walked = Arc::new(AtomicUsize::new(0));
+    let counted = walked.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut found = |file| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            sender.blocking_send(Ok(file)).map_err(|_| "Stopped taking files".into())
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| walk(&mut found)));
+        let result = result.unwrap_or_else(|panic| Err(format!("The {} stage panicked: {}", Stage::Traversal, panic_message(panic)).into()));
+        // Nobody's left to tell if the stage taking files has already given up
+        if let Err(e) = result {
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+    Files { source: Source::Walk(receiver, walked), found: Vec::new() }
+}
+
+// The files for a backup, either still coming from the traversal stage or already listed, as they are once
+// they've been checked for changes. Iterating blocks until the next one's found, so belongs on a blocking stage
+pub struct Files {
+    source: Source,
+    // Every file taken so far
+    found: Vec<PathBuf>,
+}
+
+enum Source {
+    // Along with how many the walk has found so far
+    Walk(mpsc::Receiver<StageResult<PathBuf>>, Arc<AtomicUsize>),
+    Listed(std::vec::IntoIter<PathBuf>),
+}
+
+impl Files {
+    pub fn listed(files: Vec<PathBuf>) -> Files {
+        Files { source: Source::Listed(files.into_iter()), found: Vec::new() }
+    }
+
+    // How many files there are in all, if that's known before they've all been taken
+    pub fn total(&self) -> Option<usize> {
+        match &self.source {
+            Source::Walk(..) => None,
+            Source::Listed(files) => Some(self.found.len() + files.len()),
+        }
+    }
+
+    // The files not taken yet, if they were all listed up front
+    pub fn as_listed(&self) -> Option<&[PathBuf]> {
+        match &self.source {
+            Source::Walk(..) => None,
+            Source::Listed(files) => Some(files.as_slice()),
+        }
+    }
+
+    // How many files have been found so far, taken or not
+    pub fn found_so_far(&self) -> usize {
+        match &self.source {
+            Source::Walk(_, walked) => walked.load(Ordering::Relaxed),
+            Source::Listed(files) => self.found.len() + files.len(),
+        }
+    }
+
+    // Waits for every file, for stages that need them all before they can start, e.g. to check them for changes
+    pub async fn collect(mut self) -> StageResult<Vec<PathBuf>> {
+        match &mut self.source {
+            Source::Walk(receiver, _) => {
+                while let Some(file) = receiver.recv().await {
+                    self.found.push(file?);
+                }
+            },
+            Source::Listed(files) => self.found.extend(files),
+        }
+        Ok(self.found)
+    }
+
+    // Takes the rest, for writers that need every file up front. Returns all the files, including ones already taken
+    pub fn all(&mut self) -> Result<&[PathBuf], Box<dyn Error>> {
+        for file in self.by_ref() {
+            file?;
+        }
+        Ok(&self.found)
+    }
+
+    // Every file taken
+    pub fn into_found(self) -> Vec<PathBuf> {
+        self.found
+    }
+}
+
+impl Iterator for Files {
+    type Item = Result<PathBuf, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let file = match &mut self.source {
+            Source::Walk(receiver, _) => receiver.blocking_recv()?.map_err(|e| e as Box<dyn Error>),
+            Source::Listed(files) => Ok(files.next()?),
+        };
+        if let Ok(file) = &file {
+            self.found.push(file.clone());
+        }
+        Some(file)
+    }
+}
+
+fn joined<T>(stage: Stage, joined: Result<StageResult<T>, tokio::task::JoinError>) -> StageResult<T> {
+    match joined {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => Err(format!("The {} stage panicked: {}", stage, panic_message(e.into_panic())).into()),
+        Err(_) => Err(format!("The {} stage was stopped", stage).into()),
+    }
+}
+
+// Errors the run tells apart by type keep it, the rest are passed on as their message
+fn sendable(e: Box<dyn Error>) -> Box<dyn Error + Send + Sync> {
+    let e = match e.downcast::<Cancelled>() {
+        Ok(cancelled) => return cancelled,
+        Err(e) => e,
+    };
+    match e.downcast::<LowSpace>() {
+        Ok(low) => low,
+        Err(e) => e.to_string().into(),
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map(|message| message.to_string()).unwrap_or_else(|| "unknown cause".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn walk_waits_for_the_stage_taking_files_and_ends_them_with_its_error() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let files = traverse({
+            let sent = sent.clone();
+            move |found| {
+                for i in 0..TRAVERSAL_BUFFER * 3 {
+                    found(PathBuf::from(i.to_string()))?;
+                    sent.fetch_add(1, Ordering::Relaxed);
+                }
+                Err("walk failed".into())
+            }
+        });
+        let (ahead, taken, error, ended) = tokio::task::spawn_blocking(move || {
+            let mut files = files;
+            std::thread::sleep(Duration::from_millis(200));
+            let ahead = sent.load(Ordering::Relaxed);
+            let taken = files.by_ref().take(TRAVERSAL_BUFFER * 3).filter(Result::is_ok).count();
+            let error = files.next().and_then(|file| file.err()).map(|e| e.to_string());
+            (ahead, taken, error, files.next().is_none())
+        })
+        .await
+        .unwrap();
+        // Nothing was taken for a while, so the walk got no more than the channel's worth ahead
+        assert!(ahead <= TRAVERSAL_BUFFER + 1, "{}", ahead);
+        assert_eq!(taken, TRAVERSAL_BUFFER * 3);
+        assert_eq!(error.as_deref(), Some("walk failed"));
+        assert!(ended);
+
+        let e = traverse(|_| Err("walk failed".into())).collect().await.unwrap_err();
+        assert_eq!(e.to_string(), "walk failed");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_walk_that_panics_fails_the_stage_taking_files() {
+        let files = traverse(|found| {
+            found(PathBuf::from("a"))?;
+            panic!("lost");
+        });
+        let e = files.collect().await.unwrap_err();
+        assert_eq!(e.to_string(), "The traversal stage panicked: lost");
+    }
+
+    #[test]
+    fn listed_files_are_counted_up_front() {
+        let mut files = Files::listed(vec![PathBuf::from("a"), PathBuf::from("b")]);
+        assert_eq!(files.total(), Some(2));
+        assert_eq!(files.next().unwrap().unwrap(), PathBuf::from("a"));
+        assert_eq!(files.total(), Some(2));
+        assert_eq!(files.all().unwrap(), [PathBuf::from("a"), PathBuf::from("b")]);
+    }
+}
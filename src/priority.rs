@@ -0,0 +1,55 @@
+use std::error::Error;
+
+// Lowest CPU priority, as with `nice -n 19`
+#[cfg(unix)]
+const NICE_LOWEST: libc::c_int = 19;
+
+// Lowers the process to background priority, so a backup on a busy machine only gets CPU and disk time that
+// nothing else wants. Can't be undone, which is fine since it's applied for the whole run
+#[cfg(target_os = "linux")]
+pub fn lower() -> Result<(), Box<dyn Error>> {
+    // Niceness and I/O priority are per thread on Linux, and the runtime's threads already exist, so each one is
+    // lowered. Threads started later inherit it from whichever thread starts them
+    for task in std::fs::read_dir("/proc/self/task")? {
+        let Ok(tid) = task?.file_name().to_string_lossy().parse::<libc::id_t>() else {
+            continue;
+        };
+        // Safety: plain syscalls on ids we just read
+        unsafe {
+            if libc::setpriority(libc::PRIO_PROCESS, tid, NICE_LOWEST) != 0 {
+                return Err(format!("Failed to lower CPU priority: {}", std::io::Error::last_os_error()).into());
+            }
+            // ioprio_set(IOPRIO_WHO_PROCESS, tid, IOPRIO_CLASS_IDLE), which libc has no wrapper for
+            if libc::syscall(libc::SYS_ioprio_set, 1, tid, 3 << 13) != 0 {
+                return Err(format!("Failed to set idle I/O priority: {}", std::io::Error::last_os_error()).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Elsewhere niceness covers the whole process, and there's no portable way to lower I/O priority
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn lower() -> Result<(), Box<dyn Error>> {
+    // Safety: plain syscall
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, NICE_LOWEST) } != 0 {
+        return Err(format!("Failed to lower CPU priority: {}", std::io::Error::last_os_error()).into());
+    }
+    Ok(())
+}
+
+// Background mode lowers both CPU and I/O priority for the whole process
+#[cfg(windows)]
+pub fn lower() -> Result<(), Box<dyn Error>> {
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, SetPriorityClass, PROCESS_MODE_BACKGROUND_BEGIN};
+    // Safety: GetCurrentProcess returns a pseudo handle that needs no closing
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        return Err(format!("Failed to enter background mode: {}", std::io::Error::last_os_error()).into());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn lower() -> Result<(), Box<dyn Error>> {
+    Err("--background isn't supported on this platform".into())
+}
@@ -0,0 +1,119 @@
+use std::{path::Path, error::Error};
+
+// The unprivileged account to switch to once sources have been read, from `--run-as user[:group]`
+#[derive(Clone, Debug)]
+pub struct RunAs {
+    pub user: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// Resolves `user` or `user:group`, defaulting to the user's primary group
+#[cfg(unix)]
+pub fn parse_run_as(input: &str) -> Result<RunAs, String> {
+    use std::ffi::CString;
+    let (user, group) = match input.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (input, None),
+    };
+    let name = CString::new(user).map_err(|_| format!("invalid user '{}'", user))?;
+    // Safety: getpwnam returns null or a pointer to a static record, which is copied out of straight away
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("no such user '{}'", user));
+    }
+    let (uid, primary_gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+    let gid = match group {
+        Some(group) => {
+            let name = CString::new(group).map_err(|_| format!("invalid group '{}'", group))?;
+            // Safety: as above, for the group record
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                return Err(format!("no such group '{}'", group));
+            }
+            unsafe { (*entry).gr_gid }
+        },
+        None => primary_gid,
+    };
+    Ok(RunAs { user: user.to_string(), uid, gid })
+}
+
+#[cfg(not(unix))]
+pub fn parse_run_as(_input: &str) -> Result<RunAs, String> {
+    Err("users and groups can only be looked up on unix".to_string())
+}
+
+#[cfg(unix)]
+impl RunAs {
+    // Hands a file written while privileged over to the account, so it can still be managed after the switch
+    pub fn chown(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid)).map_err(|e| format!("Failed to chown {}: {}", path.display(), e).into())
+    }
+
+    // Switches to the account for the rest of the process. Supplementary groups go first, since root's would
+    // otherwise be kept, and the group before the user, since changing it needs root
+    pub fn drop_privileges(&self) -> Result<(), Box<dyn Error>> {
+        // Safety: plain syscalls on values we own
+        unsafe {
+            if libc::setgroups(1, &self.gid) != 0 || libc::setgid(self.gid) != 0 || libc::setuid(self.uid) != 0 {
+                return Err(format!("Failed to switch to {}: {}", self.user, std::io::Error::last_os_error()).into());
+            }
+            // Getting root back has to fail, or the switch didn't stick
+            if self.uid != 0 && libc::setuid(0) == 0 {
+                return Err(format!("Failed to switch to {}: root could still be regained", self.user).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+impl RunAs {
+    pub fn chown(&self, _path: &Path) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    pub fn drop_privileges(&self) -> Result<(), Box<dyn Error>> {
+        Err("--run-as is only supported on unix".into())
+    }
+}
+
+// Whether switching accounts is possible at all, which needs root
+pub fn is_root() -> bool {
+    #[cfg(unix)]
+    // Safety: geteuid can't fail
+    return unsafe { libc::geteuid() } == 0;
+    #[cfg(not(unix))]
+    return false;
+}
+
+// Refuses destinations reached through a symlink in a world-writable directory, like /tmp. Anyone could have
+// planted or swapped that link to point a privileged write somewhere else
+pub fn check_destination(dest: &Path) -> Result<(), Box<dyn Error>> {
+    let mut current = std::path::PathBuf::new();
+    for component in dest.components() {
+        let parent = current.clone();
+        current.push(component);
+        let is_symlink = current.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false);
+        if is_symlink && is_world_writable(if parent.as_os_str().is_empty() { Path::new(".") } else { &parent }) {
+            return Err(format!(
+                "Refusing to write through {}: it's a symlink in world-writable {}",
+                current.display(),
+                parent.display()
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_world_writable(dir: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    dir.metadata().map(|m| m.permissions().mode() & 0o002 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_world_writable(_dir: &Path) -> bool {
+    false
+}
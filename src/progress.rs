@@ -0,0 +1,132 @@
+use std::{io::{self, Read}, path::Path, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::Duration};
+use indicatif::{MultiProgress, ProgressBar};
+use crate::utils;
+
+// Receives progress from archiving, so whatever drives it can show its own UI. Every method defaults to doing
+// nothing, so observers only implement what they display
+pub trait Progress: Send + Sync {
+    // A new phase of work started, with how many files it covers if known
+    fn on_phase(&self, _phase: &str, _total: Option<u64>) {}
+    // More files were found for a phase that started before they all had been, making `total` so far
+    fn on_found(&self, _total: u64) {}
+    // Started on the next file, of `size` bytes
+    fn on_file(&self, _path: &Path, _size: u64) {}
+    // Read `bytes` more of the current file
+    fn on_bytes(&self, _bytes: u64) {}
+    // Something went wrong that doesn't stop the work, e.g. a file stored as a placeholder
+    fn on_warning(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+    // Something to log alongside the progress, like each file as it's archived with verbose logging on
+    fn on_message(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+// For work nobody's watching. Warnings are still printed
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+// The CLI's observer: a bar counting files, plus a byte-level bar for each file of at least `file_threshold` bytes
+pub struct BarProgress {
+    bar: ProgressBar,
+    multi: Option<MultiProgress>,
+    file_threshold: Option<u64>,
+    file_bar: Mutex<Option<ProgressBar>>,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl BarProgress {
+    pub fn new(bar: ProgressBar, file_threshold: Option<u64>) -> BarProgress {
+        let multi = file_threshold.map(|_| {
+            let multi = MultiProgress::with_draw_target(utils::progress_target());
+            multi.add(bar.clone());
+            multi
+        });
+        BarProgress { bar, multi, file_threshold, file_bar: Mutex::new(None), files: AtomicU64::new(0), bytes: AtomicU64::new(0) }
+    }
+
+    fn finish_file(&self) {
+        if let (Some(multi), Some(bar)) = (&self.multi, self.file_bar.lock().unwrap().take()) {
+            bar.finish_and_clear();
+            multi.remove(&bar);
+        }
+    }
+
+    pub fn finish(&self) {
+        self.finish_file();
+        self.bar.finish_and_clear();
+    }
+
+    // Every byte of file contents read so far, which is what the source held whether it's local or not
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl Progress for BarProgress {
+    fn on_phase(&self, phase: &str, total: Option<u64>) {
+        self.finish_file();
+        self.bar.set_message(phase.to_string());
+        if let Some(total) = total {
+            self.bar.set_length(total);
+        }
+        self.files.store(0, Ordering::Relaxed);
+        self.bar.set_position(0);
+        self.bar.enable_steady_tick(Duration::from_millis(150));
+    }
+
+    fn on_found(&self, total: u64) {
+        self.bar.set_length(total);
+    }
+
+    fn on_file(&self, path: &Path, size: u64) {
+        self.finish_file();
+        // The bar counts finished files, which is all of them before this one
+        self.bar.set_position(self.files.fetch_add(1, Ordering::Relaxed));
+        if let (Some(multi), Some(threshold)) = (&self.multi, self.file_threshold) {
+            if size >= threshold {
+                let bar = multi.insert_before(&self.bar, utils::construct_file_progress(size));
+                bar.set_message(path.display().to_string());
+                *self.file_bar.lock().unwrap() = Some(bar);
+            }
+        }
+    }
+
+    fn on_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(bar) = &*self.file_bar.lock().unwrap() {
+            bar.inc(bytes);
+        }
+    }
+
+    fn on_warning(&self, message: &str) {
+        self.bar.suspend(|| eprintln!("{}", message));
+    }
+
+    fn on_message(&self, message: &str) {
+        self.bar.suspend(|| eprintln!("{}", message));
+    }
+}
+
+// Reports everything read through it as bytes of the current file
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a dyn Progress,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a dyn Progress) -> ProgressReader<'a, R> {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.on_bytes(read as u64);
+        Ok(read)
+    }
+}
@@ -0,0 +1,122 @@
+use std::{collections::VecDeque, fs, io, ops::Deref, path::Path, sync::{Arc, Mutex}, error::Error};
+
+// Answers yes/no questions, e.g. whether to create a missing output directory
+pub trait Prompter: Send + Sync {
+    // Asks `question` after printing `message`, returning `default` if there's no usable answer
+    fn confirm(&self, message: &str, question: &str, default: bool) -> bool;
+}
+
+// Reads answers from stdin, whether that's a terminal or a pipe
+pub struct TtyPrompter;
+
+impl Prompter for TtyPrompter {
+    fn confirm(&self, message: &str, question: &str, default: bool) -> bool {
+        ask(message, question, default);
+        let mut input = String::new();
+        loop {
+            input.clear();
+            // Nothing left to read, e.g. stdin is /dev/null or a pipe that's run dry
+            if io::stdin().read_line(&mut input).unwrap_or(0) == 0 {
+                eprintln!();
+                return default;
+            }
+            match parse(&input) {
+                Some(answer) => return answer.unwrap_or(default),
+                None => eprint!("Invalid input, please enter 'y' or 'n': "),
+            }
+        }
+    }
+}
+
+// Answers every question with the same answer, for --yes and --no
+pub struct Always(pub bool);
+
+impl Prompter for Always {
+    fn confirm(&self, message: &str, question: &str, _default: bool) -> bool {
+        answered(message, question, self.0);
+        self.0
+    }
+}
+
+// Answers questions in order from a list given up front, falling back to each question's default once it runs out
+pub struct Scripted {
+    answers: Mutex<VecDeque<bool>>,
+}
+
+impl Scripted {
+    // Answers separated by commas or newlines, e.g. "y,n,y"
+    pub fn parse(script: &str) -> Result<Scripted, Box<dyn Error>> {
+        let mut answers = VecDeque::new();
+        for answer in script.split([',', '\n']).filter(|answer| !answer.trim().is_empty()) {
+            match parse(answer) {
+                Some(Some(answer)) => answers.push_back(answer),
+                _ => return Err(format!("Invalid scripted answer '{}', expected 'y' or 'n'", answer.trim()).into()),
+            }
+        }
+        Ok(Scripted { answers: Mutex::new(answers) })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Scripted, Box<dyn Error>> {
+        let script = fs::read_to_string(path).map_err(|e| format!("Failed to read answers from {}: {}", path.display(), e))?;
+        Scripted::parse(&script)
+    }
+}
+
+impl Prompter for Scripted {
+    fn confirm(&self, message: &str, question: &str, default: bool) -> bool {
+        let answer = self.answers.lock().unwrap().pop_front().unwrap_or(default);
+        answered(message, question, answer);
+        answer
+    }
+}
+
+// A prompter that can be shared between the options of a run and the threads doing its work
+#[derive(Clone)]
+pub struct SharedPrompter(pub Arc<dyn Prompter>);
+
+impl Default for SharedPrompter {
+    fn default() -> SharedPrompter {
+        SharedPrompter(Arc::new(TtyPrompter))
+    }
+}
+
+impl Deref for SharedPrompter {
+    type Target = dyn Prompter;
+
+    fn deref(&self) -> &(dyn Prompter + 'static) {
+        self.0.as_ref()
+    }
+}
+
+// Picks the prompter for the answers given on the command line, reading from stdin if there are none
+pub fn from_args(yes: bool, no: bool, answers: Option<&str>, answers_file: Option<&Path>) -> Result<SharedPrompter, Box<dyn Error>> {
+    let prompter: Arc<dyn Prompter> = match (yes, no, answers, answers_file) {
+        (true, _, _, _) => Arc::new(Always(true)),
+        (_, true, _, _) => Arc::new(Always(false)),
+        (_, _, Some(answers), _) => Arc::new(Scripted::parse(answers)?),
+        (_, _, _, Some(path)) => Arc::new(Scripted::from_file(path)?),
+        _ => Arc::new(TtyPrompter),
+    };
+    Ok(SharedPrompter(prompter))
+}
+
+fn ask(message: &str, question: &str, default: bool) {
+    eprintln!("{}", message);
+    eprint!("{} {} ", question, if default { "[Y/n]" } else { "[y/N]" });
+}
+
+// Shows answers that weren't typed in, so logs of unattended runs still say what was decided
+fn answered(message: &str, question: &str, answer: bool) {
+    eprintln!("{}", message);
+    eprintln!("{} {}", question, if answer { "y" } else { "n" });
+}
+
+// Some(None) for an empty answer, which takes the default, or None if it isn't an answer at all
+fn parse(input: &str) -> Option<Option<bool>> {
+    match input.trim().to_lowercase().as_str() {
+        "y" | "yes" => Some(Some(true)),
+        "n" | "no" => Some(Some(false)),
+        "" => Some(None),
+        _ => None,
+    }
+}
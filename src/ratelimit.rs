@@ -0,0 +1,69 @@
+use std::{io::{self, Read}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+use crate::utils;
+
+// How far ahead of the limit a burst can get after being idle, as a fraction of a second's worth of bytes
+const BURST: f64 = 0.25;
+
+// Parses a rate such as "50MB/s" or "512KiB", which is taken to be per second as well
+pub fn parse_rate(input: &str) -> Result<u64, String> {
+    let size = input.trim().strip_suffix("/s").unwrap_or(input);
+    match utils::parse_size(size)? {
+        0 => Err("Rate must be more than 0".to_string()),
+        rate => Ok(rate),
+    }
+}
+
+// Limits the bytes per second passing through every reader it's shared with, across threads, so one limit
+// covers a whole run however many files it reads at once
+#[derive(Clone, Debug)]
+pub struct RateLimiter(Arc<Mutex<Bucket>>);
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    // Bytes that can pass before anything has to wait. Goes negative when a read takes more than there was,
+    // which the next caller waits out
+    available: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter(Arc::new(Mutex::new(Bucket { rate: bytes_per_second as f64, available: 0.0, refilled: Instant::now() })))
+    }
+
+    // Accounts for `bytes` that have passed, sleeping until they're within the limit
+    pub fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.0.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * bucket.rate;
+            bucket.available = (bucket.available + refill).min(bucket.rate * BURST) - bytes as f64;
+            bucket.refilled = now;
+            if bucket.available < 0.0 { Duration::from_secs_f64(-bucket.available / bucket.rate) } else { Duration::ZERO }
+        };
+        thread::sleep(wait);
+    }
+}
+
+// A reader held to a shared rate limit, or passed straight through without one
+pub struct LimitedReader<R> {
+    inner: R,
+    limiter: Option<RateLimiter>,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, limiter: Option<RateLimiter>) -> LimitedReader<R> {
+        LimitedReader { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(limiter) = &self.limiter {
+            limiter.take(read);
+        }
+        Ok(read)
+    }
+}
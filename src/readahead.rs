@@ -0,0 +1,113 @@
+use std::{io::{self, BufReader, Read}, path::Path, sync::mpsc, thread};
+use crate::{busy, ratelimit::LimitedReader, utils};
+
+// Read buffer for sources on local filesystems
+pub const LOCAL_BUFFER_SIZE: usize = 256 * 1024;
+// Network filesystems have much higher latency per request, so are read in bigger chunks
+pub const NETWORK_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+pub const MIN_BUFFER_SIZE: usize = 4096;
+// Buffers a file being read ahead has in memory at once: one being consumed, one waiting and one being filled
+pub const READ_BUFFERS: u64 = 3;
+
+// Picks a read buffer size for the filesystem holding `path`, along with the kind of filesystem it was picked for
+#[cfg(target_os = "linux")]
+pub fn default_buffer_size(path: &Path) -> (usize, &'static str) {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return (LOCAL_BUFFER_SIZE, "local");
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return (LOCAL_BUFFER_SIZE, "local");
+    }
+    // Magic numbers from statfs(2)
+    #[allow(clippy::unnecessary_cast)]
+    match stat.f_type as u32 {
+        0x6969 => (NETWORK_BUFFER_SIZE, "nfs"),
+        0xFF534D42 | 0xFE534D42 | 0x517B => (NETWORK_BUFFER_SIZE, "smb"),
+        0x65735546 => (NETWORK_BUFFER_SIZE, "fuse"),
+        0x6B414653 => (NETWORK_BUFFER_SIZE, "afs"),
+        0x00C36400 => (NETWORK_BUFFER_SIZE, "ceph"),
+        _ => (LOCAL_BUFFER_SIZE, "local"),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn default_buffer_size(_path: &Path) -> (usize, &'static str) {
+    (LOCAL_BUFFER_SIZE, "local")
+}
+
+// Opens a source file for reading into the archive, in chunks of the run's buffer size and held to --limit-read
+pub fn open(path: &Path, size: u64, options: &utils::Options) -> io::Result<Box<dyn Read + Send>> {
+    let file = LimitedReader::new(busy::open(&*options.fs, path, options.busy_retries)?, options.limit_read.clone());
+    Ok(reader(file, size, options.buffer_size.unwrap_or(LOCAL_BUFFER_SIZE)))
+}
+
+// Wraps a source file for reading into the archive. Files bigger than one buffer are read ahead on another thread,
+// so the next chunk is already coming off the disk while the compressor works on the last one
+pub fn reader<R: Read + Send + 'static>(file: R, size: u64, buffer_size: usize) -> Box<dyn Read + Send> {
+    if size > buffer_size as u64 {
+        Box::new(ReadAhead::new(file, buffer_size))
+    } else {
+        Box::new(BufReader::with_capacity(buffer_size, file))
+    }
+}
+
+// Double buffering: a thread fills one buffer while the other is being consumed
+pub struct ReadAhead {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl ReadAhead {
+    pub fn new<R: Read + Send + 'static>(mut inner: R, buffer_size: usize) -> ReadAhead {
+        // One chunk waiting in the channel while the reader fills the next
+        let (sender, chunks) = mpsc::sync_channel(1);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; buffer_size];
+            let result = fill(&mut inner, &mut chunk).map(|read| {
+                chunk.truncate(read);
+                chunk
+            });
+            let end = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+            // Sending fails once the reader's been dropped, so there's no one left to read for
+            if sender.send(result).is_err() || end {
+                break;
+            }
+        });
+        ReadAhead { chunks, current: Vec::new(), position: 0 }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.current.len() {
+            self.current = match self.chunks.recv() {
+                Ok(chunk) => chunk?,
+                // The thread stopped after sending the end
+                Err(_) => return Ok(0),
+            };
+            self.position = 0;
+        }
+        let read = buf.len().min(self.current.len() - self.position);
+        buf[..read].copy_from_slice(&self.current[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+// Reads until the buffer is full or the end is reached, so chunks are as big as asked for even where reads come
+// back short
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
@@ -0,0 +1,251 @@
+use std::{collections::BTreeMap, io::Read, path::{Path, PathBuf}, error::Error};
+use clap::Subcommand;
+use flate2::read::MultiGzDecoder;
+use crate::{b2::{B2Backend, LifecycleRule}, backend::{self, Backend, BackendKind, RemoteOptions, StorageClass}, download::{self, DownloadOptions}, index::{self, EntryIndex}, manifest, pathstyle::PathStyle, ratelimit, repo, restore, utils};
+
+#[derive(Subcommand, Debug)]
+pub enum RemoteCommand {
+    #[command(about = "List uploaded archives, grouped by the host that made them")]
+    Ls {
+        #[command(flatten)]
+        remote: RemoteOptions,
+        // Only list archives from this host. With --remote-prefix, it's found where the template has {hostname}
+        #[arg(long = "host", env = "ATHENA_HOST")]
+        host: Option<String>,
+    },
+    #[command(about = "Show or set B2 lifecycle rules expiring archives under the prefix server-side")]
+    Lifecycle {
+        #[command(flatten)]
+        remote: RemoteOptions,
+        // Hide archives this many days after upload, and delete them a day later
+        #[arg(long = "keep-days", env = "ATHENA_KEEP_DAYS", value_parser = clap::value_parser!(u32).range(1..))]
+        keep_days: Option<u32>,
+        // Remove the rule for the prefix
+        #[arg(long = "clear", conflicts_with = "keep_days")]
+        clear: bool,
+    },
+    #[command(about = "Restore an uploaded archive, downloading only the entries needed when it has an entry index")]
+    Restore {
+        #[command(flatten)]
+        remote: RemoteOptions,
+        // Key of the archive relative to the prefix, as shown by `remote ls`, e.g. <host>/<name>
+        key: String,
+        // Only restore these paths (and anything under them)
+        paths: Vec<String>,
+        #[arg(short = 't', long = "target", env = "ATHENA_TARGET")]
+        target: PathBuf,
+        // Point absolute symlinks under OLD at NEW instead, e.g. when restoring to a different root
+        #[arg(long = "rewrite-links", value_name = "OLD=NEW", value_parser = restore::parse_link_rewrite, env = "ATHENA_REWRITE_LINKS")]
+        rewrite_links: Vec<restore::LinkRewrite>,
+        // Restore entry paths as-is, even absolute ones or ones with `..`. Only for archives you trust
+        #[arg(long = "unsafe-paths", env = "ATHENA_UNSAFE_PATHS")]
+        unsafe_paths: bool,
+        // `unix` treats backslashes in entry paths as separators and drops drive prefixes, `native` uses them as stored
+        #[arg(long = "path-style", value_enum, default_value = "unix", env = "ATHENA_PATH_STYLE")]
+        path_style: PathStyle,
+        // Restore file capabilities and immutable/append-only flags recorded on Linux. Needs root
+        #[arg(long = "file-attrs", env = "ATHENA_FILE_ATTRS")]
+        file_attrs: bool,
+        #[command(flatten)]
+        owners: restore::OwnerOptions,
+        // Most bytes per second to download, e.g. 20MB/s, shared by all the ranges being fetched
+        #[arg(long = "limit-download", value_parser = ratelimit::parse_rate, env = "ATHENA_LIMIT_DOWNLOAD")]
+        limit_download: Option<u64>,
+        // Ranges of the archive to download at once
+        #[arg(long = "parallel-downloads", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..64), env = "ATHENA_PARALLEL_DOWNLOADS")]
+        parallel_downloads: u64,
+    },
+    #[command(about = "Copy an uploaded archive to another backend, e.g. to move between providers")]
+    Copy {
+        // URL of the archive, e.g. b2://bucket/prefix/<host>/<name>
+        from: String,
+        // URL to copy it to. Ending it in / keeps the archive's name
+        to: String,
+    },
+}
+
+pub fn run(command: RemoteCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        RemoteCommand::Ls { remote, host } => {
+            let backend = backend::connect(&remote)?;
+            let base = remote.key_for("");
+            // Archives uploaded with host scoping are at <host>/<name>, older or unscoped ones directly under the prefix
+            let mut hosts: BTreeMap<String, Vec<(String, String, u64)>> = BTreeMap::new();
+            for (key, size) in backend.list(&base)? {
+                if key.ends_with(index::INDEX_SUFFIX) || key.ends_with(manifest::RUN_MANIFEST_SUFFIX) {
+                    continue;
+                }
+                let rest = key.strip_prefix(&base).unwrap_or(&key).trim_start_matches('/');
+                // Under a --remote-prefix template the host is wherever it puts {hostname}, if anywhere
+                let templated = remote.remote_prefix.as_deref().and_then(|template| backend::split_templated_key(template, rest));
+                let (origin, name) = match (templated, rest.split_once('/')) {
+                    (Some((host, name)), _) => (host.unwrap_or_else(|| "(no host)".to_string()), name),
+                    (None, Some((origin, name))) if remote.remote_prefix.is_none() => (origin.to_string(), name.to_string()),
+                    _ => ("(no host)".to_string(), rest.to_string()),
+                };
+                hosts.entry(origin).or_default().push((key.clone(), name, size));
+            }
+            if let Some(host) = &host {
+                hosts.retain(|origin, _| origin == host);
+            }
+            if hosts.is_empty() {
+                println!("No archives found at {}", backend.url(&base));
+            }
+            for (origin, mut archives) in hosts {
+                archives.sort_by(|a, b| a.1.cmp(&b.1));
+                println!("{} ({} archives)", origin, archives.len());
+                for (key, name, size) in archives {
+                    println!("  {:<60} {:>10}", name, utils::format_size(size));
+                    let info = backend.info(&key)?;
+                    let value = |name: &str| info.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+                    if let Some(comment) = value("comment") {
+                        println!("    {}", comment);
+                    }
+                    if let Some(class) = value("storage-class").and_then(StorageClass::from_name) {
+                        if let Some(retrieval) = class.retrieval() {
+                            println!("    [{}] {}", class.name(), retrieval);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        },
+        RemoteCommand::Lifecycle { remote, keep_days, clear } => {
+            if remote.backend != BackendKind::B2 {
+                return Err("Lifecycle rules are only supported by the b2 backend".into());
+            }
+            let bucket = remote.bucket.as_deref().ok_or("No bucket specified, pass --bucket")?;
+            let backend = B2Backend::connect(bucket, &remote.http)?;
+            let prefix = remote.key_for("");
+            let rules = if keep_days.is_some() || clear {
+                if prefix.is_empty() && keep_days.is_some() {
+                    eprintln!("Warning: no --prefix given, so the rule covers the whole bucket");
+                }
+                backend.set_lifecycle(&prefix, keep_days)?
+            } else {
+                backend.lifecycle_rules()?
+            };
+            if rules.is_empty() {
+                println!("No lifecycle rules on {}", bucket);
+            }
+            for rule in rules {
+                println!("{}{}", describe(&rule), if rule.file_name_prefix == prefix { "  (athena prefix)" } else { "" });
+            }
+            Ok(())
+        },
+        RemoteCommand::Restore { remote, key, paths, target, rewrite_links, unsafe_paths, path_style, file_attrs, owners, limit_download, parallel_downloads } => {
+            owners.check()?;
+            let backend = backend::connect(&remote)?;
+            let key = remote.key_for(&key);
+            let options = restore::RestoreOptions { target: &target, paths: &paths, rewrites: &rewrite_links, password_file: None, unsafe_paths, apply_deletions: false, path_style, file_attrs, owners: &owners };
+            let downloads = DownloadOptions { limit: limit_download.map(ratelimit::RateLimiter::new), parallel: parallel_downloads as usize };
+            let objects = backend.list(&key)?;
+            let size = objects.iter().find(|(k, _)| *k == key).map(|(_, size)| *size).ok_or_else(|| format!("No archive at {}", backend.url(&key)))?;
+            let info = backend.info(&key)?;
+            if let Some(class) = info.iter().find(|(k, _)| k == "storage-class").and_then(|(_, v)| StorageClass::from_name(v)) {
+                if let Some(retrieval) = class.retrieval() {
+                    eprintln!("Note: archive is in {} storage: {}", class.name(), retrieval);
+                }
+            }
+
+            std::fs::create_dir_all(&target)?;
+            let index_key = format!("{}{}", key, index::INDEX_SUFFIX);
+            let restored = if !paths.is_empty() && objects.iter().any(|(k, _)| *k == index_key) {
+                restore_ranges(backend.as_ref(), &key, &index_key, size, &options, &downloads)?
+            } else {
+                let archive = download::fetch(backend.as_ref(), &key, size, &downloads)?;
+                println!("Downloaded {}", utils::format_size(archive.fetched));
+                // Nothing is extracted from an archive that didn't arrive intact. It's fetched from scratch next time
+                match download::verify(backend.as_ref(), &key, &archive.path) {
+                    Ok(true) => println!("Verified sha256 of {}", backend.url(&key)),
+                    Ok(false) => eprintln!("Warning: {} has no run manifest, so the download can't be checked", backend.url(&key)),
+                    Err(e) => {
+                        archive.remove();
+                        return Err(e);
+                    },
+                }
+                let restored = restore::run(&archive.path, &options);
+                archive.remove();
+                restored?
+            };
+            if restored == 0 && !paths.is_empty() {
+                return Err("No entries in the archive match the given paths".into());
+            }
+            println!("Restored {} files to {}", restored, target.display());
+            Ok(())
+        },
+        RemoteCommand::Copy { from, to } => {
+            let (source, from_key) = object(&from)?;
+            let to = match to.ends_with('/') {
+                true => format!("{}{}", to, from_key.rsplit('/').next().unwrap_or(&from_key)),
+                false => to,
+            };
+            let (dest, to_key) = object(&to)?;
+            let copied = backend::copy(source.as_ref(), &from_key, dest.as_ref(), &to_key)?;
+            // The entry index and run manifest go along with the archive, so ranged restores still work from the copy
+            let keys: Vec<String> = source.list(&from_key)?.into_iter().map(|(key, _)| key).collect();
+            for suffix in [index::INDEX_SUFFIX, manifest::RUN_MANIFEST_SUFFIX] {
+                let sidecar = format!("{}{}", from_key, suffix);
+                if keys.contains(&sidecar) {
+                    backend::copy(source.as_ref(), &sidecar, dest.as_ref(), &format!("{}{}", to_key, suffix))?;
+                }
+            }
+            println!("Copied {} from {} to {}", utils::format_size(copied), source.url(&from_key), dest.url(&to_key));
+            Ok(())
+        },
+    }
+}
+
+// Fetches just the byte ranges of the wanted entries and restores them as if they were a tar of their own. For a
+// seekable compressed archive, that's the frames holding the entries, decompressed and trimmed to them
+fn restore_ranges(backend: &dyn Backend, key: &str, index_key: &str, size: u64, options: &restore::RestoreOptions, downloads: &DownloadOptions) -> Result<usize, Box<dyn Error>> {
+    let index: EntryIndex = serde_json::from_slice(&backend.download(index_key)?)?;
+    let mut data = Vec::new();
+    let mut downloaded = 0;
+    for (start, end) in index.ranges(|path| repo::matches_paths(path, options.paths)) {
+        if index.frames.is_empty() {
+            data.extend(download::range(backend, key, start, end, downloads)?);
+            downloaded += end - start;
+            continue;
+        }
+        let (offset, offset_end, frame_start) = index.frame_span(start, end, size);
+        let compressed = download::range(backend, key, offset, offset_end, downloads)?;
+        downloaded += compressed.len() as u64;
+        let mut frames = Vec::new();
+        MultiGzDecoder::new(&compressed[..]).read_to_end(&mut frames)?;
+        let entries = frames.get((start - frame_start) as usize..(end - frame_start) as usize).ok_or("Entry index doesn't match the archive")?;
+        data.extend_from_slice(entries);
+    }
+    println!("Downloaded {} of {}", utils::format_size(downloaded), utils::format_size(size));
+    // Two zero blocks end the archive
+    data.extend([0; 1024]);
+    restore::restore_tar(&data[..], options)
+}
+
+// Connects to the backend a URL like b2://bucket/key or file:///dir/name points into, returning the object's key
+fn object(url: &str) -> Result<(Box<dyn Backend>, String), Box<dyn Error>> {
+    let mut remote = RemoteOptions::from_url(url)?.ok_or_else(|| format!("'{}' isn't a URL, expected e.g. b2://bucket/key or file:///dir/name", url))?;
+    // file:// URLs are all path, so the last part is the key and the rest the directory it's in
+    if remote.backend == BackendKind::Local {
+        let path = PathBuf::from(remote.bucket.take().unwrap_or_default());
+        let name = path.file_name().ok_or_else(|| format!("No file name in '{}'", url))?;
+        remote.prefix = name.to_string_lossy().into_owned();
+        remote.bucket = Some(path.parent().unwrap_or(Path::new("/")).to_string_lossy().into_owned());
+    }
+    let key = remote.key_for("").trim_matches('/').to_string();
+    if key.is_empty() {
+        return Err(format!("No key in '{}'", url).into());
+    }
+    Ok((backend::connect(&remote)?, key))
+}
+
+fn describe(rule: &LifecycleRule) -> String {
+    let prefix = if rule.file_name_prefix.is_empty() { "(whole bucket)" } else { &rule.file_name_prefix };
+    let days = |days: Option<u32>| days.map(|d| format!("{} day{}", d, if d == 1 { "" } else { "s" })).unwrap_or_else(|| "never".to_string());
+    format!(
+        "{}: hidden after {}, deleted {} after hiding",
+        prefix,
+        days(rule.days_from_uploading_to_hiding),
+        days(rule.days_from_hiding_to_deleting)
+    )
+}
@@ -0,0 +1,1132 @@
+use std::{cell::RefCell, collections::{BTreeMap, HashMap, HashSet}, fs, io::{Read, Write}, path::{Path, PathBuf}, rc::Rc, error::Error};
+use clap::Subcommand;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use crate::{chunker::{ChunkerAlgorithm, ChunkerConfig}, crypto::{KeyConfig, RepoKey}, dictionary::{self, Dictionary}, restore::{self, LinkRewrite}, retention::{self, Policy}, store::{self, Store}, utils, webhook};
+
+// 2: snapshots record each chunk's size. 3: small chunks can be stored in packs under packs/
+const REPO_VERSION: u32 = 3;
+// Starts chunks compressed against a dictionary, followed by the dictionary's id. Can't be mistaken for a zlib
+// header, whose first byte always has 8 in its low bits
+const DICT_CHUNK_TAG: u8 = b'D';
+const DICT_ID_LEN: usize = 16;
+// Chunks up to this size go into packs when packing is on, which in practice means small files and the tails of
+// large ones
+const PACKED_CHUNK_MAX: usize = 128 * 1024;
+// Size a pack is written out at
+const PACK_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Subcommand, Debug)]
+pub enum RepoCommand {
+    #[command(about = "Create a new deduplicating repository")]
+    Init {
+        repo: String,
+        #[arg(long = "chunker", value_enum, default_value = "fastcdc", env = "ATHENA_CHUNKER")]
+        chunker: ChunkerAlgorithm,
+        #[arg(long = "chunk-min", value_parser = utils::parse_size, env = "ATHENA_CHUNK_MIN")]
+        chunk_min: Option<u64>,
+        // Smaller averages dedup better (e.g. source trees) at the cost of more chunks to store and index
+        #[arg(long = "chunk-avg", value_parser = utils::parse_size, env = "ATHENA_CHUNK_AVG")]
+        chunk_avg: Option<u64>,
+        #[arg(long = "chunk-max", value_parser = utils::parse_size, env = "ATHENA_CHUNK_MAX")]
+        chunk_max: Option<u64>,
+        // Encrypt chunks and snapshots with a key protected by a password
+        #[arg(long = "encrypt", env = "ATHENA_ENCRYPT")]
+        encrypt: bool,
+        // Store small chunks together in packs rather than one object each. Far fewer objects and uploads for
+        // sources with many small files
+        #[arg(long = "pack", env = "ATHENA_PACK")]
+        pack: bool,
+    },
+    #[command(about = "Back up a file or directory into the repository as a new snapshot")]
+    Backup {
+        repo: String,
+        #[arg(short = 'i', long = "src", env = "ATHENA_SRC")]
+        src: String,
+        // Describes the snapshot, shown when listing snapshots
+        #[arg(long = "comment", env = "ATHENA_COMMENT")]
+        comment: Option<String>,
+        // First train a compression dictionary on the source's small files, and compress small chunks against it
+        // from then on. Helps most with many small, similar files such as JSON logs or configs
+        #[arg(long = "train-dict", env = "ATHENA_TRAIN_DICT")]
+        train_dict: bool,
+        // Pack small chunks for this backup, even if the repository wasn't created with --pack
+        #[arg(long = "pack", env = "ATHENA_PACK")]
+        pack: bool,
+    },
+    #[command(about = "List snapshots in the repository")]
+    Snapshots {
+        repo: String,
+    },
+    #[command(about = "List the files in a snapshot")]
+    Ls {
+        repo: String,
+        snapshot: String,
+        // Only list entries under this path
+        path: Option<String>,
+    },
+    #[command(about = "Restore a snapshot, or only some paths from it")]
+    Restore {
+        repo: String,
+        snapshot: String,
+        paths: Vec<String>,
+        #[arg(short = 't', long = "target", env = "ATHENA_TARGET")]
+        target: String,
+        // Point absolute symlinks under OLD at NEW instead, e.g. when restoring to a different root
+        #[arg(long = "rewrite-links", value_name = "OLD=NEW", value_parser = restore::parse_link_rewrite, env = "ATHENA_REWRITE_LINKS")]
+        rewrite_links: Vec<LinkRewrite>,
+    },
+    #[command(about = "Remove a snapshot (run gc afterwards to free its data)")]
+    Forget {
+        repo: String,
+        snapshot: String,
+    },
+    #[command(about = "Remove snapshots a retention policy doesn't keep (run gc afterwards to free their data)")]
+    Prune {
+        repo: String,
+        // Preset: gfs (7 daily, 4 weekly, 12 monthly) or last-n=N. Any --keep-* flags override its counts
+        #[arg(long = "policy", value_parser = retention::parse_policy, env = "ATHENA_POLICY")]
+        policy: Option<Policy>,
+        #[arg(long = "keep-last", env = "ATHENA_KEEP_LAST")]
+        keep_last: Option<usize>,
+        #[arg(long = "keep-daily", env = "ATHENA_KEEP_DAILY")]
+        keep_daily: Option<usize>,
+        #[arg(long = "keep-weekly", env = "ATHENA_KEEP_WEEKLY")]
+        keep_weekly: Option<usize>,
+        #[arg(long = "keep-monthly", env = "ATHENA_KEEP_MONTHLY")]
+        keep_monthly: Option<usize>,
+        #[arg(long = "keep-yearly", env = "ATHENA_KEEP_YEARLY")]
+        keep_yearly: Option<usize>,
+        // Show what would be removed without removing anything
+        #[arg(long = "dry-run", env = "ATHENA_DRY_RUN")]
+        dry_run: bool,
+    },
+    #[command(about = "Show the snapshots past prunes removed, and the policy each was removed under")]
+    PruneLog {
+        repo: String,
+    },
+    #[command(about = "Verify snapshots and chunks are consistent")]
+    Check {
+        repo: String,
+        // Also decompress and hash every chunk, rather than just checking they exist
+        #[arg(long = "read-data", env = "ATHENA_READ_DATA")]
+        read_data: bool,
+        // Remove corrupt chunks so the next backup stores them again
+        #[arg(long = "repair", requires = "read_data", env = "ATHENA_REPAIR")]
+        repair: bool,
+    },
+    #[command(about = "Show raw, deduplicated and stored sizes, overall and per snapshot")]
+    Stats {
+        repo: String,
+    },
+    #[command(about = "Remove chunks not referenced by any snapshot")]
+    Gc {
+        repo: String,
+    },
+    #[command(about = "Manage an encrypted repository's password")]
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyCommand {
+    #[command(about = "Change the repository password. Data doesn't need re-encrypting, so this is quick")]
+    Change {
+        repo: String,
+        #[arg(long = "new-password-file", env = "ATHENA_NEW_PASSWORD_FILE")]
+        new_password_file: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RepoConfig {
+    pub version: u32,
+    pub chunker: ChunkerConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<KeyConfig>,
+    // Dictionary new small chunks are compressed against. Older ones stay in the repository for the chunks that
+    // were compressed against them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<String>,
+    // Whether backups store small chunks in packs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pack: bool,
+}
+
+// Where a packed chunk is kept, as listed in its pack's index
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PackEntry {
+    hash: String,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Clone, Debug)]
+struct PackedChunk {
+    pack: String,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Default)]
+struct Packs {
+    // Every packed chunk, including those in the pack still being filled
+    located: HashMap<String, PackedChunk>,
+    // The pack being filled, and the chunks in it so far
+    current: Option<String>,
+    pending: Vec<u8>,
+    entries: Vec<PackEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Symlink,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub mtime: i64,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub chunks: Vec<ChunkRef>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChunkRef {
+    pub hash: String,
+    // Uncompressed length
+    pub size: u64,
+}
+
+// Written to prunes/ by each prune that removed something, so deletions can be audited later
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PruneRecord {
+    pub time: String,
+    pub policy: String,
+    pub removed: Vec<PrunedSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrunedSnapshot {
+    pub id: String,
+    pub time: String,
+    pub source: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub id: String,
+    pub time: String,
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+// Content-addressed, compressed chunks plus the snapshots that reference them, in a local directory or a
+// remote bucket. Each backup also writes an index of the chunks it added, so remote repos can tell which
+// chunks exist from a few small index files (cached locally) rather than listing every chunk. Small chunks can
+// instead be stored together in packs under packs/, each with an index of where its chunks are
+pub struct Repository {
+    store: Box<dyn Store>,
+    pub config: RepoConfig,
+    key: Option<RepoKey>,
+    // Local copies of snapshots and indexes, for remote repos only
+    cache: Option<PathBuf>,
+    // Chunk ids listed in the indexes, and those written since opening the repo
+    known: RefCell<HashSet<String>>,
+    written: RefCell<Vec<String>>,
+    // Dictionaries read so far, by id
+    dictionaries: RefCell<HashMap<String, Rc<Dictionary>>>,
+    packs: RefCell<Packs>,
+}
+
+impl Repository {
+    // Creates a repository, encrypted if a password is given
+    pub fn init(location: &str, chunker: ChunkerConfig, password: Option<&str>, pack: bool) -> Result<Repository, Box<dyn Error>> {
+        let store = store::open(location)?;
+        if store.exists("config.json")? {
+            return Err(format!("{} is already a repository", store.location()).into());
+        }
+        let (key, encryption) = match password {
+            Some(password) => {
+                let (key, config) = RepoKey::generate(password)?;
+                (Some(key), Some(config))
+            },
+            None => (None, None),
+        };
+        let config = RepoConfig { version: REPO_VERSION, chunker, encryption, dictionary: None, pack };
+        let repo = Repository::new(store, config, key)?;
+        repo.save_config()?;
+        Ok(repo)
+    }
+
+    // Opens a repository, reading the password from `password_file` (or prompting for it) if it's encrypted
+    pub fn open(location: &str, password_file: Option<&Path>) -> Result<Repository, Box<dyn Error>> {
+        let store = store::open(location)?;
+        let contents = store.read("config.json").map_err(|_| format!("{} is not a repository", store.location()))?;
+        let config: RepoConfig = serde_json::from_slice(&contents)?;
+        if config.version != REPO_VERSION {
+            return Err(format!("Unsupported repository version {}", config.version).into());
+        }
+        let key = match &config.encryption {
+            Some(encryption) => Some(RepoKey::unlock(encryption, &utils::read_secret(password_file, "ATHENA_PASSWORD", "Repository password", false)?)?),
+            None => None,
+        };
+        let repo = Repository::new(store, config, key)?;
+        repo.load_indexes()?;
+        repo.load_packs()?;
+        Ok(repo)
+    }
+
+    fn new(store: Box<dyn Store>, config: RepoConfig, key: Option<RepoKey>) -> Result<Repository, Box<dyn Error>> {
+        let cache = if store.is_remote() {
+            let id = blake3::hash(store.location().as_bytes()).to_hex();
+            let cache = utils::cache_dir().join("repos").join(&id[..16]);
+            fs::create_dir_all(cache.join("snapshots"))?;
+            fs::create_dir_all(cache.join("index"))?;
+            fs::create_dir_all(cache.join("packs"))?;
+            Some(cache)
+        } else {
+            None
+        };
+        Ok(Repository { store, config, key, cache, known: RefCell::new(HashSet::new()), written: RefCell::new(Vec::new()), dictionaries: RefCell::new(HashMap::new()), packs: RefCell::new(Packs::default()) })
+    }
+
+    fn save_config(&self) -> Result<(), Box<dyn Error>> {
+        self.store.write("config.json", &serde_json::to_vec_pretty(&self.config)?)
+    }
+
+    pub fn change_password(&mut self, password: &str) -> Result<(), Box<dyn Error>> {
+        let key = self.key.as_ref().ok_or("Repository is not encrypted")?;
+        self.config.encryption = Some(key.wrap(password)?);
+        self.save_config()
+    }
+
+    fn chunk_id(&self, data: &[u8]) -> String {
+        match &self.key {
+            Some(key) => key.chunk_id(data),
+            None => blake3::hash(data).to_hex().to_string(),
+        }
+    }
+
+    fn seal(&self, data: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        match &self.key {
+            Some(key) => key.encrypt(&data),
+            None => Ok(data),
+        }
+    }
+
+    fn unseal(&self, data: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        match &self.key {
+            Some(key) => key.decrypt(&data),
+            None => Ok(data),
+        }
+    }
+
+    // Reads a snapshot or index, from the local cache if it's there. Both are never modified once written,
+    // so a cached copy can't be stale
+    fn read_cached(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let cached = match &self.cache {
+            Some(cache) => cache.join(key),
+            None => return self.store.read(key),
+        };
+        if let Ok(contents) = fs::read(&cached) {
+            return Ok(contents);
+        }
+        let contents = self.store.read(key)?;
+        write_atomic(&cached, &contents)?;
+        Ok(contents)
+    }
+
+    // Writes a snapshot or index, keeping a cached copy
+    fn write_cached(&self, key: &str, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.store.write(key, contents)?;
+        if let Some(cache) = &self.cache {
+            write_atomic(&cache.join(key), contents)?;
+        }
+        Ok(())
+    }
+
+    // Drops cached copies of anything no longer in the repository under the prefix
+    fn prune_cache(&self, prefix: &str, present: &[(String, u64)]) -> Result<(), Box<dyn Error>> {
+        if let Some(cache) = &self.cache {
+            for entry in fs::read_dir(cache.join(prefix))? {
+                let entry = entry?;
+                let key = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+                if !present.iter().any(|(present, _)| *present == key) {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load_indexes(&self) -> Result<(), Box<dyn Error>> {
+        if self.cache.is_none() {
+            return Ok(());
+        }
+        let indexes = self.store.list("index/")?;
+        self.prune_cache("index", &indexes)?;
+        let mut known = self.known.borrow_mut();
+        for (key, _) in &indexes {
+            let chunks: Vec<String> = self
+                .unseal(self.read_cached(key)?)
+                .and_then(|contents| Ok(serde_json::from_slice(&contents)?))
+                .map_err(|e| format!("Index {} is unreadable: {}", key, e))?;
+            known.extend(chunks);
+        }
+        Ok(())
+    }
+
+    fn load_packs(&self) -> Result<(), Box<dyn Error>> {
+        let keys = self.store.list("packs/")?;
+        self.prune_cache("packs", &keys)?;
+        let mut packs = self.packs.borrow_mut();
+        for (key, _) in keys.iter().filter(|(key, _)| key.ends_with(".json")) {
+            let entries: Vec<PackEntry> = self
+                .unseal(self.read_cached(key)?)
+                .and_then(|contents| Ok(serde_json::from_slice(&contents)?))
+                .map_err(|e| format!("Pack index {} is unreadable: {}", key, e))?;
+            let pack = key.trim_start_matches("packs/").trim_end_matches(".json");
+            for entry in entries {
+                packs.located.insert(entry.hash, PackedChunk { pack: pack.to_string(), offset: entry.offset, length: entry.length });
+            }
+        }
+        Ok(())
+    }
+
+    fn write_index(&self, chunks: &[String]) -> Result<(), Box<dyn Error>> {
+        let id = blake3::hash(format!("{}\0{}\0{}", chrono::Local::now().to_rfc3339(), std::process::id(), chunks.len()).as_bytes()).to_hex();
+        self.write_cached(&format!("index/{}.json", &id[..32]), &self.seal(serde_json::to_vec(chunks)?)?)
+    }
+
+    // Records the chunks written since the repo was opened. Must happen before a snapshot referencing them is saved,
+    // so a snapshot never refers to chunks other clients don't know exist
+    pub fn flush_index(&self) -> Result<(), Box<dyn Error>> {
+        let written = std::mem::take(&mut *self.written.borrow_mut());
+        if written.is_empty() {
+            return Ok(());
+        }
+        self.write_index(&written)
+    }
+
+    // Replaces all indexes with a single one listing the chunks actually stored. Run after removing chunks,
+    // so no index claims a chunk that's gone, or to spare opening the repo from reading one per backup. Returns
+    // how many were replaced
+    pub fn rebuild_index(&self) -> Result<usize, Box<dyn Error>> {
+        let old = self.store.list("index/")?;
+        let replaced = old.len();
+        let chunks: Vec<String> = self.chunks()?.into_iter().map(|(hash, _)| hash).collect();
+        self.write_index(&chunks)?;
+        for (key, _) in old {
+            self.store.delete(&key)?;
+            if let Some(cache) = &self.cache {
+                let _ = fs::remove_file(cache.join(&key));
+            }
+        }
+        *self.known.borrow_mut() = chunks.into_iter().collect();
+        Ok(replaced)
+    }
+
+    fn chunk_key(&self, hash: &str) -> String {
+        format!("chunks/{}/{}", &hash[..2], hash)
+    }
+
+    fn has_chunk(&self, hash: &str) -> Result<bool, Box<dyn Error>> {
+        if self.packs.borrow().located.contains_key(hash) {
+            Ok(true)
+        } else if self.cache.is_some() {
+            Ok(self.known.borrow().contains(hash))
+        } else {
+            self.store.exists(&self.chunk_key(hash))
+        }
+    }
+
+    // Stores a chunk unless it's already present. Returns its hash and the number of bytes newly written
+    pub fn write_chunk(&self, data: &[u8]) -> Result<(String, u64), Box<dyn Error>> {
+        let hash = self.chunk_id(data);
+        if self.has_chunk(&hash)? {
+            return Ok((hash, 0));
+        }
+        let compressed = match &self.config.dictionary {
+            Some(id) if data.len() <= dictionary::MAX_CHUNK => {
+                let mut compressed = vec![DICT_CHUNK_TAG];
+                compressed.extend(id.as_bytes());
+                compressed.extend(self.dictionary(id)?.compress(data)?);
+                compressed
+            },
+            _ => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            },
+        };
+        let stored = self.seal(compressed)?;
+        if self.config.pack && data.len() <= PACKED_CHUNK_MAX {
+            self.add_packed(hash.clone(), &stored)?;
+            return Ok((hash, stored.len() as u64));
+        }
+        self.store.write(&self.chunk_key(&hash), &stored)?;
+        self.known.borrow_mut().insert(hash.clone());
+        self.written.borrow_mut().push(hash.clone());
+        Ok((hash, stored.len() as u64))
+    }
+
+    // Reads a chunk back, verifying its content against its hash
+    pub fn read_chunk(&self, hash: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let packed = self.packs.borrow().located.get(hash).cloned();
+        let stored = match packed {
+            Some(chunk) => self.read_packed(&chunk),
+            None => self.store.read(&self.chunk_key(hash)),
+        };
+        let stored = stored.map_err(|e| format!("Chunk {} unreadable: {}", hash, e))?;
+        let compressed = self.unseal(stored).map_err(|e| format!("Chunk {} is corrupt: {}", hash, e))?;
+        let data = match compressed.split_first() {
+            Some((&DICT_CHUNK_TAG, rest)) if rest.len() >= DICT_ID_LEN => {
+                let (id, compressed) = rest.split_at(DICT_ID_LEN);
+                self.dictionary(&String::from_utf8_lossy(id))?.decompress(compressed)
+            },
+            _ => {
+                let mut data = Vec::new();
+                ZlibDecoder::new(&compressed[..]).read_to_end(&mut data).map(|_| data)
+            },
+        };
+        let data = data.map_err(|e| format!("Chunk {} is corrupt: {}", hash, e))?;
+        if self.chunk_id(&data) != hash {
+            return Err(format!("Chunk {} is corrupt: content does not match its hash", hash).into());
+        }
+        Ok(data)
+    }
+
+    // Adds a stored chunk to the pack being filled, writing the pack out once it's full
+    fn add_packed(&self, hash: String, stored: &[u8]) -> Result<(), Box<dyn Error>> {
+        let full = {
+            let mut packs = self.packs.borrow_mut();
+            let packs = &mut *packs;
+            let pack = packs
+                .current
+                .get_or_insert_with(|| blake3::hash(format!("{}\0{}\0{}", chrono::Local::now().to_rfc3339(), std::process::id(), packs.located.len()).as_bytes()).to_hex()[..32].to_string())
+                .clone();
+            let entry = PackEntry { hash, offset: packs.pending.len() as u64, length: stored.len() as u64 };
+            packs.pending.extend_from_slice(stored);
+            packs.located.insert(entry.hash.clone(), PackedChunk { pack, offset: entry.offset, length: entry.length });
+            packs.entries.push(entry);
+            packs.pending.len() >= PACK_SIZE
+        };
+        if full {
+            self.flush_pack()?;
+        }
+        Ok(())
+    }
+
+    // Writes out the pack being filled, if any, then its index. Like flush_index, must happen before a snapshot
+    // referencing its chunks is saved
+    pub fn flush_pack(&self) -> Result<(), Box<dyn Error>> {
+        let (pack, data, entries) = {
+            let mut packs = self.packs.borrow_mut();
+            let Some(pack) = packs.current.take() else {
+                return Ok(());
+            };
+            (pack, std::mem::take(&mut packs.pending), std::mem::take(&mut packs.entries))
+        };
+        self.store.write(&format!("packs/{}.pack", pack), &data)?;
+        self.write_cached(&format!("packs/{}.json", pack), &self.seal(serde_json::to_vec(&entries)?)?)
+    }
+
+    // Reads only the chunk's bytes from its pack, so reading chunks spread across packs doesn't download whole ones
+    fn read_packed(&self, chunk: &PackedChunk) -> Result<Vec<u8>, Box<dyn Error>> {
+        let data = self.store.read_range(&format!("packs/{}.pack", chunk.pack), chunk.offset, chunk.offset + chunk.length)?;
+        if data.len() as u64 != chunk.length {
+            return Err(format!("pack {} is truncated", chunk.pack).into());
+        }
+        Ok(data)
+    }
+
+    fn dictionary(&self, id: &str) -> Result<Rc<Dictionary>, Box<dyn Error>> {
+        if let Some(dictionary) = self.dictionaries.borrow().get(id) {
+            return Ok(dictionary.clone());
+        }
+        let data = self
+            .store
+            .read(&format!("dicts/{}.bin", id))
+            .and_then(|stored| self.unseal(stored))
+            .map_err(|e| format!("Dictionary {} unreadable: {}", id, e))?;
+        let dictionary = Rc::new(Dictionary::new(data)?);
+        self.dictionaries.borrow_mut().insert(id.to_string(), dictionary.clone());
+        Ok(dictionary)
+    }
+
+    // Trains a dictionary on a sample of the small files among `files`, and compresses new small chunks against it
+    // from now on. Returns its size and how many files it was trained on, or None if there weren't enough to go on
+    pub fn train_dictionary(&mut self, files: &[PathBuf]) -> Result<Option<(usize, usize)>, Box<dyn Error>> {
+        let small: Vec<(&PathBuf, u64)> = files
+            .iter()
+            .filter_map(|path| path.symlink_metadata().ok().filter(|metadata| metadata.is_file()).map(|metadata| (path, metadata.len())))
+            .filter(|(_, size)| (1..=dictionary::MAX_SAMPLE).contains(size))
+            .collect();
+        // Spread the sample over all of them, rather than only the first directories walked
+        let total: u64 = small.iter().map(|(_, size)| size).sum();
+        let step = total.div_ceil(dictionary::SAMPLE_BUDGET).max(1) as usize;
+        let samples: Vec<Vec<u8>> = small.iter().step_by(step).filter_map(|(path, _)| fs::read(path).ok()).collect();
+        if samples.len() < dictionary::MIN_SAMPLES {
+            return Ok(None);
+        }
+        let data = dictionary::train(&samples, dictionary::SIZE);
+        let id = blake3::hash(&data).to_hex()[..DICT_ID_LEN].to_string();
+        self.store.write(&format!("dicts/{}.bin", id), &self.seal(data.clone())?)?;
+        let size = data.len();
+        self.dictionaries.borrow_mut().insert(id.clone(), Rc::new(Dictionary::new(data)?));
+        self.config.dictionary = Some(id);
+        self.save_config()?;
+        Ok(Some((size, samples.len())))
+    }
+
+    // Hashes and stored sizes of every chunk, packed ones last in the order they're packed in. This lists every
+    // chunk, so is slow for large remote repos
+    pub fn chunks(&self) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+        let mut chunks: Vec<(String, u64)> = self
+            .store
+            .list("chunks/")?
+            .into_iter()
+            .filter_map(|(key, size)| key.rsplit('/').next().map(|hash| (hash.to_string(), size)))
+            .collect();
+        let packs = self.packs.borrow();
+        let mut packed: Vec<(&String, &PackedChunk)> = packs.located.iter().collect();
+        packed.sort_by(|(_, a), (_, b)| (&a.pack, a.offset).cmp(&(&b.pack, b.offset)));
+        chunks.extend(packed.into_iter().map(|(hash, chunk)| (hash.clone(), chunk.length)));
+        Ok(chunks)
+    }
+
+    // Number of packs, and of chunks in them
+    pub fn pack_counts(&self) -> (usize, usize) {
+        let packs = self.packs.borrow();
+        (packs.located.values().map(|chunk| &chunk.pack).collect::<HashSet<_>>().len(), packs.located.len())
+    }
+
+    // Deletes chunks. Packs holding any are rewritten without them, or deleted if nothing else is left in them.
+    // `progress` counts each chunk as it's gone
+    pub fn remove_chunks(&self, hashes: &HashSet<String>, progress: &ProgressBar) -> Result<(), Box<dyn Error>> {
+        let mut packs: BTreeMap<String, u64> = BTreeMap::new();
+        for hash in hashes {
+            let pack = self.packs.borrow().located.get(hash).map(|chunk| chunk.pack.clone());
+            match pack {
+                Some(pack) => {
+                    *packs.entry(pack).or_default() += 1;
+                },
+                None => {
+                    self.store.delete(&self.chunk_key(hash))?;
+                    self.known.borrow_mut().remove(hash);
+                    progress.inc(1);
+                },
+            }
+        }
+        for (pack, removed) in packs {
+            self.repack(&pack, hashes)?;
+            progress.inc(removed);
+        }
+        Ok(())
+    }
+
+    // Moves the chunks in a pack that aren't being removed into a new one, then deletes it. The new pack is
+    // written first, so nothing kept is ever missing
+    fn repack(&self, pack: &str, removed: &HashSet<String>) -> Result<(), Box<dyn Error>> {
+        let mut kept: Vec<(String, PackedChunk)> = self
+            .packs
+            .borrow()
+            .located
+            .iter()
+            .filter(|(hash, chunk)| chunk.pack == pack && !removed.contains(*hash))
+            .map(|(hash, chunk)| (hash.clone(), chunk.clone()))
+            .collect();
+        kept.sort_by_key(|(_, chunk)| chunk.offset);
+        // Read whole, as most of it is usually kept
+        let data = match kept.is_empty() {
+            true => Vec::new(),
+            false => self.store.read(&format!("packs/{}.pack", pack))?,
+        };
+        for (hash, chunk) in kept {
+            let stored = data.get(chunk.offset as usize..(chunk.offset + chunk.length) as usize).ok_or_else(|| format!("pack {} is truncated", pack))?;
+            self.add_packed(hash, stored)?;
+        }
+        self.flush_pack()?;
+        let mut packs = self.packs.borrow_mut();
+        packs.located.retain(|_, chunk| chunk.pack != pack);
+        drop(packs);
+        self.store.delete(&format!("packs/{}.pack", pack))?;
+        let index = format!("packs/{}.json", pack);
+        self.store.delete(&index)?;
+        if let Some(cache) = &self.cache {
+            let _ = fs::remove_file(cache.join(&index));
+        }
+        Ok(())
+    }
+
+    // All snapshots, oldest first
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let keys = self.store.list("snapshots/")?;
+        self.prune_cache("snapshots", &keys)?;
+        let mut snapshots = Vec::new();
+        for (key, _) in keys.iter().filter(|(key, _)| key.ends_with(".json")) {
+            let snapshot: Snapshot = self
+                .read_cached(key)
+                .and_then(|contents| self.unseal(contents))
+                .and_then(|contents| Ok(serde_json::from_slice(&contents)?))
+                .map_err(|e| format!("Snapshot {} is unreadable: {}", key, e))?;
+            snapshots.push(snapshot);
+        }
+        snapshots.sort_by(|a, b| a.time.cmp(&b.time));
+        Ok(snapshots)
+    }
+
+    // Finds a snapshot by its id, or an unambiguous prefix of it. "latest" picks the newest
+    pub fn snapshot(&self, id: &str) -> Result<Snapshot, Box<dyn Error>> {
+        let mut snapshots = self.snapshots()?;
+        if id == "latest" {
+            return snapshots.pop().ok_or_else(|| "Repository has no snapshots".into());
+        }
+        let mut matching: Vec<Snapshot> = snapshots.into_iter().filter(|s| s.id.starts_with(id)).collect();
+        match matching.len() {
+            0 => Err(format!("No snapshot matches '{}'", id).into()),
+            1 => Ok(matching.remove(0)),
+            _ => Err(format!("'{}' matches more than one snapshot", id).into()),
+        }
+    }
+
+    pub fn save_snapshot(&self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        self.flush_pack()?;
+        self.flush_index()?;
+        self.write_cached(&format!("snapshots/{}.json", snapshot.id), &self.seal(serde_json::to_vec(snapshot)?)?)
+    }
+
+    pub fn remove_snapshot(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        self.store.delete(&format!("snapshots/{}.json", id))
+    }
+
+    pub fn save_prune_record(&self, record: &PruneRecord) -> Result<(), Box<dyn Error>> {
+        let name = chrono::Local::now().format("%Y%m%d%H%M%S%f");
+        self.store.write(&format!("prunes/{}.json", name), &self.seal(serde_json::to_vec(record)?)?)
+    }
+
+    // Oldest first
+    pub fn prune_records(&self) -> Result<Vec<PruneRecord>, Box<dyn Error>> {
+        let mut keys = self.store.list("prunes/")?;
+        keys.sort();
+        let mut records = Vec::new();
+        for (key, _) in keys.iter().filter(|(key, _)| key.ends_with(".json")) {
+            let contents = self.unseal(self.store.read(key)?)?;
+            records.push(serde_json::from_slice(&contents).map_err(|e| format!("Prune record {} is unreadable: {}", key, e))?);
+        }
+        Ok(records)
+    }
+}
+
+pub fn run(command: RepoCommand, password_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    match command {
+        RepoCommand::Init { repo, chunker, chunk_min, chunk_avg, chunk_max, encrypt, pack } => {
+            let chunker = ChunkerConfig::new(chunker, chunk_min, chunk_avg, chunk_max)?;
+            let password = if encrypt { Some(utils::read_secret(password_file, "ATHENA_PASSWORD", "Repository password", true)?) } else { None };
+            Repository::init(&repo, chunker, password.as_deref(), pack)?;
+            println!(
+                "Created {}repository at {} ({})",
+                if encrypt { "encrypted " } else { "" },
+                repo,
+                chunker.describe()
+            );
+        },
+        RepoCommand::Backup { repo, src, comment, train_dict, pack } => {
+            let mut repo = Repository::open(&repo, password_file)?;
+            // Only for this backup, the config isn't saved with it
+            repo.config.pack |= pack;
+            let source = crate::validate::input(PathBuf::from(src))?;
+            let files = crate::process_sources(vec![source.clone()], crate::cancel::CancellationToken::default(), None, None, None, None).map_err(|e| e.to_string())?;
+            if train_dict {
+                match repo.train_dictionary(&files)? {
+                    Some((size, samples)) => println!("Trained a {} dictionary on {} files", utils::format_size(size as u64), samples),
+                    None => eprintln!("Warning: too few small files to train a dictionary on, backing up without a new one"),
+                }
+            }
+            let (snapshot, added) = backup(&repo, &source, &files, comment)?;
+            println!(
+                "Created snapshot {} ({} files, {} of new data)",
+                &snapshot.id[..8],
+                snapshot.entries.len(),
+                utils::format_size(added)
+            );
+        },
+        RepoCommand::Snapshots { repo } => {
+            let repo = Repository::open(&repo, password_file)?;
+            for snapshot in repo.snapshots()? {
+                let size: u64 = snapshot.entries.iter().map(|e| e.size).sum();
+                println!(
+                    "{}  {}  {} files, {}  {}",
+                    &snapshot.id[..8],
+                    display_time(&snapshot.time),
+                    snapshot.entries.len(),
+                    utils::format_size(size),
+                    snapshot.source
+                );
+                if let Some(comment) = &snapshot.comment {
+                    println!("          {}", comment);
+                }
+            }
+        },
+        RepoCommand::Ls { repo, snapshot, path } => {
+            let repo = Repository::open(&repo, password_file)?;
+            let snapshot = repo.snapshot(&snapshot)?;
+            let filter: Vec<String> = path.into_iter().collect();
+            for entry in snapshot.entries.iter().filter(|entry| matches_paths(&entry.path, &filter)) {
+                let time = chrono::TimeZone::timestamp_opt(&chrono::Local, entry.mtime, 0)
+                    .single()
+                    .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                match &entry.target {
+                    Some(target) => println!("{:04o} {:>10} {} {} -> {}", entry.mode, entry.size, time, entry.path, target),
+                    None => println!("{:04o} {:>10} {} {}", entry.mode, entry.size, time, entry.path),
+                }
+            }
+        },
+        RepoCommand::Restore { repo, snapshot, paths, target, rewrite_links } => {
+            let repo = Repository::open(&repo, password_file)?;
+            let snapshot = repo.snapshot(&snapshot)?;
+            let restored = restore(&repo, &snapshot, &paths, Path::new(&target), &rewrite_links)?;
+            if restored == 0 {
+                return Err("No files in the snapshot match the given paths".into());
+            }
+            println!("Restored {} files to {}", restored, target);
+        },
+        RepoCommand::Forget { repo, snapshot } => {
+            let repo = Repository::open(&repo, password_file)?;
+            let snapshot = repo.snapshot(&snapshot)?;
+            repo.remove_snapshot(&snapshot.id)?;
+            println!("Removed snapshot {}", &snapshot.id[..8]);
+        },
+        RepoCommand::Prune { repo, policy, keep_last, keep_daily, keep_weekly, keep_monthly, keep_yearly, dry_run } => {
+            let mut policy = policy.unwrap_or_default();
+            policy.last = keep_last.unwrap_or(policy.last);
+            policy.daily = keep_daily.unwrap_or(policy.daily);
+            policy.weekly = keep_weekly.unwrap_or(policy.weekly);
+            policy.monthly = keep_monthly.unwrap_or(policy.monthly);
+            policy.yearly = keep_yearly.unwrap_or(policy.yearly);
+            if policy.is_empty() {
+                return Err("Nothing would be kept, pass --policy or at least one --keep-* flag".into());
+            }
+            let location = repo;
+            let repo = Repository::open(&location, password_file)?;
+            let removed = prune(&repo, &policy, dry_run)?;
+            webhook::emit("prune.complete", serde_json::json!({ "repo": location, "removed": removed, "policy": policy.to_string(), "dry_run": dry_run }));
+            if dry_run {
+                println!("Would remove {} snapshots ({})", removed, policy);
+            } else {
+                println!("Removed {} snapshots ({}), run gc to free their data", removed, policy);
+            }
+        },
+        RepoCommand::PruneLog { repo } => {
+            let repo = Repository::open(&repo, password_file)?;
+            for record in repo.prune_records()? {
+                println!("{}  {}", display_time(&record.time), record.policy);
+                for snapshot in record.removed {
+                    println!("  removed {}  {}  {}", &snapshot.id[..8], display_time(&snapshot.time), snapshot.source);
+                }
+            }
+        },
+        RepoCommand::Check { repo, read_data, repair } => {
+            let repo = Repository::open(&repo, password_file)?;
+            let problems = check(&repo, read_data, repair)?;
+            if problems > 0 {
+                return Err(format!("Repository check found {} problems", problems).into());
+            }
+            println!("No problems found");
+        },
+        RepoCommand::Stats { repo } => {
+            let repo = Repository::open(&repo, password_file)?;
+            print_stats(&repo)?;
+        },
+        RepoCommand::Gc { repo } => {
+            let repo = Repository::open(&repo, password_file)?;
+            let (removed, freed) = gc(&repo)?;
+            println!("Removed {} unreferenced chunks, freeing {}", removed, utils::format_size(freed));
+        },
+        RepoCommand::Key { command: KeyCommand::Change { repo, new_password_file } } => {
+            let mut repo = Repository::open(&repo, password_file)?;
+            let password = utils::read_secret(new_password_file.as_deref().map(Path::new), "ATHENA_NEW_PASSWORD", "New repository password", true)?;
+            repo.change_password(&password)?;
+            println!("Changed repository password");
+        },
+    }
+    Ok(())
+}
+
+// Chunks every file into the repository and records a snapshot of them. Returns the snapshot and the
+// number of bytes of new chunk data written
+pub fn backup(repo: &Repository, source: &Path, files: &[PathBuf], comment: Option<String>) -> Result<(Snapshot, u64), Box<dyn Error>> {
+    let base = crate::get_inp_path_only(source);
+    let chunker = repo.config.chunker;
+    let progress = utils::construct_progress(files.len() as u64);
+    progress.set_message("Backing up files...");
+    let mut entries = Vec::new();
+    let mut added = 0;
+
+    for path in files {
+        let metadata = path.symlink_metadata()?;
+        let mut entry = SnapshotEntry {
+            path: path.strip_prefix(&base)?.to_string_lossy().to_string(),
+            kind: EntryKind::File,
+            mode: mode(&metadata),
+            mtime: metadata.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64).unwrap_or(0),
+            size: 0,
+            target: None,
+            chunks: Vec::new(),
+        };
+        if metadata.file_type().is_symlink() {
+            entry.kind = EntryKind::Symlink;
+            entry.target = Some(path.read_link()?.to_string_lossy().to_string());
+        } else {
+            let file = fs::File::open(path)?;
+            chunker.chunk(file, |data| {
+                let (hash, written) = repo.write_chunk(data)?;
+                entry.size += data.len() as u64;
+                added += written;
+                entry.chunks.push(ChunkRef { hash, size: data.len() as u64 });
+                Ok(())
+            }).map_err(|e| format!("Failed to back up {}: {}", path.display(), e))?;
+        }
+        entries.push(entry);
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+
+    let time = chrono::Local::now().to_rfc3339();
+    let source = source.to_string_lossy().to_string();
+    let id = blake3::hash(format!("{}\0{}\0{}", time, source, std::process::id()).as_bytes()).to_hex().to_string();
+    let snapshot = Snapshot { id, time, source, comment, entries };
+    repo.save_snapshot(&snapshot)?;
+    Ok((snapshot, added))
+}
+
+// Writes the snapshot's entries (or those under any of `paths`) into the target directory. Returns the number restored
+pub fn restore(repo: &Repository, snapshot: &Snapshot, paths: &[String], target: &Path, rewrites: &[LinkRewrite]) -> Result<usize, Box<dyn Error>> {
+    let entries: Vec<&SnapshotEntry> = snapshot.entries.iter().filter(|entry| matches_paths(&entry.path, paths)).collect();
+    let progress = utils::construct_progress(entries.len() as u64);
+    progress.set_message("Restoring files...");
+
+    for entry in &entries {
+        // Snapshots are only as trustworthy as whoever can write to the repo, so entries can't leave the target
+        let dest = restore::safe_destination(target, Path::new(&entry.path))?;
+        restore_entry(repo, entry, &dest, rewrites).map_err(|e| format!("Failed to restore {}: {}", entry.path, e))?;
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+    Ok(entries.len())
+}
+
+fn restore_entry(repo: &Repository, entry: &SnapshotEntry, dest: &Path, rewrites: &[LinkRewrite]) -> Result<(), Box<dyn Error>> {
+    match entry.kind {
+        EntryKind::Symlink => restore::symlink(&restore::rewrite_link(Path::new(entry.target.as_deref().unwrap_or_default()), rewrites), dest),
+        EntryKind::File => {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = fs::File::create(dest)?;
+            for chunk in &entry.chunks {
+                file.write_all(&repo.read_chunk(&chunk.hash)?)?;
+            }
+            file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(entry.mtime.max(0) as u64))?;
+            #[cfg(unix)]
+            fs::set_permissions(dest, std::os::unix::fs::PermissionsExt::from_mode(entry.mode))?;
+            Ok(())
+        },
+    }
+}
+
+// Whether an entry is one of, or inside one of, the given paths. No paths means everything matches
+pub fn matches_paths(entry_path: &str, paths: &[String]) -> bool {
+    paths.is_empty() || paths.iter().any(|path| {
+        let path = path.trim_end_matches('/');
+        entry_path == path || entry_path.starts_with(&format!("{}/", path))
+    })
+}
+
+// Checks every snapshot's chunks exist (and with `read_data`, that every chunk is intact), printing each problem.
+// Returns the number of problems found
+pub fn check(repo: &Repository, read_data: bool, repair: bool) -> Result<usize, Box<dyn Error>> {
+    let chunks = repo.chunks()?;
+    let stored: HashSet<String> = chunks.iter().map(|(hash, _)| hash.clone()).collect();
+    let mut problems = 0;
+
+    let mut corrupt = HashSet::new();
+    if read_data {
+        let progress = utils::construct_progress(chunks.len() as u64);
+        progress.set_message("Verifying chunks...");
+        for (hash, _) in &chunks {
+            if let Err(e) = repo.read_chunk(hash) {
+                progress.suspend(|| eprintln!("{}", e));
+                corrupt.insert(hash.clone());
+                problems += 1;
+            }
+            progress.inc(1);
+        }
+        progress.finish_and_clear();
+        if repair {
+            repo.remove_chunks(&corrupt, &ProgressBar::hidden())?;
+        }
+    }
+
+    for snapshot in repo.snapshots()? {
+        let damaged: Vec<&SnapshotEntry> = snapshot
+            .entries
+            .iter()
+            .filter(|entry| entry.chunks.iter().any(|chunk| !stored.contains(&chunk.hash) || corrupt.contains(&chunk.hash)))
+            .collect();
+        if !damaged.is_empty() {
+            problems += damaged.len();
+            eprintln!("Snapshot {} has {} damaged files:", &snapshot.id[..8], damaged.len());
+            for entry in damaged {
+                eprintln!("  {}", entry.path);
+            }
+        }
+    }
+    if repair && !corrupt.is_empty() {
+        repo.rebuild_index()?;
+        eprintln!("Removed {} corrupt chunks; backing up the affected files again will restore them", corrupt.len());
+    }
+    Ok(problems)
+}
+
+// Prints how much data the snapshots hold before and after deduplication and compression, and for each
+// snapshot, how much data only it references - which is what deleting it would free
+fn print_stats(repo: &Repository) -> Result<(), Box<dyn Error>> {
+    let stored: HashMap<String, u64> = repo.chunks()?.into_iter().collect();
+    let snapshots = repo.snapshots()?;
+
+    // Number of snapshots referencing each chunk, and its uncompressed size
+    let mut references: HashMap<&str, (usize, u64)> = HashMap::new();
+    for snapshot in &snapshots {
+        let unique: HashSet<(&str, u64)> = snapshot.entries.iter().flat_map(|e| e.chunks.iter().map(|c| (c.hash.as_str(), c.size))).collect();
+        for (hash, size) in unique {
+            references.entry(hash).or_insert((0, size)).0 += 1;
+        }
+    }
+
+    let raw: u64 = snapshots.iter().flat_map(|s| s.entries.iter()).map(|e| e.size).sum();
+    let deduplicated: u64 = references.values().map(|(_, size)| size).sum();
+    let on_disk: u64 = stored.values().sum();
+    println!("Snapshots:     {}", snapshots.len());
+    println!("Raw size:      {}", utils::format_size(raw));
+    println!("Deduplicated:  {}", utils::format_size(deduplicated));
+    println!("Stored:        {} ({} chunks)", utils::format_size(on_disk), stored.len());
+    let (packs, packed) = repo.pack_counts();
+    if packs > 0 {
+        println!("Packed:        {} chunks in {} packs", packed, packs);
+    }
+    if snapshots.is_empty() {
+        return Ok(());
+    }
+
+    println!();
+    println!("{:<10}{:<21}{:>12}{:>12}{:>12}", "ID", "Time", "Raw", "Unique", "Frees");
+    for snapshot in &snapshots {
+        let snapshot_raw: u64 = snapshot.entries.iter().map(|e| e.size).sum();
+        let unique: HashSet<&str> = snapshot
+            .entries
+            .iter()
+            .flat_map(|e| e.chunks.iter().map(|c| c.hash.as_str()))
+            .filter(|hash| references.get(hash).map(|(count, _)| *count == 1).unwrap_or(false))
+            .collect();
+        let unique_raw: u64 = unique.iter().map(|hash| references[hash].1).sum();
+        let frees: u64 = unique.iter().filter_map(|hash| stored.get(*hash)).sum();
+        println!(
+            "{:<10}{:<21}{:>12}{:>12}{:>12}",
+            &snapshot.id[..8],
+            display_time(&snapshot.time),
+            utils::format_size(snapshot_raw),
+            utils::format_size(unique_raw),
+            utils::format_size(frees)
+        );
+    }
+    Ok(())
+}
+
+// Deletes chunks that no snapshot references. Returns the number of chunks removed and bytes freed
+pub fn gc(repo: &Repository) -> Result<(usize, u64), Box<dyn Error>> {
+    let referenced: HashSet<String> = repo
+        .snapshots()?
+        .into_iter()
+        .flat_map(|snapshot| snapshot.entries.into_iter().flat_map(|entry| entry.chunks))
+        .map(|chunk| chunk.hash)
+        .collect();
+    let unreferenced: Vec<(String, u64)> = repo.chunks()?.into_iter().filter(|(hash, _)| !referenced.contains(hash)).collect();
+
+    let progress = utils::construct_progress(unreferenced.len() as u64);
+    progress.set_message("Removing unreferenced chunks...");
+    let freed = unreferenced.iter().map(|(_, size)| size).sum();
+    repo.remove_chunks(&unreferenced.iter().map(|(hash, _)| hash.clone()).collect(), &progress)?;
+    progress.finish_and_clear();
+    // Also consolidates the indexes written by each backup into one
+    repo.rebuild_index()?;
+    Ok((unreferenced.len(), freed))
+}
+
+// Removes the snapshots the policy doesn't keep, recording them in a prune record. Returns how many were (or,
+// for a dry run, would be) removed
+fn prune(repo: &Repository, policy: &Policy, dry_run: bool) -> Result<usize, Box<dyn Error>> {
+    // Snapshots with a time that can't be read are never removed
+    let (snapshots, times): (Vec<Snapshot>, Vec<_>) = repo
+        .snapshots()?
+        .into_iter()
+        .filter_map(|snapshot| chrono::DateTime::parse_from_rfc3339(&snapshot.time).ok().map(|time| (snapshot, time)))
+        .unzip();
+    let kept = policy.apply(&times);
+    let mut removed = Vec::new();
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        match kept.get(&i) {
+            Some(reasons) => println!("keep    {}  {}  ({})", &snapshot.id[..8], display_time(&snapshot.time), reasons.join(", ")),
+            None => {
+                println!("remove  {}  {}", &snapshot.id[..8], display_time(&snapshot.time));
+                removed.push(PrunedSnapshot { id: snapshot.id.clone(), time: snapshot.time.clone(), source: snapshot.source.clone() });
+            },
+        }
+    }
+    if dry_run || removed.is_empty() {
+        return Ok(removed.len());
+    }
+    // Recorded first, so a prune that fails partway still leaves a trace of what it meant to remove
+    let record = PruneRecord { time: chrono::Local::now().to_rfc3339(), policy: policy.to_string(), removed };
+    repo.save_prune_record(&record)?;
+    for snapshot in &record.removed {
+        repo.remove_snapshot(&snapshot.id)?;
+    }
+    Ok(record.removed.len())
+}
+
+// Formats a snapshot's RFC 3339 time for display
+fn display_time(time: &str) -> String {
+    match chrono::DateTime::parse_from_rfc3339(time) {
+        Ok(time) => time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+        Err(_) => time.to_string(),
+    }
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn mode(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    return std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777;
+    #[cfg(not(unix))]
+    return if metadata.permissions().readonly() { 0o444 } else { 0o644 };
+}
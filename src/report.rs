@@ -0,0 +1,249 @@
+use std::{fmt::Write as _, fs, io::{BufRead, BufReader, Write}, net::TcpStream, path::{Path, PathBuf}, sync::Mutex, time::{Duration, Instant}, error::Error};
+use base64::Engine;
+use crate::utils;
+
+// Failures listed in a report before the rest are only counted
+const MAX_FAILURES: usize = 20;
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+static ACTIVE: Mutex<Option<Report>> = Mutex::new(None);
+
+// Addresses a report is emailed to
+#[derive(Clone, Debug)]
+pub struct Recipients(pub Vec<String>);
+
+// Parses `email://ops@example.com`, or several addresses separated by commas
+pub fn parse_recipients(input: &str) -> Result<Recipients, String> {
+    let addresses = input.strip_prefix("email://").ok_or("Expected an email:// address, e.g. email://ops@example.com")?;
+    let addresses: Vec<String> = addresses.split(',').map(|address| address.trim().to_string()).collect();
+    match addresses.iter().find(|address| !address.contains('@') || address.contains(['<', '>', '\r', '\n', ' '])) {
+        Some(address) => Err(format!("Invalid email address '{}'", address)),
+        None => Ok(Recipients(addresses)),
+    }
+}
+
+// SMTP relay reports are sent through. Plain SMTP without authentication, e.g. a local MTA or an internal relay
+#[derive(clap::Args, Clone, Debug)]
+pub struct SmtpOptions {
+    #[arg(id = "smtp_host", long = "smtp-host", default_value = "localhost", env = "ATHENA_SMTP_HOST")]
+    pub host: String,
+    #[arg(id = "smtp_port", long = "smtp-port", default_value_t = 25, env = "ATHENA_SMTP_PORT")]
+    pub port: u16,
+    // Sender of reports. Defaults to athena@<hostname>
+    #[arg(id = "smtp_from", long = "smtp-from", env = "ATHENA_SMTP_FROM")]
+    pub from: Option<String>,
+}
+
+// What a run did, filled in as it goes and sent when it exits, however it exits
+#[derive(Default)]
+pub struct Report {
+    recipients: Vec<String>,
+    smtp: Option<SmtpOptions>,
+    started: Option<Instant>,
+    pub host: String,
+    pub source: String,
+    pub files: Option<usize>,
+    pub deleted: usize,
+    pub archive: Option<PathBuf>,
+    pub uploaded: Option<String>,
+    pub failures: Vec<String>,
+    pub error: Option<String>,
+}
+
+// Starts collecting a report of this run for the recipients
+pub fn start(Recipients(recipients): Recipients, smtp: SmtpOptions, host: String, source: String) {
+    *ACTIVE.lock().unwrap() = Some(Report { recipients, smtp: Some(smtp), started: Some(Instant::now()), host, source, ..Report::default() });
+}
+
+// Records something about the run, if it's being reported on
+pub fn update(f: impl FnOnce(&mut Report)) {
+    if let Some(report) = ACTIVE.lock().unwrap().as_mut() {
+        f(report);
+    }
+}
+
+// Sends the report, if there is one, now the run has finished with `code`. A report that can't be sent is only
+// warned about, as the backup itself is done either way
+pub fn send(code: i32) {
+    let Some(report) = ACTIVE.lock().unwrap().take() else {
+        return;
+    };
+    if let Err(e) = report.send(code) {
+        eprintln!("Warning: failed to send report: {}", e);
+    }
+}
+
+impl Report {
+    fn status(&self, code: i32) -> &'static str {
+        match code {
+            0 if self.error.is_some() => "failed",
+            0 => "succeeded",
+            130 => "was cancelled",
+            crate::errors::PARTIAL_FAILURE => "finished with errors",
+            _ => "failed",
+        }
+    }
+
+    // Facts about the run as label and value, shared by the text and HTML versions
+    fn rows(&self, code: i32) -> Vec<(&'static str, String)> {
+        let mut rows = vec![("Status", self.status(code).to_string()), ("Host", self.host.clone()), ("Source", self.source.clone())];
+        if let Some(started) = self.started {
+            rows.push(("Duration", format!("{}s", started.elapsed().as_secs())));
+        }
+        if let Some(files) = self.files {
+            rows.push(("Files archived", files.to_string()));
+        }
+        if self.deleted > 0 {
+            rows.push(("Files deleted since last run", self.deleted.to_string()));
+        }
+        if let Some(archive) = &self.archive {
+            rows.push(("Archive", archive.display().to_string()));
+            if let Ok(metadata) = archive.metadata() {
+                rows.push(("Size", size_change(metadata.len(), previous_archive(archive).as_deref())));
+            }
+        }
+        if let Some(url) = &self.uploaded {
+            rows.push(("Uploaded to", url.clone()));
+        }
+        if let Some(error) = &self.error {
+            rows.push(("Error", error.clone()));
+        }
+        rows
+    }
+
+    fn failures(&self) -> Vec<String> {
+        let mut failures: Vec<String> = self.failures.iter().take(MAX_FAILURES).cloned().collect();
+        if self.failures.len() > MAX_FAILURES {
+            failures.push(format!("and {} more", self.failures.len() - MAX_FAILURES));
+        }
+        failures
+    }
+
+    pub fn text(&self, code: i32) -> String {
+        let mut text = String::new();
+        for (label, value) in self.rows(code) {
+            let _ = writeln!(text, "{:<30}{}", format!("{}:", label), value);
+        }
+        if !self.failures.is_empty() {
+            let _ = writeln!(text, "\n{} paths couldn't be backed up:", self.failures.len());
+            for failure in self.failures() {
+                let _ = writeln!(text, "  {}", failure);
+            }
+        }
+        text
+    }
+
+    pub fn html(&self, code: i32) -> String {
+        let colour = if code == 0 { "#2e7d32" } else { "#c62828" };
+        let mut html = format!("<html><body style=\"font-family: sans-serif\">\n<h3 style=\"color: {}\">Backup of {} {}</h3>\n<table>\n", colour, escape(&self.source), self.status(code));
+        for (label, value) in self.rows(code) {
+            let _ = writeln!(html, "<tr><td><b>{}</b></td><td>{}</td></tr>", label, escape(&value));
+        }
+        html.push_str("</table>\n");
+        if !self.failures.is_empty() {
+            let _ = writeln!(html, "<p>{} paths couldn't be backed up:</p>\n<ul>", self.failures.len());
+            for failure in self.failures() {
+                let _ = writeln!(html, "<li>{}</li>", escape(&failure));
+            }
+            html.push_str("</ul>\n");
+        }
+        html.push_str("</body></html>\n");
+        html
+    }
+
+    fn send(&self, code: i32) -> Result<(), Box<dyn Error>> {
+        let smtp = self.smtp.as_ref().ok_or("No SMTP relay configured")?;
+        let from = smtp.from.clone().unwrap_or_else(|| format!("athena@{}", self.host));
+        let subject = format!("[athena] {}: backup of {} {}", self.host, self.source, self.status(code));
+        let boundary = format!("athena-{}", chrono::Local::now().timestamp_nanos());
+        let message = format!(
+            "From: {from}\r\nTo: {to}\r\nSubject: {subject}\r\nDate: {date}\r\nMIME-Version: 1.0\r\n\
+            Content-Type: multipart/alternative; boundary=\"{boundary}\"\r\n\r\n\
+            --{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{text}\r\n\
+            --{boundary}\r\nContent-Type: text/html; charset=utf-8\r\n\r\n{html}\r\n\
+            --{boundary}--\r\n",
+            to = self.recipients.join(", "),
+            subject = encode_header(&subject),
+            date = chrono::Local::now().to_rfc2822(),
+            text = self.text(code),
+            html = self.html(code),
+        );
+        deliver(smtp, &self.host, &from, &self.recipients, &message)
+    }
+}
+
+// Runs the SMTP conversation for one message
+fn deliver(smtp: &SmtpOptions, helo: &str, from: &str, recipients: &[String], message: &str) -> Result<(), Box<dyn Error>> {
+    let stream = TcpStream::connect((smtp.host.as_str(), smtp.port)).map_err(|e| format!("Failed to connect to {}:{}: {}", smtp.host, smtp.port, e))?;
+    stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut command = |line: Option<&str>, expected: u16| -> Result<(), Box<dyn Error>> {
+        if let Some(line) = line {
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\r\n")?;
+        }
+        // Replies can span lines, all but the last with a - after the code
+        loop {
+            let mut reply = String::new();
+            if reader.read_line(&mut reply)? == 0 {
+                return Err("SMTP server closed the connection".into());
+            }
+            let code: u16 = reply.get(..3).and_then(|code| code.parse().ok()).ok_or_else(|| format!("Invalid SMTP reply '{}'", reply.trim_end()))?;
+            if reply.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            if code != expected {
+                return Err(format!("SMTP server replied '{}'", reply.trim_end()).into());
+            }
+            return Ok(());
+        }
+    };
+    command(None, 220)?;
+    command(Some(&format!("HELO {}", helo)), 250)?;
+    command(Some(&format!("MAIL FROM:<{}>", from)), 250)?;
+    for recipient in recipients {
+        command(Some(&format!("RCPT TO:<{}>", recipient)), 250)?;
+    }
+    command(Some("DATA"), 354)?;
+    // Lines starting with a dot are escaped with another, so none of them end the message early
+    let body: Vec<String> = message.replace("\r\n", "\n").lines().map(|line| if line.starts_with('.') { format!(".{}", line) } else { line.to_string() }).collect();
+    command(Some(&format!("{}\r\n.", body.join("\r\n"))), 250)?;
+    command(Some("QUIT"), 221)?;
+    Ok(())
+}
+
+// The newest other archive in the same directory from the same host and sources, which names end the same way
+fn previous_archive(archive: &Path) -> Option<PathBuf> {
+    let name = archive.file_name()?.to_string_lossy().to_string();
+    let suffix = name.split_once('-')?.1.to_string();
+    let mut previous: Vec<PathBuf> = fs::read_dir(archive.parent()?).ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().map(|other| other.to_string_lossy()).is_some_and(|other| other != name && other.ends_with(&format!("-{}", suffix))))
+        .collect();
+    previous.sort();
+    previous.pop()
+}
+
+fn size_change(size: u64, previous: Option<&Path>) -> String {
+    match previous.and_then(|previous| previous.metadata().ok()) {
+        Some(metadata) => {
+            let (sign, change) = if size >= metadata.len() { ("+", size - metadata.len()) } else { ("-", metadata.len() - size) };
+            format!("{} ({}{} since the last run)", utils::format_size(size), sign, utils::format_size(change))
+        },
+        None => utils::format_size(size),
+    }
+}
+
+// Headers have to be ASCII, so anything else, like a source path with accents, is sent base64 encoded
+fn encode_header(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_string();
+    }
+    format!("=?utf-8?B?{}?=", base64::engine::general_purpose::STANDARD.encode(text))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,515 @@
+use std::{collections::BTreeSet, fs, io::{self, BufReader, Read, Write}, path::{Component, Path, PathBuf}, error::Error};
+use flate2::read::MultiGzDecoder;
+use crate::{crypto, footer, incremental, list, manifest, merge, ntfs, fileattrs, pathstyle::{self, PathStyle}, privileges::{self, RunAs}, utils, validate::{self, ArchiveKind}};
+
+// Maps symlink targets under one absolute prefix to another, e.g. /home/old=/home/new
+#[derive(Clone, Debug)]
+pub struct LinkRewrite {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+pub fn parse_link_rewrite(input: &str) -> Result<LinkRewrite, String> {
+    match input.split_once('=') {
+        Some((from, to)) if Path::new(from).is_absolute() => Ok(LinkRewrite { from: PathBuf::from(from), to: PathBuf::from(to) }),
+        Some(_) => Err("the prefix to rewrite must be an absolute path".to_string()),
+        None => Err("expected OLD=NEW".to_string()),
+    }
+}
+
+// Applies the first rewrite whose prefix the target is under. Relative targets are left alone, since they
+// still point at the right place once restored anywhere
+pub fn rewrite_link(target: &Path, rewrites: &[LinkRewrite]) -> PathBuf {
+    for rewrite in rewrites {
+        if let Ok(rest) = target.strip_prefix(&rewrite.from) {
+            return if rest.as_os_str().is_empty() { rewrite.to.clone() } else { rewrite.to.join(rest) };
+        }
+    }
+    target.to_path_buf()
+}
+
+// Who restored files are owned by. Recorded owners are kept when restoring as root, like tar does, and otherwise
+// files belong to whoever restores them
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct OwnerOptions {
+    // Give every restored file to this user, and their primary group unless another's given, e.g. `backup:backup`
+    #[arg(long = "chown", value_name = "USER[:GROUP]", value_parser = privileges::parse_run_as, env = "ATHENA_CHOWN")]
+    pub chown: Option<RunAs>,
+    // Restore files recorded as owned by one uid as owned by another, e.g. `--map-uid 1000:2000`. Needs root
+    #[arg(long = "map-uid", value_name = "FROM:TO", value_parser = parse_uid_map, value_delimiter = ',', env = "ATHENA_MAP_UID")]
+    pub map_uid: Vec<(u32, u32)>,
+    // Leave restored files owned by whoever's restoring, even as root
+    #[arg(long = "no-same-owner", conflicts_with_all = ["chown", "map_uid"], env = "ATHENA_NO_SAME_OWNER")]
+    pub no_same_owner: bool,
+}
+
+pub fn parse_uid_map(input: &str) -> Result<(u32, u32), String> {
+    let (from, to) = input.split_once(':').ok_or("expected FROM:TO")?;
+    let uid = |uid: &str| uid.parse::<u32>().map_err(|_| format!("invalid uid '{}'", uid));
+    Ok((uid(from)?, uid(to)?))
+}
+
+impl OwnerOptions {
+    // Only root can give files to someone else, so mapping uids without it would fail on the first file
+    pub fn check(&self) -> Result<(), String> {
+        if !self.map_uid.is_empty() && !privileges::is_root() {
+            return Err("--map-uid needs root, files restored without it are owned by whoever restores them".to_string());
+        }
+        Ok(())
+    }
+
+    // The uid and gid to give an entry recorded as owned by `recorded`, if it was, or None to leave it as created
+    fn owner(&self, recorded: Option<(u32, u32)>) -> Option<(u32, u32)> {
+        if let Some(chown) = &self.chown {
+            return Some((chown.uid, chown.gid));
+        }
+        if self.no_same_owner || !privileges::is_root() {
+            return None;
+        }
+        let (uid, gid) = recorded?;
+        let uid = self.map_uid.iter().find(|(from, _)| *from == uid).map_or(uid, |(_, to)| *to);
+        Some((uid, gid))
+    }
+
+    // Changing the owner clears setuid and setgid bits, so the mode's set again afterwards
+    fn apply(&self, path: &Path, recorded: Option<(u32, u32)>, mode: Option<u32>) -> Result<(), Box<dyn Error>> {
+        let Some((uid, gid)) = self.owner(recorded) else {
+            return Ok(());
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::os::unix::fs::lchown(path, Some(uid), Some(gid)).map_err(|e| format!("Failed to set the owner of {}: {}", path.display(), e))?;
+            if let Some(mode) = mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (path, uid, gid, mode);
+        Ok(())
+    }
+}
+
+pub struct RestoreOptions<'a> {
+    pub target: &'a Path,
+    // Only restore entries under these paths; empty means everything
+    pub paths: &'a [String],
+    pub rewrites: &'a [LinkRewrite],
+    pub password_file: Option<&'a Path>,
+    // Trust entry paths as they are, allowing absolute paths, `..` and writing through restored symlinks
+    pub unsafe_paths: bool,
+    // Remove the files an incremental archive's manifest records as deleted since the run before it
+    pub apply_deletions: bool,
+    pub path_style: PathStyle,
+    // Restore capabilities and chattr flags, see fileattrs
+    pub file_attrs: bool,
+    pub owners: &'a OwnerOptions,
+}
+
+// Extracts a tar, tgz or zip archive made by athena into the target directory. Returns the number of entries restored
+pub fn run(archive: &Path, options: &RestoreOptions) -> Result<usize, Box<dyn Error>> {
+    fs::create_dir_all(options.target)?;
+    let kind = validate::readable(validate::detect(archive)?)?;
+    let spinner = utils::construct_spinner();
+    spinner.set_message("Restoring files...");
+    let restored = match kind {
+        ArchiveKind::Zip => restore_zip(archive, options),
+        ArchiveKind::Gzip => restore_tar(MultiGzDecoder::new(BufReader::new(fs::File::open(archive)?)), options),
+        ArchiveKind::Sealed => crypto::open_sealed(archive, options.password_file).and_then(|mut reader| {
+            let restored = restore_tar(&mut reader, options)?;
+            // The tar ends before the last chunk might, which has to be read for a truncated archive to be caught
+            io::copy(&mut reader, &mut io::sink())?;
+            Ok(restored)
+        }),
+        _ => restore_tar(BufReader::new(fs::File::open(archive)?), options),
+    };
+    spinner.finish_and_clear();
+    restored
+}
+
+// Writes the wanted entries of an archive to `out` as a plain tar, decompressed and decrypted, for --to-stdout.
+// Deletions recorded by incrementals can't be expressed in a tar, so aren't applied. Returns the number of
+// entries written
+pub fn stream<W: Write>(archive: &Path, options: &RestoreOptions, out: &mut tar::Builder<W>) -> Result<usize, Box<dyn Error>> {
+    match validate::readable(validate::detect(archive)?)? {
+        ArchiveKind::Zip => stream_zip(archive, options, out),
+        ArchiveKind::Gzip => stream_tar(MultiGzDecoder::new(BufReader::new(fs::File::open(archive)?)), options, out),
+        ArchiveKind::Sealed => {
+            let mut reader = crypto::open_sealed(archive, options.password_file)?;
+            let streamed = stream_tar(&mut reader, options, out)?;
+            io::copy(&mut reader, &mut io::sink())?;
+            Ok(streamed)
+        },
+        _ => stream_tar(BufReader::new(fs::File::open(archive)?), options, out),
+    }
+}
+
+fn stream_tar<R: Read, W: Write>(reader: R, options: &RestoreOptions, out: &mut tar::Builder<W>) -> Result<usize, Box<dyn Error>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut written = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = pathstyle::entry_path(&entry.path()?, options.path_style);
+        let kind = entry.header().entry_type();
+        if !wanted(&name, options) || kind.is_pax_global_extensions() {
+            continue;
+        }
+        merge::copy_entry(out, &mut entry, &name, |target, symlink| match symlink {
+            true => rewrite_link(&pathstyle::link_target(target, options.path_style), options.rewrites),
+            false => pathstyle::entry_path(target, options.path_style),
+        })?;
+        if !kind.is_dir() {
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+fn stream_zip<W: Write>(archive: &Path, options: &RestoreOptions, out: &mut tar::Builder<W>) -> Result<usize, Box<dyn Error>> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(fs::File::open(archive)?))?;
+    let mut password: Option<String> = None;
+    let mut written = 0;
+    for i in 0..zip.len() {
+        let encrypted = zip.by_index_raw(i)?.encrypted();
+        if encrypted && password.is_none() {
+            password = Some(utils::read_secret(options.password_file, "ATHENA_PASSWORD", "Archive password", false)?);
+        }
+        let mut entry = match (&password, encrypted) {
+            (Some(password), true) => zip.by_index_decrypt(i, password.as_bytes()).map_err(|_| "Wrong archive password")?,
+            _ => zip.by_index(i)?,
+        };
+        let name = pathstyle::entry_path(Path::new(entry.name()), options.path_style);
+        if !wanted(&name, options) {
+            continue;
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(entry.last_modified().and_then(list::zip_timestamp).map_or(0, |mtime| mtime.max(0) as u64));
+        let mode = entry.unix_mode();
+        if entry.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(mode.unwrap_or(0o755) & 0o7777);
+            header.set_size(0);
+            out.append_data(&mut header, &name, std::io::empty())?;
+            continue;
+        }
+        header.set_mode(mode.unwrap_or(0o644) & 0o7777);
+        if mode.is_some_and(|mode| mode & 0o170000 == 0o120000) {
+            let mut target = String::new();
+            entry.read_to_string(&mut target)?;
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            out.append_link(&mut header, &name, rewrite_link(&pathstyle::link_target(Path::new(&target), options.path_style), options.rewrites))?;
+        } else {
+            header.set_size(entry.size());
+            out.append_data(&mut header, &name, &mut entry)?;
+        }
+        written += 1;
+    }
+    Ok(written)
+}
+
+// How many of the top-level directories a restore creates are named in its preview
+const PREVIEW_DIRS: usize = 10;
+
+// What a restore is about to write, shown before anything is extracted so a restore too big for the target can
+// be stopped before it fills the disk
+pub struct Preview {
+    pub entries: usize,
+    // Uncompressed size of everything to be restored. For a chain that's an upper bound, as later archives can
+    // overwrite files from earlier ones
+    pub size: u64,
+    // Top-level directories the restore creates in the target
+    pub new_dirs: Vec<String>,
+    // Space free on the target's filesystem, if it could be found out
+    pub available: Option<u64>,
+}
+
+impl Preview {
+    pub fn fits(&self) -> bool {
+        self.available.is_none_or(|available| self.size <= available)
+    }
+}
+
+impl std::fmt::Display for Preview {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} entries, {} uncompressed", self.entries, utils::format_size(self.size))?;
+        if !self.new_dirs.is_empty() {
+            write!(f, "\nCreates {}", self.new_dirs.iter().take(PREVIEW_DIRS).map(|dir| format!("{}/", dir)).collect::<Vec<_>>().join(", "))?;
+            if self.new_dirs.len() > PREVIEW_DIRS {
+                write!(f, " and {} more", self.new_dirs.len() - PREVIEW_DIRS)?;
+            }
+        }
+        match self.available {
+            Some(available) if !self.fits() => write!(f, "\nOnly {} free on the target, not enough room", utils::format_size(available)),
+            Some(available) => write!(f, "\n{} free on the target", utils::format_size(available)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Works out what restoring `archives` would write, from their footer indexes where they have them and by reading
+// through them otherwise
+pub fn preview(archives: &[PathBuf], options: &RestoreOptions) -> Result<Preview, Box<dyn Error>> {
+    let mut entries = 0;
+    let mut size = 0;
+    let mut new_dirs = BTreeSet::new();
+    for archive in archives {
+        for entry in list::list(archive, options.password_file)? {
+            let name = &pathstyle::entry_path(Path::new(&entry.path), options.path_style);
+            if !wanted(name, options) {
+                continue;
+            }
+            entries += 1;
+            size += entry.size;
+            let is_dir = entry.path.ends_with('/') || name.components().nth(1).is_some();
+            if let Some(Component::Normal(top)) = name.components().next().filter(|_| is_dir) {
+                if !options.target.join(top).exists() {
+                    new_dirs.insert(top.to_string_lossy().to_string());
+                }
+            }
+        }
+    }
+    // The target's created when the restore starts, so free space is looked up on the nearest directory that exists
+    let existing = options.target.ancestors().find(|dir| dir.exists()).unwrap_or(Path::new("."));
+    Ok(Preview { entries, size, new_dirs: new_dirs.into_iter().collect(), available: utils::available_space(existing) })
+}
+
+// The archives that make up the tree as of `archive` when it came from an incremental run: the last full backup
+// before it, then each incremental after that up to and including it, oldest first. The chain is read from the
+// incremental state, which by default is kept alongside the archives
+pub fn chain(archive: &Path, state_file: Option<&Path>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let dir = archive.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = archive.file_name().ok_or("Archive path has no file name")?.to_string_lossy().to_string();
+    let candidates = match state_file {
+        Some(state_file) => vec![state_file.to_path_buf()],
+        None => {
+            let mut candidates: Vec<PathBuf> = fs::read_dir(dir)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.file_name().map(|name| name.to_string_lossy()).is_some_and(|name| name.starts_with(".athena-") && name.ends_with(".state.json")))
+                .collect();
+            candidates.sort();
+            candidates
+        },
+    };
+    for candidate in candidates {
+        let state = incremental::State::load(&candidate)?;
+        let Some(end) = state.runs.iter().rposition(|run| run.archive == name) else {
+            continue;
+        };
+        let start = state.runs[..=end].iter().rposition(|run| run.full).ok_or_else(|| format!("No full backup before {} in {}", name, candidate.display()))?;
+        return state.runs[start..=end]
+            .iter()
+            .map(|run| {
+                let path = dir.join(&run.archive);
+                if path.exists() { Ok(path) } else { Err(format!("{} is part of the chain but missing from {}", run.archive, dir.display()).into()) }
+            })
+            .collect();
+    }
+    Err(format!("{} isn't recorded in any incremental state in {}, pass --state-file", name, dir.display()).into())
+}
+
+fn wanted(name: &Path, options: &RestoreOptions) -> bool {
+    name != Path::new(manifest::MANIFEST_NAME) && name != Path::new(footer::INDEX_NAME) && crate::repo::matches_paths(&name.to_string_lossy(), options.paths)
+}
+
+fn destination(name: &Path, options: &RestoreOptions) -> Result<PathBuf, Box<dyn Error>> {
+    match options.unsafe_paths {
+        true => Ok(options.target.join(name)),
+        false => safe_destination(options.target, name),
+    }
+}
+
+// Where an entry should be written, refusing names that would land outside the target directory. That covers
+// absolute paths, `..` components, and paths through a symlink an earlier entry restored (e.g. `dir -> /etc`
+// followed by `dir/passwd`)
+pub fn safe_destination(target: &Path, name: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    if name.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("Refusing to restore {}: path leaves the target directory (use --unsafe-paths for trusted archives)", name.display()).into());
+    }
+    let is_symlink = |path: &Path| path.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    let mut dest = target.to_path_buf();
+    for (i, component) in name.components().enumerate() {
+        if i > 0 && is_symlink(&dest) {
+            return Err(format!("Refusing to restore {}: path goes through a symlink (use --unsafe-paths for trusted archives)", name.display()).into());
+        }
+        dest.push(component);
+    }
+    // Replace rather than write through a symlink already at the destination
+    if is_symlink(&dest) {
+        fs::remove_file(&dest)?;
+    }
+    Ok(dest)
+}
+
+pub fn restore_tar<R: Read>(reader: R, options: &RestoreOptions) -> Result<usize, Box<dyn Error>> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    let mut restored = 0;
+    // Flags are set once everything's restored, as nothing can be written into an immutable directory
+    let mut flags = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let raw = entry.path()?.into_owned();
+        let name = pathstyle::entry_path(&raw, options.path_style);
+        if options.apply_deletions && name == Path::new(manifest::MANIFEST_NAME) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            apply_deletions(&contents, options)?;
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        // Global PAX headers only hold metadata, like where a footer index starts
+        if !wanted(&name, options) || kind.is_pax_global_extensions() {
+            continue;
+        }
+        let dest = destination(&name, options)?;
+        // Archives written by other tools may leave the owner out
+        let owner = entry.header().uid().ok().zip(entry.header().gid().ok()).map(|(uid, gid)| (uid as u32, gid as u32));
+        if kind.is_symlink() {
+            let target = entry.link_name()?.map(|t| t.into_owned()).unwrap_or_default();
+            symlink(&rewrite_link(&pathstyle::link_target(&target, options.path_style), options.rewrites), &dest)?;
+            options.owners.apply(&dest, owner, None)?;
+        } else {
+            // Hard links point at another entry's path, which has to be inside the target too
+            let link = match kind.is_hard_link() {
+                true => Some(destination(&pathstyle::entry_path(&entry.link_name()?.unwrap_or_default(), options.path_style), options)?),
+                false => None,
+            };
+            let records = pax_records(&mut entry, |key| key.starts_with(ntfs::KEY_PREFIX))?;
+            let attrs = match options.file_attrs {
+                true => pax_records(&mut entry, |key| key == fileattrs::CAPABILITY_KEY || key == fileattrs::FLAGS_KEY)?,
+                false => Vec::new(),
+            };
+            // unpack_in would use the name as stored, so renamed entries are written to where they're meant to go
+            if options.unsafe_paths || name != raw {
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                match link {
+                    Some(link) => {
+                        if dest.symlink_metadata().is_ok() {
+                            fs::remove_file(&dest)?;
+                        }
+                        fs::hard_link(link, &dest)?;
+                    },
+                    None => {
+                        entry.unpack(&dest)?;
+                    },
+                }
+            } else {
+                entry.unpack_in(options.target)?;
+            }
+            // Before capabilities are set, as a new owner clears them
+            options.owners.apply(&dest, owner, entry.header().mode().ok())?;
+            if !records.is_empty() {
+                ntfs::apply(&dest, &records)?;
+            }
+            fileattrs::apply_capability(&dest, &attrs)?;
+            if let Some((_, letters)) = attrs.into_iter().find(|(key, _)| key == fileattrs::FLAGS_KEY) {
+                flags.push((dest, letters));
+            }
+        }
+        if !kind.is_dir() {
+            restored += 1;
+        }
+    }
+    // Children before their directories, in case those are immutable too
+    for (dest, letters) in flags.iter().rev() {
+        fileattrs::apply_flags(dest, letters)?;
+    }
+    Ok(restored)
+}
+
+// Removes the files the manifest has tombstones for. It's the last entry, so nothing restored after it could
+// bring them back
+fn apply_deletions(manifest: &[u8], options: &RestoreOptions) -> Result<(), Box<dyn Error>> {
+    let manifest: manifest::Manifest = serde_json::from_slice(manifest)?;
+    for path in manifest.deleted.iter().map(Path::new).filter(|path| wanted(path, options)) {
+        match fs::remove_file(destination(path, options)?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(format!("Failed to remove {}: {}", path.display(), e).into()),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+// The entry's PAX records with keys `keep` accepts, like Windows metadata recorded with --windows-metadata
+fn pax_records<R: Read>(entry: &mut tar::Entry<R>, keep: impl Fn(&str) -> bool) -> Result<crate::pax::Records, Box<dyn Error>> {
+    let mut records = Vec::new();
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let key = extension.key()?;
+            if keep(key) {
+                records.push((key.to_string(), extension.value_bytes().to_vec()));
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn restore_zip(archive: &Path, options: &RestoreOptions) -> Result<usize, Box<dyn Error>> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(fs::File::open(archive)?))?;
+    let mut password: Option<String> = None;
+    let mut restored = 0;
+    for i in 0..zip.len() {
+        let encrypted = zip.by_index_raw(i)?.encrypted();
+        if encrypted && password.is_none() {
+            password = Some(utils::read_secret(options.password_file, "ATHENA_PASSWORD", "Archive password", false)?);
+        }
+        let mut entry = match (&password, encrypted) {
+            (Some(password), true) => zip.by_index_decrypt(i, password.as_bytes()).map_err(|_| "Wrong archive password")?,
+            _ => zip.by_index(i)?,
+        };
+        let name = pathstyle::entry_path(Path::new(entry.name()), options.path_style);
+        if options.apply_deletions && name == Path::new(manifest::MANIFEST_NAME) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            apply_deletions(&contents, options)?;
+            continue;
+        }
+        if !wanted(&name, options) {
+            continue;
+        }
+        let dest = destination(&name, options)?;
+        if entry.is_dir() {
+            fs::create_dir_all(&dest)?;
+            options.owners.apply(&dest, None, None)?;
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mode = entry.unix_mode();
+        if mode.map(|mode| mode & 0o170000 == 0o120000).unwrap_or(false) {
+            let mut target = String::new();
+            entry.read_to_string(&mut target)?;
+            symlink(&rewrite_link(&pathstyle::link_target(Path::new(&target), options.path_style), options.rewrites), &dest)?;
+        } else {
+            std::io::copy(&mut entry, &mut fs::File::create(&dest)?)?;
+            #[cfg(unix)]
+            if let Some(mode) = mode {
+                fs::set_permissions(&dest, std::os::unix::fs::PermissionsExt::from_mode(mode & 0o7777))?;
+            }
+        }
+        // Zips don't record owners, so only --chown applies
+        options.owners.apply(&dest, None, mode.filter(|mode| mode & 0o170000 != 0o120000))?;
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+// Makes the link, replacing whatever an earlier restore left at its path
+pub fn symlink(target: &Path, link: &Path) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if link.symlink_metadata().is_ok() {
+        fs::remove_file(link)?;
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, link)?;
+    #[cfg(not(unix))]
+    eprintln!("Skipping symlink {} -> {} (unsupported on this platform)", link.display(), target.display());
+    Ok(())
+}
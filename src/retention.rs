@@ -0,0 +1,79 @@
+use std::{collections::HashMap, fmt};
+use chrono::{DateTime, Datelike, FixedOffset};
+
+// Identifies the day, week, month or year a snapshot falls in
+type Period = fn(&DateTime<FixedOffset>) -> (i32, u32);
+
+// How many snapshots to keep: the newest `last`, plus the newest snapshot of each of the newest `daily` days,
+// `weekly` ISO weeks, `monthly` months and `yearly` years that have one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize,
+}
+
+// Parses a preset: `gfs` (grandfather-father-son: 7 daily, 4 weekly, 12 monthly) or `last-n=N`
+pub fn parse_policy(input: &str) -> Result<Policy, String> {
+    match input.split_once('=') {
+        None if input == "gfs" => Ok(Policy { daily: 7, weekly: 4, monthly: 12, ..Policy::default() }),
+        Some(("last-n", n)) => match n.parse() {
+            Ok(n) if n > 0 => Ok(Policy { last: n, ..Policy::default() }),
+            _ => Err(format!("invalid count '{}'", n)),
+        },
+        _ => Err("expected 'gfs' or 'last-n=N'".to_string()),
+    }
+}
+
+impl Policy {
+    pub fn is_empty(&self) -> bool {
+        *self == Policy::default()
+    }
+
+    // Which snapshots to keep, by index into `times`, with the rules that kept each one
+    pub fn apply(&self, times: &[DateTime<FixedOffset>]) -> HashMap<usize, Vec<&'static str>> {
+        let mut order: Vec<usize> = (0..times.len()).collect();
+        order.sort_by(|&a, &b| times[b].cmp(&times[a]));
+
+        let mut kept: HashMap<usize, Vec<&'static str>> = HashMap::new();
+        for &i in order.iter().take(self.last) {
+            kept.entry(i).or_default().push("last");
+        }
+        let rules: [(&'static str, usize, Period); 4] = [
+            ("daily", self.daily, |t| (t.year(), t.ordinal())),
+            ("weekly", self.weekly, |t| (t.iso_week().year(), t.iso_week().week())),
+            ("monthly", self.monthly, |t| (t.year(), t.month())),
+            ("yearly", self.yearly, |t| (t.year(), 0)),
+        ];
+        for (name, count, period) in rules {
+            let mut last_period = None;
+            let mut remaining = count;
+            // Newest first, so the first snapshot seen in each period is the one kept for it
+            for &i in &order {
+                if remaining == 0 {
+                    break;
+                }
+                let current = period(&times[i]);
+                if last_period != Some(current) {
+                    last_period = Some(current);
+                    kept.entry(i).or_default().push(name);
+                    remaining -= 1;
+                }
+            }
+        }
+        kept
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rules: Vec<String> = [("last", self.last), ("daily", self.daily), ("weekly", self.weekly), ("monthly", self.monthly), ("yearly", self.yearly)]
+            .iter()
+            .filter(|(_, n)| *n > 0)
+            .map(|(name, n)| format!("keep-{}={}", name, n))
+            .collect();
+        write!(f, "{}", rules.join(", "))
+    }
+}
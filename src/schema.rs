@@ -0,0 +1,250 @@
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use crate::{footer, index, manifest};
+
+// Bumped whenever a schema changes in a way existing readers could trip on. Adding optional fields doesn't count
+const SCHEMA_VERSION: u32 = 1;
+
+// The JSON athena writes that other tools can rely on
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Document {
+    // The object uploaded next to each archive
+    RunManifest,
+    // The manifest entry stored in an archive
+    Manifest,
+    // The entry index kept next to seekable archives, and at the end of ones with a footer index
+    Index,
+}
+
+// JSON Schema (draft 2020-12) of the document. The tests below check it against the structs in manifest.rs and
+// index.rs, fully populated
+pub fn schema(document: Document) -> Value {
+    let (name, title, description, mut schema) = match document {
+        Document::RunManifest => ("run-manifest", "Athena run manifest", format!("Uploaded next to each archive as <archive>{}", manifest::RUN_MANIFEST_SUFFIX), run_manifest()),
+        Document::Manifest => ("manifest", "Athena archive manifest", format!("Stored as the last entry of an archive, named {}, when there's something to note", manifest::MANIFEST_NAME), archive_manifest()),
+        Document::Index => ("index", "Athena entry index", format!("Kept next to seekable archives as <archive>{}, and as the entry {} at the end of archives written with --footer-index", index::INDEX_SUFFIX, footer::INDEX_NAME), entry_index()),
+    };
+    schema["$schema"] = json!("https://json-schema.org/draft/2020-12/schema");
+    schema["$id"] = json!(format!("urn:athena:{}:v{}", name, SCHEMA_VERSION));
+    schema["title"] = json!(title);
+    schema["description"] = json!(description);
+    schema
+}
+
+fn run_manifest() -> Value {
+    json!({
+        "type": "object",
+        "required": ["archive", "size", "checksums", "tool_version", "created"],
+        "properties": {
+            "archive": { "type": "string", "description": "File name of the archive" },
+            "size": { "type": "integer", "minimum": 0, "description": "Size of the archive in bytes" },
+            "checksums": {
+                "type": "object",
+                "description": "Hex digest of the archive by algorithm name, always including sha256",
+                "additionalProperties": { "type": "string" },
+            },
+            "files": { "type": "integer", "minimum": 0, "description": "Files archived, absent for archives uploaded with `athena upload`" },
+            "hostname": { "type": "string", "description": "Machine the archive was made on, absent without host scoping" },
+            "machine_id": { "type": "string", "description": "Stable ID of the install, telling apart machines sharing a hostname" },
+            "comment": { "type": "string" },
+            "tags": { "type": "array", "items": { "type": "string" } },
+            "tool_version": { "type": "string", "description": "Version of athena that made the archive" },
+            "created": { "type": "string", "format": "date-time" },
+        },
+    })
+}
+
+fn archive_manifest() -> Value {
+    let file_error = json!({
+        "type": "object",
+        "required": ["path", "error"],
+        "properties": {
+            "path": { "type": "string", "description": "Path relative to the source" },
+            "error": { "type": "string" },
+        },
+    });
+    json!({
+        "type": "object",
+        "required": ["tool_version", "created"],
+        "properties": {
+            "tool_version": { "type": "string", "description": "Version of athena that made the archive" },
+            "created": { "type": "string", "format": "date-time" },
+            "hostname": { "type": "string", "description": "Machine the archive was made on, absent without host scoping" },
+            "machine_id": { "type": "string", "description": "Stable ID of the install, telling apart machines sharing a hostname" },
+            "comment": { "type": "string" },
+            "placeholders": {
+                "type": "array",
+                "description": "Files stored empty because they couldn't be read, or zero-filled because they shrank while being read",
+                "items": file_error,
+            },
+            "skipped": { "type": "array", "description": "Files left out because they were busy or unreadable", "items": file_error },
+            "deleted": {
+                "type": "array",
+                "description": "Paths an incremental run found deleted since the previous run",
+                "items": { "type": "string" },
+            },
+            "stopped": {
+                "type": "object",
+                "description": "Present when archiving stopped before every file was in",
+                "required": ["reason", "archived", "total", "next"],
+                "properties": {
+                    "reason": { "type": "string" },
+                    "archived": { "type": "integer", "minimum": 0 },
+                    "total": { "type": "integer", "minimum": 0 },
+                    "next": { "type": "string", "description": "First file left out" },
+                },
+            },
+        },
+    })
+}
+
+fn entry_index() -> Value {
+    json!({
+        "type": "object",
+        "required": ["entries"],
+        "properties": {
+            "entries": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["path", "start", "end"],
+                    "properties": {
+                        "path": { "type": "string" },
+                        "start": { "type": "integer", "minimum": 0, "description": "Offset of the entry's first header in the uncompressed tar" },
+                        "end": { "type": "integer", "minimum": 0, "description": "Offset just past the entry's padded data" },
+                        "size": { "type": "integer", "minimum": 0, "description": "Size of the entry's contents" },
+                        "hash": { "type": "string", "description": "blake3 of a regular file's contents, in footer indexes" },
+                        "mtime": { "type": "integer", "description": "Modification time of the source file, in seconds since the epoch" },
+                    },
+                },
+            },
+            "frames": {
+                "type": "array",
+                "description": "Where each gzip member of a seekable archive starts",
+                "items": {
+                    "type": "object",
+                    "required": ["offset", "start"],
+                    "properties": {
+                        "offset": { "type": "integer", "minimum": 0, "description": "Offset of the member in the archive file" },
+                        "start": { "type": "integer", "minimum": 0, "description": "Offset of its first byte in the uncompressed tar" },
+                    },
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the value against the parts of JSON Schema used above: types, required keys, properties (with no
+    // others allowed), items and additionalProperties
+    fn check(value: &Value, schema: &Value, at: &str) -> Result<(), String> {
+        let matches = match schema["type"].as_str() {
+            Some("object") => value.is_object(),
+            Some("array") => value.is_array(),
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            kind => return Err(format!("{}: unexpected schema type {:?}", at, kind)),
+        };
+        if !matches {
+            return Err(format!("{}: {} isn't of type {}", at, value, schema["type"]));
+        }
+        if schema.get("minimum").is_some() && value.as_u64().is_none() {
+            return Err(format!("{}: {} is negative", at, value));
+        }
+        for key in schema["required"].as_array().into_iter().flatten() {
+            if value.get(key.as_str().unwrap()).is_none() {
+                return Err(format!("{}: {} is required", at, key));
+            }
+        }
+        if let Some(object) = value.as_object() {
+            for (key, field) in object {
+                let at = format!("{}.{}", at, key);
+                match (schema["properties"].get(key), schema.get("additionalProperties")) {
+                    (Some(property), _) | (None, Some(property)) => check(field, property, &at)?,
+                    (None, None) => return Err(format!("{} isn't in the schema", at)),
+                }
+            }
+        }
+        for (i, item) in value.as_array().into_iter().flatten().enumerate() {
+            check(item, &schema["items"], &format!("{}[{}]", at, i))?;
+        }
+        Ok(())
+    }
+
+    // Every property the schema describes, so a fully populated document can be checked for having them all
+    fn properties(schema: &Value, at: &str, found: &mut Vec<String>) {
+        for (key, property) in schema["properties"].as_object().into_iter().flatten() {
+            let at = format!("{}.{}", at, key);
+            properties(property, &at, found);
+            properties(&property["items"], &format!("{}[]", at), found);
+            found.push(at);
+        }
+    }
+
+    fn written(value: &Value, at: &str, found: &mut Vec<String>) {
+        for (key, field) in value.as_object().into_iter().flatten() {
+            let at = format!("{}.{}", at, key);
+            written(field, &at, found);
+            if let Some(item) = field.as_array().and_then(|items| items.first()) {
+                written(item, &format!("{}[]", at), found);
+            }
+            found.push(at);
+        }
+    }
+
+    // Struct literals without `..Default::default()`, so a field added to one of them fails to build here until
+    // it's given a value, and so checked against its schema
+    fn documents() -> Vec<(Document, Value)> {
+        let placeholder = || manifest::Placeholder { path: "var/log/syslog".to_string(), error: "permission denied".to_string() };
+        let manifest = manifest::Manifest {
+            tool_version: "1.0.0".to_string(),
+            created: "2026-10-15T09:00:00+00:00".to_string(),
+            hostname: Some("nas".to_string()),
+            machine_id: Some("0123abcd".to_string()),
+            comment: Some("before upgrade".to_string()),
+            placeholders: vec![placeholder()],
+            skipped: vec![placeholder()],
+            deleted: vec!["old.txt".to_string()],
+            stopped: Some(manifest::StoppedEarly { reason: "--max-duration 2h passed".to_string(), archived: 10, total: 20, next: "b.txt".to_string() }),
+        };
+        let run_manifest = manifest::RunManifest {
+            archive: "202610150900-nas-home.tgz".to_string(),
+            size: 1024,
+            checksums: [("sha256".to_string(), "ab".repeat(32))].into(),
+            files: Some(10),
+            hostname: Some("nas".to_string()),
+            machine_id: Some("0123abcd".to_string()),
+            comment: Some("before upgrade".to_string()),
+            tags: vec!["nightly".to_string()],
+            tool_version: "1.0.0".to_string(),
+            created: "2026-10-15T09:00:00+00:00".to_string(),
+        };
+        let index = index::EntryIndex {
+            entries: vec![index::IndexedEntry { path: "a.txt".to_string(), start: 0, end: 1024, size: 5, hash: Some("cd".repeat(32)), mtime: Some(1_760_000_000) }],
+            frames: vec![index::Frame { offset: 0, start: 0 }],
+        };
+        vec![
+            (Document::Manifest, serde_json::to_value(manifest).unwrap()),
+            (Document::RunManifest, serde_json::to_value(run_manifest).unwrap()),
+            (Document::Index, serde_json::to_value(index).unwrap()),
+        ]
+    }
+
+    #[test]
+    fn schemas_match_what_is_written() {
+        for (document, value) in documents() {
+            let schema = schema(document);
+            let name = schema["$id"].as_str().unwrap().to_string();
+            check(&value, &schema, &name).unwrap();
+            // Nothing described that's no longer written
+            let (mut described, mut found) = (Vec::new(), Vec::new());
+            properties(&schema, &name, &mut described);
+            written(&value, &name, &mut found);
+            described.retain(|property| !found.contains(property));
+            assert!(described.is_empty(), "{:?} in the schema but not written", described);
+        }
+    }
+}
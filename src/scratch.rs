@@ -0,0 +1,95 @@
+use std::{fs, io, path::PathBuf, sync::Mutex};
+use tempfile::TempDir;
+
+// Scratch files and directories go in a directory of athena's own inside the temp directory, one per run, each
+// with a lock file the run holds until it exits. A run that crashed or was killed lets go of its lock, so what it
+// left can be told apart from what's still in use, even by runs in other containers or on other hosts sharing TMPDIR
+const ROOT: &str = "athena-scratch";
+
+static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+// This run's directory, and its locked lock file alongside it
+static RUN: Mutex<Option<(PathBuf, fs::File)>> = Mutex::new(None);
+
+// Sets where scratch space goes for the rest of the run, instead of TMPDIR or the system's temp directory
+pub fn configure(dir: Option<PathBuf>) -> Result<(), String> {
+    if let Some(dir) = &dir {
+        if !dir.is_dir() {
+            return Err(format!("Temp directory {} doesn't exist", dir.display()));
+        }
+    }
+    *DIR.lock().unwrap() = dir;
+    Ok(())
+}
+
+pub fn dir() -> PathBuf {
+    DIR.lock().unwrap().clone().unwrap_or_else(std::env::temp_dir)
+}
+
+// This run's directory, made along with its lock file the first time it's needed
+fn run_dir() -> io::Result<PathBuf> {
+    let mut run = RUN.lock().unwrap();
+    if let Some((dir, _)) = &*run {
+        return Ok(dir.clone());
+    }
+    let root = dir().join(ROOT);
+    fs::create_dir_all(&root)?;
+    // Locked before it gets the name other runs look for, so they never find it unlocked
+    let lock = tempfile::Builder::new().prefix("run-").suffix(".new").tempfile_in(&root)?;
+    lock.as_file().lock()?;
+    let lock_path = lock.path().with_extension("lock");
+    let lock = lock.persist(&lock_path)?;
+    let dir = lock_path.with_extension("");
+    fs::create_dir(&dir)?;
+    *run = Some((dir.clone(), lock));
+    Ok(dir)
+}
+
+// A scratch directory, removed with everything in it when it's dropped or the run exits
+pub fn tempdir() -> io::Result<TempDir> {
+    tempfile::Builder::new().prefix("athena-").tempdir_in(run_dir()?)
+}
+
+// Removes this run's scratch files. Needed because exiting skips the destructors that would otherwise do it
+pub fn cleanup() {
+    let Some((dir, lock)) = RUN.lock().unwrap().take() else {
+        return;
+    };
+    remove_run(&dir, lock);
+}
+
+// Removes scratch files left behind by runs that are no longer running, e.g. ones that were killed: those whose
+// lock file can be locked. Nothing else in the temp directory is touched
+pub fn remove_stale() {
+    let root = dir().join(ROOT);
+    let Ok(entries) = fs::read_dir(&root) else {
+        return;
+    };
+    let own = RUN.lock().unwrap().as_ref().map(|(dir, _)| dir.with_extension("lock"));
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != "lock") || own.as_ref() == Some(&path) {
+            continue;
+        }
+        let Ok(lock) = fs::OpenOptions::new().read(true).write(true).open(&path) else {
+            continue;
+        };
+        if lock.try_lock().is_ok() {
+            remove_run(&path.with_extension(""), lock);
+        }
+    }
+}
+
+// Removes a run's directory and then its lock file, and athena's directory once no run is using it
+fn remove_run(dir: &std::path::Path, lock: fs::File) {
+    if let Err(e) = fs::remove_dir_all(dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!("Warning: failed to remove temp directory {}: {}", dir.display(), e);
+        }
+    }
+    drop(lock);
+    let _ = fs::remove_file(dir.with_extension("lock"));
+    if let Some(root) = dir.parent() {
+        let _ = fs::remove_dir(root);
+    }
+}
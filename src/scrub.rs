@@ -0,0 +1,109 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}, error::Error};
+use chrono::{DateTime, Local};
+use crate::{backend::{self, Backend, RemoteOptions}, download::{self, DownloadOptions}, find, index, manifest, status, verify, webhook};
+
+// Where each archive was last scrubbed, so each run carries on with the ones checked longest ago
+pub fn default_state_path() -> PathBuf {
+    status::default_path().with_file_name("scrub.json")
+}
+
+// What a scrub checked, each archive by its path or URL
+#[derive(Default)]
+pub struct Report {
+    pub archives: usize,
+    pub checked: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+enum Archive {
+    Local(PathBuf),
+    Remote(String, u64),
+}
+
+// Reads `count` archives end to end, local ones in `dest` and uploaded ones under the remote's prefix, picking
+// the ones that have gone longest without being checked. Local archives are checked against their footer index
+// and checksum file, uploaded ones against the sha256 in their run manifest too
+pub fn run(dest: Option<&Path>, remote: Option<&RemoteOptions>, count: usize, state_file: &Path, password_file: Option<&Path>) -> Result<Report, Box<dyn Error>> {
+    let mut candidates = BTreeMap::new();
+    // Where the archives looked through are, as the start of their ids
+    let mut scopes = Vec::new();
+    if let Some(dest) = dest {
+        scopes.push(dest.join("").display().to_string());
+        for path in find::archives(&[dest.to_path_buf()])? {
+            candidates.insert(path.display().to_string(), Archive::Local(path));
+        }
+    }
+    let backend = remote.map(backend::connect).transpose()?;
+    if let (Some(backend), Some(remote)) = (&backend, remote) {
+        scopes.push(backend.url(&remote.key_for("")));
+        for (key, size) in backend.list(&remote.key_for(""))? {
+            if !key.ends_with(index::INDEX_SUFFIX) && !key.ends_with(manifest::RUN_MANIFEST_SUFFIX) {
+                candidates.insert(backend.url(&key), Archive::Remote(key, size));
+            }
+        }
+    }
+
+    let mut scrubbed: BTreeMap<String, String> = match fs::read(state_file) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| format!("Invalid scrub state {}: {}", state_file.display(), e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", state_file.display(), e).into()),
+    };
+    // Archives that are gone, e.g. pruned, are forgotten. Ones somewhere this scrub didn't look are left be, in case
+    // the state is shared with other scrubs
+    scrubbed.retain(|id, _| candidates.contains_key(id) || !scopes.iter().any(|scope| id.starts_with(scope.as_str())));
+    let last = |id: &String| scrubbed.get(id).and_then(|time| DateTime::parse_from_rfc3339(time).ok()).map(|time| time.timestamp());
+    let mut order: Vec<&String> = candidates.keys().collect();
+    // Never-scrubbed archives first, then oldest check first
+    order.sort_by_key(|id| last(id));
+    let due: Vec<String> = order.into_iter().take(count).cloned().collect();
+
+    let mut report = Report { archives: candidates.len(), ..Report::default() };
+    for id in due {
+        let result = match &candidates[&id] {
+            Archive::Local(path) => check_local(path, password_file),
+            Archive::Remote(key, size) => check_remote(backend.as_deref().ok_or("No backend")?, key, *size, password_file),
+        };
+        match result {
+            Ok(()) => println!("OK    {}", id),
+            Err(e) => {
+                eprintln!("FAIL  {}: {}", id, e);
+                report.failed.push((id.clone(), e.to_string()));
+            },
+        }
+        scrubbed.insert(id.clone(), Local::now().to_rfc3339());
+        report.checked.push(id);
+    }
+
+    if let Some(dir) = state_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written aside and renamed into place, so an interrupted scrub never leaves the state truncated
+    let mut temp = state_file.as_os_str().to_os_string();
+    temp.push(".tmp");
+    fs::write(&temp, serde_json::to_vec_pretty(&scrubbed)?)
+        .and_then(|_| fs::rename(&temp, state_file))
+        .map_err(|e| format!("Failed to write {}: {}", state_file.display(), e))?;
+    let failed: Vec<_> = report.failed.iter().map(|(archive, error)| serde_json::json!({ "archive": archive, "error": error })).collect();
+    webhook::emit("scrub.complete", serde_json::json!({ "archives": report.archives, "checked": report.checked.len(), "failed": failed }));
+    Ok(report)
+}
+
+fn check_local(path: &Path, password_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    clean(verify::run(path, password_file)?)
+}
+
+// The whole archive is downloaded to scratch, so it's read just like a local one once its checksum matches
+fn check_remote(backend: &dyn Backend, key: &str, size: u64, password_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let archive = download::fetch(backend, key, size, &DownloadOptions { limit: None, parallel: 4 })?;
+    let result = download::verify(backend, key, &archive.path).and_then(|_| verify::run(&archive.path, password_file)).and_then(clean);
+    archive.remove();
+    result
+}
+
+fn clean(report: verify::Report) -> Result<(), Box<dyn Error>> {
+    match report.is_clean() {
+        true => Ok(()),
+        false if report.checksum.is_some_and(|(_, matched)| !matched) => Err(format!("{} checksum doesn't match", report.checksum.unwrap().0).into()),
+        false => Err(format!("{} entries mismatched, {} missing", report.mismatched, report.missing).into()),
+    }
+}
@@ -0,0 +1,158 @@
+use std::{fs, path::Path, error::Error, io::{Seek, SeekFrom, Write}};
+use crate::utils;
+
+const SPARSE_SIZE: u64 = 4 * 1024 * 1024;
+
+// Archives a generated fixture tree, restores it to a temp dir and compares the result against the original.
+// Returns false if any case didn't survive the round trip
+pub fn run(verbose: bool) -> Result<bool, Box<dyn Error>> {
+    let scratch = crate::scratch::tempdir()?;
+    let fixture = scratch.path().join("fixture");
+    let archives = scratch.path().join("archives");
+    let restored = scratch.path().join("restored");
+    for dir in [&fixture, &archives, &restored] {
+        fs::create_dir_all(dir)?;
+    }
+    let skipped = generate_fixture(&fixture)?;
+
+    let files = crate::process_sources(vec![fixture.clone()], crate::cancel::CancellationToken::default(), None, None, None, None).map_err(|e| e.to_string())?;
+    let mut options = utils::Options {
+        compression: true,
+        input_path: fixture.clone(),
+        sources: vec![fixture.clone()],
+        output_path: archives,
+        ..Default::default()
+    };
+    options.file_name = crate::archive_file_name(&options);
+    let archive_path = crate::construct_archive(&mut crate::pipeline::Files::listed(files), options, std::sync::Arc::new(crate::progress::NoProgress))?;
+
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(fs::File::open(&archive_path)?));
+    archive.set_preserve_permissions(true);
+    archive.unpack(&restored)?;
+
+    let mut failures = Vec::new();
+    let checked = compare_tree(&fixture, &restored, &fixture, &mut failures)?;
+
+    if verbose {
+        println!("Compared {} entries", checked);
+    }
+    for case in &skipped {
+        println!("[skip] {}", case);
+    }
+    for failure in &failures {
+        println!("[fail] {}", failure);
+    }
+    if failures.is_empty() {
+        println!("Self-test passed: {} entries round-tripped", checked);
+    } else {
+        println!("Self-test failed: {} of {} entries differ", failures.len(), checked);
+    }
+    Ok(failures.is_empty())
+}
+
+// Writes the fixture tree, returning descriptions of any cases this platform can't exercise
+fn generate_fixture(root: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut skipped = vec!["extended attributes (not archived)".to_string()];
+
+    fs::write(root.join("plain.txt"), "athena self-test\n")?;
+    fs::write(root.join("empty"), "")?;
+    fs::create_dir_all(root.join("nested/deeper"))?;
+    fs::write(root.join("nested/deeper/data.bin"), (0..=255).cycle().take(100000).collect::<Vec<u8>>())?;
+    fs::write(root.join("ünïcødé-ファイル-🦀.txt"), "unicode\n")?;
+    fs::write(root.join("100% done"), "percent\n")?;
+
+    // Well over the 100 byte ustar name field and the 255 byte prefix+name limit
+    let mut long_dir = root.to_path_buf();
+    for i in 0..6 {
+        long_dir.push(format!("{}-{}", "long-directory-name".repeat(2), i));
+    }
+    fs::create_dir_all(&long_dir)?;
+    fs::write(long_dir.join(format!("{}.txt", "long-file-name-".repeat(10))), "long path\n")?;
+
+    // Mostly holes; the archive stores it densely but the content must still match
+    let mut sparse = fs::File::create(root.join("sparse.img"))?;
+    sparse.seek(SeekFrom::Start(SPARSE_SIZE - 5))?;
+    sparse.write_all(b"tail\n")?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        std::os::unix::fs::symlink("plain.txt", root.join("relative-link"))?;
+        std::os::unix::fs::symlink("/nonexistent/target", root.join("dangling-link"))?;
+        let executable = root.join("nested/script.sh");
+        fs::write(&executable, "#!/bin/sh\n")?;
+        fs::set_permissions(&executable, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+
+        // Names that aren't valid UTF-8 are fine on most unix filesystems, but some (e.g. APFS) refuse them
+        let raw_name = std::ffi::OsStr::from_bytes(b"raw-\xff\xfe-name.txt");
+        match fs::write(root.join(raw_name), "raw bytes\n") {
+            Ok(()) => std::os::unix::fs::symlink(raw_name, root.join(std::ffi::OsStr::from_bytes(b"raw-link-\xff")))?,
+            Err(_) => skipped.push("non-UTF-8 file names (unsupported by this filesystem)".to_string()),
+        }
+    }
+    #[cfg(not(unix))]
+    skipped.push("symlinks and permissions (unix only)".to_string());
+
+    Ok(skipped)
+}
+
+// Recursively compares entries under `original` against their restored counterparts, recording any differences.
+// Returns the number of entries checked
+fn compare_tree(dir: &Path, restored_root: &Path, original_root: &Path, failures: &mut Vec<String>) -> Result<usize, Box<dyn Error>> {
+    let mut checked = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let rel_path = path.strip_prefix(original_root)?.to_path_buf();
+        let restored = restored_root.join(&rel_path);
+        let metadata = path.symlink_metadata()?;
+        if metadata.is_dir() {
+            checked += compare_tree(&path, restored_root, original_root, failures)?;
+            continue;
+        }
+        checked += 1;
+        if let Some(failure) = compare_entry(&path, &restored, &metadata) {
+            failures.push(format!("{}: {}", rel_path.display(), failure));
+        }
+    }
+    Ok(checked)
+}
+
+fn compare_entry(original: &Path, restored: &Path, metadata: &fs::Metadata) -> Option<String> {
+    let restored_metadata = match restored.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return Some("missing from restored tree".to_string()),
+    };
+    if metadata.file_type().is_symlink() {
+        if !restored_metadata.file_type().is_symlink() {
+            return Some("restored as a regular file instead of a symlink".to_string());
+        }
+        if original.read_link().ok() != restored.read_link().ok() {
+            return Some("symlink target differs".to_string());
+        }
+        return None;
+    }
+    if metadata.len() != restored_metadata.len() {
+        return Some(format!("size differs ({} vs {})", metadata.len(), restored_metadata.len()));
+    }
+    match (fs::read(original), fs::read(restored)) {
+        (Ok(a), Ok(b)) if a == b => (),
+        (Ok(_), Ok(_)) => return Some("content differs".to_string()),
+        _ => return Some("could not be read".to_string()),
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o7777 != restored_metadata.permissions().mode() & 0o7777 {
+            return Some("permissions differ".to_string());
+        }
+    }
+    // Tar only stores whole seconds
+    if modified_secs(metadata) != modified_secs(&restored_metadata) {
+        return Some("modification time differs".to_string());
+    }
+    None
+}
+
+fn modified_secs(metadata: &fs::Metadata) -> Option<u64> {
+    metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
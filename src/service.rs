@@ -0,0 +1,74 @@
+use std::{env, fs, path::PathBuf, error::Error};
+
+// Writes a systemd service + timer pair that runs athena with the given arguments on a schedule.
+// Returns the paths of the written unit files
+pub fn install(name: &str, on_calendar: &str, system: bool, unit_dir: Option<PathBuf>, args: &[String]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Service name may only contain letters, numbers, '-' and '_'".into());
+    }
+    let unit_dir = match unit_dir {
+        Some(dir) => dir,
+        None => default_unit_dir(system)?,
+    };
+    fs::create_dir_all(&unit_dir)?;
+
+    let executable = env::current_exe()?;
+    let mut exec_start = vec![quote(&executable.to_string_lossy())];
+    exec_start.extend(args.iter().map(|arg| quote(arg)));
+
+    // Scheduled runs keep their status where `athena status` looks by default. Runs as root use the system-wide
+    // one, so a user service installed by root does too
+    let status_file = match system {
+        true => PathBuf::from("/var/lib/athena/status.json"),
+        false => crate::status::default_path(),
+    };
+
+    let unit_name = format!("athena-{}", name);
+    let service = format!(
+        "[Unit]\nDescription=Athena backup ({name})\nWants=network-online.target\nAfter=network-online.target\n\n\
+        [Service]\nType=oneshot\nStandardInput=null\nEnvironment={status}\nExecStart={exec}\n",
+        name = name,
+        status = quote(&format!("ATHENA_STATUS_FILE={}", status_file.display())),
+        exec = exec_start.join(" ")
+    );
+    let timer = format!(
+        "[Unit]\nDescription=Run Athena backup ({name}) on schedule\n\n\
+        [Timer]\nOnCalendar={calendar}\nPersistent=true\n\n\
+        [Install]\nWantedBy=timers.target\n",
+        name = name,
+        calendar = on_calendar
+    );
+
+    let service_path = unit_dir.join(format!("{}.service", unit_name));
+    let timer_path = unit_dir.join(format!("{}.timer", unit_name));
+    fs::write(&service_path, service)?;
+    fs::write(&timer_path, timer)?;
+    Ok(vec![service_path, timer_path])
+}
+
+fn default_unit_dir(system: bool) -> Result<PathBuf, Box<dyn Error>> {
+    if system {
+        return Ok(PathBuf::from("/etc/systemd/system"));
+    }
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(config) => Ok(PathBuf::from(config).join("systemd/user")),
+        None => match env::var_os("HOME") {
+            Some(home) => Ok(PathBuf::from(home).join(".config/systemd/user")),
+            None => Err("Could not determine the user unit directory, pass --unit-dir".into()),
+        },
+    }
+}
+
+// Quotes an argument for an ExecStart= line, escaping systemd's specifier and variable expansion
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if escaped.is_empty() || escaped.contains(char::is_whitespace) || escaped != arg {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
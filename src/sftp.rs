@@ -0,0 +1,379 @@
+use std::{collections::hash_map::DefaultHasher, env, hash::{Hash, Hasher}, io::{self, BufReader, Read, Write}, path::{Path, PathBuf}, process::{Child, ChildStdin, ChildStdout, Command, Stdio}, sync::{Arc, Mutex}, time::{Duration, SystemTime}, error::Error};
+use crate::{exclude::FileType, sourcefs::{SourceFs, Stat}};
+
+// The protocol version OpenSSH and most other servers speak
+const VERSION: u32 = 3;
+// Bytes asked for per read. Servers cap reads, OpenSSH at 256KiB, and a short read is just followed by another
+const READ_SIZE: u32 = 64 * 1024;
+
+const INIT: u8 = 1;
+const OPEN: u8 = 3;
+const CLOSE: u8 = 4;
+const READ: u8 = 5;
+const LSTAT: u8 = 7;
+const OPENDIR: u8 = 11;
+const READDIR: u8 = 12;
+const REALPATH: u8 = 16;
+const STAT: u8 = 17;
+const READLINK: u8 = 19;
+const STATUS: u8 = 101;
+const HANDLE: u8 = 102;
+const DATA: u8 = 103;
+const NAME: u8 = 104;
+const ATTRS: u8 = 105;
+
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+const OPEN_READ: u32 = 1;
+
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+// A source given as ssh://[user@]host[:port]/path
+#[derive(Clone, Debug)]
+pub struct SshSource {
+    // user@host, as ssh takes it
+    pub destination: String,
+    pub port: Option<u16>,
+    pub path: PathBuf,
+}
+
+impl SshSource {
+    // Returns None for anything that isn't an ssh:// URL
+    pub fn parse(input: &str) -> Result<Option<SshSource>, Box<dyn Error>> {
+        let Some(rest) = input.strip_prefix("ssh://") else {
+            return Ok(None);
+        };
+        let (authority, path) = rest.split_once('/').ok_or_else(|| format!("No path in '{}', expected e.g. ssh://user@host/var/www", input))?;
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((destination, port)) => (destination, Some(port.parse().map_err(|_| format!("Invalid port '{}' in '{}'", port, input))?)),
+            None => (authority, None),
+        };
+        if destination.is_empty() || destination.ends_with('@') {
+            return Err(format!("No host in '{}'", input).into());
+        }
+        Ok(Some(SshSource { destination: destination.to_string(), port, path: Path::new("/").join(path) }))
+    }
+}
+
+impl std::fmt::Display for SshSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "ssh://{}:{}{}", self.destination, port, self.path.display()),
+            None => write!(f, "ssh://{}{}", self.destination, self.path.display()),
+        }
+    }
+}
+
+// Reads sources on another machine through its SFTP server, over the system's ssh so its config, keys and agent
+// are used as they would be anywhere else. ATHENA_SSH replaces the ssh command, e.g. with `ssh -i key`
+pub struct SftpFs {
+    session: Arc<Mutex<Session>>,
+}
+
+impl SftpFs {
+    pub fn connect(source: &SshSource) -> Result<SftpFs, Box<dyn Error>> {
+        let ssh = env::var("ATHENA_SSH").unwrap_or_else(|_| "ssh".to_string());
+        let mut words = ssh.split_whitespace();
+        let mut command = Command::new(words.next().ok_or("ATHENA_SSH is empty")?);
+        command.args(words);
+        if let Some(port) = source.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        let mut child = command.arg("-s").arg(&source.destination).arg("sftp")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run {}: {}", ssh, e))?;
+        let input = child.stdin.take().ok_or("ssh has no stdin")?;
+        let output = BufReader::new(child.stdout.take().ok_or("ssh has no stdout")?);
+        let mut session = Session { child, input, output, next_id: 0 };
+        let (kind, body) = session.init().map_err(|e| format!("Failed to start an SFTP session with {}: {}", source.destination, e))?;
+        let version = Parser(&body).u32()?;
+        if kind != 2 || version != VERSION {
+            return Err(format!("{} speaks SFTP version {}, only {} is supported", source.destination, version, VERSION).into());
+        }
+        Ok(SftpFs { session: Arc::new(Mutex::new(session)) })
+    }
+
+    fn stat(&self, kind: u8, path: &Path) -> io::Result<Stat> {
+        let (reply, body) = self.session.lock().unwrap().request(kind, &Packet::new().path(path).0)?;
+        expect(reply, ATTRS, &body)?;
+        attrs(&mut Parser(&body))
+    }
+}
+
+impl SourceFs for SftpFs {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut session = self.session.lock().unwrap();
+        let handle = session.handle(OPENDIR, &Packet::new().path(dir).0)?;
+        let mut entries = Vec::new();
+        let listed = loop {
+            let (reply, body) = match session.request(READDIR, &Packet::new().string(&handle).0) {
+                Ok(reply) => reply,
+                Err(e) => break Err(e),
+            };
+            if reply == STATUS && status(&body).is_err_and(|e| e.kind() == io::ErrorKind::UnexpectedEof) {
+                break Ok(());
+            }
+            match expect(reply, NAME, &body).and_then(|_| names(&body)) {
+                Ok(names) => entries.extend(names.into_iter().filter(|name| name != "." && name != "..").map(|name| dir.join(name))),
+                Err(e) => break Err(e),
+            }
+        };
+        session.close(&handle);
+        listed.map(|_| entries)
+    }
+
+    // Directories get an id from their real path, since SFTP has no inode numbers, so loops through symlinks are
+    // still caught
+    fn metadata(&self, path: &Path) -> io::Result<Stat> {
+        let mut stat = self.stat(STAT, path)?;
+        if stat.is_dir() {
+            let (reply, body) = self.session.lock().unwrap().request(REALPATH, &Packet::new().path(path).0)?;
+            expect(reply, NAME, &body)?;
+            let mut hasher = DefaultHasher::new();
+            names(&body)?.first().hash(&mut hasher);
+            stat.id = Some((0, hasher.finish()));
+        }
+        Ok(stat)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Stat> {
+        self.stat(LSTAT, path)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let (reply, body) = self.session.lock().unwrap().request(READLINK, &Packet::new().path(path).0)?;
+        expect(reply, NAME, &body)?;
+        names(&body)?.into_iter().next().map(PathBuf::from).ok_or_else(|| io::Error::other("Empty READLINK reply"))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let handle = self.session.lock().unwrap().handle(OPEN, &Packet::new().path(path).u32(OPEN_READ).u32(0).0)?;
+        Ok(Box::new(SftpFile { session: self.session.clone(), handle, offset: 0 }))
+    }
+}
+
+struct Session {
+    child: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    next_id: u32,
+}
+
+impl Session {
+    fn init(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        self.send(INIT, &VERSION.to_be_bytes())?;
+        self.receive()
+    }
+
+    // Sends a request and waits for its reply, returning the reply's type and what follows its id
+    fn request(&mut self, kind: u8, body: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+        self.send(kind, &[&id.to_be_bytes()[..], body].concat())?;
+        let (reply, body) = self.receive()?;
+        let mut parser = Parser(&body);
+        if parser.u32()? != id {
+            return Err(io::Error::other("SFTP reply out of order"));
+        }
+        Ok((reply, parser.0.to_vec()))
+    }
+
+    fn send(&mut self, kind: u8, body: &[u8]) -> io::Result<()> {
+        self.input.write_all(&(body.len() as u32 + 1).to_be_bytes())?;
+        self.input.write_all(&[kind])?;
+        self.input.write_all(body)?;
+        self.input.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut len = [0; 4];
+        self.output.read_exact(&mut len).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => io::Error::other("ssh closed the connection"),
+            _ => e,
+        })?;
+        let mut packet = vec![0; u32::from_be_bytes(len) as usize];
+        self.output.read_exact(&mut packet)?;
+        match packet.split_first() {
+            Some((&kind, body)) => Ok((kind, body.to_vec())),
+            None => Err(io::Error::other("Empty SFTP packet")),
+        }
+    }
+
+    fn handle(&mut self, kind: u8, body: &[u8]) -> io::Result<Vec<u8>> {
+        let (reply, body) = self.request(kind, body)?;
+        expect(reply, HANDLE, &body)?;
+        Parser(&body).string().map(|handle| handle.to_vec())
+    }
+
+    fn close(&mut self, handle: &[u8]) {
+        let _ = self.request(CLOSE, &Packet::new().string(handle).0);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// A remote file being read, closed when dropped
+struct SftpFile {
+    session: Arc<Mutex<Session>>,
+    handle: Vec<u8>,
+    offset: u64,
+}
+
+impl Read for SftpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = READ_SIZE.min(buf.len() as u32);
+        let (reply, body) = self.session.lock().unwrap().request(READ, &Packet::new().string(&self.handle).u64(self.offset).u32(len).0)?;
+        if reply == STATUS {
+            return match status(&body) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                result => result.map(|_| 0),
+            };
+        }
+        expect(reply, DATA, &body)?;
+        let data = Parser(&body).string()?;
+        let read = data.len().min(buf.len());
+        buf[..read].copy_from_slice(&data[..read]);
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl Drop for SftpFile {
+    fn drop(&mut self) {
+        self.session.lock().unwrap().close(&self.handle);
+    }
+}
+
+// A request being built up
+struct Packet(Vec<u8>);
+
+impl Packet {
+    fn new() -> Packet {
+        Packet(Vec::new())
+    }
+
+    fn u32(mut self, value: u32) -> Packet {
+        self.0.extend(value.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Packet {
+        self.0.extend(value.to_be_bytes());
+        self
+    }
+
+    fn string(self, value: &[u8]) -> Packet {
+        let mut packet = self.u32(value.len() as u32);
+        packet.0.extend(value);
+        packet
+    }
+
+    fn path(self, path: &Path) -> Packet {
+        self.string(path.to_string_lossy().as_bytes())
+    }
+}
+
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::other("Truncated SFTP packet"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+// Turns a reply that isn't the one wanted into an error: the server's own if it sent a status
+fn expect(reply: u8, wanted: u8, body: &[u8]) -> io::Result<()> {
+    match reply {
+        _ if reply == wanted => Ok(()),
+        STATUS => status(body).and(Err(io::Error::other("Unexpected SFTP status"))),
+        _ => Err(io::Error::other(format!("Unexpected SFTP reply {}", reply))),
+    }
+}
+
+fn status(body: &[u8]) -> io::Result<()> {
+    let mut parser = Parser(body);
+    let code = parser.u32()?;
+    let message = String::from_utf8_lossy(parser.string().unwrap_or_default()).to_string();
+    match code {
+        0 => Ok(()),
+        FX_EOF => Err(io::Error::new(io::ErrorKind::UnexpectedEof, message)),
+        FX_NO_SUCH_FILE => Err(io::Error::new(io::ErrorKind::NotFound, message)),
+        FX_PERMISSION_DENIED => Err(io::Error::new(io::ErrorKind::PermissionDenied, message)),
+        _ => Err(io::Error::other(message)),
+    }
+}
+
+// The file names in a NAME reply
+fn names(body: &[u8]) -> io::Result<Vec<String>> {
+    let mut parser = Parser(body);
+    let count = parser.u32()?;
+    let mut names = Vec::new();
+    for _ in 0..count {
+        names.push(String::from_utf8_lossy(parser.string()?).to_string());
+        parser.string()?;
+        attrs(&mut parser)?;
+    }
+    Ok(names)
+}
+
+fn attrs(parser: &mut Parser) -> io::Result<Stat> {
+    let flags = parser.u32()?;
+    let len = if flags & ATTR_SIZE != 0 { parser.u64()? } else { 0 };
+    let (uid, gid) = if flags & ATTR_UIDGID != 0 { (parser.u32()?, parser.u32()?) } else { (0, 0) };
+    let permissions = if flags & ATTR_PERMISSIONS != 0 { parser.u32()? } else { 0o100644 };
+    let modified = if flags & ATTR_ACMODTIME != 0 {
+        parser.u32()?;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(parser.u32()?.into()))
+    } else {
+        None
+    };
+    if flags & ATTR_EXTENDED != 0 {
+        for _ in 0..parser.u32()? {
+            parser.string()?;
+            parser.string()?;
+        }
+    }
+    let kind = match permissions & 0o170000 {
+        0o040000 => FileType::Dir,
+        0o120000 => FileType::Symlink,
+        0o010000 => FileType::Fifo,
+        0o140000 => FileType::Socket,
+        0o060000 => FileType::BlockDevice,
+        0o020000 => FileType::CharDevice,
+        _ => FileType::File,
+    };
+    Ok(Stat { kind, len, mode: permissions & 0o7777, uid, gid, modified, id: None, local: None })
+}
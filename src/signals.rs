@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::{status, window};
+
+// Logging each file as it's archived, switched on and off with a signal
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// What a user signal does to a running process
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    // Print how far the run's got to stderr, as `athena status` would
+    Snapshot,
+    // Toggle logging each file as it's archived
+    Verbose,
+    // Pause uploads between parts, or resume them
+    Pause,
+}
+
+pub fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+// Listens for SIGUSR1 and SIGUSR2 for the rest of the process. There are no such signals elsewhere
+#[cfg(unix)]
+pub fn handle(usr1: Action, usr2: Action) {
+    use tokio::signal::unix::{signal, SignalKind};
+    for (kind, action) in [(SignalKind::user_defined1(), usr1), (SignalKind::user_defined2(), usr2)] {
+        let Ok(mut signals) = signal(kind) else {
+            continue;
+        };
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                act(action);
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn handle(_usr1: Action, _usr2: Action) {}
+
+fn act(action: Action) {
+    match action {
+        Action::Snapshot => match status::snapshot() {
+            Some(snapshot) => eprint!("{}", snapshot),
+            None => eprintln!("No backup running"),
+        },
+        Action::Verbose => {
+            let on = !VERBOSE.fetch_xor(true, Ordering::Relaxed);
+            eprintln!("Verbose logging {}", if on { "on" } else { "off" });
+        },
+        Action::Pause => match window::toggle_pause() {
+            true => eprintln!("Pausing uploads after the current part"),
+            false => eprintln!("Resuming uploads"),
+        },
+    }
+}
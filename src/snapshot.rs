@@ -0,0 +1,95 @@
+use std::{path::{Path, PathBuf}, sync::Mutex, error::Error};
+use clap::ValueEnum;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotKind {
+    // Windows Volume Shadow Copy, so files held open by other programs can still be read consistently
+    Vss,
+}
+
+// Id of the shadow copy currently in use, so it can be deleted however the run ends
+static ACTIVE: Mutex<Option<String>> = Mutex::new(None);
+
+// A point-in-time copy of the volume holding the sources, which are read through it instead
+pub struct Snapshot {
+    #[cfg_attr(not(windows), allow(dead_code))]
+    device: String,
+}
+
+impl Snapshot {
+    // Takes a snapshot of the volume holding `path`. Needs to run as an administrator
+    pub fn create(kind: SnapshotKind, path: &Path) -> Result<Snapshot, Box<dyn Error>> {
+        match kind {
+            SnapshotKind::Vss => create_vss(path),
+        }
+    }
+
+    // Where `path` can be read from inside the snapshot
+    #[cfg(windows)]
+    pub fn map(&self, path: &Path) -> PathBuf {
+        use std::path::Component;
+        let rest: PathBuf = path.components().filter(|c| !matches!(c, Component::Prefix(_) | Component::RootDir)).collect();
+        PathBuf::from(format!("{}\\", self.device)).join(rest)
+    }
+
+    #[cfg(not(windows))]
+    pub fn map(&self, path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+}
+
+#[cfg(windows)]
+fn create_vss(path: &Path) -> Result<Snapshot, Box<dyn Error>> {
+    use std::path::{Component, Prefix};
+    let volume = match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => PathBuf::from(format!("{}:\\", letter as char)),
+            _ => return Err(format!("Can't snapshot {}: not on a local drive", path.display()).into()),
+        },
+        _ => return Err(format!("Can't snapshot {}: path has no drive letter", path.display()).into()),
+    };
+    let output = powershell(&format!(
+        "$r = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+         if ($r.ReturnValue -ne 0) {{ Write-Error \"Shadow copy failed with code $($r.ReturnValue)\"; exit 1 }}; \
+         $s = Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq $r.ShadowID }}; \
+         Write-Output \"$($s.ID)|$($s.DeviceObject)\"",
+        volume.display()
+    ))?;
+    let (id, device) = output.trim().split_once('|').ok_or("Unexpected output creating shadow copy")?;
+    *ACTIVE.lock().unwrap() = Some(id.to_string());
+    Ok(Snapshot { device: device.to_string() })
+}
+
+#[cfg(not(windows))]
+fn create_vss(_path: &Path) -> Result<Snapshot, Box<dyn Error>> {
+    Err("VSS snapshots are only available on Windows".into())
+}
+
+// Deletes the active snapshot, if there is one. Safe to call more than once
+pub fn release() {
+    if let Some(id) = ACTIVE.lock().unwrap().take() {
+        delete(&id);
+    }
+}
+
+#[cfg(windows)]
+fn delete(id: &str) {
+    let script = format!("Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }} | ForEach-Object {{ $_.Delete() }}", id);
+    if let Err(e) = powershell(&script) {
+        eprintln!("Warning: failed to delete shadow copy {}: {}", id, e);
+    }
+}
+
+#[cfg(not(windows))]
+fn delete(_id: &str) {}
+
+#[cfg(windows)]
+fn powershell(script: &str) -> Result<String, Box<dyn Error>> {
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
@@ -0,0 +1,271 @@
+use std::{fs, io::{self, Read}, ops::Deref, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+use crate::exclude::FileType;
+
+// What the walk and archiving need to know about a path, without tying them to a local fs::Metadata
+#[derive(Clone, Debug)]
+pub struct Stat {
+    pub kind: FileType,
+    pub len: u64,
+    // Permission bits, without the file type
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub modified: Option<SystemTime>,
+    // (device, inode) where the filesystem has them, so a directory reached twice can be told apart
+    pub id: Option<(u64, u64)>,
+    // The full metadata of a local file, for what only it has, like extended attributes or memory-mapping
+    pub local: Option<fs::Metadata>,
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileType::Dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == FileType::File
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileType::Symlink
+    }
+
+    // A tar header for the path, to be given its size and name when it's appended
+    pub fn header(&self) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        if let Some(metadata) = &self.local {
+            header.set_metadata(metadata);
+            return header;
+        }
+        header.set_mode(self.mode);
+        header.set_uid(self.uid.into());
+        header.set_gid(self.gid.into());
+        header.set_mtime(self.modified.and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok()).map_or(0, |since| since.as_secs()));
+        header.set_entry_type(match self.kind {
+            FileType::Dir => tar::EntryType::Directory,
+            FileType::Symlink => tar::EntryType::Symlink,
+            FileType::Fifo => tar::EntryType::Fifo,
+            _ => tar::EntryType::Regular,
+        });
+        header.set_size(if self.is_file() { self.len } else { 0 });
+        header
+    }
+}
+
+// Where source files are walked and read from. The walk and archiving go through this rather than std::fs, so
+// they can run against sources that aren't local files
+pub trait SourceFs: Send + Sync {
+    // Paths of the directory's entries, in the order the filesystem gives them
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    // Follows symlinks
+    fn metadata(&self, path: &Path) -> io::Result<Stat>;
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Stat>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+}
+
+// The machine's own filesystem
+pub struct LocalFs;
+
+impl LocalFs {
+    fn stat(metadata: fs::Metadata) -> Stat {
+        #[cfg(unix)]
+        let (mode, uid, gid, id) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.mode() & 0o7777, metadata.uid(), metadata.gid(), Some((metadata.dev(), metadata.ino())))
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid, id) = (if metadata.permissions().readonly() { 0o444 } else { 0o644 }, 0, 0, None);
+        Stat { kind: FileType::of(metadata.file_type()), len: metadata.len(), mode, uid, gid, modified: metadata.modified().ok(), id, local: Some(metadata) }
+    }
+}
+
+impl SourceFs for LocalFs {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Stat> {
+        path.metadata().map(LocalFs::stat)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Stat> {
+        path.symlink_metadata().map(LocalFs::stat)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        path.read_link()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+}
+
+// The filesystem a run's sources are on, shared by its stages. Local unless said otherwise
+#[derive(Clone)]
+pub struct SharedFs(Arc<dyn SourceFs>);
+
+impl SharedFs {
+    pub fn new(fs: impl SourceFs + 'static) -> SharedFs {
+        SharedFs(Arc::new(fs))
+    }
+}
+
+impl Default for SharedFs {
+    fn default() -> SharedFs {
+        SharedFs::new(LocalFs)
+    }
+}
+
+impl Deref for SharedFs {
+    type Target = dyn SourceFs;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+// An in-memory filesystem for tests, which fails the ways real ones do: paths that can't be read, files that
+// are gone by the time they're looked at after being listed, and files that are busy or shrink while read
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemFs {
+    nodes: std::sync::Mutex<std::collections::BTreeMap<PathBuf, (u64, Node)>>,
+    // Fail to be listed or opened with permission denied
+    denied: std::collections::HashSet<PathBuf>,
+    // Removed as soon as their directory is listed
+    vanishing: std::collections::HashSet<PathBuf>,
+    // Busy for this many more opens
+    busy: std::sync::Mutex<std::collections::HashMap<PathBuf, u32>>,
+    // Read back this many bytes short of their size, as if truncated once looked at
+    shrinking: std::collections::HashMap<PathBuf, usize>,
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+enum Node {
+    Dir,
+    File(Vec<u8>),
+    Symlink(PathBuf),
+}
+
+#[cfg(test)]
+impl MemFs {
+    pub fn dir(self, path: impl AsRef<Path>) -> MemFs {
+        self.insert(path.as_ref(), Node::Dir)
+    }
+
+    pub fn file(self, path: impl AsRef<Path>, contents: &[u8]) -> MemFs {
+        self.insert(path.as_ref(), Node::File(contents.to_vec()))
+    }
+
+    pub fn symlink(self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> MemFs {
+        self.insert(path.as_ref(), Node::Symlink(target.as_ref().to_path_buf()))
+    }
+
+    pub fn deny(mut self, path: impl AsRef<Path>) -> MemFs {
+        self.denied.insert(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn vanish(mut self, path: impl AsRef<Path>) -> MemFs {
+        self.vanishing.insert(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn busy(self, path: impl AsRef<Path>, opens: u32) -> MemFs {
+        self.busy.lock().unwrap().insert(path.as_ref().to_path_buf(), opens);
+        self
+    }
+
+    pub fn shrink(mut self, path: impl AsRef<Path>, by: usize) -> MemFs {
+        self.shrinking.insert(path.as_ref().to_path_buf(), by);
+        self
+    }
+
+    // Parent directories are made as needed, and each node gets an inode number of its own
+    fn insert(self, path: &Path, node: Node) -> MemFs {
+        if let Some(parent) = path.parent().filter(|parent| !self.nodes.lock().unwrap().contains_key(*parent) && parent.parent().is_some()) {
+            return self.dir(parent).insert(path, node);
+        }
+        {
+            let mut nodes = self.nodes.lock().unwrap();
+            let ino = nodes.len() as u64 + 1;
+            nodes.insert(path.to_path_buf(), (ino, node));
+        }
+        self
+    }
+
+    fn node(&self, path: &Path) -> io::Result<(u64, Node)> {
+        self.nodes.lock().unwrap().get(path).cloned().ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn check(&self, path: &Path) -> io::Result<()> {
+        match self.denied.contains(path) {
+            true => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            false => Ok(()),
+        }
+    }
+
+    fn stat(ino: u64, node: &Node) -> Stat {
+        let (kind, len, mode) = match node {
+            Node::Dir => (FileType::Dir, 0, 0o755),
+            Node::File(contents) => (FileType::File, contents.len() as u64, 0o644),
+            Node::Symlink(target) => (FileType::Symlink, target.as_os_str().len() as u64, 0o777),
+        };
+        Stat { kind, len, mode, uid: 0, gid: 0, modified: Some(SystemTime::UNIX_EPOCH), id: Some((0, ino)), local: None }
+    }
+}
+
+#[cfg(test)]
+impl SourceFs for MemFs {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.check(dir)?;
+        if !matches!(self.node(dir)?.1, Node::Dir) {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory));
+        }
+        let mut nodes = self.nodes.lock().unwrap();
+        let entries: Vec<PathBuf> = nodes.keys().filter(|path| path.parent() == Some(dir)).cloned().collect();
+        nodes.retain(|path, _| !(path.parent() == Some(dir) && self.vanishing.contains(path)));
+        Ok(entries)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Stat> {
+        match self.node(path)? {
+            (_, Node::Symlink(target)) => self.metadata(&path.parent().unwrap_or(Path::new("/")).join(target)),
+            (ino, node) => Ok(MemFs::stat(ino, &node)),
+        }
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Stat> {
+        self.node(path).map(|(ino, node)| MemFs::stat(ino, &node))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.node(path)?.1 {
+            Node::Symlink(target) => Ok(target),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        self.check(path)?;
+        if let Some(opens) = self.busy.lock().unwrap().get_mut(path).filter(|opens| **opens > 0) {
+            *opens -= 1;
+            #[cfg(unix)]
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+            // ERROR_SHARING_VIOLATION
+            #[cfg(not(unix))]
+            return Err(io::Error::from_raw_os_error(32));
+        }
+        match self.node(path)?.1 {
+            Node::File(mut contents) => {
+                contents.truncate(contents.len().saturating_sub(self.shrinking.get(path).copied().unwrap_or(0)));
+                Ok(Box::new(io::Cursor::new(contents)))
+            },
+            Node::Symlink(target) => self.open(&path.parent().unwrap_or(Path::new("/")).join(target)),
+            Node::Dir => Err(io::Error::from(io::ErrorKind::IsADirectory)),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::{fmt, path::{Path, PathBuf}, time::{Duration, Instant}, error::Error};
+use crate::{device, utils};
+
+// How often free space is checked while writing. Often enough to stop well before a disk fills, rarely enough
+// not to slow down archiving lots of small files
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Returned when a filesystem the run writes to has less free space than --min-free-space
+#[derive(Debug)]
+pub struct LowSpace {
+    pub path: PathBuf,
+    pub available: u64,
+    pub min: u64,
+}
+
+impl fmt::Display for LowSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "only {} free on {}, below --min-free-space {}",
+            utils::format_size(self.available), self.path.display(), utils::format_size(self.min)
+        )
+    }
+}
+
+impl Error for LowSpace {}
+
+// Errors if any of the paths is on a filesystem with less than `min` free. Ones whose free space can't be told are
+// let through, as are devices, which aren't on a filesystem at all
+pub fn check(paths: &[&Path], min: u64) -> Result<(), LowSpace> {
+    for path in paths.iter().filter(|path| !device::is_device(path)) {
+        match utils::available_space(path) {
+            Some(available) if available < min => return Err(LowSpace { path: path.to_path_buf(), available, min }),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+// Checks free space on where the run writes at most every CHECK_INTERVAL, as it writes
+pub struct Guard {
+    paths: Vec<PathBuf>,
+    min: Option<u64>,
+    last: Option<Instant>,
+}
+
+impl Guard {
+    pub fn new(paths: Vec<PathBuf>, min: Option<u64>) -> Guard {
+        Guard { paths, min, last: None }
+    }
+
+    pub fn check(&mut self) -> Result<(), LowSpace> {
+        let Some(min) = self.min else {
+            return Ok(());
+        };
+        if self.last.is_some_and(|last| last.elapsed() < CHECK_INTERVAL) {
+            return Ok(());
+        }
+        self.last = Some(Instant::now());
+        check(&self.paths.iter().map(PathBuf::as_path).collect::<Vec<_>>(), min)
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fs, path::{Path, PathBuf}, error::Error};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use crate::{backend::{self, Backend, BackendKind, ObjectInfo, RemoteOptions}, cancel::{CancellationToken, Cancelled}, http::HttpOptions, index, manifest::{self, RunManifest}, webhook};
+
+// Suffix of the files in the spool directory, one per archive waiting to be uploaded
+pub const JOB_SUFFIX: &str = ".upload.json";
+
+// An archive to upload and where to, kept in the spool directory until `athena flush` uploads it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Job {
+    pub archive: PathBuf,
+    pub backend: BackendKind,
+    pub bucket: Option<String>,
+    // Connection settings in force when the job was queued, since a flush may not be given them again. The proxy's
+    // credentials are left out, for the flush to be given with its own --proxy
+    #[serde(default)]
+    pub http: HttpOptions,
+    pub key: String,
+    pub info: ObjectInfo,
+    pub run: RunManifest,
+    // Failed flushes so far, and why the last one failed
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    // Whether an earlier flush got the archive uploaded before failing, so it's this archive already at the key
+    #[serde(default)]
+    pub archive_uploaded: bool,
+}
+
+impl Job {
+    pub fn new(archive: &Path, remote: &RemoteOptions, key: String, info: ObjectInfo, run: RunManifest) -> Result<Job, Box<dyn Error>> {
+        Ok(Job { archive: archive.canonicalize()?, backend: remote.backend, bucket: remote.bucket.clone(), http: remote.http.without_credentials(), key, info, run, attempts: 0, last_error: None, archive_uploaded: false })
+    }
+
+    // Where to upload to, through `proxy` if given rather than the one the job was queued with
+    pub fn remote(&self, proxy: Option<&str>) -> RemoteOptions {
+        let http = HttpOptions { proxy: proxy.map(String::from).or(self.http.proxy.clone()), ..self.http.clone() };
+        RemoteOptions { backend: self.backend, bucket: self.bucket.clone(), http, ..RemoteOptions::default() }
+    }
+
+    // Uploads the archive followed by its entry index and the run's manifest, returning the archive's URL
+    pub fn upload(&mut self, backend: &dyn Backend, verbose: bool, cancel: &CancellationToken) -> Result<String, Box<dyn Error>> {
+        let url = backend::upload(backend, &self.archive, &self.key, &self.info, verbose, cancel)?;
+        self.archive_uploaded = true;
+        // The entry index lets single files be restored later without downloading the whole archive
+        if let Some(index) = index::EntryIndex::build(&self.archive)? {
+            let index_key = format!("{}{}", self.key, index::INDEX_SUFFIX);
+            backend.upload_stream(&mut &serde_json::to_vec(&index)?[..], &index_key, &[], &ProgressBar::hidden())?;
+        }
+        self.run.describe(&self.archive)?;
+        upload_run_manifest(backend, &self.key, &self.run)?;
+        Ok(url)
+    }
+}
+
+pub fn upload_run_manifest(backend: &dyn Backend, key: &str, run: &RunManifest) -> Result<(), Box<dyn Error>> {
+    let manifest_key = format!("{}{}", key, manifest::RUN_MANIFEST_SUFFIX);
+    backend.upload_stream(&mut &serde_json::to_vec_pretty(run)?[..], &manifest_key, &[], &ProgressBar::hidden())?;
+    Ok(())
+}
+
+// Adds the job to the spool, written whole or not at all so a flush never picks up half of one
+pub fn queue(spool: &Path, job: &Job) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(spool)?;
+    let name = job.archive.file_name().ok_or("Archive has no file name")?;
+    let path = spool.join(format!("{}{}", name.to_string_lossy(), JOB_SUFFIX));
+    save(&path, job)?;
+    Ok(path)
+}
+
+fn save(path: &Path, job: &Job) -> Result<(), Box<dyn Error>> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer_pretty(&mut file, job)?;
+    file.persist(path)?;
+    Ok(())
+}
+
+pub struct Flushed {
+    pub uploaded: usize,
+    pub failed: usize,
+}
+
+// Uploads every archive waiting in the spool, oldest first. Uploaded ones leave the spool, failed ones stay for the
+// next flush with the error recorded, and one failing doesn't stop the rest being tried
+pub fn flush(spool: &Path, proxy: Option<&str>, verbose: bool, cancel: &CancellationToken) -> Result<Flushed, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(spool).map_err(|e| format!("Failed to read spool {}: {}", spool.display(), e))? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(JOB_SUFFIX) {
+            paths.push(path);
+        }
+    }
+    // Archive names start with when they were made, so this is oldest first
+    paths.sort();
+
+    let mut flushed = Flushed { uploaded: 0, failed: 0 };
+    for path in paths {
+        let mut job: Job = serde_json::from_slice(&fs::read(&path)?).map_err(|e| format!("Invalid spool entry {}: {}", path.display(), e))?;
+        let archive = job.archive.clone();
+        if verbose && job.attempts > 0 {
+            println!("Retrying {} after {} failed flushes", archive.display(), job.attempts);
+        }
+        let result = backend::connect(&job.remote(proxy)).and_then(|backend| {
+            // Nobody's around to ask whether to overwrite, so a name that's taken is left for someone to sort out
+            if !job.archive_uploaded && backend::exists(backend.as_ref(), &job.key)? {
+                return Err(format!("{} already exists, remove it to upload this archive in its place", backend.url(&job.key)).into());
+            }
+            job.upload(backend.as_ref(), verbose, cancel)
+        });
+        match result {
+            Ok(url) => {
+                fs::remove_file(&path)?;
+                println!("Uploaded {} to {}", archive.display(), url);
+                webhook::emit("upload.complete", serde_json::json!({ "url": url, "archive": archive.to_string_lossy() }));
+                flushed.uploaded += 1;
+            },
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                eprintln!("Failed to upload {}: {}", archive.display(), e);
+                job.attempts += 1;
+                job.last_error = Some(e.to_string());
+                save(&path, &job)?;
+                flushed.failed += 1;
+            },
+        }
+    }
+    Ok(flushed)
+}
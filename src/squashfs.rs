@@ -0,0 +1,551 @@
+use std::{collections::BTreeMap, fs, io::{self, BufWriter, Read, Seek, SeekFrom, Write}, path::{Component, Path, PathBuf}, error::Error};
+use flate2::{write::ZlibEncoder, Compression};
+use crate::{exclude::FileType, format, manifest::{self, Manifest}, pathstyle, progress::{Progress, ProgressReader}, readahead, scratch, space, sourcefs::Stat, utils};
+
+const MAGIC: &[u8; 4] = b"hsqs";
+pub const BLOCK_SIZE: usize = 128 * 1024;
+const BLOCK_LOG: u16 = 17;
+// Inodes and directory listings are packed into blocks of this much before compressing
+const METADATA_SIZE: usize = 8192;
+const SUPERBLOCK_SIZE: usize = 96;
+// Squashfs's gzip is zlib streams, at the default options
+const GZIP: u16 = 1;
+const UNCOMPRESSED_INODES: u16 = 0x1;
+const UNCOMPRESSED_DATA: u16 = 0x2;
+const UNCOMPRESSED_FRAGMENTS: u16 = 0x8;
+const NO_FRAGMENTS: u16 = 0x10;
+const NO_XATTRS: u16 = 0x200;
+const UNCOMPRESSED_IDS: u16 = 0x800;
+// Marks blocks stored as they are, because compressing didn't make them any smaller
+const UNCOMPRESSED_BLOCK: u32 = 1 << 24;
+const UNCOMPRESSED_METADATA: u16 = 0x8000;
+const NO_TABLE: u64 = u64::MAX;
+const NO_FRAGMENT: u32 = u32::MAX;
+const NO_XATTR: u32 = u32::MAX;
+const MAX_NAME: usize = 256;
+// Loop devices read whole pages, so images are padded out to one
+const PAD: u64 = 4096;
+
+// Inode types. Directory entries give the basic type even for extended inodes
+const DIR: u16 = 1;
+const FILE: u16 = 2;
+const SYMLINK: u16 = 3;
+const BLOCK_DEVICE: u16 = 4;
+const CHAR_DEVICE: u16 = 5;
+const FIFO: u16 = 6;
+const SOCKET: u16 = 7;
+const EXTENDED_DIR: u16 = 8;
+const EXTENDED_FILE: u16 = 9;
+
+// What an entry's inode is built from. Names are raw bytes, as squashfs stores them
+struct Entry {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    kind: Kind,
+    number: u32,
+}
+
+enum Kind {
+    Dir(BTreeMap<Vec<u8>, Entry>),
+    // Where its blocks start in the image, and each block's stored size
+    File { start: u64, size: u64, blocks: Vec<u32> },
+    Symlink(Vec<u8>),
+    Device(u16, u32),
+    Ipc(u16),
+}
+
+impl Entry {
+    fn new(stat: &Stat, kind: Kind) -> Entry {
+        let mtime = stat.modified.map_or(0, |modified| chrono::DateTime::<chrono::Utc>::from(modified).timestamp().clamp(0, u32::MAX as i64) as u32);
+        Entry { mode: (stat.mode & 0o7777) as u16, uid: stat.uid, gid: stat.gid, mtime, kind, number: 0 }
+    }
+
+    // A directory the archive needs but that wasn't given itself, like the parents of a glob's matches
+    fn dir(mtime: u32) -> Entry {
+        Entry { mode: 0o755, uid: 0, gid: 0, mtime, kind: Kind::Dir(BTreeMap::new()), number: 0 }
+    }
+
+    fn basic_type(&self) -> u16 {
+        match self.kind {
+            Kind::Dir(_) => DIR,
+            Kind::File { .. } => FILE,
+            Kind::Symlink(_) => SYMLINK,
+            Kind::Device(kind, _) | Kind::Ipc(kind) => kind,
+        }
+    }
+
+    // Numbers every inode children first, so the root comes last as mksquashfs has it
+    fn number(&mut self, next: &mut u32) {
+        if let Kind::Dir(children) = &mut self.kind {
+            for child in children.values_mut() {
+                child.number(next);
+            }
+        }
+        *next += 1;
+        self.number = *next;
+    }
+}
+
+// Writes the given files into a squashfs image, a compressed read-only filesystem that can be mounted and browsed
+// like the source tree (`mount -o loop,ro`). File data goes out as it's read, with the inode and directory tables
+// kept in memory until the end
+pub fn write_squashfs(paths: &[PathBuf], file: fs::File, options: &utils::Options, base: &Path, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+    let now = chrono::Local::now().timestamp().clamp(0, u32::MAX as i64) as u32;
+    let mut image = Image { out: BufWriter::new(file), position: SUPERBLOCK_SIZE as u64, compress: options.compression };
+    image.out.write_all(&[0; SUPERBLOCK_SIZE])?;
+    let mut root = match options.fs.metadata(base) {
+        Ok(stat) if stat.is_dir() => Entry::new(&stat, Kind::Dir(BTreeMap::new())),
+        _ => Entry::dir(now),
+    };
+    let mut manifest = Manifest::new(options.host.as_ref(), options.comment.as_deref());
+    manifest.deleted = options.deleted.clone();
+
+    let mut space = space::Guard::new(vec![options.output_path.clone(), scratch::dir()], options.min_free_space);
+    for (i, path) in paths.iter().enumerate() {
+        options.cancel.check("archiving", i, Some(paths.len()))?;
+        space.check()?;
+        let rel_path = path.strip_prefix(base)?;
+        if let Some(deadline) = options.deadline.as_ref().filter(|deadline| deadline.passed()) {
+            manifest.stopped = Some(deadline.stop(i, paths.len(), rel_path));
+            break;
+        }
+        let stat = match manifest::check_readable(&*options.fs, path, options.busy_retries) {
+            Ok(stat) => stat,
+            Err(e) if options.skips(&e) => {
+                progress.on_warning(&format!("Skipping {}: {}", path.display(), e));
+                options.errors.record(path, "open", &e);
+                manifest.skipped.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error: e.to_string() });
+                continue;
+            },
+            Err(e) if options.placeholder_on_error => {
+                progress.on_file(rel_path, 0);
+                progress.on_warning(&format!("Storing placeholder for {}: {}", path.display(), e));
+                options.errors.record(path, "open", &e);
+                let placeholder = Entry { mode: 0o644, uid: 0, gid: 0, mtime: now, kind: image.write_file(&mut io::empty(), None)?, number: 0 };
+                insert(&mut root, rel_path, placeholder, now)?;
+                manifest.placeholders.push(manifest::Placeholder { path: rel_path.to_string_lossy().to_string(), error: e.to_string() });
+                continue;
+            },
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+        };
+        progress.on_file(rel_path, if stat.is_file() { stat.len } else { 0 });
+        let kind = match stat.kind {
+            FileType::Dir => Kind::Dir(BTreeMap::new()),
+            FileType::Symlink => {
+                let target = pathstyle::link_target(&options.fs.read_link(path)?, options.path_style);
+                Kind::Symlink(target.as_os_str().as_encoded_bytes().to_vec())
+            },
+            FileType::File => {
+                // A file that changes size while it's read is stored as read, since nothing's been written ahead
+                // of its data claiming otherwise
+                let file = readahead::open(path, stat.len, options)?;
+                let level = match format::method_for(&options.compress_overrides, path) {
+                    Some(method) => Some(method.level()),
+                    None => options.compression.then(Compression::best),
+                };
+                image.write_file(&mut ProgressReader::new(file, progress), level)?
+            },
+            FileType::BlockDevice => Kind::Device(BLOCK_DEVICE, device_number(&stat)),
+            FileType::CharDevice => Kind::Device(CHAR_DEVICE, device_number(&stat)),
+            FileType::Fifo => Kind::Ipc(FIFO),
+            FileType::Socket => Kind::Ipc(SOCKET),
+        };
+        insert(&mut root, rel_path, Entry::new(&stat, kind), now)?;
+    }
+    if manifest.is_needed() {
+        let kind = image.write_file(&mut &manifest.to_json()?[..], options.compression.then(Compression::best))?;
+        insert(&mut root, Path::new(manifest::MANIFEST_NAME), Entry { mode: 0o644, uid: 0, gid: 0, mtime: now, kind, number: 0 }, now)?;
+    }
+    image.finish(root, now)
+}
+
+// Puts the entry in its place in the tree, adding any parent directories that weren't given. A directory given
+// after one of its children keeps them
+fn insert(root: &mut Entry, rel_path: &Path, mut entry: Entry, now: u32) -> Result<(), Box<dyn Error>> {
+    let names: Vec<&[u8]> = rel_path.components().filter_map(|component| match component {
+        Component::Normal(name) => Some(name.as_encoded_bytes()),
+        _ => None,
+    }).collect();
+    let Some((name, parents)) = names.split_last() else {
+        // The source itself, when it's a directory
+        if let (Kind::Dir(children), Kind::Dir(_)) = (&mut root.kind, &entry.kind) {
+            entry.kind = Kind::Dir(std::mem::take(children));
+            *root = entry;
+        }
+        return Ok(());
+    };
+    if name.len() > MAX_NAME {
+        return Err(format!("Can't store {} in a squashfs image, names can be at most {} bytes", rel_path.display(), MAX_NAME).into());
+    }
+    let mut dir = root;
+    for parent in parents {
+        let Kind::Dir(children) = &mut dir.kind else { unreachable!() };
+        dir = children.entry(parent.to_vec()).or_insert_with(|| Entry::dir(now));
+        if !matches!(dir.kind, Kind::Dir(_)) {
+            return Err(format!("Can't store {}, a parent of it isn't a directory", rel_path.display()).into());
+        }
+    }
+    let Kind::Dir(children) = &mut dir.kind else { unreachable!() };
+    if let (Some(Entry { kind: Kind::Dir(existing), .. }), Kind::Dir(_)) = (children.get_mut(*name), &entry.kind) {
+        entry.kind = Kind::Dir(std::mem::take(existing));
+    }
+    children.insert(name.to_vec(), entry);
+    Ok(())
+}
+
+// Squashfs stores device numbers the way the kernel's new_encode_dev does
+#[cfg(unix)]
+fn device_number(stat: &Stat) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+    let rdev = stat.local.as_ref().map_or(0, |metadata| metadata.rdev());
+    let (major, minor) = (libc::major(rdev) as u32, libc::minor(rdev) as u32);
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+#[cfg(not(unix))]
+fn device_number(_stat: &Stat) -> u32 {
+    0
+}
+
+struct Image {
+    out: BufWriter<fs::File>,
+    position: u64,
+    compress: bool,
+}
+
+impl Image {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    // Writes a file's data in blocks, each compressed on its own so it can be read without the rest. Without a level
+    // they're stored as they are
+    fn write_file(&mut self, reader: &mut dyn Read, level: Option<Compression>) -> io::Result<Kind> {
+        let start = self.position;
+        let mut size = 0;
+        let mut blocks = Vec::new();
+        let mut block = vec![0; BLOCK_SIZE];
+        loop {
+            let len = fill(reader, &mut block)?;
+            if len == 0 {
+                break;
+            }
+            size += len as u64;
+            match compress(&block[..len], level)? {
+                Some(compressed) => {
+                    self.write(&compressed)?;
+                    blocks.push(compressed.len() as u32);
+                },
+                None => {
+                    self.write(&block[..len])?;
+                    blocks.push(len as u32 | UNCOMPRESSED_BLOCK);
+                },
+            }
+            if len < BLOCK_SIZE {
+                break;
+            }
+        }
+        Ok(Kind::File { start, size, blocks })
+    }
+
+    // Writes the inode, directory and id tables after the data, then goes back to fill in the superblock
+    fn finish(mut self, mut root: Entry, now: u32) -> Result<(), Box<dyn Error>> {
+        let mut count = 0;
+        root.number(&mut count);
+        let mut tables = Tables { inodes: Metadata::new(self.compress), dirs: Metadata::new(self.compress), ids: Vec::new() };
+        let root_ref = tables.write(&root, count + 1)?;
+
+        let inode_table = self.position;
+        self.write(&tables.inodes.finish()?)?;
+        let directory_table = self.position;
+        self.write(&tables.dirs.finish()?)?;
+        // There are no fragments, so their table is empty and starts where the next would
+        let fragment_table = self.position;
+        let mut ids = Metadata::new(self.compress);
+        for id in &tables.ids {
+            ids.write(&id.to_le_bytes())?;
+        }
+        let mut id_index = Vec::new();
+        let id_blocks = ids.finish()?;
+        let mut offset = 0;
+        while offset < id_blocks.len() {
+            id_index.extend((self.position + offset as u64).to_le_bytes());
+            let header = u16::from_le_bytes([id_blocks[offset], id_blocks[offset + 1]]);
+            offset += 2 + (header & !UNCOMPRESSED_METADATA) as usize;
+        }
+        self.write(&id_blocks)?;
+        let id_table = self.position;
+        self.write(&id_index)?;
+        let bytes_used = self.position;
+        self.write(&vec![0; (bytes_used.next_multiple_of(PAD) - bytes_used) as usize])?;
+
+        let flags = NO_FRAGMENTS | NO_XATTRS | match self.compress {
+            true => 0,
+            false => UNCOMPRESSED_INODES | UNCOMPRESSED_DATA | UNCOMPRESSED_FRAGMENTS | UNCOMPRESSED_IDS,
+        };
+        let mut superblock = Vec::with_capacity(SUPERBLOCK_SIZE);
+        superblock.extend(MAGIC);
+        superblock.extend(count.to_le_bytes());
+        superblock.extend(now.to_le_bytes());
+        superblock.extend((BLOCK_SIZE as u32).to_le_bytes());
+        superblock.extend(0u32.to_le_bytes());
+        superblock.extend(GZIP.to_le_bytes());
+        superblock.extend(BLOCK_LOG.to_le_bytes());
+        superblock.extend(flags.to_le_bytes());
+        superblock.extend((tables.ids.len() as u16).to_le_bytes());
+        superblock.extend(4u16.to_le_bytes());
+        superblock.extend(0u16.to_le_bytes());
+        for value in [root_ref, bytes_used, id_table, NO_TABLE, inode_table, directory_table, fragment_table, NO_TABLE] {
+            superblock.extend(value.to_le_bytes());
+        }
+        let mut file = self.out.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&superblock)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+struct Tables {
+    inodes: Metadata,
+    dirs: Metadata,
+    // Every uid and gid, which inodes refer to by their place in this list
+    ids: Vec<u32>,
+}
+
+impl Tables {
+    fn id(&mut self, id: u32) -> Result<u16, Box<dyn Error>> {
+        let index = match self.ids.iter().position(|known| *known == id) {
+            Some(index) => index,
+            None => {
+                self.ids.push(id);
+                self.ids.len() - 1
+            },
+        };
+        u16::try_from(index).map_err(|_| "Too many different owners for a squashfs image".into())
+    }
+
+    // Writes the entry's inode, and first everything under it, since a directory's listing points at its children's
+    // inodes. Returns the reference to the inode: its metadata block's offset in the table, and its offset in that
+    fn write(&mut self, entry: &Entry, parent: u32) -> Result<u64, Box<dyn Error>> {
+        let mut inode = Vec::new();
+        let kind = match &entry.kind {
+            Kind::Dir(children) => {
+                let mut listed = Vec::with_capacity(children.len());
+                for (name, child) in children {
+                    listed.push((name, self.write(child, entry.number)?, child));
+                }
+                let (block, offset) = self.dirs.position();
+                let size = self.list(&listed)? + 3;
+                let links = 2 + children.values().filter(|child| matches!(child.kind, Kind::Dir(_))).count() as u32;
+                match u16::try_from(size) {
+                    Ok(size) => {
+                        inode.extend(block.to_le_bytes());
+                        inode.extend(links.to_le_bytes());
+                        inode.extend(size.to_le_bytes());
+                        inode.extend(offset.to_le_bytes());
+                        inode.extend(parent.to_le_bytes());
+                        DIR
+                    },
+                    Err(_) => {
+                        inode.extend(links.to_le_bytes());
+                        inode.extend(size.to_le_bytes());
+                        inode.extend(block.to_le_bytes());
+                        inode.extend(parent.to_le_bytes());
+                        inode.extend(0u16.to_le_bytes());
+                        inode.extend(offset.to_le_bytes());
+                        inode.extend(NO_XATTR.to_le_bytes());
+                        EXTENDED_DIR
+                    },
+                }
+            },
+            Kind::File { start, size, blocks } => {
+                let kind = match (u32::try_from(*start), u32::try_from(*size)) {
+                    (Ok(start), Ok(size)) => {
+                        inode.extend(start.to_le_bytes());
+                        inode.extend(NO_FRAGMENT.to_le_bytes());
+                        inode.extend(0u32.to_le_bytes());
+                        inode.extend(size.to_le_bytes());
+                        FILE
+                    },
+                    _ => {
+                        inode.extend(start.to_le_bytes());
+                        inode.extend(size.to_le_bytes());
+                        inode.extend(0u64.to_le_bytes());
+                        inode.extend(1u32.to_le_bytes());
+                        inode.extend(NO_FRAGMENT.to_le_bytes());
+                        inode.extend(0u32.to_le_bytes());
+                        inode.extend(NO_XATTR.to_le_bytes());
+                        EXTENDED_FILE
+                    },
+                };
+                for block in blocks {
+                    inode.extend(block.to_le_bytes());
+                }
+                kind
+            },
+            Kind::Symlink(target) => {
+                inode.extend(1u32.to_le_bytes());
+                inode.extend((target.len() as u32).to_le_bytes());
+                inode.extend(target);
+                SYMLINK
+            },
+            Kind::Device(kind, number) => {
+                inode.extend(1u32.to_le_bytes());
+                inode.extend(number.to_le_bytes());
+                *kind
+            },
+            Kind::Ipc(kind) => {
+                inode.extend(1u32.to_le_bytes());
+                *kind
+            },
+        };
+        let mode = match entry.kind {
+            Kind::Symlink(_) => 0o777,
+            _ => entry.mode,
+        };
+        let mut header = Vec::with_capacity(16 + inode.len());
+        header.extend(kind.to_le_bytes());
+        header.extend(mode.to_le_bytes());
+        header.extend(self.id(entry.uid)?.to_le_bytes());
+        header.extend(self.id(entry.gid)?.to_le_bytes());
+        header.extend(entry.mtime.to_le_bytes());
+        header.extend(entry.number.to_le_bytes());
+        header.extend(inode);
+        let reference = self.inodes.reference();
+        self.inodes.write(&header)?;
+        Ok(reference)
+    }
+
+    // Writes a directory's listing, returning its size. Entries are grouped under headers, each covering up to 256
+    // entries whose inodes are in the same metadata block and numbered close enough to the header's to be offsets
+    fn list(&mut self, listed: &[(&Vec<u8>, u64, &Entry)]) -> Result<u32, Box<dyn Error>> {
+        let mut size = 0;
+        let mut i = 0;
+        while i < listed.len() {
+            let (_, reference, first) = listed[i];
+            let block = (reference >> 16) as u32;
+            let count = listed[i..].iter()
+                .take(256)
+                .take_while(|(_, reference, entry)| (reference >> 16) as u32 == block && (entry.number as i64 - first.number as i64).abs() <= i16::MAX as i64)
+                .count();
+            let mut listing = Vec::new();
+            listing.extend((count as u32 - 1).to_le_bytes());
+            listing.extend(block.to_le_bytes());
+            listing.extend(first.number.to_le_bytes());
+            for (name, reference, entry) in &listed[i..i + count] {
+                listing.extend((*reference as u16).to_le_bytes());
+                listing.extend(((entry.number as i64 - first.number as i64) as i16).to_le_bytes());
+                listing.extend(entry.basic_type().to_le_bytes());
+                listing.extend((name.len() as u16 - 1).to_le_bytes());
+                listing.extend(name.as_slice());
+            }
+            self.dirs.write(&listing)?;
+            size += listing.len() as u32;
+            i += count;
+        }
+        Ok(size)
+    }
+}
+
+// A table of metadata blocks, each with a two byte header giving its stored size
+struct Metadata {
+    out: Vec<u8>,
+    block: Vec<u8>,
+    compress: bool,
+}
+
+impl Metadata {
+    fn new(compress: bool) -> Metadata {
+        Metadata { out: Vec::new(), block: Vec::with_capacity(METADATA_SIZE), compress }
+    }
+
+    // Where the next byte written goes: the offset of its block in the table, and its offset in the block
+    fn position(&self) -> (u32, u16) {
+        (self.out.len() as u32, self.block.len() as u16)
+    }
+
+    fn reference(&self) -> u64 {
+        (self.out.len() as u64) << 16 | self.block.len() as u64
+    }
+
+    fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let len = data.len().min(METADATA_SIZE - self.block.len());
+            self.block.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.block.len() == METADATA_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match compress(&self.block, self.compress.then(Compression::best))? {
+            Some(compressed) => {
+                self.out.extend((compressed.len() as u16).to_le_bytes());
+                self.out.extend(compressed);
+            },
+            None => {
+                self.out.extend((self.block.len() as u16 | UNCOMPRESSED_METADATA).to_le_bytes());
+                self.out.extend(&self.block);
+            },
+        }
+        self.block.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<Vec<u8>> {
+        if !self.block.is_empty() {
+            self.flush()?;
+        }
+        Ok(self.out)
+    }
+}
+
+// The block compressed, or None if it's to be stored as is because compression's off or didn't help
+fn compress(data: &[u8], level: Option<Compression>) -> io::Result<Option<Vec<u8>>> {
+    let Some(level) = level.filter(|level| level.level() > 0) else {
+        return Ok(None);
+    };
+    let mut encoder = ZlibEncoder::new(Vec::with_capacity(data.len()), level);
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    Ok(Some(compressed).filter(|compressed| compressed.len() < data.len()))
+}
+
+// Reads until the buffer's full or the reader's done, returning how much was read
+fn fill(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+// Checks an image looks whole: a squashfs 4.0 superblock whose tables all fall within the file
+pub fn check(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+    let mut superblock = [0; SUPERBLOCK_SIZE];
+    file.read_exact(&mut superblock)?;
+    let u16_at = |offset: usize| u16::from_le_bytes([superblock[offset], superblock[offset + 1]]);
+    let u64_at = |offset: usize| u64::from_le_bytes(superblock[offset..offset + 8].try_into().unwrap());
+    if &superblock[..4] != MAGIC || u16_at(28) != 4 {
+        return Err("Not a squashfs 4.0 image".into());
+    }
+    let bytes_used = u64_at(40);
+    if bytes_used > file.metadata()?.len() || [48, 64, 72].iter().any(|offset| u64_at(*offset) >= bytes_used) {
+        return Err("Squashfs image is truncated".into());
+    }
+    Ok(())
+}
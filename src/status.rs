@@ -0,0 +1,392 @@
+use std::{fs, io, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}, error::Error};
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+use crate::{progress::Progress, utils};
+
+// Finished runs kept in the status file, newest first
+const HISTORY: usize = 10;
+// Progress is written out at most this often, so keeping the file up to date doesn't slow the backup down
+const WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+static ACTIVE: Mutex<Option<Tracker>> = Mutex::new(None);
+
+// What `athena status` shows: the runs in progress and the last few that finished. Times are unix seconds
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Status {
+    #[serde(default)]
+    pub running: Vec<Running>,
+    #[serde(default)]
+    pub runs: Vec<Run>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Running {
+    pub pid: u32,
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub started: i64,
+    pub phase: String,
+    pub phase_started: i64,
+    pub files_done: u64,
+    pub files_total: Option<u64>,
+    pub bytes_done: u64,
+    pub bytes_total: Option<u64>,
+    pub updated: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Run {
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub started: i64,
+    pub finished: i64,
+    pub code: i32,
+    pub files: Option<u64>,
+    pub archive: Option<String>,
+    pub uploaded: Option<String>,
+    pub error: Option<String>,
+}
+
+// Where the status of scheduled runs is kept unless --status-file says otherwise. Services installed with
+// --system run as root, so root's is the system-wide one
+pub fn default_path() -> PathBuf {
+    #[cfg(unix)]
+    if unsafe { libc::geteuid() } == 0 {
+        return PathBuf::from("/var/lib/athena/status.json");
+    }
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".local/state"),
+    };
+    base.join("athena/status.json")
+}
+
+// This run's entry, kept up to date in the file as it goes, if there is one
+struct Tracker {
+    path: Option<PathBuf>,
+    running: Running,
+    outcome: Outcome,
+    written: Instant,
+    warned: bool,
+}
+
+// What a run did, recorded in its history entry once it finishes
+#[derive(Default)]
+pub struct Outcome {
+    pub files: Option<u64>,
+    pub archive: Option<PathBuf>,
+    pub uploaded: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Tracker {
+    fn write(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let running = self.running.clone();
+        if let Err(e) = save(path, |status| status.running.push(running)) {
+            if !self.warned {
+                eprintln!("Warning: failed to write status to {}: {}", path.display(), e);
+                self.warned = true;
+            }
+        }
+        self.written = Instant::now();
+    }
+}
+
+// Starts keeping track of this run, in `path` if given. Either way it's there for a snapshot on SIGUSR1
+pub fn start(path: Option<PathBuf>, source: String, profile: Option<String>) {
+    let now = chrono::Local::now().timestamp();
+    let running = Running {
+        pid: std::process::id(),
+        source,
+        profile,
+        started: now,
+        phase: "Starting".to_string(),
+        phase_started: now,
+        files_done: 0,
+        files_total: None,
+        bytes_done: 0,
+        bytes_total: None,
+        updated: now,
+    };
+    let mut tracker = Tracker { path, running, outcome: Outcome::default(), written: Instant::now(), warned: false };
+    tracker.write();
+    *ACTIVE.lock().unwrap() = Some(tracker);
+}
+
+pub fn active() -> bool {
+    ACTIVE.lock().unwrap().is_some()
+}
+
+// Whether the run's status is being written to a file for others to see
+pub fn writing() -> bool {
+    ACTIVE.lock().unwrap().as_ref().is_some_and(|tracker| tracker.path.is_some())
+}
+
+// The run's progress so far, as `athena status` would show it
+pub fn snapshot() -> Option<String> {
+    ACTIVE.lock().unwrap().as_ref().map(|tracker| {
+        let mut running = tracker.running.clone();
+        running.updated = chrono::Local::now().timestamp();
+        running.text()
+    })
+}
+
+// Moves the run on to a new phase, starting its counts over. Written straight away, since phases are few
+pub fn phase(phase: &str, files_total: Option<u64>, bytes_total: Option<u64>) {
+    if let Some(tracker) = ACTIVE.lock().unwrap().as_mut() {
+        let now = chrono::Local::now().timestamp();
+        tracker.running.phase = phase.trim_end_matches("...").to_string();
+        tracker.running.phase_started = now;
+        tracker.running.updated = now;
+        tracker.running.files_done = 0;
+        tracker.running.files_total = files_total;
+        tracker.running.bytes_done = 0;
+        tracker.running.bytes_total = bytes_total;
+        tracker.write();
+    }
+}
+
+// Changes the run's progress, writing it out if it hasn't been for a while
+pub fn progress(f: impl FnOnce(&mut Running)) {
+    if let Some(tracker) = ACTIVE.lock().unwrap().as_mut() {
+        f(&mut tracker.running);
+        if tracker.written.elapsed() >= WRITE_INTERVAL {
+            tracker.running.updated = chrono::Local::now().timestamp();
+            tracker.write();
+        }
+    }
+}
+
+// Records something about how the run turned out
+pub fn update(f: impl FnOnce(&mut Outcome)) {
+    if let Some(tracker) = ACTIVE.lock().unwrap().as_mut() {
+        f(&mut tracker.outcome);
+    }
+}
+
+// Moves the run into the history now it's finished with `code`
+pub fn finish(code: i32) {
+    let Some(Tracker { path: Some(path), running, outcome, .. }) = ACTIVE.lock().unwrap().take() else {
+        return;
+    };
+    let run = Run {
+        source: running.source,
+        profile: running.profile,
+        started: running.started,
+        finished: chrono::Local::now().timestamp(),
+        code,
+        files: outcome.files,
+        archive: outcome.archive.map(|archive| archive.display().to_string()),
+        uploaded: outcome.uploaded,
+        error: outcome.error,
+    };
+    if let Err(e) = save(&path, |status| {
+        status.runs.insert(0, run);
+        status.runs.truncate(HISTORY);
+    }) {
+        eprintln!("Warning: failed to write status to {}: {}", path.display(), e);
+    }
+}
+
+// Passes progress on to `inner` while keeping the status file up to date with it
+pub struct StatusProgress {
+    inner: Arc<dyn Progress>,
+    // Size of the files being archived, which the progress itself doesn't know
+    bytes_total: Option<u64>,
+}
+
+// Wraps the run's progress observer if its status is being kept
+pub fn track(inner: Arc<dyn Progress>, bytes_total: Option<u64>) -> Arc<dyn Progress> {
+    match active() {
+        true => Arc::new(StatusProgress { inner, bytes_total }),
+        false => inner,
+    }
+}
+
+impl Progress for StatusProgress {
+    fn on_phase(&self, name: &str, total: Option<u64>) {
+        phase(name, total, self.bytes_total);
+        self.inner.on_phase(name, total);
+    }
+
+    fn on_found(&self, total: u64) {
+        self.inner.on_found(total);
+    }
+
+    fn on_file(&self, path: &Path, size: u64) {
+        progress(|running| running.files_done += 1);
+        self.inner.on_file(path, size);
+        if crate::signals::verbose() {
+            self.inner.on_message(&format!("{} ({})", path.display(), utils::format_size(size)));
+        }
+    }
+
+    fn on_bytes(&self, bytes: u64) {
+        progress(|running| running.bytes_done += bytes);
+        self.inner.on_bytes(bytes);
+    }
+
+    fn on_warning(&self, message: &str) {
+        self.inner.on_warning(message);
+    }
+
+    fn on_message(&self, message: &str) {
+        self.inner.on_message(message);
+    }
+}
+
+pub fn load(path: &Path) -> Result<Status, Box<dyn Error>> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+// Changes the status file under a lock, so runs of different profiles sharing it don't lose each other's updates.
+// Entries of runs that died without finishing are dropped, along with this run's, for `f` to put back
+fn save(path: &Path, f: impl FnOnce(&mut Status)) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let _lock = lock(path)?;
+    let mut status = match load(path) {
+        Ok(status) => status,
+        Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => Status::default(),
+        Err(e) => return Err(format!("existing status is unreadable: {}", e).into()),
+    };
+    let pid = std::process::id();
+    status.running.retain(|running| running.pid != pid && alive(running.pid));
+    f(&mut status);
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(format!(".{}.tmp", pid));
+    fs::write(&temp, serde_json::to_vec_pretty(&status)?)?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn lock(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::io::AsRawFd;
+    let mut lock_path = path.as_os_str().to_os_string();
+    lock_path.push(".lock");
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(PathBuf::from(lock_path))?;
+    // Released when the file's closed
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn lock(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Whether a run's process is still there, as far as can be told
+pub fn alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        unsafe { libc::kill(pid as libc::pid_t, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+fn local_time(timestamp: i64) -> String {
+    chrono::Local.timestamp_opt(timestamp, 0).single().map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default()
+}
+
+fn format_seconds(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    match seconds {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+impl Running {
+    // How far through the current phase the run is, by bytes where the total's known and by files otherwise
+    fn fraction(&self) -> Option<f64> {
+        match (self.bytes_total, self.files_total) {
+            (Some(total), _) if total > 0 && self.bytes_done > 0 => Some((self.bytes_done as f64 / total as f64).min(1.)),
+            (_, Some(total)) if total > 0 => Some((self.files_done as f64 / total as f64).min(1.)),
+            _ => None,
+        }
+    }
+
+    // Seconds left in the current phase, going by how fast it's gone so far
+    pub fn eta(&self) -> Option<i64> {
+        let fraction = self.fraction().filter(|fraction| *fraction > 0.)?;
+        let elapsed = (self.updated - self.phase_started) as f64;
+        Some((elapsed / fraction * (1. - fraction)).round() as i64)
+    }
+
+    pub fn text(&self) -> String {
+        let now = chrono::Local::now().timestamp();
+        let mut text = format!("Backup of {}", self.source);
+        if let Some(profile) = &self.profile {
+            text.push_str(&format!(" for profile {}", profile));
+        }
+        text.push_str(&format!(" (pid {}", self.pid));
+        text.push_str(match alive(self.pid) {
+            true => ")\n",
+            false => ", no longer running)\n",
+        });
+        text.push_str(&format!("  {:<12}{}\n", "Started:", local_time(self.started)));
+        text.push_str(&format!("  {:<12}{} for {}\n", "Phase:", self.phase, format_seconds(now - self.phase_started)));
+        let mut files = match self.files_total {
+            Some(total) => format!("{} of {} files", self.files_done, total),
+            None => format!("{} files", self.files_done),
+        };
+        match self.bytes_total {
+            Some(total) => files.push_str(&format!(", {} of {}", utils::format_size(self.bytes_done), utils::format_size(total))),
+            None if self.bytes_done > 0 => files.push_str(&format!(", {}", utils::format_size(self.bytes_done))),
+            None => {},
+        }
+        if let Some(fraction) = self.fraction() {
+            files.push_str(&format!(" ({:.0}%)", fraction * 100.));
+        }
+        text.push_str(&format!("  {:<12}{}\n", "Progress:", files));
+        if let Some(eta) = self.eta() {
+            text.push_str(&format!("  {:<12}{}\n", "ETA:", format_seconds(eta - (now - self.updated))));
+        }
+        text.push_str(&format!("  {:<12}{}\n", "Updated:", local_time(self.updated)));
+        text
+    }
+}
+
+impl Run {
+    pub fn result(&self) -> &'static str {
+        match self.code {
+            0 if self.error.is_some() => "failed",
+            0 => "succeeded",
+            130 => "cancelled",
+            crate::errors::PARTIAL_FAILURE => "finished with errors",
+            _ => "failed",
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let source = match &self.profile {
+            Some(profile) => format!("{} [{}]", self.source, profile),
+            None => self.source.clone(),
+        };
+        let mut text = format!("{}  {:<20} {} ({})", local_time(self.finished), self.result(), source, format_seconds(self.finished - self.started));
+        if let Some(files) = self.files {
+            text.push_str(&format!(", {} files", files));
+        }
+        if let Some(uploaded) = self.uploaded.as_ref().or(self.archive.as_ref()) {
+            text.push_str(&format!(" -> {}", uploaded));
+        }
+        if let Some(error) = &self.error {
+            text.push_str(&format!("\n    {}", error));
+        }
+        text
+    }
+}
@@ -0,0 +1,155 @@
+use std::{fs, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, thread, time::Duration, error::Error};
+use indicatif::ProgressBar;
+use crate::backend::{self, Backend, RemoteOptions};
+
+// Where a repository's files live. Keys are '/'-separated paths relative to the repository root
+pub trait Store {
+    // Human-readable location, e.g. a directory or b2://bucket/prefix
+    fn location(&self) -> String;
+    // Whether reads are slow enough that metadata is worth caching locally
+    fn is_remote(&self) -> bool;
+    fn read(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>>;
+    // Bytes `start` up to `end` of the object, which may come back short if the object is
+    fn read_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, Box<dyn Error>>;
+    // Writes atomically, so an interrupted write never leaves a partial object under the key
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>>;
+    fn exists(&self, key: &str) -> Result<bool, Box<dyn Error>>;
+    // Keys and sizes of everything under the prefix
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>>;
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>>;
+}
+
+// Opens a repository location: a URL understood by the backends, or a local directory
+pub fn open(location: &str) -> Result<Box<dyn Store>, Box<dyn Error>> {
+    match RemoteOptions::from_url(location)? {
+        Some(remote) => Ok(Box::new(RemoteStore { backend: backend::connect(&remote)?, remote })),
+        None => Ok(Box::new(LocalStore { root: PathBuf::from(location) })),
+    }
+}
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl Store for LocalStore {
+    fn location(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn is_remote(&self) -> bool {
+        false
+    }
+
+    fn read(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(fs::read(self.root.join(key))?)
+    }
+
+    fn read_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut file = fs::File::open(self.root.join(key))?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = Vec::new();
+        file.take(end.saturating_sub(start)).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let path = self.root.join(key);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.root.join(key).exists())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+        let mut objects = Vec::new();
+        let dir = self.root.join(prefix.trim_end_matches('/'));
+        if dir.is_dir() {
+            list_dir(&self.root, &dir, &mut objects)?;
+        }
+        Ok(objects)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(self.root.join(key))?;
+        Ok(())
+    }
+}
+
+fn list_dir(root: &Path, dir: &Path, objects: &mut Vec<(String, u64)>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if metadata.is_dir() {
+            list_dir(root, &entry.path(), objects)?;
+        } else if !name.ends_with(".tmp") {
+            // Leftovers from interrupted writes are skipped
+            objects.push((entry.path().strip_prefix(root)?.to_string_lossy().replace('\\', "/"), metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+// A repository in a bucket, under the remote's prefix
+pub struct RemoteStore {
+    backend: Box<dyn Backend>,
+    remote: RemoteOptions,
+}
+
+impl Store for RemoteStore {
+    fn location(&self) -> String {
+        self.backend.url(&self.remote.key_for(""))
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+
+    fn read(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.backend.download(&self.remote.key_for(key))
+    }
+
+    fn read_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.backend.download_range(&self.remote.key_for(key), start, end)
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let key = self.remote.key_for(key);
+        let mut attempt = 1;
+        loop {
+            match self.backend.upload_stream(&mut &data[..], &key, &[], &ProgressBar::hidden()) {
+                Ok(_) => return Ok(()),
+                Err(_) if attempt < backend::UPLOAD_ATTEMPTS => {
+                    thread::sleep(Duration::from_secs(2_u64.pow(attempt - 1)));
+                    attempt += 1;
+                },
+                Err(e) => return Err(format!("Failed to upload {}: {}", self.backend.url(&key), e).into()),
+            }
+        }
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, Box<dyn Error>> {
+        let key = self.remote.key_for(key);
+        Ok(self.backend.list(&key)?.iter().any(|(found, _)| *found == key))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+        let base = self.remote.key_for("");
+        Ok(self
+            .backend
+            .list(&self.remote.key_for(prefix))?
+            .into_iter()
+            .filter_map(|(key, size)| key.strip_prefix(&base).map(|key| (key.to_string(), size)))
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.backend.delete(&self.remote.key_for(key))
+    }
+}
@@ -1,44 +1,232 @@
-use std::{fmt::Write, time::Duration};
-use indicatif::{ProgressBar, ProgressStyle, HumanDuration, ProgressState};
+use std::{fmt::Write, fs, path::Path, sync::atomic::{AtomicBool, Ordering}, thread, time::Duration, error::Error};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle, HumanDuration, ProgressState};
 
-#[derive(Clone)]
+// Set when stderr isn't a terminal (or with --no-tty), so progress is logged as plain lines instead of redrawn bars
+static PLAIN_PROGRESS: AtomicBool = AtomicBool::new(false);
+// How often plain progress lines are logged for work that's still running
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Default)]
 pub struct Options {
     pub verbose: bool,
     pub upload: bool,
     pub compression: bool,
+    // Patterns whose files are compressed differently from the rest
+    pub compress_overrides: Vec<crate::format::CompressOverride>,
+    pub seekable: bool,
+    pub footer_index: bool,
+    pub max_memory: Option<u64>,
+    pub mmap_threshold: Option<u64>,
+    // Read buffer for source files, the default for local filesystems if not set
+    pub buffer_size: Option<usize>,
+    // Shared by every source file read, to keep the run's disk reads under --limit-read
+    pub limit_read: Option<crate::ratelimit::RateLimiter>,
+    pub file_progress_threshold: Option<u64>,
+    pub hash: Option<crate::hash::HashAlgorithm>,
+    pub on_invalid: crate::validate::InvalidPolicy,
+    // Size of each write when the destination is a device, the device module's default if not set
+    pub block_size: Option<usize>,
+    pub state_path: Option<std::path::PathBuf>,
+    pub rescan: bool,
+    pub full_every: Option<Duration>,
+    // This run's place in its incremental chain, e.g. `full-2024-06-01.2`, which goes in the archive's name
+    pub chain: Option<String>,
+    pub trust_mtime: bool,
+    pub full_hash_every: Duration,
+    pub format: crate::format::ArchiveFormat,
+    pub password: Option<String>,
+    pub no_local_copy: bool,
+    pub spool: Option<std::path::PathBuf>,
+    pub placeholder_on_error: bool,
+    pub busy_retries: u32,
+    pub on_busy: crate::busy::BusyPolicy,
+    pub fsync: bool,
+    pub min_free_space: Option<u64>,
+    pub deadline: Option<crate::deadline::Deadline>,
+    // Leave out files that can't be read, recording them in `errors`, rather than failing the run
+    pub keep_going: bool,
+    pub errors: crate::errors::ErrorLog,
+    pub windows_metadata: bool,
+    pub path_style: crate::pathstyle::PathStyle,
+    pub remote: crate::backend::RemoteOptions,
+    pub host: Option<crate::host::Host>,
+    pub comment: Option<String>,
+    pub tags: Vec<String>,
+    // Archive paths of files deleted since the previous incremental run
+    pub deleted: Vec<String>,
     pub input_path: std::path::PathBuf,
+    // The archive's name and the key it's uploaded under, settled before anything's written
+    pub file_name: std::ffi::OsString,
+    pub remote_key: String,
+    pub sources: Vec<std::path::PathBuf>,
+    // What the sources are read through
+    pub fs: crate::sourcefs::SharedFs,
     pub output_path: std::path::PathBuf,
+    pub cancel: crate::cancel::CancellationToken,
+    pub prompter: crate::prompt::SharedPrompter,
 }
 
-// Generic util for prompting user for y/n input
-pub fn prompt_user(message: String, prompt: String, default: Option<bool>) -> bool {
-    let default = match default {
-        Some(true) => "y",
-        Some(false) => "n",
-        None => "n",
-    };
-    eprintln!("{}", message);
-    eprint!("{} {} ", prompt, if default == "y" { "[Y/n]" } else { "[y/N]" });
-    let mut input = String::new();
-    loop {
-        std::io::stdin().read_line(&mut input).unwrap();
-        let answer = match input.trim().to_lowercase().as_str() {
-            "y" => "y",
-            "yes" => "y",
-            "n" => "n",
-            "no" => "n",
-            "" => default,
-            _ => {
-                eprint!("Invalid input, please enter 'y' or 'n': ");
-                input.clear();
-                continue;
+impl Options {
+    // Whether a source file that failed to open should be left out of the archive rather than failing the run
+    pub fn skips(&self, e: &std::io::Error) -> bool {
+        (crate::busy::is_busy(e) && self.on_busy == crate::busy::BusyPolicy::Skip) || (self.keep_going && !self.placeholder_on_error)
+    }
+}
+
+// Reads a password from a file, the environment variable `env`, or prompts for it without echoing. With `confirm`
+// a prompted password is asked for twice, to catch typos
+pub fn read_secret(file: Option<&Path>, env: &str, prompt: &str, confirm: bool) -> Result<String, Box<dyn Error>> {
+    let secret = match (file, std::env::var(env)) {
+        (Some(path), _) => fs::read_to_string(path)?.trim_end_matches(['\r', '\n']).to_string(),
+        (None, Ok(secret)) => secret,
+        (None, Err(_)) => {
+            let secret = rpassword::prompt_password(format!("{}: ", prompt))?;
+            if confirm && rpassword::prompt_password("Confirm password: ")? != secret {
+                return Err("Passwords do not match".into());
             }
+            secret
+        },
+    };
+    if secret.is_empty() {
+        return Err("Password must not be empty".into());
+    }
+    Ok(secret)
+}
+
+// Where athena keeps data that's safe to delete, such as local copies of remote repository metadata
+pub fn cache_dir() -> std::path::PathBuf {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".cache"),
+    };
+    base.join("athena")
+}
+
+// Flushes a finished file to disk, along with the directory entry for it, so a crash or power cut straight
+// afterwards can't lose a file that was reported as written
+pub fn fsync(path: &Path) -> std::io::Result<()> {
+    fs::File::open(path)?.sync_all()?;
+    // Directories can only be opened and synced like this on unix
+    #[cfg(unix)]
+    {
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Parses a human-readable size such as "512", "64K", "1.5GB" or "1MiB" into bytes.
+// Decimal suffixes (K, KB, M, MB...) are powers of 1000, binary ones (KiB, MiB...) powers of 1024
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: '{}'", input))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000_u64.pow(2),
+        "g" | "gb" => 1000_u64.pow(3),
+        "t" | "tb" => 1000_u64.pow(4),
+        "ki" | "kib" => 1024,
+        "mi" | "mib" => 1024_u64.pow(2),
+        "gi" | "gib" => 1024_u64.pow(3),
+        "ti" | "tib" => 1024_u64.pow(4),
+        _ => return Err(format!("Invalid size unit: '{}'", unit)),
+    };
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+// Parses a duration such as "90s", "30m", "12h", "7d" or "2w"
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid duration: '{}'", input))?;
+    let seconds = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Invalid duration unit: '{}' (expected s, m, h, d or w)", unit)),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+// Formats a byte count using the same decimal units as the rest of the output
+pub fn format_size(bytes: u64) -> String {
+    let bytes = bytes as f64;
+    match bytes {
+        b if b > 1000000000000. => format!("{:.2}TB", b / 1000000000000.),
+        b if b > 1000000000. => format!("{:.2}GB", b / 1000000000.),
+        b if b > 1000000. => format!("{:.2}MB", b / 1000000.),
+        b if b > 1000. => format!("{:.2}KB", b / 1000.),
+        b => format!("{}B", b),
+    }
+}
+
+// Returns the space available to unprivileged users on the filesystem containing the given path
+#[cfg(unix)]
+pub fn available_space(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &std::path::Path) -> Option<u64> {
+    None
+}
+
+pub fn set_plain_progress(plain: bool) {
+    PLAIN_PROGRESS.store(plain, Ordering::Relaxed);
+}
+
+pub fn plain_progress() -> bool {
+    PLAIN_PROGRESS.load(Ordering::Relaxed)
+}
+
+// Where bars are drawn, given the current output mode
+pub fn progress_target() -> ProgressDrawTarget {
+    if plain_progress() {
+        ProgressDrawTarget::hidden()
+    } else {
+        ProgressDrawTarget::stderr()
+    }
+}
+
+// In plain mode, hides the bar and logs a line describing it every PLAIN_PROGRESS_INTERVAL until it's finished or dropped
+fn log_progress(bar: ProgressBar, bytes: bool) -> ProgressBar {
+    if !plain_progress() {
+        return bar;
+    }
+    bar.set_draw_target(ProgressDrawTarget::hidden());
+    let weak = bar.downgrade();
+    thread::spawn(move || loop {
+        thread::sleep(PLAIN_PROGRESS_INTERVAL);
+        let bar = match weak.upgrade() {
+            Some(bar) if !bar.is_finished() => bar,
+            _ => break,
         };
-        if answer == "y" || answer == "n" {
-            return answer == "y";
+        let position = |n: u64| if bytes { format_size(n) } else { n.to_string() };
+        match bar.length() {
+            Some(len) => eprintln!(
+                "{} {}/{} ({}%)",
+                bar.message(),
+                position(bar.position()),
+                position(len),
+                bar.position() * 100 / len.max(1)
+            ),
+            None => eprintln!("{}", bar.message()),
         }
-    }
-    
+    });
+    bar
 }
 
 pub fn construct_progress(len: u64) -> ProgressBar {
@@ -51,7 +239,7 @@ pub fn construct_progress(len: u64) -> ProgressBar {
                     w,
                     "~{:#}",
                     HumanDuration(Duration::from_millis(
-                        (s.elapsed().as_millis() * (len as u128 - pos as u128) / (std::cmp::max(1 as u128, pos as u128)))
+                        (s.elapsed().as_millis() * (len as u128 - pos as u128) / (std::cmp::max(1_u128, pos as u128)))
                             as u64
                     ))
                 )
@@ -73,7 +261,19 @@ pub fn construct_progress(len: u64) -> ProgressBar {
         .tick_strings(&[".  ",".. ","..."," ..","  .","   "])
         .progress_chars("=>-");
     bar.set_style(style);
-    bar
+    log_progress(bar, false)
+}
+
+// Byte-level progress for a single large file
+pub fn construct_file_progress(len: u64) -> ProgressBar {
+    let bar = ProgressBar::new(len);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("  {msg:.dim} [{bar:30.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta} remaining)")
+            .unwrap()
+            .progress_chars("=>-"),
+    );
+    log_progress(bar, true)
 }
 
 pub fn construct_spinner() -> ProgressBar {
@@ -84,5 +284,5 @@ pub fn construct_spinner() -> ProgressBar {
             .template("{spinner:.green} {msg}")
             .unwrap(),
     );
-    spinner
+    log_progress(spinner, false)
 }
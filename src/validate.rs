@@ -1,4 +1,16 @@
-use std::{fs, path::PathBuf, error::Error};
+use std::{fs, io::{self, Read}, path::{Path, PathBuf}, error::Error};
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use crate::prompt::Prompter;
+
+// What to do with an archive that fails validation
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidPolicy {
+    Keep,
+    Trash,
+    #[default]
+    Delete,
+}
 
 // Validates input dir / file exists
 pub fn input(input: PathBuf) -> Result<PathBuf, Box<dyn Error>> {
@@ -8,22 +20,43 @@ pub fn input(input: PathBuf) -> Result<PathBuf, Box<dyn Error>> {
     Ok(input)
 }
 
+// Resolves a glob pattern given as input (e.g. '/home/*/Documents') into the matching paths, along with the
+// pattern's literal leading directory, which matched entries are stored relative to in the archive
+pub fn glob_input(pattern: &str) -> Result<(PathBuf, Vec<PathBuf>), Box<dyn Error>> {
+    let cwd = std::env::current_dir()?;
+    let absolute = cwd.join(pattern);
+    let mut base = PathBuf::new();
+    for component in absolute.components() {
+        if is_glob(&component.as_os_str().to_string_lossy()) {
+            break;
+        }
+        base.push(component);
+    }
+
+    let mut matches = Vec::new();
+    for entry in glob::glob(&absolute.to_string_lossy())? {
+        matches.push(entry?);
+    }
+    if matches.is_empty() {
+        return Err(format!("No files or directories match '{}'", pattern).into());
+    }
+    Ok((base, matches))
+}
+
+// Whether the input contains glob metacharacters and should be expanded rather than used as a path
+pub fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
 // Validates output dir is valid
-pub fn output(output: PathBuf) -> Result<PathBuf, Box<dyn Error>> {
+pub fn output(output: PathBuf, prompter: &dyn Prompter) -> Result<PathBuf, Box<dyn Error>> {
     // If output doesn't exist, we should prompt the user whether to create it
     if !output.exists() {
-        if output.is_file() {
-            if output.parent().unwrap().exists() {
-                return Ok(output);
-            }
+        if output.is_file() && output.parent().unwrap().exists() {
+            return Ok(output);
         }
-        eprintln!("Output directory does not exist: '{}'", output.display());
-        eprint!("Create it? [y/N] ");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-
-        if input.trim().to_lowercase() == "y" {
-            fs::create_dir(&output).unwrap();
+        if prompter.confirm(&format!("Output directory does not exist: '{}'", output.display()), "Create it?", false) {
+            fs::create_dir_all(&output).map_err(|e| format!("Failed to create {}: {}", output.display(), e))?;
         } else {
             return Err("Output directory does not exist".into())
         }
@@ -31,21 +64,142 @@ pub fn output(output: PathBuf) -> Result<PathBuf, Box<dyn Error>> {
     Ok(output)
 }
 
-// Validates the generated archive file to ensure files were written and archive is a valid tar.gzip file
-pub fn archive(out: PathBuf) -> Result<PathBuf, Box<dyn Error>> {
+// Validates the generated archive file to ensure files were written and it's an archive athena can read back
+pub fn archive(out: PathBuf, on_invalid: InvalidPolicy, password: Option<&str>) -> Result<PathBuf, Box<dyn Error>> {
     if !out.exists() {
         return Err("Failed to write archive".into());
     }
     if out.metadata()?.len() == 0 {
-        fs::remove_file(&out)?;
-        return Err("No files were processed".into());
+        return Err(handle_invalid(&out, on_invalid, "No files were processed"));
     }
-    let mut file = std::fs::File::open(&out)?;
-    let mut buf = [0; 2];
-    std::io::Read::read_exact(&mut file, &mut buf)?;
-    if buf != [0x1f, 0x8b] {
-        fs::remove_file(&out)?;
-        return Err("Invalid archive".into());
+    // Squashfs images are for mounting and cpio archives for cpio, so they only need to look whole: a squashfs
+    // superblock in bounds, and cpio headers all the way to the trailer
+    let open = || fs::File::open(&out).map(io::BufReader::new);
+    let valid = match detect(&out)? {
+        Some(ArchiveKind::Squashfs) => crate::squashfs::check(&out).is_ok(),
+        Some(ArchiveKind::Cpio) => crate::cpio::check(open()?).is_ok(),
+        Some(ArchiveKind::Gzip) if crate::cpio::is_cpio(MultiGzDecoder::new(open()?)) => crate::cpio::check(MultiGzDecoder::new(open()?)).is_ok(),
+        // Sealed tars are decrypted through, as a chunk that doesn't authenticate makes the whole archive unreadable
+        Some(ArchiveKind::Sealed) => password.is_some_and(|password| crate::crypto::check_sealed(&out, password).is_ok()),
+        kind => readable(kind).is_ok(),
+    };
+    if !valid {
+        return Err(handle_invalid(&out, on_invalid, "Invalid archive"));
     }
     Ok(out)
 }
+
+// Archive formats told apart by their first bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Tar,
+    Gzip,
+    Zstd,
+    Xz,
+    Zip,
+    Squashfs,
+    Cpio,
+    // A tar or tgz encrypted with --encrypt, see crypto::SealWriter
+    Sealed,
+}
+
+impl ArchiveKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArchiveKind::Tar => "tar",
+            ArchiveKind::Gzip => "gzip",
+            ArchiveKind::Zstd => "zstd",
+            ArchiveKind::Xz => "xz",
+            ArchiveKind::Zip => "zip",
+            ArchiveKind::Squashfs => "squashfs",
+            ArchiveKind::Cpio => "cpio",
+            ArchiveKind::Sealed => "sealed",
+        }
+    }
+}
+
+// Works out what kind of archive a file is, or None if it doesn't look like one
+pub fn detect(path: &Path) -> io::Result<Option<ArchiveKind>> {
+    let mut header = Vec::with_capacity(512);
+    fs::File::open(path)?.take(512).read_to_end(&mut header)?;
+    Ok(kind_of(&header))
+}
+
+fn kind_of(header: &[u8]) -> Option<ArchiveKind> {
+    match header {
+        _ if crate::crypto::is_sealed(header) => Some(ArchiveKind::Sealed),
+        [0x1f, 0x8b, ..] => Some(ArchiveKind::Gzip),
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(ArchiveKind::Zstd),
+        [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Some(ArchiveKind::Xz),
+        // A local file header, or the end of central directory record of a zip with nothing in it
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => Some(ArchiveKind::Zip),
+        [b'h', b's', b'q', b's', ..] => Some(ArchiveKind::Squashfs),
+        [b'0', b'7', b'0', b'7', b'0', b'1', ..] => Some(ArchiveKind::Cpio),
+        // Both ustar and GNU headers carry "ustar" at offset 257
+        _ if header.get(257..262) == Some(b"ustar") => Some(ArchiveKind::Tar),
+        // A tar with no entries is just its zeroed end-of-archive blocks
+        _ if header.len() == 512 && header.iter().all(|&b| b == 0) => Some(ArchiveKind::Tar),
+        _ => None,
+    }
+}
+
+// The kinds of archive athena can read, with an error for the rest
+pub fn readable(kind: Option<ArchiveKind>) -> Result<ArchiveKind, Box<dyn Error>> {
+    match kind {
+        Some(kind @ (ArchiveKind::Zstd | ArchiveKind::Xz)) => Err(format!("{} compressed archives aren't supported", kind.name()).into()),
+        Some(ArchiveKind::Squashfs) => Err("Squashfs images aren't read by athena, mount them instead (mount -o loop,ro)".into()),
+        Some(ArchiveKind::Cpio) => Err("Cpio archives aren't read by athena, extract them with cpio instead (cpio -idv < archive)".into()),
+        Some(kind) => Ok(kind),
+        None => Err("Not a tar, tgz or zip archive".into()),
+    }
+}
+
+// Applies the invalid-archive policy, returning the error to report
+fn handle_invalid(out: &Path, on_invalid: InvalidPolicy, reason: &str) -> Box<dyn Error> {
+    let result = match on_invalid {
+        InvalidPolicy::Keep => Ok(format!("{} (kept at {})", reason, out.display())),
+        InvalidPolicy::Trash => {
+            let mut trashed = out.as_os_str().to_owned();
+            trashed.push(".invalid");
+            fs::rename(out, &trashed).map(|_| format!("{} (moved to {})", reason, PathBuf::from(trashed).display()))
+        },
+        InvalidPolicy::Delete => fs::remove_file(out).map(|_| reason.to_string()),
+    };
+    match result {
+        Ok(message) => message.into(),
+        Err(e) => format!("{} (failed to clean up {}: {})", reason, out.display(), e).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_archives_are_trashed_kept_or_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("backup.tgz");
+
+        fs::write(&out, "not an archive").unwrap();
+        let e = archive(out.clone(), InvalidPolicy::Trash, None).unwrap_err();
+        let trashed = dir.path().join("backup.tgz.invalid");
+        assert_eq!(e.to_string(), format!("Invalid archive (moved to {})", trashed.display()));
+        assert!(!out.exists());
+        assert_eq!(fs::read_to_string(&trashed).unwrap(), "not an archive");
+
+        // Empty output is invalid too, and trashed over an earlier one
+        fs::write(&out, "").unwrap();
+        let e = archive(out.clone(), InvalidPolicy::Trash, None).unwrap_err();
+        assert!(e.to_string().starts_with("No files were processed (moved to"), "{}", e);
+        assert_eq!(fs::read_to_string(&trashed).unwrap(), "");
+
+        fs::write(&out, "not an archive").unwrap();
+        let e = archive(out.clone(), InvalidPolicy::Keep, None).unwrap_err();
+        assert_eq!(e.to_string(), format!("Invalid archive (kept at {})", out.display()));
+        assert!(out.exists());
+
+        let e = archive(out.clone(), InvalidPolicy::Delete, None).unwrap_err();
+        assert_eq!(e.to_string(), "Invalid archive");
+        assert!(!out.exists());
+    }
+}
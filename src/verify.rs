@@ -0,0 +1,145 @@
+use std::{collections::BTreeMap, fs, io::{self, BufReader, Read}, path::Path, error::Error};
+use flate2::read::MultiGzDecoder;
+use indicatif::ProgressBar;
+use crate::{crypto, footer, hash::{self, HashAlgorithm}, manifest, utils, validate::{self, ArchiveKind}};
+
+// What reading an archive end to end found
+#[derive(Default)]
+pub struct Report {
+    pub entries: usize,
+    // Entries whose contents were checked against a recorded hash: the footer index's for tars, their CRC for zips
+    pub checked: usize,
+    pub mismatched: usize,
+    // Entries in the footer index that aren't in the archive
+    pub missing: usize,
+    // Files stored empty or zero-filled because they couldn't be read when the archive was made
+    pub placeholders: usize,
+    // The algorithm of the checksum file next to the archive, if there is one, and whether the archive matches it
+    pub checksum: Option<(&'static str, bool)>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.mismatched == 0 && self.missing == 0 && self.checksum.is_none_or(|(_, matched)| matched)
+    }
+}
+
+// Reads the whole archive, failing if it's truncated or corrupt, and checks each entry against the blake3 hash the
+// footer index has for it, and the archive against the checksum file written with --hash, if there is one
+pub fn run(archive: &Path, password_file: Option<&Path>) -> Result<Report, Box<dyn Error>> {
+    let kind = validate::readable(validate::detect(archive)?)?;
+    let progress = utils::construct_file_progress(fs::metadata(archive)?.len());
+    progress.set_message("Verifying");
+    let mut report = Report::default();
+    let result = match kind {
+        ArchiveKind::Zip => verify_zip(archive, password_file, &progress, &mut report),
+        _ => verify_tar(archive, kind, password_file, &progress, &mut report),
+    };
+    progress.finish_and_clear();
+    result.map_err(|e| format!("Archive is corrupt after {} entries: {}", report.entries, e))?;
+    Ok(report)
+}
+
+fn verify_tar(archive: &Path, kind: ArchiveKind, password_file: Option<&Path>, progress: &ProgressBar, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    let mut expected: BTreeMap<String, Option<String>> = BTreeMap::new();
+    if let Some(index) = footer::read(archive)? {
+        expected.extend(index.entries.into_iter().map(|entry| (entry.path, entry.hash)));
+    }
+    let file = progress.wrap_read(fs::File::open(archive)?);
+    match checksum_file(archive)? {
+        Some((algorithm, digest)) => {
+            let mut reader = hash::HashingReader::new(file, algorithm);
+            read_tar(&mut reader, kind, password_file, &mut expected, progress, report)?;
+            report.checksum = Some((algorithm.name(), reader.finish() == digest));
+        },
+        None => read_tar(file, kind, password_file, &mut expected, progress, report)?,
+    }
+    for path in expected.keys() {
+        progress.suspend(|| println!("missing:  {}", path));
+        report.missing += 1;
+    }
+    Ok(())
+}
+
+// Sealed archives are decrypted as they're read, each chunk authenticated on the way
+fn read_tar<R: Read>(reader: R, kind: ArchiveKind, password_file: Option<&Path>, expected: &mut BTreeMap<String, Option<String>>, progress: &ProgressBar, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    let reader: Box<dyn Read> = match kind {
+        ArchiveKind::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(reader))),
+        ArchiveKind::Sealed => {
+            let password = progress.suspend(|| utils::read_secret(password_file, "ATHENA_PASSWORD", "Archive password", false))?;
+            crypto::unseal_tar(reader, &password)?
+        },
+        _ => Box::new(BufReader::new(reader)),
+    };
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        // The footer index and global headers are athena's own, read but not counted as entries
+        if entry.header().entry_type().is_pax_global_extensions() || name == footer::INDEX_NAME {
+            io::copy(&mut entry, &mut io::sink())?;
+            continue;
+        }
+        report.entries += 1;
+        let hash = expected.remove(&name).flatten();
+        if name == manifest::MANIFEST_NAME {
+            let manifest: manifest::Manifest = serde_json::from_reader(&mut entry).map_err(|e| format!("Invalid manifest: {}", e))?;
+            report.placeholders = manifest.placeholders.len();
+            continue;
+        }
+        match hash {
+            Some(hash) if entry.header().entry_type().is_file() => {
+                report.checked += 1;
+                if hash::reader(&mut entry, HashAlgorithm::Blake3)? != hash {
+                    progress.suspend(|| println!("mismatch: {}", name));
+                    report.mismatched += 1;
+                }
+            },
+            _ => {
+                io::copy(&mut entry, &mut io::sink())?;
+            },
+        }
+    }
+    // The rest of the stream, like the final gzip members or the last sealed chunk, has to be read too for its
+    // checksums and tags to be checked
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(())
+}
+
+// Zip entries carry a CRC of their contents, which is checked as each one's read
+fn verify_zip(archive: &Path, password_file: Option<&Path>, progress: &ProgressBar, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(fs::File::open(archive)?))?;
+    let mut password: Option<String> = None;
+    for i in 0..zip.len() {
+        let encrypted = zip.by_index_raw(i)?.encrypted();
+        if encrypted && password.is_none() {
+            password = Some(progress.suspend(|| utils::read_secret(password_file, "ATHENA_PASSWORD", "Archive password", false))?);
+        }
+        let mut entry = match (&password, encrypted) {
+            (Some(password), true) => zip.by_index_decrypt(i, password.as_bytes()).map_err(|_| "Wrong archive password")?,
+            _ => zip.by_index(i)?,
+        };
+        let name = entry.name().to_string();
+        io::copy(&mut entry, &mut io::sink()).map_err(|e| format!("Failed to read {}: {}", name, e))?;
+        report.entries += 1;
+        report.checked += 1;
+        progress.inc(entry.compressed_size());
+    }
+    if let Some((algorithm, digest)) = checksum_file(archive)? {
+        report.checksum = Some((algorithm.name(), hash::file(archive, algorithm)? == digest));
+    }
+    Ok(())
+}
+
+// The digest from the checksum file next to the archive, e.g. backup.tgz.sha256
+fn checksum_file(archive: &Path) -> Result<Option<(HashAlgorithm, String)>, Box<dyn Error>> {
+    let name = archive.file_name().ok_or("Archive has no file name")?.to_string_lossy().to_string();
+    for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Sha256, HashAlgorithm::Xxh3] {
+        let path = archive.with_file_name(format!("{}.{}", name, algorithm.name()));
+        if let Ok(contents) = fs::read_to_string(&path) {
+            let digest = contents.split_whitespace().next().ok_or_else(|| format!("Empty checksum file {}", path.display()))?;
+            return Ok(Some((algorithm, digest.to_lowercase())));
+        }
+    }
+    Ok(None)
+}
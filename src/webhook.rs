@@ -0,0 +1,49 @@
+use std::{sync::Mutex, thread, time::Duration};
+use serde_json::{json, Value};
+
+// Attempts per event and URL before giving up on it, with a short backoff between them
+const ATTEMPTS: u32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+static URLS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn parse_url(input: &str) -> Result<String, String> {
+    match input.starts_with("http://") || input.starts_with("https://") {
+        true => Ok(input.to_string()),
+        false => Err(format!("Webhook URL '{}' must start with http:// or https://", input)),
+    }
+}
+
+// Sets where events are posted for the rest of the run
+pub fn configure(urls: Vec<String>) {
+    *URLS.lock().unwrap() = urls;
+}
+
+// Posts an event, e.g. "scan.complete", to every webhook as JSON, with `data`'s fields alongside the event's name,
+// time and host. A webhook that can't be reached is only warned about, so it never fails the run it's reporting on
+pub fn emit(event: &str, data: Value) {
+    let urls = URLS.lock().unwrap().clone();
+    if urls.is_empty() {
+        return;
+    }
+    let mut payload = json!({
+        "event": event,
+        "time": chrono::Local::now().to_rfc3339(),
+        "host": crate::host::HostOptions::default().resolve().map(|host| host.name),
+    });
+    if let (Some(payload), Value::Object(data)) = (payload.as_object_mut(), data) {
+        payload.extend(data);
+    }
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    for url in urls {
+        let mut attempt = 1;
+        while let Err(e) = agent.post(&url).send_json(&payload) {
+            if attempt == ATTEMPTS {
+                eprintln!("Warning: failed to send {} event to {}: {}", event, url, e);
+                break;
+            }
+            thread::sleep(Duration::from_secs(attempt as u64));
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{fmt, sync::{atomic::{AtomicBool, Ordering}, Mutex}, thread, time::Duration};
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+
+// How often a paused upload checks whether the window's opened
+const POLL: Duration = Duration::from_secs(1);
+
+static WINDOW: Mutex<Option<Window>> = Mutex::new(None);
+// Uploads paused by hand, with a signal
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+// Hours of the day uploads are allowed in, e.g. 22:00-06:00 for overnight. Wraps past midnight when it ends
+// earlier than it starts
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+pub fn parse(input: &str) -> Result<Window, String> {
+    let time = |time: &str| {
+        NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(time.trim(), "%H:%M:%S"))
+            .map_err(|_| format!("Invalid time '{}' in window, expected e.g. 22:00-06:00", time.trim()))
+    };
+    let (start, end) = input.split_once('-').ok_or_else(|| format!("Invalid window '{}', expected e.g. 22:00-06:00", input))?;
+    let window = Window { start: time(start)?, end: time(end)? };
+    if window.start == window.end {
+        return Err(format!("Window '{}' starts and ends at the same time", input));
+    }
+    Ok(window)
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+impl Window {
+    pub fn contains(&self, now: DateTime<Local>) -> bool {
+        let time = now.time();
+        match self.start < self.end {
+            true => self.start <= time && time < self.end,
+            false => time >= self.start || time < self.end,
+        }
+    }
+
+    // When the window next opens after `now`
+    pub fn opens(&self, now: DateTime<Local>) -> DateTime<Local> {
+        let mut date = now.date_naive();
+        loop {
+            if let Some(opens) = Local.from_local_datetime(&date.and_time(self.start)).earliest().filter(|opens| *opens > now) {
+                return opens;
+            }
+            date = date.succ_opt().unwrap_or(date);
+        }
+    }
+}
+
+// Sets the window uploads keep to for the rest of the run
+pub fn configure(window: Option<Window>) {
+    *WINDOW.lock().unwrap() = window;
+}
+
+// Pauses uploads if they're going, or resumes them if they were paused. Returns whether they're now paused
+pub fn toggle_pause() -> bool {
+    !PAUSED.fetch_xor(true, Ordering::Relaxed)
+}
+
+// Blocks while outside the window or paused, so an upload pauses between requests and carries on where it left off
+// once the window reopens. Called before each upload and each part of a large one, never part way through sending
+// one. `stop` is checked while waiting, so a cancelled run doesn't wait for the window to stop
+pub fn wait(stop: &dyn Fn() -> bool) {
+    let window = *WINDOW.lock().unwrap();
+    if let Some(window) = window.filter(|window| !window.contains(Local::now())) {
+        eprintln!("Outside the backup window {}, pausing uploads until {}", window, window.opens(Local::now()).format("%Y-%m-%d %H:%M:%S"));
+        while !window.contains(Local::now()) && !stop() {
+            thread::sleep(POLL);
+        }
+        if !stop() {
+            eprintln!("Backup window {} open, resuming uploads", window);
+        }
+    }
+    if PAUSED.load(Ordering::Relaxed) {
+        eprintln!("Uploads paused, waiting to be resumed");
+        while PAUSED.load(Ordering::Relaxed) && !stop() {
+            thread::sleep(POLL);
+        }
+    }
+}